@@ -0,0 +1,91 @@
+# Deutsche Meldungen für fup. Fehlende Meldungen werden auf Englisch
+# angezeigt (siehe en.ftl).
+
+## Fehler
+
+error-prefix = Fehler:
+error-not-root = Muss als root ausgeführt werden. Verwendung: sudo fup
+error-no-network = Keine Netzwerkverbindung
+error-captive-portal = Der Netzzugang wird von einem Captive Portal blockiert ({ $url }); bitte zuerst im Browser anmelden
+error-command-failed = Befehl fehlgeschlagen: { $cmd }
+error-metadata-fetch = Repository-Metadaten konnten nicht geladen werden: { $cmd }
+error-dependency-problem = Das Update hat ungelöste Abhängigkeiten: { $cmd }
+error-transaction-test = Die Transaktionsprüfung ist fehlgeschlagen: { $cmd }
+error-disk-full = Nicht genug Speicherplatz: { $cmd }
+error-gpg = Eine Paketsignatur konnte nicht geprüft werden: { $cmd }
+error-exit-code = Exit-Code: { $code }
+error-details = Details: { $details }
+error-command-not-found = Befehl nicht gefunden: { $cmd }
+error-config = Konfigurationsfehler: { $details }
+error-io = E/A-Fehler: { $details }
+error-cancelled = Vorgang vom Benutzer abgebrochen
+error-lock-held = Die Paketverwaltung ist beschäftigt: { $holder }
+error-preflight = Vorabprüfung fehlgeschlagen: { $details }
+error-backup = Sicherung vor dem Update fehlgeschlagen: { $details }
+error-timeout = Zeitüberschreitung nach { $mins } Min. ohne Ausgabe: { $cmd }
+error-verification = Die Prüfung nach dem Update hat { $count } Problem(e) gefunden
+error-outside-window = Außerhalb des Wartungsfensters ({ $window }); mit --ignore-window trotzdem ausführen
+error-self-update = Selbstaktualisierung fehlgeschlagen: { $details }
+error-bundle = Update-Bündel abgelehnt: { $details }
+error-mirror = Paketspiegel: { $details }
+error-unexpected-output = Ausgabe von { $cmd } nicht lesbar: { $details }
+
+## Remediation hints, printed after an error
+
+hint-prefix = Tipp:
+hint-metadata-fetch = Prüfe die Netzwerkverbindung. `fup repo-check` zeigt, welches Repository nicht erreichbar ist; ein Drittanbieter-Repository lässt sich mit `sudo dnf5 config-manager setopt <repo>.enabled=0` abschalten.
+hint-dependency-problem = Ein Repository hat Pakete veröffentlicht, die andere, noch nicht veröffentlichte brauchen. Das erledigt sich meist innerhalb eines Tages; bis dahin das Paket mit --exclude zurückhalten.
+hint-transaction-test = Zwei Pakete liefern dieselbe Datei, oft weil zwei Repositories dieselbe Software anbieten. Später erneut versuchen oder eines davon mit --exclude zurückhalten.
+hint-disk-full = Speicherplatz freigeben: `sudo fup clean --apply` entfernt zwischengespeicherte Pakete und alte Journal-Einträge, `[kernel] keep` entfernt alte Kernel.
+hint-gpg = Das Repository hat vielleicht einen neuen Signaturschlüssel: den dort dokumentierten mit `sudo rpm --import` importieren. Die Prüfung bei einem nicht vertrauenswürdigen Repository nie umgehen.
+hint-lock-held = Eine andere Paketverwaltung, etwa GNOME Software, installiert gerade Updates. Warte, bis sie fertig ist, und starte fup erneut.
+
+## Update-Plan und Bestätigung
+
+plan-title = Folgendes wird aktualisiert:
+plan-pending = { $count } ausstehend
+plan-only = nur { $items }
+plan-download = Download
+plan-announce-pending = { $stage }-Updates: { $count } ausstehend.
+plan-announce-only = Nur: { $items }.
+plan-announce-download = Download insgesamt: { $size }.
+confirm-proceed = Fortfahren? [j/N]:
+confirm-yes = j, ja, y, yes
+confirm-no-terminal = kein Terminal für die Bestätigung vorhanden; für unbeaufsichtigte Läufe --assume-yes (-y) angeben
+
+## Neustart
+
+reboot-recommended = Ein Neustart des Systems wird empfohlen.
+reboot-exit = Beenden ohne Neustart
+reboot-choice = Auswahl [1-{ $max }]:
+reboot-skipped = Beendet ohne Neustart.
+
+## Zusammenfassung
+
+summary-title = Zusammenfassung
+summary-system = System (dnf5):
+summary-flatpak = Flatpak:
+summary-firmware = Firmware:
+summary-run = Lauf:
+summary-etc-backup = /etc-Sicherung:
+summary-artifacts = Artefakte:
+summary-download-limit = Download-Limit:
+summary-halted = übersprungen, { $stage } fehlgeschlagen (fail-fast)
+summary-timing = Dauer
+summary-packages = { $count } Paket(e)
+summary-retried-ok = erfolgreich nach { $count } Wiederholung(en)
+summary-retried-failed = fehlgeschlagen nach { $count } Wiederholung(en)
+summary-firmware-devices = Firmware-Geräte
+summary-flatpak-users = Flatpak-Benutzer
+summary-removed = Entfernte Pakete
+summary-package-changes = Paketänderungen
+summary-more = ...und { $count } weitere
+summary-cancelled = Abgebrochen - die übrigen Schritte wurden übersprungen
+summary-errors = Fehler:
+summary-verification = Probleme bei der Prüfung:
+summary-announce-title = Zusammenfassung.
+summary-announce-updated = { $stage }: aktualisiert.
+summary-announce-not-updated = { $stage }: nicht aktualisiert.
+summary-announce-cancelled = Der Lauf wurde abgebrochen; die übrigen Schritte wurden übersprungen.
+summary-announce-errors = { $count } Fehler:
+summary-announce-error = Fehler: { $error }
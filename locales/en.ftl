@@ -0,0 +1,92 @@
+# English messages for fup. Other locales fall back to these for any message
+# they don't define. Placeholders use Fluent syntax: { $name }.
+
+## Errors
+
+error-prefix = Error:
+error-not-root = Must run as root. Use: sudo fup
+error-no-network = No network connectivity
+error-captive-portal = Network access is blocked by a captive portal ({ $url }); log in through a browser first
+error-command-failed = Command failed: { $cmd }
+error-metadata-fetch = Could not download repository metadata: { $cmd }
+error-dependency-problem = The update has unresolved dependencies: { $cmd }
+error-transaction-test = The transaction check failed: { $cmd }
+error-disk-full = Not enough disk space: { $cmd }
+error-gpg = A package signature check failed: { $cmd }
+error-exit-code = Exit code: { $code }
+error-details = Details: { $details }
+error-command-not-found = Command not found: { $cmd }
+error-config = Configuration error: { $details }
+error-io = IO error: { $details }
+error-cancelled = Operation cancelled by user
+error-lock-held = Package manager is busy: { $holder }
+error-preflight = Pre-flight check failed: { $details }
+error-backup = Pre-update backup failed: { $details }
+error-timeout = Command timed out after { $mins } min without output: { $cmd }
+error-verification = Post-update verification found { $count } problem(s)
+error-outside-window = Outside the maintenance window ({ $window }); use --ignore-window to run anyway
+error-self-update = Self-update failed: { $details }
+error-bundle = Update bundle rejected: { $details }
+error-mirror = Package mirror: { $details }
+error-unexpected-output = Could not read the output of { $cmd }: { $details }
+
+## Remediation hints, printed after an error
+
+hint-prefix = Hint:
+hint-metadata-fetch = Check the network connection. `fup repo-check` shows which repository is unreachable; a third-party one can be switched off with `sudo dnf5 config-manager setopt <repo>.enabled=0`.
+hint-dependency-problem = A repository published packages that need others it has not published yet. This usually clears within a day; until then, hold the package back with --exclude.
+hint-transaction-test = Two packages ship the same file, often because two repositories provide the same software. Try again later, or hold one of them back with --exclude.
+hint-disk-full = Free some space: `sudo fup clean --apply` removes cached packages and old journal entries, and `[kernel] keep` removes old kernels.
+hint-gpg = The repository may have a new signing key: import the one it documents with `sudo rpm --import`. Do not bypass the check on a repository you do not trust.
+hint-lock-held = Another package manager, such as GNOME Software, is installing updates. Let it finish and run fup again.
+
+## Update plan and confirmation
+
+plan-title = The following will be updated:
+plan-pending = { $count } pending
+plan-only = only { $items }
+plan-download = Download
+plan-announce-pending = { $stage } updates: { $count } pending.
+plan-announce-only = Only: { $items }.
+plan-announce-download = Total download: { $size }.
+confirm-proceed = Proceed? [y/N]:
+# Comma-separated answers accepted as "yes"
+confirm-yes = y, yes
+confirm-no-terminal = no terminal to confirm the updates on; pass --assume-yes (-y) to run unattended
+
+## Reboot prompt
+
+reboot-recommended = A system reboot is recommended.
+reboot-exit = Exit without rebooting
+reboot-choice = Choice [1-{ $max }]:
+reboot-skipped = Exiting without reboot.
+
+## Summary
+
+summary-title = Update Summary
+summary-system = System (dnf5):
+summary-flatpak = Flatpak:
+summary-firmware = Firmware:
+summary-run = Run:
+summary-etc-backup = /etc backup:
+summary-artifacts = Artifacts:
+summary-download-limit = Download limit:
+summary-halted = skipped, { $stage } failed (fail-fast)
+summary-timing = Timing
+summary-packages = { $count } package(s)
+summary-retried-ok = succeeded after { $count } retry(s)
+summary-retried-failed = failed after { $count } retry(s)
+summary-firmware-devices = Firmware devices
+summary-flatpak-users = Flatpak users
+summary-removed = Removed packages
+summary-package-changes = Package changes
+summary-more = ...and { $count } more
+summary-cancelled = Cancelled - remaining stages were skipped
+summary-errors = Errors:
+summary-verification = Verification problems:
+summary-announce-title = Update summary.
+summary-announce-updated = { $stage }: updated.
+summary-announce-not-updated = { $stage }: not updated.
+summary-announce-cancelled = The run was cancelled; remaining stages were skipped.
+summary-announce-errors = { $count } error(s):
+summary-announce-error = Error: { $error }
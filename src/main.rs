@@ -2,976 +2,19149 @@
 //! A robust tool for automating system, Flatpak, and firmware updates
 //! with proper error handling, logging, and user feedback.
 
-use std::{path::PathBuf, process::ExitCode, sync::Arc, time::Duration};
 use clap::Parser;
 use colored::Colorize;
-use tokio::{process::Command, sync::Mutex};
-use tracing::{debug, error, info, warn, Level};
+use std::{io::IsTerminal, path::Path, process::ExitCode};
+use tokio::process::Command;
+use tracing::{Instrument, debug, error, info, level_filters::LevelFilter, warn};
+use ui::Themed;
+
+/// Looks up a message in the current locale (see `mod i18n`), filling in
+/// `{ $name }` placeholders: `t!("error-timeout", mins = 30, cmd = cmd)`.
+macro_rules! t {
+    ($key:literal) => {
+        $crate::i18n::tr($key, &[])
+    };
+    ($key:literal, $($name:ident = $value:expr),+ $(,)?) => {
+        $crate::i18n::tr($key, &[$((stringify!($name), $value.to_string())),+])
+    };
+}
 
 mod error {
+    use std::fmt;
     use thiserror::Error;
 
+    /// Displayed in the user's language, so the messages live in `locales/`.
     #[derive(Error, Debug)]
     pub enum UpdateError {
-        #[error("Must run as root. Use: sudo fup")]
         NotRoot,
-        #[error("No network connectivity")]
         NoNetwork,
-        #[error("Command failed: {cmd}\n  Exit code: {code}\n  Details: {details}")]
-        CommandFailed { cmd: String, code: i32, details: String },
-        #[error("Command not found: {0}")]
+        CaptivePortal(String),
+        CommandFailed {
+            cmd: String,
+            code: i32,
+            details: String,
+        },
+        /// Failed commands whose output names the cause: `reason` is the line
+        /// that did, `details` the whole output.
+        MetadataFetchFailed {
+            cmd: String,
+            reason: String,
+            details: String,
+        },
+        DependencyProblem {
+            cmd: String,
+            reason: String,
+            details: String,
+        },
+        TransactionTestFailed {
+            cmd: String,
+            reason: String,
+            details: String,
+        },
+        DiskFull {
+            cmd: String,
+            reason: String,
+            details: String,
+        },
+        GpgFailure {
+            cmd: String,
+            reason: String,
+            details: String,
+        },
         CommandNotFound(String),
-        #[error("Configuration error: {0}")]
         Config(String),
-        #[error("IO error: {0}")]
         Io(#[from] std::io::Error),
-        #[error("Operation cancelled by user")]
         Cancelled,
+        LockHeld(String),
+        Preflight(String),
+        Backup(String),
+        Timeout {
+            cmd: String,
+            mins: u64,
+        },
+        Verification(usize),
+        OutsideWindow(String),
+        SelfUpdate(String),
+        Bundle(String),
+        Mirror(String),
+        /// A backend's machine-readable output couldn't be read.
+        UnexpectedOutput {
+            cmd: String,
+            details: String,
+        },
+    }
+
+    impl fmt::Display for UpdateError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let message = match self {
+                Self::NotRoot => t!("error-not-root"),
+                Self::NoNetwork => t!("error-no-network"),
+                Self::CaptivePortal(url) => t!("error-captive-portal", url = url),
+                Self::CommandFailed { cmd, code, details } => format!(
+                    "{}\n  {}\n  {}",
+                    t!("error-command-failed", cmd = cmd),
+                    t!("error-exit-code", code = code),
+                    t!("error-details", details = details)
+                ),
+                Self::MetadataFetchFailed { cmd, reason, .. } => {
+                    format!("{}\n  {}", t!("error-metadata-fetch", cmd = cmd), reason)
+                }
+                Self::DependencyProblem { cmd, reason, .. } => {
+                    format!(
+                        "{}\n  {}",
+                        t!("error-dependency-problem", cmd = cmd),
+                        reason
+                    )
+                }
+                Self::TransactionTestFailed { cmd, reason, .. } => {
+                    format!("{}\n  {}", t!("error-transaction-test", cmd = cmd), reason)
+                }
+                Self::DiskFull { cmd, reason, .. } => {
+                    format!("{}\n  {}", t!("error-disk-full", cmd = cmd), reason)
+                }
+                Self::GpgFailure { cmd, reason, .. } => {
+                    format!("{}\n  {}", t!("error-gpg", cmd = cmd), reason)
+                }
+                Self::CommandNotFound(cmd) => t!("error-command-not-found", cmd = cmd),
+                Self::Config(details) => t!("error-config", details = details),
+                Self::Io(e) => t!("error-io", details = e),
+                Self::Cancelled => t!("error-cancelled"),
+                Self::LockHeld(holder) => t!("error-lock-held", holder = holder),
+                Self::Preflight(details) => t!("error-preflight", details = details),
+                Self::Backup(details) => t!("error-backup", details = details),
+                Self::Timeout { cmd, mins } => t!("error-timeout", mins = mins, cmd = cmd),
+                Self::Verification(count) => t!("error-verification", count = count),
+                Self::OutsideWindow(window) => t!("error-outside-window", window = window),
+                Self::SelfUpdate(details) => t!("error-self-update", details = details),
+                Self::Bundle(details) => t!("error-bundle", details = details),
+                Self::Mirror(details) => t!("error-mirror", details = details),
+                Self::UnexpectedOutput { cmd, details } => {
+                    t!("error-unexpected-output", cmd = cmd, details = details)
+                }
+            };
+            f.write_str(&message)
+        }
+    }
+
+    impl UpdateError {
+        /// The message in English, for what gets stored or parsed: history,
+        /// audit, porcelain events, MQTT and the quiet status line.
+        pub fn english(&self) -> String {
+            crate::i18n::english(|| self.to_string())
+        }
+    }
+
+    /// Lowercase fragments of dnf5, rpm, flatpak and fwupd output, checked in
+    /// order: running out of space breaks downloads and transactions alike.
+    const CAUSES: &[(Cause, &[&str])] = &[
+        (
+            Cause::DiskFull,
+            &[
+                "no space left on device",
+                "not enough free space",
+                "more space on the",
+                "disk quota exceeded",
+            ],
+        ),
+        (
+            Cause::Gpg,
+            &[
+                "openpgp check",
+                "gpg check failed",
+                "signature verification failed",
+                "gpg signatures found, but none",
+                "public key not found",
+                "bad gpg signature",
+                "is not signed",
+            ],
+        ),
+        (
+            Cause::Lock,
+            &[
+                "transaction lock",
+                "another process is holding",
+                "waiting for process with pid",
+            ],
+        ),
+        (
+            Cause::Metadata,
+            &[
+                "failed to download metadata",
+                "cannot download repomd.xml",
+                "failed to synchronize cache",
+                "all mirrors were tried",
+                "cannot prepare internal mirrorlist",
+                "error while fetching",
+            ],
+        ),
+        (
+            Cause::Dependencies,
+            &[
+                "nothing provides",
+                "conflicting requests",
+                "cannot install both",
+                "problem: ",
+                "problem 1: ",
+            ],
+        ),
+        (
+            Cause::TransactionTest,
+            &[
+                "transaction test error",
+                "transaction check error",
+                "conflicts with file from package",
+                "rpm transaction failed",
+            ],
+        ),
+    ];
+
+    #[derive(Clone, Copy)]
+    enum Cause {
+        DiskFull,
+        Gpg,
+        Lock,
+        Metadata,
+        Dependencies,
+        TransactionTest,
+    }
+
+    impl UpdateError {
+        /// The error for a command that exited with `code`: a specific one
+        /// when its output points at a known cause, CommandFailed otherwise.
+        pub fn from_output(cmd: String, code: i32, details: String) -> Self {
+            let found = CAUSES.iter().find_map(|(cause, markers)| {
+                let line = details.lines().find(|l| {
+                    let l = l.to_lowercase();
+                    markers.iter().any(|m| l.contains(m))
+                })?;
+                Some((*cause, line.trim().to_string()))
+            });
+            let Some((cause, reason)) = found else {
+                return Self::CommandFailed { cmd, code, details };
+            };
+            match cause {
+                Cause::DiskFull => Self::DiskFull {
+                    cmd,
+                    reason,
+                    details,
+                },
+                Cause::Gpg => Self::GpgFailure {
+                    cmd,
+                    reason,
+                    details,
+                },
+                Cause::Lock => Self::LockHeld(reason),
+                Cause::Metadata => Self::MetadataFetchFailed {
+                    cmd,
+                    reason,
+                    details,
+                },
+                Cause::Dependencies => Self::DependencyProblem {
+                    cmd,
+                    reason,
+                    details,
+                },
+                Cause::TransactionTest => Self::TransactionTestFailed {
+                    cmd,
+                    reason,
+                    details,
+                },
+            }
+        }
+
+        /// The full output of the command that failed, if one did.
+        pub fn output(&self) -> Option<&str> {
+            match self {
+                Self::CommandFailed { details, .. }
+                | Self::MetadataFetchFailed { details, .. }
+                | Self::DependencyProblem { details, .. }
+                | Self::TransactionTestFailed { details, .. }
+                | Self::DiskFull { details, .. }
+                | Self::GpgFailure { details, .. } => Some(details),
+                _ => None,
+            }
+        }
+
+        /// What the user can do about it, printed after the error.
+        pub fn hint(&self) -> Option<String> {
+            let key = match self {
+                Self::MetadataFetchFailed { .. } => "hint-metadata-fetch",
+                Self::DependencyProblem { .. } => "hint-dependency-problem",
+                Self::TransactionTestFailed { .. } => "hint-transaction-test",
+                Self::DiskFull { .. } => "hint-disk-full",
+                Self::GpgFailure { .. } => "hint-gpg",
+                Self::LockHeld(_) => "hint-lock-held",
+                _ => return None,
+            };
+            Some(crate::i18n::tr(key, &[]))
+        }
     }
 
     pub type Result<T> = std::result::Result<T, UpdateError>;
 }
 
 mod config {
+    use crate::error::{Result, UpdateError};
     use serde::{Deserialize, Serialize};
-    use std::path::PathBuf;
+    use std::{
+        collections::BTreeMap,
+        path::{Path, PathBuf},
+    };
 
-    #[derive(Debug, Clone, Serialize, Deserialize)]
-    #[serde(default)]
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
     pub struct Config {
+        pub run: RunConfig,
         pub system: SystemConfig,
         pub flatpak: FlatpakConfig,
         pub firmware: FirmwareConfig,
         pub logging: LoggingConfig,
         pub network: NetworkConfig,
+        pub history: HistoryConfig,
+        pub power: PowerConfig,
+        pub backup: BackupConfig,
+        pub disk: DiskConfig,
+        pub health: HealthConfig,
+        pub retry: RetryConfig,
+        pub artifacts: ArtifactsConfig,
+        pub kernel: KernelConfig,
+        pub services: ServicesConfig,
+        pub packagekit: PackageKitConfig,
+        pub reboot: RebootConfig,
+        pub notify: NotifyConfig,
+        pub schedule: ScheduleConfig,
+        pub scope: ScopeConfig,
+        pub verify: VerifyConfig,
+        pub clean: CleanConfig,
+        pub audit: AuditConfig,
+        pub telemetry: TelemetryConfig,
+        pub self_update: SelfUpdateConfig,
+        pub bundle: BundleConfig,
+        pub mirror: MirrorConfig,
+        pub ui: UiConfig,
+        /// Named sets of overrides (`[profile.laptop.power]`), picked with
+        /// `--profile`. Applied while loading, so never shown as a section.
+        #[serde(skip_serializing)]
+        pub profile: BTreeMap<String, toml::Table>,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
-    #[serde(default)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct RunConfig {
+        /// Skip the remaining backends once one fails, as with --fail-fast.
+        pub fail_fast: bool,
+        /// Run a failed backend again this many times after the main pass.
+        pub retry_failed: u32,
+        pub retry_delay_secs: u64,
+        /// Pairs of backends --parallel may run at the same time; any other
+        /// pair runs one after the other.
+        pub overlap: Vec<[crate::updater::Stage; 2]>,
+        /// Also write each run's summary JSON here; empty for none.
+        pub summary_file: String,
+        /// Unattended runs stop when the updates would download more than
+        /// this ("2G", "500M"); empty for no limit.
+        pub max_download: String,
+    }
+
+    impl Default for RunConfig {
+        fn default() -> Self {
+            Self {
+                fail_fast: false,
+                retry_failed: 0,
+                retry_delay_secs: 60,
+                overlap: vec![[
+                    crate::updater::Stage::System,
+                    crate::updater::Stage::Flatpak,
+                ]],
+                summary_file: String::new(),
+                max_download: String::new(),
+            }
+        }
+    }
+
+    impl RunConfig {
+        /// Rejects overlaps that are never safe: a firmware flash must not
+        /// race a system transaction that may replace fwupd or the kernel.
+        pub fn check(&self) -> Result<()> {
+            use crate::updater::Stage;
+            for &[a, b] in &self.overlap {
+                if a == b {
+                    return Err(UpdateError::Config(format!(
+                        "[run] overlap: `{}` cannot overlap with itself",
+                        a.label().to_lowercase()
+                    )));
+                }
+                if matches!(
+                    (a, b),
+                    (Stage::System, Stage::Firmware) | (Stage::Firmware, Stage::System)
+                ) {
+                    return Err(UpdateError::Config(
+                        "[run] overlap: firmware never runs alongside system updates".to_string(),
+                    ));
+                }
+            }
+            if !self.max_download.trim().is_empty() && self.max_download_bytes().is_none() {
+                return Err(UpdateError::Config(format!(
+                    "[run] max_download: {:?} is not a size like \"2G\" or \"500M\"",
+                    self.max_download
+                )));
+            }
+            Ok(())
+        }
+
+        pub fn max_download_bytes(&self) -> Option<u64> {
+            crate::size::parse(&self.max_download).filter(|_| !self.max_download.trim().is_empty())
+        }
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
     pub struct SystemConfig {
         pub enabled: bool,
         pub auto_remove: bool,
         pub refresh: bool,
+        pub lock_timeout_secs: u64,
+        pub timeout_mins: u64,
+        /// Package globs dnf5 must leave alone ("kernel*", "nvidia*").
+        pub exclude: Vec<String>,
+        /// Run backend commands at low CPU and I/O priority, as with --background.
+        pub background: bool,
+        /// Niceness of backend commands in background mode (0-19).
+        pub background_nice: i32,
+        pub background_io_class: crate::system::IoClass,
+        /// What to do about a dnf transaction a crash left unfinished.
+        pub interrupted_transaction: crate::updater::Recovery,
+        /// How to drive dnf5: its CLI, or dnf5daemon over D-Bus.
+        pub backend: crate::updater::DnfBackend,
+        /// Package globs the update, an obsolete or autoremove must not take
+        /// away, like dnf's protected_packages.
+        pub protected: Vec<String>,
+        /// What happens when a transaction would remove a protected package.
+        pub protected_removal: crate::updater::ProtectedRemoval,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
-    #[serde(default)]
+    #[serde(default, deny_unknown_fields)]
     pub struct FlatpakConfig {
         pub enabled: bool,
         pub remove_unused: bool,
+        pub timeout_mins: u64,
+        /// Application or runtime IDs to leave at their installed version.
+        pub skip: Vec<String>,
+        /// Update the per-user installation of every user, not just the sudo caller's.
+        pub all_users: bool,
+        /// Apps updated at the same time, each with its own `flatpak update`;
+        /// 1 updates an installation in a single call.
+        pub jobs: usize,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
-    #[serde(default)]
+    #[serde(default, deny_unknown_fields)]
     pub struct FirmwareConfig {
         pub enabled: bool,
+        pub timeout_mins: u64,
+        /// fwupd devices never updated, by GUID, device ID or name glob.
+        pub skip_devices: Vec<String>,
+        /// Enable or disable the lvfs-testing remote; unset leaves it as it is.
+        pub lvfs_testing: Option<bool>,
+        /// Release branch to keep a device on, keyed like `skip_devices`.
+        pub branches: BTreeMap<String, String>,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
-    #[serde(default)]
+    #[serde(default, deny_unknown_fields)]
     pub struct LoggingConfig {
         pub file: PathBuf,
         pub level: String,
+        /// Also send events, with their fields, to the systemd journal.
+        pub journald: bool,
+        pub format: crate::logfile::Format,
+        pub rotation: crate::logfile::Rotation,
+        /// File size that starts a new file with `rotation = "size"`.
+        pub max_size_mb: u64,
+        /// Rotated files to retain.
+        pub keep: usize,
+        /// Gzip rotated files.
+        pub compress: bool,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
-    #[serde(default)]
+    #[serde(default, deny_unknown_fields)]
     pub struct NetworkConfig {
         pub check_url: String,
+        /// Additional endpoints; the network is up if any of them answers.
+        pub check_urls: Vec<String>,
         pub timeout_secs: u64,
+        /// URL whose body must equal `captive_portal_expect`; empty (the
+        /// default) disables the check.
+        pub captive_portal_url: String,
+        pub captive_portal_expect: String,
+        /// dnf5 download throttle in bytes/s, with an optional k/M/G suffix ("500k", "2M").
+        pub max_download_rate: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct PowerConfig {
+        pub inhibit: bool,
+        pub require_ac_for_firmware: bool,
+        pub min_battery_percent: u8,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct RebootConfig {
+        /// Reboot without prompting when updates need it.
+        pub auto: bool,
+        /// "HH:MM-HH:MM" when automatic reboots may happen; empty means any time.
+        pub window: String,
+        /// Broadcast to logged-in users when a reboot is scheduled.
+        pub message: String,
+        /// full, soft (systemctl soft-reboot), kexec, or auto to pick the
+        /// fastest one that applies.
+        pub method: crate::reboot::RebootMethod,
+        /// After an unattended run, tell logged-in users with `wall` that a
+        /// reboot is pending.
+        pub wall: bool,
+        /// After an unattended run, leave a login message about the pending
+        /// reboot until it happens.
+        pub motd: bool,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct NotifyConfig {
+        /// After an unattended run that changed or failed something, show a
+        /// desktop notification in each graphical session.
+        pub desktop: bool,
+        /// "mqtt://host[:port]" to publish check results and run outcomes
+        /// to as retained messages; empty for none.
+        pub mqtt_broker: String,
+        /// Topic prefix; `{host}` is replaced with the hostname.
+        pub mqtt_topic: String,
+        pub mqtt_username: String,
+        pub mqtt_password: String,
+        /// JSON file rewritten after every check and run, for status bar
+        /// widgets to poll; empty for none.
+        pub status_file: String,
     }
 
-    impl Default for Config {
+    impl Default for NotifyConfig {
         fn default() -> Self {
             Self {
-                system: SystemConfig::default(),
-                flatpak: FlatpakConfig::default(),
-                firmware: FirmwareConfig::default(),
-                logging: LoggingConfig::default(),
-                network: NetworkConfig::default(),
+                desktop: false,
+                mqtt_broker: String::new(),
+                mqtt_topic: "fup/{host}".into(),
+                mqtt_username: String::new(),
+                mqtt_password: String::new(),
+                status_file: String::new(),
             }
         }
     }
 
-    impl Default for SystemConfig {
-        fn default() -> Self {
-            Self { enabled: true, auto_remove: true, refresh: true }
+    impl NotifyConfig {
+        /// Host and port of `mqtt_broker`, None when MQTT is off. IPv6 hosts
+        /// keep their brackets, since the client joins host and port with a
+        /// colon.
+        pub fn mqtt_address(&self) -> Result<Option<(String, u16)>> {
+            let broker = self.mqtt_broker.trim();
+            if broker.is_empty() {
+                return Ok(None);
+            }
+            let invalid = |why: &str| {
+                UpdateError::Config(format!("[notify] mqtt_broker {:?}: {}", broker, why))
+            };
+            let address = match broker.split_once("://") {
+                Some(("mqtt" | "tcp", address)) => address.trim_end_matches('/'),
+                Some(("mqtts" | "ssl", _)) => {
+                    return Err(invalid(
+                        "TLS isn't supported; reach the broker over the LAN or a tunnel",
+                    ));
+                }
+                _ => return Err(invalid("expected mqtt://host or mqtt://host:port")),
+            };
+            let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+                let (ip, port) = rest
+                    .split_once(']')
+                    .ok_or_else(|| invalid("unclosed [ around the IPv6 address"))?;
+                if ip.parse::<std::net::Ipv6Addr>().is_err() {
+                    return Err(invalid("invalid IPv6 address"));
+                }
+                let port = match port {
+                    "" => 1883,
+                    port => port
+                        .strip_prefix(':')
+                        .and_then(|p| p.parse().ok())
+                        .ok_or_else(|| invalid("invalid port"))?,
+                };
+                (format!("[{}]", ip), port)
+            } else if address.matches(':').count() > 1 {
+                return Err(invalid(
+                    "put IPv6 addresses in brackets, as in mqtt://[::1]:1883",
+                ));
+            } else {
+                match address.rsplit_once(':') {
+                    Some((host, port)) => (
+                        host.to_string(),
+                        port.parse().map_err(|_| invalid("invalid port"))?,
+                    ),
+                    None => (address.to_string(), 1883),
+                }
+            };
+            if host.is_empty() {
+                return Err(invalid("no host"));
+            }
+            Ok(Some((host, port)))
         }
     }
 
-    impl Default for FlatpakConfig {
+    impl Default for RebootConfig {
         fn default() -> Self {
-            Self { enabled: true, remove_unused: true }
+            Self {
+                auto: false,
+                window: String::new(),
+                message: "fup: rebooting to finish installing updates".into(),
+                method: crate::reboot::RebootMethod::Full,
+                wall: false,
+                motd: false,
+            }
         }
     }
 
-    impl Default for FirmwareConfig {
-        fn default() -> Self {
-            Self { enabled: false }
+    impl RebootConfig {
+        pub fn check(&self) -> Result<()> {
+            if !self.window.trim().is_empty() {
+                crate::reboot::parse_window(&self.window)
+                    .map_err(|e| UpdateError::Config(format!("[reboot] window: {}", e)))?;
+            }
+            Ok(())
         }
     }
 
-    impl Default for LoggingConfig {
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct ScheduleConfig {
+        /// When update runs may change the system ("Sat 02:00-06:00",
+        /// "Mon-Fri 22:00-01:00"); empty means any time.
+        pub allowed_windows: Vec<String>,
+        /// abort, or defer until the next window opens.
+        pub outside_window: crate::schedule::OutsideWindow,
+    }
+
+    impl Default for ScheduleConfig {
         fn default() -> Self {
             Self {
-                file: PathBuf::from("/var/log/fup.log"),
-                level: "info".into(),
+                allowed_windows: Vec::new(),
+                outside_window: crate::schedule::OutsideWindow::Abort,
             }
         }
     }
 
-    impl Default for NetworkConfig {
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct ScopeConfig {
+        /// Run each backend command in a transient `systemd-run --scope` unit.
+        pub enabled: bool,
+        /// Slice the scopes are grouped under.
+        pub slice: String,
+        /// systemd MemoryMax= ("4G", "50%"); empty means no limit.
+        pub memory_max: String,
+        /// systemd CPUQuota= ("200%" is two CPUs); empty means no limit.
+        pub cpu_quota: String,
+    }
+
+    impl Default for ScopeConfig {
         fn default() -> Self {
             Self {
-                check_url: "https://fedoraproject.org".into(),
-                timeout_secs: 10,
+                enabled: false,
+                slice: "fup.slice".into(),
+                memory_max: String::new(),
+                cpu_quota: String::new(),
             }
         }
     }
 
-    impl Config {
-        pub fn load(path: Option<&PathBuf>) -> Self {
-            let paths = [
-                path.cloned(),
-                Some(PathBuf::from("/etc/fup.toml")),
-                dirs::config_dir().map(|p| p.join("fup/config.toml")),
-            ];
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct VerifyConfig {
+        /// Check the system after updating and fail the run on regressions.
+        pub enabled: bool,
+        /// Run `dnf5 check` for broken dependencies and duplicates.
+        pub dnf_check: bool,
+        /// Report systemd units that failed during the update.
+        pub failed_units: bool,
+        /// Verify the files of `critical_packages` with `rpm -V`.
+        pub rpm_verify: bool,
+        pub critical_packages: Vec<String>,
+    }
 
-            for p in paths.into_iter().flatten() {
-                if p.exists() {
-                    if let Ok(content) = std::fs::read_to_string(&p) {
-                        if let Ok(cfg) = toml::from_str(&content) {
-                            tracing::info!("Loaded config from {}", p.display());
-                            return cfg;
-                        }
-                    }
-                }
+    impl Default for VerifyConfig {
+        fn default() -> Self {
+            Self {
+                enabled: true,
+                dnf_check: true,
+                failed_units: true,
+                rpm_verify: false,
+                critical_packages: [
+                    "glibc",
+                    "systemd",
+                    "rpm",
+                    "dnf5",
+                    "openssl-libs",
+                    "kernel-core",
+                ]
+                .map(String::from)
+                .to_vec(),
             }
-            Self::default()
         }
     }
-}
-
-mod cli {
-    use clap::Parser;
-    use std::path::PathBuf;
-
-    #[derive(Parser, Debug, Clone)]
-    #[command(name = "fup", version, about = "Fedora Utility Patchworker", long_about = None)]
-    #[command(propagate_version = true)]
-    pub struct Args {
-        /// Check and display available updates without installing
-        #[arg(long, short = 'r')]
-        pub refresh: bool,
-
-        /// Update everything (system, flatpak, and optionally firmware)
-        #[arg(long, short = 'u')]
-        pub update_all: bool,
-
-        /// Update only system packages (dnf5)
-        #[arg(long)]
-        pub update_system: bool,
-
-        /// Update only Flatpak applications
-        #[arg(long)]
-        pub update_flatpak: bool,
-
-        /// Update only firmware (requires fwupd)
-        #[arg(long)]
-        pub update_firmware: bool,
-
-        /// Include firmware in --update-all
-        #[arg(long, short = 'f')]
-        pub firmware: bool,
 
-        /// Dry run - show what would be done without executing
-        #[arg(long, short = 'n')]
-        pub dry_run: bool,
+    /// What `fup clean` looks at and reclaims.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct CleanConfig {
+        /// Downloaded packages in the dnf5 cache.
+        pub dnf_packages: bool,
+        /// Repository metadata; it is downloaded again on the next update.
+        pub dnf_metadata: bool,
+        pub flatpak_unused: bool,
+        /// Journal entries older than this are vacuumed ("4weeks"); empty disables.
+        pub journal_max_age: String,
+        /// Firmware archives fwupd left behind in its cache.
+        pub fwupd_downloads: bool,
+    }
 
-        /// Skip reboot prompt after updates
-        #[arg(long)]
-        pub no_reboot_prompt: bool,
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct AuditConfig {
+        /// Append who ran fup, the commands it ran and the package changes.
+        pub enabled: bool,
+        pub file: PathBuf,
+    }
 
-        /// Skip network connectivity check
-        #[arg(long)]
-        pub no_network_check: bool,
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct TelemetryConfig {
+        /// Export a trace of each run (backends and commands) over OTLP.
+        pub enabled: bool,
+        /// OTLP/HTTP traces endpoint of the collector, Tempo or Jaeger.
+        pub endpoint: String,
+        pub service_name: String,
+        /// Extra request headers, e.g. for authentication.
+        pub headers: BTreeMap<String, String>,
+    }
 
-        /// Run updates in parallel (may produce interleaved output)
-        #[arg(long)]
-        pub parallel: bool,
+    /// Where `fup self-update` looks for new releases.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct SelfUpdateConfig {
+        /// A GitHub `releases/latest` API URL, or anything serving the same JSON.
+        pub url: String,
+        /// Release asset holding the binary.
+        pub asset: String,
+        /// Base64 ed25519 key SHA256SUMS must carry a valid signature from.
+        pub public_key: String,
+        /// Install releases checked against SHA256SUMS alone when no
+        /// `public_key` is set.
+        pub allow_unsigned: bool,
+        /// Mention a newer release in `--refresh`, which asks the release
+        /// URL on every refresh.
+        pub notify: bool,
+    }
 
-        /// Path to config file
-        #[arg(long, short = 'c')]
-        pub config: Option<PathBuf>,
+    impl Default for SelfUpdateConfig {
+        fn default() -> Self {
+            Self {
+                url: "https://api.github.com/repos/overdox/fedora-utility-patchworker/releases/latest".into(),
+                asset: "fup".into(),
+                public_key: String::new(),
+                allow_unsigned: false,
+                notify: false,
+            }
+        }
+    }
 
-        /// Increase verbosity (-v, -vv, -vvv)
-        #[arg(long, short = 'v', action = clap::ArgAction::Count)]
-        pub verbose: u8,
+    /// Signing of `fup export-bundle` output and its check on import.
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct BundleConfig {
+        /// PKCS#8 ed25519 private key that export-bundle signs with, unless --sign-key is given.
+        pub signing_key: String,
+        /// Base64 ed25519 key; when set, import-bundle requires a valid signature.
+        pub public_key: String,
+    }
 
-        /// Quiet mode - minimal output
-        #[arg(long, short = 'q')]
-        pub quiet: bool,
+    /// A LAN mirror of the dnf repositories: kept by `fup mirror sync` on
+    /// the host serving it, and updated from by the hosts that set `url`.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct MirrorConfig {
+        /// Where `fup mirror sync` keeps one directory per repository.
+        pub dir: String,
+        /// Repository IDs to mirror; empty means every enabled repository.
+        pub repos: Vec<String>,
+        /// Versions of each package to keep; 0 keeps every version ever synced.
+        pub keep: usize,
+        /// Base URL the mirror is served at; when set, updates come from
+        /// `<url>/<repo id>` instead of the configured repositories.
+        pub url: String,
     }
 
-    impl Args {
-        /// Returns true if no action flags were provided
-        pub fn is_default(&self) -> bool {
-            !self.refresh
-                && !self.update_all
-                && !self.update_system
-                && !self.update_flatpak
-                && !self.update_firmware
+    impl Default for MirrorConfig {
+        fn default() -> Self {
+            Self {
+                dir: String::new(),
+                repos: Vec::new(),
+                keep: 2,
+                url: String::new(),
+            }
         }
     }
-}
 
-mod system {
-    use crate::error::{Result, UpdateError};
-    use std::time::Duration;
+    /// Colors and symbols, for terminal themes and color blindness.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct UiConfig {
+        /// Headings, rules and progress bars.
+        pub accent: crate::ui::ColorName,
+        pub palette: crate::ui::Palette,
+        pub success_glyph: String,
+        pub warning_glyph: String,
+        pub failure_glyph: String,
+        /// The box printed when an update run starts.
+        pub banner: bool,
+        /// Message language ("en", "de"), or "auto" to follow LANG.
+        pub language: String,
+        /// Page update lists longer than the screen instead of cutting them short.
+        pub pager: bool,
+    }
 
-    pub fn check_root() -> Result<()> {
-        if nix::unistd::Uid::effective().is_root() {
-            Ok(())
-        } else {
-            Err(UpdateError::NotRoot)
+    impl Default for UiConfig {
+        fn default() -> Self {
+            Self {
+                accent: crate::ui::ColorName::new("cyan"),
+                palette: crate::ui::Palette::Default,
+                success_glyph: "✓".into(),
+                warning_glyph: "⚠".into(),
+                failure_glyph: "✗".into(),
+                banner: true,
+                language: "auto".into(),
+                pager: true,
+            }
         }
     }
 
-    pub fn command_exists(cmd: &str) -> bool {
-        std::process::Command::new("which")
-            .arg(cmd)
-            .output()
-            .map(|o| o.status.success())
-            .unwrap_or(false)
+    impl Default for TelemetryConfig {
+        fn default() -> Self {
+            Self {
+                enabled: false,
+                endpoint: "http://localhost:4318/v1/traces".into(),
+                service_name: "fup".into(),
+                headers: BTreeMap::new(),
+            }
+        }
     }
 
-    pub async fn check_network(url: &str, timeout: Duration) -> Result<()> {
-        let client = reqwest::Client::builder()
-            .timeout(timeout)
-            .build()
-            .map_err(|e| UpdateError::Config(e.to_string()))?;
-
-        client
-            .head(url)
-            .send()
-            .await
-            .map_err(|_| UpdateError::NoNetwork)?;
-
-        Ok(())
+    impl Default for AuditConfig {
+        fn default() -> Self {
+            Self {
+                enabled: true,
+                file: PathBuf::from("/var/log/fup-audit.jsonl"),
+            }
+        }
     }
-}
 
-mod updater {
-    use crate::error::{Result, UpdateError};
-    use colored::Colorize;
-    use indicatif::{ProgressBar, ProgressStyle};
-    use std::{process::Stdio, sync::Arc, time::Duration};
-    use tokio::{
-        io::{AsyncBufReadExt, BufReader},
-        process::Command,
-        sync::Mutex,
-    };
-    use tracing::{debug, info};
+    impl Default for CleanConfig {
+        fn default() -> Self {
+            Self {
+                dnf_packages: true,
+                dnf_metadata: false,
+                flatpak_unused: true,
+                journal_max_age: "4weeks".into(),
+                fwupd_downloads: true,
+            }
+        }
+    }
 
-    #[derive(Debug, Clone, Default)]
-    pub struct UpdateSummary {
-        pub system_updated: bool,
-        pub flatpak_updated: bool,
-        pub firmware_updated: bool,
-        pub errors: Vec<String>,
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct ServicesConfig {
+        /// Restart services using outdated libraries when no reboot is needed.
+        pub restart: bool,
+        /// Services never restarted automatically, because doing so would end
+        /// the user's session or cut off the machine.
+        pub exclude: Vec<String>,
     }
 
-    #[derive(Debug, Clone, Default)]
-    pub struct AvailableUpdates {
-        pub system: Vec<String>,
-        pub flatpak: Vec<String>,
-        pub firmware: Vec<String>,
+    /// Keeping PackageKit (GNOME Software, KDE Discover) out of the way.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct PackageKitConfig {
+        /// Ask packagekitd to exit before updating, as `pkcon quit` does.
+        pub quit: bool,
+        /// Also keep it from being started again until fup is done.
+        pub hold: bool,
+        /// Tell PackageKit the packages changed afterwards, so GNOME
+        /// Software stops offering applied updates.
+        pub refresh_after: bool,
+        /// Also have it download fresh metadata (`pkcon refresh force`).
+        pub refresh_cache: bool,
+        pub refresh_timeout_secs: u64,
     }
 
-    impl AvailableUpdates {
-        pub fn total_count(&self) -> usize {
-            self.system.len() + self.flatpak.len() + self.firmware.len()
+    impl Default for PackageKitConfig {
+        fn default() -> Self {
+            Self {
+                quit: true,
+                hold: false,
+                refresh_after: true,
+                refresh_cache: false,
+                refresh_timeout_secs: 300,
+            }
         }
+    }
 
-        pub fn is_empty(&self) -> bool {
-            self.total_count() == 0
+    impl Default for ServicesConfig {
+        fn default() -> Self {
+            Self {
+                restart: false,
+                exclude: [
+                    "display-manager.service",
+                    "gdm.service",
+                    "sddm.service",
+                    "lightdm.service",
+                    "dbus.service",
+                    "dbus-broker.service",
+                    "systemd-logind.service",
+                    "NetworkManager.service",
+                ]
+                .map(String::from)
+                .to_vec(),
+            }
         }
     }
 
-    pub struct Updater {
-        dry_run: bool,
-        quiet: bool,
-        summary: Arc<Mutex<UpdateSummary>>,
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct KernelConfig {
+        /// Installed kernels to keep, newest first, besides the running one.
+        /// 0 leaves cleanup to dnf's `installonly_limit`.
+        pub keep: usize,
+        /// Check that akmod/DKMS modules were built for a new kernel before
+        /// suggesting a reboot.
+        pub verify_modules: bool,
+        pub module_build_timeout_mins: u64,
     }
 
-    impl Updater {
-        pub fn new(dry_run: bool, quiet: bool) -> Self {
+    impl Default for KernelConfig {
+        fn default() -> Self {
             Self {
-                dry_run,
-                quiet,
-                summary: Arc::new(Mutex::new(UpdateSummary::default())),
+                keep: 0,
+                verify_modules: true,
+                module_build_timeout_mins: 15,
             }
         }
+    }
 
-        pub async fn summary(&self) -> UpdateSummary {
-            self.summary.lock().await.clone()
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct ArtifactsConfig {
+        pub enabled: bool,
+        pub dir: PathBuf,
+        pub keep: usize,
+    }
+
+    /// Backoff policy shared by everything that retries transient failures.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct RetryConfig {
+        pub max_attempts: u32,
+        pub initial_delay_secs: f64,
+        pub max_delay_secs: f64,
+        pub multiplier: f64,
+        /// Random spread applied to each delay, as a fraction (0.2 = ±20%).
+        pub jitter: f64,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct DiskConfig {
+        pub check: bool,
+        pub min_free_root_mb: u64,
+        pub min_free_var_mb: u64,
+        pub min_free_boot_mb: u64,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct HealthConfig {
+        /// Look for a pending SELinux relabel and a damaged RPM database
+        /// before system updates.
+        pub check: bool,
+        pub on_problem: crate::preflight::OnProblem,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct BackupConfig {
+        pub etc: bool,
+        pub dir: PathBuf,
+        pub keep: usize,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct HistoryConfig {
+        pub enabled: bool,
+        pub file: PathBuf,
+        pub max_entries: usize,
+    }
+
+    impl Default for SystemConfig {
+        fn default() -> Self {
+            Self {
+                enabled: true,
+                auto_remove: true,
+                refresh: true,
+                lock_timeout_secs: 300,
+                timeout_mins: 60,
+                exclude: Vec::new(),
+                background: false,
+                background_nice: 10,
+                background_io_class: crate::system::IoClass::Idle,
+                interrupted_transaction: crate::updater::Recovery::Ask,
+                backend: crate::updater::DnfBackend::Cli,
+                protected: [
+                    "kernel",
+                    "kernel-core",
+                    "dnf5",
+                    "rpm",
+                    "sudo",
+                    "systemd",
+                    "glibc",
+                    "openssh-server",
+                    "NetworkManager",
+                    "firewalld",
+                    "gdm",
+                    "sddm",
+                    "gnome-shell",
+                    "plasma-workspace",
+                ]
+                .map(String::from)
+                .to_vec(),
+                protected_removal: crate::updater::ProtectedRemoval::Abort,
+            }
         }
+    }
 
-        fn create_spinner(&self, msg: &str) -> ProgressBar {
-            if self.quiet {
-                return ProgressBar::hidden();
+    impl Default for FlatpakConfig {
+        fn default() -> Self {
+            Self {
+                enabled: true,
+                remove_unused: true,
+                timeout_mins: 30,
+                skip: Vec::new(),
+                all_users: false,
+                jobs: 1,
             }
-            let pb = ProgressBar::new_spinner();
-            pb.set_style(
-                ProgressStyle::default_spinner()
-                    .tick_chars("⠁⠂⠄⡀⢀⠠⠐⠈ ")
-                    .template("{spinner:.cyan} {msg}")
-                    .unwrap(),
-            );
-            pb.set_message(msg.to_string());
-            pb.enable_steady_tick(Duration::from_millis(80));
-            pb
         }
+    }
 
-        async fn run_command(
-            &self,
-            cmd: &str,
-            args: &[&str],
-            prefix: &str,
-        ) -> Result<Vec<String>> {
-            let full_cmd = format!("{} {}", cmd, args.join(" "));
-            info!("Executing: {}", full_cmd);
+    impl Default for FirmwareConfig {
+        fn default() -> Self {
+            Self {
+                enabled: false,
+                timeout_mins: 30,
+                skip_devices: Vec::new(),
+                lvfs_testing: None,
+                branches: BTreeMap::new(),
+            }
+        }
+    }
 
-            if self.dry_run {
-                println!("{} [DRY RUN] {}", prefix.cyan().bold(), full_cmd);
-                return Ok(vec![]);
+    impl Default for LoggingConfig {
+        fn default() -> Self {
+            Self {
+                file: PathBuf::from("/var/log/fup.log"),
+                level: "info".into(),
+                journald: false,
+                format: crate::logfile::Format::Text,
+                rotation: crate::logfile::Rotation::Daily,
+                max_size_mb: 10,
+                keep: 7,
+                compress: true,
             }
+        }
+    }
 
-            let mut child = Command::new(cmd)
-                .args(args)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()
-                .map_err(|e| {
-                    if e.kind() == std::io::ErrorKind::NotFound {
-                        UpdateError::CommandNotFound(cmd.to_string())
-                    } else {
-                        UpdateError::Io(e)
-                    }
-                })?;
+    impl Default for NetworkConfig {
+        fn default() -> Self {
+            Self {
+                check_url: "https://fedoraproject.org".into(),
+                check_urls: vec!["https://mirrors.fedoraproject.org".into()],
+                timeout_secs: 10,
+                captive_portal_url: String::new(),
+                captive_portal_expect: "OK".into(),
+                max_download_rate: None,
+            }
+        }
+    }
 
-            let stdout = child.stdout.take().expect("stdout piped");
-            let stderr = child.stderr.take().expect("stderr piped");
+    impl NetworkConfig {
+        /// All connectivity check endpoints, `check_url` first.
+        pub fn endpoints(&self) -> Vec<&str> {
+            std::iter::once(self.check_url.as_str())
+                .chain(self.check_urls.iter().map(String::as_str))
+                .filter(|u| !u.is_empty())
+                .collect()
+        }
 
-            let prefix_out = format!("{}", prefix.white().bold());
-            let prefix_err = format!("{}", prefix.red().bold());
-            let quiet = self.quiet;
-            let output_lines = Arc::new(Mutex::new(Vec::new()));
-            let lines_clone = output_lines.clone();
+        pub fn check(&self) -> Result<()> {
+            if let Some(rate) = &self.max_download_rate
+                && !crate::updater::is_valid_rate(rate.trim())
+            {
+                return Err(UpdateError::Config(format!(
+                    "[network] max_download_rate: {:?} is not a rate like \"2M\" or \"500k\"",
+                    rate
+                )));
+            }
+            Ok(())
+        }
+    }
 
-            let stdout_handle = tokio::spawn(async move {
-                let mut reader = BufReader::new(stdout).lines();
-                while let Ok(Some(line)) = reader.next_line().await {
-                    if !quiet {
-                        println!("{} {}", prefix_out, line);
-                    }
-                    lines_clone.lock().await.push(line);
-                }
-            });
+    impl Default for PowerConfig {
+        fn default() -> Self {
+            Self {
+                inhibit: true,
+                require_ac_for_firmware: true,
+                min_battery_percent: 0,
+            }
+        }
+    }
 
-            let stderr_handle = tokio::spawn(async move {
-                let mut reader = BufReader::new(stderr).lines();
-                while let Ok(Some(line)) = reader.next_line().await {
-                    if !quiet {
-                        eprintln!("{} {}", prefix_err, line);
-                    }
-                    debug!("stderr: {}", line);
-                }
-            });
+    impl Default for ArtifactsConfig {
+        fn default() -> Self {
+            Self {
+                enabled: true,
+                dir: PathBuf::from("/var/lib/fup/runs"),
+                keep: 10,
+            }
+        }
+    }
 
-            let _ = tokio::join!(stdout_handle, stderr_handle);
+    impl Default for RetryConfig {
+        fn default() -> Self {
+            Self {
+                max_attempts: 3,
+                initial_delay_secs: 2.0,
+                max_delay_secs: 60.0,
+                multiplier: 2.0,
+                jitter: 0.2,
+            }
+        }
+    }
 
-            let status = child.wait().await?;
-            let lines = output_lines.lock().await.clone();
+    impl Default for DiskConfig {
+        fn default() -> Self {
+            Self {
+                check: true,
+                min_free_root_mb: 1024,
+                min_free_var_mb: 1024,
+                min_free_boot_mb: 150,
+            }
+        }
+    }
 
-            if !status.success() {
-                let code = status.code().unwrap_or(-1);
-                return Err(UpdateError::CommandFailed {
-                    cmd: full_cmd,
-                    code,
-                    details: lines.join("\n"),
-                });
+    impl Default for HealthConfig {
+        fn default() -> Self {
+            Self {
+                check: true,
+                on_problem: crate::preflight::OnProblem::Abort,
             }
+        }
+    }
 
-            Ok(lines)
+    impl Default for BackupConfig {
+        fn default() -> Self {
+            Self {
+                etc: false,
+                dir: PathBuf::from("/var/backups"),
+                keep: 5,
+            }
         }
+    }
 
-        async fn run_command_silent(&self, cmd: &str, args: &[&str]) -> Result<Vec<String>> {
-            let output = Command::new(cmd)
-                .args(args)
-                .output()
-                .await
-                .map_err(|e| {
-                    if e.kind() == std::io::ErrorKind::NotFound {
-                        UpdateError::CommandNotFound(cmd.to_string())
-                    } else {
-                        UpdateError::Io(e)
-                    }
-                })?;
+    impl Default for HistoryConfig {
+        fn default() -> Self {
+            Self {
+                enabled: true,
+                file: PathBuf::from("/var/lib/fup/history.jsonl"),
+                max_entries: 500,
+            }
+        }
+    }
+
+    /// The commented default configuration written by `fup config init`.
+    pub const TEMPLATE: &str = include_str!("../fup.toml");
+
+    impl Config {
+        /// Config files in lookup order; the first one that exists wins.
+        pub fn search_paths(path: Option<&PathBuf>) -> Vec<PathBuf> {
+            [
+                path.cloned(),
+                Some(PathBuf::from("/etc/fup.toml")),
+                dirs::config_dir().map(|p| p.join("fup/config.toml")),
+            ]
+            .into_iter()
+            .flatten()
+            .collect()
+        }
 
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            Ok(stdout.lines().map(|s| s.to_string()).collect())
+        pub fn load(path: Option<&PathBuf>, profile: Option<&str>) -> Result<Self> {
+            Ok(Self::load_with_source(path, profile)?.0)
         }
 
-        pub async fn check_available_updates(&self) -> Result<AvailableUpdates> {
-            let mut updates = AvailableUpdates::default();
+        /// Like `load`, but also reports where the settings came from, to tell
+        /// configured values from defaults. Only missing files are skipped; an
+        /// unreadable or invalid one is an error, as is an unknown profile.
+        pub fn load_with_source(
+            path: Option<&PathBuf>,
+            profile: Option<&str>,
+        ) -> Result<(Self, Source)> {
+            if let Some(explicit) = path
+                && !explicit.exists()
+            {
+                return Err(UpdateError::Config(format!(
+                    "{} does not exist",
+                    explicit.display()
+                )));
+            }
 
-            // Check system updates
-            if crate::system::command_exists("dnf5") {
-                let spinner = self.create_spinner("Checking system updates...");
-                if let Ok(lines) = self
-                    .run_command_silent("dnf5", &["check-upgrade", "--refresh", "-q"])
-                    .await
-                {
-                    updates.system = lines
-                        .into_iter()
-                        .filter(|l| !l.is_empty() && !l.starts_with("Last metadata"))
-                        .collect();
-                }
-                spinner.finish_and_clear();
+            let mut source = Source::default();
+            if let Some(p) = Self::search_paths(path).into_iter().find(|p| p.exists()) {
+                let content = std::fs::read_to_string(&p)
+                    .map_err(|e| UpdateError::Config(format!("{}: {}", p.display(), e)))?;
+                // Parse once as a Config for errors with line numbers
+                Self::parse(&p, &content)?;
+                tracing::info!("Loaded config from {}", p.display());
+                source.raw = toml::from_str(&content).unwrap_or_default();
+                source.file = Some(p);
             }
 
-            // Check flatpak updates
-            if crate::system::command_exists("flatpak") {
-                let spinner = self.create_spinner("Checking Flatpak updates...");
-                if let Ok(lines) = self
-                    .run_command_silent("flatpak", &["remote-ls", "--updates"])
-                    .await
-                {
-                    updates.flatpak = lines.into_iter().filter(|l| !l.is_empty()).collect();
+            let profiles = match source.raw.remove("profile") {
+                Some(toml::Value::Table(profiles)) => profiles,
+                _ => toml::Table::new(),
+            };
+            source.profiles = profiles.keys().cloned().collect();
+            let mut merged = source.raw.clone();
+            if let Some(name) = profile {
+                let Some(toml::Value::Table(overrides)) = profiles.get(name) else {
+                    let available = if source.profiles.is_empty() {
+                        "none defined".to_string()
+                    } else {
+                        source.profiles.join(", ")
+                    };
+                    return Err(UpdateError::Config(format!(
+                        "unknown profile `{}` (available: {})",
+                        name, available
+                    )));
+                };
+                apply_profile(&mut merged, overrides);
+                source.profile = Some((name.to_string(), overrides.clone()));
+            }
+
+            source.env = apply_env_overrides(&mut merged, std::env::vars());
+            let config = Self::deserialize(merged).map_err(|e| {
+                let mut message = format!("{} (set via {})", e.message(), source.override_names());
+                if let Some(hint) = suggest_field(e.message()) {
+                    message.push_str(&format!("\n  hint: did you mean `{}`?", hint));
                 }
-                spinner.finish_and_clear();
+                UpdateError::Config(message)
+            })?;
+            config.run.check()?;
+            config.reboot.check()?;
+            config.network.check()?;
+            config.notify.mqtt_address()?;
+            Ok((config, source))
+        }
+
+        /// Parses a config file, pointing out the likely intended key when
+        /// an unknown one is found.
+        pub fn parse(path: &Path, content: &str) -> Result<Self> {
+            toml::from_str(content).map_err(|e| {
+                let mut message = format!("{}: {}", path.display(), e.to_string().trim_end());
+                if let Some(hint) = suggest_field(e.message()) {
+                    message.push_str(&format!("\n  hint: did you mean `{}`?", hint));
+                }
+                UpdateError::Config(message)
+            })
+        }
+    }
+
+    /// Where the effective configuration came from.
+    #[derive(Debug, Default)]
+    pub struct Source {
+        pub file: Option<PathBuf>,
+        /// The file's base settings, before profile and environment overrides.
+        pub raw: toml::Table,
+        /// Profiles defined in the file.
+        pub profiles: Vec<String>,
+        /// The selected profile and its overrides.
+        pub profile: Option<(String, toml::Table)>,
+        /// `(section, key)` → the `SYSUPDATER_*` variable that set it.
+        pub env: BTreeMap<(String, String), String>,
+    }
+
+    impl Source {
+        /// Whether the selected profile sets `section.key`.
+        pub fn profile_setting(&self, section: &str, key: &str) -> Option<&str> {
+            let (name, overrides) = self.profile.as_ref()?;
+            overrides.get(section)?.get(key)?;
+            Some(name)
+        }
+
+        fn override_names(&self) -> String {
+            let mut names: Vec<String> = self.env.values().cloned().collect();
+            if let Some((name, _)) = &self.profile {
+                names.push(format!("profile `{}`", name));
             }
+            if names.is_empty() {
+                names.push("config file".to_string());
+            }
+            names.join(", ")
+        }
+    }
 
-            // Check firmware updates
-            if crate::system::command_exists("fwupdmgr") {
-                let spinner = self.create_spinner("Checking firmware updates...");
-                let _ = self.run_command_silent("fwupdmgr", &["refresh", "--force"]).await;
-                if let Ok(lines) = self
-                    .run_command_silent("fwupdmgr", &["get-updates", "-y"])
-                    .await
-                {
-                    updates.firmware = lines
-                        .into_iter()
-                        .filter(|l| l.contains("→") || l.contains("New version"))
-                        .collect();
+    /// Overlays a profile's sections onto the base settings key by key, so a
+    /// profile only needs to list what differs.
+    pub fn apply_profile(table: &mut toml::Table, overrides: &toml::Table) {
+        for (section, values) in overrides {
+            match (table.get_mut(section), values) {
+                (Some(toml::Value::Table(base)), toml::Value::Table(values)) => {
+                    base.extend(values.iter().map(|(k, v)| (k.clone(), v.clone())));
                 }
-                spinner.finish_and_clear();
+                _ => {
+                    table.insert(section.clone(), values.clone());
+                }
+            }
+        }
+    }
+
+    const ENV_PREFIX: &str = "SYSUPDATER_";
+
+    /// Layers `SYSUPDATER_<SECTION>_<KEY>` variables over the file's settings,
+    /// e.g. `SYSUPDATER_LOGGING_LEVEL=debug` or `SYSUPDATER_FLATPAK_ENABLED=false`.
+    /// Settings that are strings take the value as it is; others read it as
+    /// TOML (numbers, booleans, arrays), falling back to a string. Variables
+    /// naming no section are left alone.
+    pub fn apply_env_overrides(
+        table: &mut toml::Table,
+        vars: impl Iterator<Item = (String, String)>,
+    ) -> BTreeMap<(String, String), String> {
+        let defaults = match toml::Value::try_from(Config::default()) {
+            Ok(toml::Value::Table(defaults)) => defaults,
+            _ => toml::Table::new(),
+        };
+
+        let mut applied = BTreeMap::new();
+        for (name, raw) in vars {
+            let Some(rest) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let rest = rest.to_lowercase();
+            let Some((section, key)) = defaults.keys().find_map(|section| {
+                let key = rest.strip_prefix(section.as_str())?.strip_prefix('_')?;
+                Some((section.clone(), key.to_string()))
+            }) else {
+                continue;
+            };
+
+            // A password of 1234 is still a string
+            let value = match defaults.get(&section).and_then(|s| s.get(&key)) {
+                Some(toml::Value::String(_)) => toml::Value::String(raw),
+                _ => toml::from_str::<toml::Table>(&format!("v = {}", raw))
+                    .ok()
+                    .and_then(|mut t| t.remove("v"))
+                    .unwrap_or(toml::Value::String(raw)),
+            };
+            let entry = table
+                .entry(section.clone())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if let toml::Value::Table(section_table) = entry {
+                section_table.insert(key.clone(), value);
+                applied.insert((section, key), name);
+            }
+        }
+        applied
+    }
+
+    /// Picks the closest expected name out of serde's "unknown field `x`,
+    /// expected one of `a`, `b`" message.
+    pub fn suggest_field(message: &str) -> Option<&str> {
+        let rest = message.strip_prefix("unknown field `")?;
+        let (unknown, expected) = rest.split_once('`')?;
+        expected
+            .split('`')
+            .skip(1)
+            .step_by(2)
+            .map(|candidate| (edit_distance(unknown, candidate), candidate))
+            .filter(|(distance, candidate)| *distance <= 2.max(candidate.len() / 3))
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate)
+    }
+
+    pub fn edit_distance(a: &str, b: &str) -> usize {
+        let b: Vec<char> = b.chars().collect();
+        let mut row: Vec<usize> = (0..=b.len()).collect();
+        for (i, ca) in a.chars().enumerate() {
+            let mut diagonal = row[0];
+            row[0] = i + 1;
+            for (j, cb) in b.iter().enumerate() {
+                let substitution = diagonal + usize::from(ca != *cb);
+                diagonal = row[j + 1];
+                row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
             }
+        }
+        row[b.len()]
+    }
+}
+
+mod cli {
+    use clap::{CommandFactory, Parser, Subcommand, error::ErrorKind};
+    use std::path::PathBuf;
+
+    #[derive(Parser, Debug, Clone)]
+    #[command(name = "fup", version, about = "Fedora Utility Patchworker", long_about = None)]
+    #[command(propagate_version = true)]
+    #[command(long_version = concat!(
+        env!("CARGO_PKG_VERSION"),
+        "\n",
+        env!("CARGO_PKG_REPOSITORY"),
+        "\nRun `fup doctor` for backend versions and environment details to include in bug reports."
+    ))]
+    #[command(
+        after_help = "CONFIG FILES:\n  /etc/fup.toml\n  ~/.config/fup/config.toml\n\nRun `fup examples [topic]` for common scenarios."
+    )]
+    pub struct Args {
+        #[command(subcommand)]
+        pub command: Option<Commands>,
+
+        /// Check and display available updates without installing
+        #[arg(long, short = 'r')]
+        pub refresh: bool,
+
+        /// With --refresh, show advisories, changelogs and release notes in a pager
+        #[arg(long, requires = "refresh")]
+        pub changelog: bool,
+
+        /// With --refresh, show the last check instead if it is recent enough
+        #[arg(long, requires = "refresh", conflicts_with = "target")]
+        pub cached: bool,
+
+        /// With --refresh, list only updates that weren't pending at the previous check
+        #[arg(long, requires = "refresh", conflicts_with = "target")]
+        pub only_new: bool,
+
+        /// With --refresh, list only updates whose package, app or device name matches this glob (repeatable)
+        #[arg(long, value_name = "GLOB", requires = "refresh")]
+        pub filter: Vec<String>,
+
+        /// With --refresh, order each section by name, download size, repository or advisory severity
+        #[arg(long, value_enum, value_name = "KEY", requires = "refresh")]
+        pub sort: Option<crate::updater::SortKey>,
+
+        /// With --refresh, list at most this many updates per section
+        #[arg(long, value_name = "N", requires = "refresh")]
+        pub limit: Option<usize>,
+
+        /// How old a cached check may be before --cached checks again (30m, 6h, 2d)
+        #[arg(long, value_name = "AGE", default_value = "6h", value_parser = crate::cache::parse_age, requires = "cached")]
+        pub max_age: chrono::TimeDelta,
+
+        /// Packages (with --update-system) or Flatpak refs (with --update-flatpak) to update
+        #[arg(value_name = "NAME", conflicts_with_all = ["refresh", "security_report", "update_all", "update_firmware"])]
+        pub items: Vec<String>,
+
+        /// Update only kernel packages, then remove old kernels per [kernel] keep
+        #[arg(long, conflicts_with_all = ["items", "update_all", "update_flatpak", "update_firmware", "refresh", "security_report"])]
+        pub kernel_only: bool,
+
+        /// Leave packages matching this glob alone (repeatable, adds to [system] exclude)
+        #[arg(long, short = 'x', value_name = "PATTERN")]
+        pub exclude: Vec<String>,
+
+        /// Update only Flatpaks from this remote, e.g. flathub (repeatable)
+        #[arg(long, value_name = "REMOTE")]
+        pub flatpak_remote: Vec<String>,
+
+        /// Update only this fwupd device: a GUID, device ID or name glob (repeatable)
+        #[arg(long, value_name = "DEVICE")]
+        pub firmware_device: Vec<String>,
+
+        /// Flash firmware without asking first, despite fwupd problems and safety warnings
+        #[arg(long)]
+        pub firmware_force: bool,
+
+        /// Update even outside [schedule] allowed_windows
+        #[arg(long)]
+        pub ignore_window: bool,
+
+        /// List pending security advisories with their CVEs, severity and packages
+        #[arg(long)]
+        pub security_report: bool,
+
+        /// Only report and apply security fixes of at least this severity
+        #[arg(long, value_enum, value_name = "LEVEL")]
+        pub min_severity: Option<crate::security::Severity>,
+
+        /// Update everything (system, flatpak, and optionally firmware)
+        #[arg(long, short = 'u')]
+        pub update_all: bool,
+
+        /// Update only system packages (dnf5)
+        #[arg(long)]
+        pub update_system: bool,
+
+        /// Update only Flatpak applications
+        #[arg(long)]
+        pub update_flatpak: bool,
+
+        /// Update only firmware (requires fwupd)
+        #[arg(long)]
+        pub update_firmware: bool,
+
+        /// Include firmware in --update-all
+        #[arg(long, short = 'f')]
+        pub firmware: bool,
+
+        /// Dry run - show what would be done without executing
+        #[arg(long, short = 'n')]
+        pub dry_run: bool,
+
+        /// Apply updates without asking for confirmation
+        #[arg(long, short = 'y', conflicts_with = "assume_no")]
+        pub assume_yes: bool,
+
+        /// Show what would be updated, then stop without changing anything
+        #[arg(long)]
+        pub assume_no: bool,
+
+        /// Skip reboot prompt after updates
+        #[arg(long)]
+        pub no_reboot_prompt: bool,
+
+        /// Reboot without prompting when needed, within [reboot] window if set
+        #[arg(long)]
+        pub auto_reboot: bool,
+
+        /// Schedule the reboot, if one is needed, for this time (HH:MM)
+        #[arg(long, value_name = "HH:MM", value_parser = crate::reboot::parse_time)]
+        pub reboot_at: Option<chrono::NaiveTime>,
+
+        /// Restart services using outdated libraries when no reboot is needed
+        #[arg(long)]
+        pub restart_services: bool,
+
+        /// Remove packages that are no longer needed after a system update
+        #[arg(long, overrides_with = "no_autoremove")]
+        pub autoremove: bool,
+
+        /// Keep unneeded packages (overrides [system] auto_remove)
+        #[arg(long)]
+        pub no_autoremove: bool,
+
+        /// Remove unused Flatpak runtimes after a Flatpak update
+        #[arg(long, overrides_with = "no_remove_unused")]
+        pub remove_unused: bool,
+
+        /// Keep unused Flatpak runtimes (overrides [flatpak] remove_unused)
+        #[arg(long)]
+        pub no_remove_unused: bool,
+
+        /// Write a report of the run (Markdown, or HTML for .html paths)
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["refresh", "security_report"])]
+        pub report: Option<PathBuf>,
+
+        /// Skip network connectivity check
+        #[arg(long)]
+        pub no_network_check: bool,
+
+        /// Wait a random time up to this long (30s, 15m, 1h) before going online; 0 disables it
+        #[arg(long, value_name = "DURATION", value_parser = crate::cache::parse_age)]
+        pub splay: Option<chrono::TimeDelta>,
+
+        /// Run updates in parallel, with a live pane per backend
+        #[arg(long)]
+        pub parallel: bool,
+
+        /// Skip the remaining backends after the first one fails (overrides [run] fail_fast)
+        #[arg(long, overrides_with = "continue_on_error")]
+        pub fail_fast: bool,
+
+        /// Run every backend even after one fails (overrides [run] fail_fast)
+        #[arg(long, overrides_with = "fail_fast")]
+        pub continue_on_error: bool,
+
+        /// Retry failed backends up to N times once the others are done (overrides [run] retry_failed)
+        #[arg(long, value_name = "N")]
+        pub retry_failed: Option<u32>,
+
+        /// Run backend commands at low CPU and I/O priority so the desktop stays responsive
+        #[arg(long)]
+        pub background: bool,
+
+        /// Path to config file
+        #[arg(long, short = 'c', global = true)]
+        pub config: Option<PathBuf>,
+
+        /// Apply a named `[profile.NAME]` from the config file
+        #[arg(long, global = true, env = "SYSUPDATER_PROFILE", value_name = "NAME")]
+        pub profile: Option<String>,
+
+        /// Increase verbosity (-v, -vv, -vvv)
+        #[arg(long, short = 'v', action = clap::ArgAction::Count)]
+        pub verbose: u8,
+
+        /// Quiet mode - no output, just a status line when something failed or needs a reboot
+        #[arg(long, short = 'q')]
+        pub quiet: bool,
+
+        /// Screen-reader friendly output: no spinners, box drawing, or color
+        #[arg(long)]
+        pub accessible: bool,
+
+        /// When to use color (auto honours NO_COLOR and whether stdout is a terminal)
+        #[arg(long, value_enum, global = true, value_name = "WHEN", default_value_t = crate::ui::ColorChoice::Auto)]
+        pub color: crate::ui::ColorChoice,
+
+        /// ASCII-only output for logs: no box drawing, emoji or spinner glyphs
+        #[arg(long, global = true, env = "SYSUPDATER_PLAIN", value_parser = clap::builder::BoolishValueParser::new())]
+        pub plain: bool,
+
+        /// Print only newline-delimited JSON events on stdout, for GUIs and wrappers
+        #[arg(long, conflicts_with_all = ["refresh", "security_report", "accessible"])]
+        pub porcelain: bool,
+
+        /// Proceed even when pre-flight safety checks fail
+        #[arg(long)]
+        pub force: bool,
+
+        /// Update even from repositories with signature checking (gpgcheck) disabled
+        #[arg(long)]
+        pub allow_unsigned: bool,
+
+        /// Check or update an alternate root (chroot, mounted partition) instead of the running system
+        #[arg(long, value_name = "PATH")]
+        pub target: Option<PathBuf>,
+    }
+
+    #[derive(Subcommand, Debug, Clone)]
+    pub enum Commands {
+        /// Show usage examples, optionally filtered by topic or keyword
+        Examples {
+            /// Topic or keyword to search for (e.g. firmware, timers, scripting)
+            topic: Option<String>,
+        },
+        /// Show release notes for the installed version of fup
+        News {
+            /// Show the notes for every release
+            #[arg(long)]
+            all: bool,
+        },
+        /// Show the last run, pending updates, reboot, timer and lock state
+        Status {
+            /// Print as JSON for monitoring agents
+            #[arg(long)]
+            json: bool,
+        },
+        /// Print shell completions to stdout
+        Completions {
+            /// Shell to generate completions for
+            #[arg(value_enum)]
+            shell: clap_complete::Shell,
+        },
+        /// Print the man page (roff) to stdout
+        Man,
+        /// Create, check or inspect the configuration file
+        Config {
+            #[command(subcommand)]
+            action: ConfigAction,
+        },
+        /// Show reclaimable cache and journal space, and reclaim it with --apply
+        Clean {
+            /// Remove what is listed instead of only reporting it
+            #[arg(long)]
+            apply: bool,
+        },
+        /// Check that every enabled dnf repository can be reached and flag dead ones
+        RepoCheck,
+        /// Report tool versions, distro, config files, paths, lock and network state for bug reports
+        Doctor,
+        /// Install the latest fup release in place of this binary
+        SelfUpdate {
+            /// Only report whether a newer release is out
+            #[arg(long)]
+            check: bool,
+        },
+        /// Download pending packages, Flatpaks and firmware into a bundle for an offline host
+        ExportBundle {
+            /// Empty directory to write the bundle to (e.g. on a USB drive)
+            dir: PathBuf,
+            /// PKCS#8 ed25519 private key to sign the bundle with (overrides [bundle] signing_key)
+            #[arg(long, value_name = "FILE")]
+            sign_key: Option<PathBuf>,
+        },
+        /// Install the updates in a bundle made by export-bundle, without network access
+        ImportBundle {
+            /// Directory holding the bundle
+            dir: PathBuf,
+        },
+        /// Keep a LAN mirror of the dnf repositories for other hosts to update from
+        Mirror {
+            #[command(subcommand)]
+            action: MirrorAction,
+        },
+    }
+
+    #[derive(Subcommand, Debug, Clone)]
+    pub enum MirrorAction {
+        /// Download new packages into [mirror] dir, prune old versions and rebuild the metadata
+        Sync,
+        /// Show each mirrored repository with its package count, size and last sync
+        Status,
+    }
+
+    #[derive(Subcommand, Debug, Clone)]
+    pub enum ConfigAction {
+        /// Write a fully commented default config (to --config or /etc/fup.toml)
+        Init {
+            /// Overwrite an existing file
+            #[arg(long)]
+            force: bool,
+        },
+        /// Parse the config file and report errors
+        Validate,
+        /// Print the effective configuration and where each value comes from
+        Show,
+    }
+
+    pub fn print_completions(shell: clap_complete::Shell) {
+        clap_complete::generate(shell, &mut Args::command(), "fup", &mut std::io::stdout());
+    }
+
+    pub fn print_man_page() -> std::io::Result<()> {
+        clap_mangen::Man::new(Args::command()).render(&mut std::io::stdout())
+    }
+
+    impl Args {
+        /// Checks argument combinations clap's attributes can't express.
+        pub fn validate(&self) {
+            if !self.items.is_empty() && self.update_system == self.update_flatpak {
+                Self::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "package names need exactly one of --update-system or --update-flatpak",
+                    )
+                    .exit();
+            }
+            // A prompt would land in the middle of the event stream
+            if self.porcelain && self.command.is_none() && !self.assume_yes && !self.dry_run {
+                Self::command()
+                    .error(
+                        ErrorKind::MissingRequiredArgument,
+                        "--porcelain needs --assume-yes (or --dry-run)",
+                    )
+                    .exit();
+            }
+        }
+
+        /// Returns true if no action flags or subcommand were provided
+        pub fn is_default(&self) -> bool {
+            self.command.is_none()
+                && !self.refresh
+                && !self.security_report
+                && !self.update_all
+                && !self.update_system
+                && !self.kernel_only
+                && !self.update_flatpak
+                && !self.update_firmware
+        }
+    }
+}
+
+mod system {
+    use crate::config::NetworkConfig;
+    use crate::error::{Result, UpdateError};
+    use serde::{Deserialize, Serialize};
+    use std::{
+        collections::HashMap,
+        ffi::OsStr,
+        os::unix::fs::PermissionsExt,
+        path::{Path, PathBuf},
+        process::Stdio,
+        sync::{Mutex, OnceLock},
+        time::Duration,
+    };
+    use tracing::{debug, info, warn};
+
+    pub fn check_root() -> Result<()> {
+        if nix::unistd::Uid::effective().is_root() {
+            Ok(())
+        } else {
+            Err(UpdateError::NotRoot)
+        }
+    }
+
+    /// Makes sure an alternate root looks like an installed system. Returns
+    /// true for ostree deployments, which dnf5 can inspect but not update.
+    pub fn check_target(root: &Path) -> Result<bool> {
+        if !root.is_dir() {
+            return Err(UpdateError::Preflight(format!(
+                "{} is not a directory",
+                root.display()
+            )));
+        }
+        let has_rpmdb = ["usr/lib/sysimage/rpm", "var/lib/rpm"]
+            .iter()
+            .any(|db| root.join(db).is_dir());
+        if !has_rpmdb {
+            return Err(UpdateError::Preflight(format!(
+                "{} has no rpm database; mount the target's root filesystem first",
+                root.display()
+            )));
+        }
+        Ok(root.starts_with("/ostree/deploy") || root.join("ostree").is_dir())
+    }
+
+    /// Tools fup can make use of, with what each one enables. They are
+    /// looked up once per run and listed with `-v`.
+    pub const CAPABILITIES: &[(&str, &str)] = &[
+        ("dnf5", "system updates"),
+        ("flatpak", "Flatpak updates"),
+        ("fwupdmgr", "firmware updates"),
+        ("rpm", "package snapshots and kernel cleanup"),
+        (
+            "systemctl",
+            "soft-reboot, service restarts and timer status",
+        ),
+        ("systemd-inhibit", "sleep/shutdown inhibitor"),
+        (
+            "busctl",
+            "inhibitor lock and PackageKit coordination over D-Bus",
+        ),
+        ("pkcon", "PackageKit cache refresh after updates"),
+        ("etckeeper", "/etc backup commits"),
+        ("tar", "/etc backup tarballs"),
+        ("akmods", "akmod verification"),
+        ("dkms", "DKMS verification"),
+        ("kexec", "kexec reboots"),
+        ("shutdown", "scheduled reboots"),
+        ("journalctl", "journal cleanup"),
+        ("gzip", "log compression"),
+    ];
+
+    /// Lookups made so far this run, including misses.
+    static FOUND: OnceLock<Mutex<HashMap<String, Option<PathBuf>>>> = OnceLock::new();
+
+    pub fn command_exists(cmd: &str) -> bool {
+        find_command(cmd).is_some()
+    }
+
+    /// Resolves `cmd` against `$PATH` the way a shell would, without
+    /// spawning anything. Results are cached for the rest of the run.
+    pub fn find_command(cmd: &str) -> Option<PathBuf> {
+        let found = FOUND.get_or_init(Default::default);
+        if let Some(path) = found.lock().unwrap().get(cmd) {
+            return path.clone();
+        }
+        let path = search_path(cmd, std::env::var_os("PATH").as_deref());
+        found.lock().unwrap().insert(cmd.to_string(), path.clone());
+        path
+    }
+
+    pub fn search_path(cmd: &str, path: Option<&OsStr>) -> Option<PathBuf> {
+        if cmd.contains('/') {
+            return is_executable(Path::new(cmd)).then(|| PathBuf::from(cmd));
+        }
+        // An unset PATH gets the usual default rather than finding nothing
+        let dirs = path.unwrap_or(OsStr::new(
+            "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+        ));
+        std::env::split_paths(dirs)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| dir.join(cmd))
+            .find(|candidate| is_executable(candidate))
+    }
+
+    fn is_executable(path: &Path) -> bool {
+        path.metadata()
+            .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    }
+
+    /// Looks up every tool fup knows about, so later checks hit the cache,
+    /// and lists what was found at debug level.
+    pub fn detect_capabilities() {
+        for (cmd, enables) in CAPABILITIES {
+            match find_command(cmd) {
+                Some(path) => debug!("Found {} at {} ({})", cmd, path.display(), enables),
+                None => debug!("{} not found; no {}", cmd, enables),
+            }
+        }
+    }
+
+    /// Checks that DNS works, that at least one endpoint answers, and that
+    /// responses aren't being rewritten by a captive portal.
+    pub async fn check_network(config: &NetworkConfig) -> Result<()> {
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| UpdateError::Config(e.to_string()))?;
+
+        let endpoints = config.endpoints();
+
+        if !resolves_any(&endpoints, timeout).await {
+            warn!("DNS resolution failed for all connectivity check endpoints");
+            return Err(UpdateError::NoNetwork);
+        }
+
+        let mut reachable = false;
+        for url in &endpoints {
+            match client.head(*url).send().await {
+                Ok(_) => {
+                    debug!("Connectivity check endpoint reachable: {}", url);
+                    reachable = true;
+                    break;
+                }
+                Err(e) => debug!("Connectivity check endpoint {} failed: {}", url, e),
+            }
+        }
+        if !reachable {
+            return Err(UpdateError::NoNetwork);
+        }
+
+        if !config.captive_portal_url.is_empty() {
+            let body = client
+                .get(&config.captive_portal_url)
+                .send()
+                .await
+                .map_err(|_| UpdateError::NoNetwork)?
+                .text()
+                .await
+                .map_err(|_| UpdateError::NoNetwork)?;
+
+            if body.trim() != config.captive_portal_expect {
+                return Err(UpdateError::CaptivePortal(
+                    config.captive_portal_url.clone(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn resolves_any(endpoints: &[&str], timeout: Duration) -> bool {
+        for url in endpoints {
+            let Ok(parsed) = reqwest::Url::parse(url) else {
+                continue;
+            };
+            let (Some(host), Some(port)) = (parsed.host_str(), parsed.port_or_known_default())
+            else {
+                continue;
+            };
+            let lookup = tokio::net::lookup_host((host, port));
+            if let Ok(Ok(mut addrs)) = tokio::time::timeout(timeout, lookup).await
+                && addrs.next().is_some()
+            {
+                return true;
+            }
+            debug!("DNS lookup failed for {}", host);
+        }
+        false
+    }
+
+    /// Process names that take the RPM/dnf lock when running.
+    const PACKAGE_MANAGERS: &[&str] = &[
+        "dnf",
+        "dnf5",
+        "dnf-automatic",
+        "yum",
+        "rpm",
+        "dnf5daemon-serv",
+    ];
+
+    const RPM_LOCK_FILES: &[&str] = &["/usr/lib/sysimage/rpm/.rpm.lock", "/var/lib/rpm/.rpm.lock"];
+
+    /// Describes whoever currently holds the package manager lock, if anyone.
+    pub async fn package_lock_holder() -> Option<String> {
+        if let Some(pid) = rpm_lock_owner() {
+            return Some(format!("RPM database locked by {}", describe_pid(pid)));
+        }
+
+        if let Some(pid) = running_package_manager() {
+            return Some(format!("{} is running", describe_pid(pid)));
+        }
+
+        if packagekit_locked().await {
+            return Some("PackageKit (GNOME Software) transaction in progress".into());
+        }
+
+        None
+    }
+
+    /// Asks the kernel whether any process holds a write lock on the rpmdb lock file.
+    fn rpm_lock_owner() -> Option<i32> {
+        use nix::{
+            fcntl::{FcntlArg, fcntl},
+            libc,
+        };
+        use std::os::fd::AsRawFd;
+
+        let file = RPM_LOCK_FILES
+            .iter()
+            .find_map(|p| std::fs::File::open(p).ok())?;
+
+        // SAFETY: flock is a plain C struct for which all-zeroes is a valid value.
+        let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+        lock.l_type = libc::F_WRLCK as libc::c_short;
+        lock.l_whence = libc::SEEK_SET as libc::c_short;
+
+        fcntl(file.as_raw_fd(), FcntlArg::F_GETLK(&mut lock)).ok()?;
+
+        (lock.l_type != libc::F_UNLCK as libc::c_short).then_some(lock.l_pid)
+    }
+
+    fn running_package_manager() -> Option<i32> {
+        find_process(PACKAGE_MANAGERS)
+    }
+
+    /// I/O scheduling class for background mode.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum IoClass {
+        /// Only gets disk time nobody else wants.
+        Idle,
+        /// The normal class at its lowest level.
+        BestEffort,
+    }
+
+    impl IoClass {
+        pub fn label(self) -> &'static str {
+            match self {
+                IoClass::Idle => "idle",
+                IoClass::BestEffort => "best-effort",
+            }
+        }
+    }
+
+    /// Lowers fup's own CPU and I/O priority; the backend commands it starts
+    /// inherit both.
+    pub fn lower_priority(nice: i32, io_class: IoClass) -> std::io::Result<()> {
+        use nix::libc;
+
+        // IOPRIO_WHO_PROCESS, and IOPRIO_PRIO_VALUE(class, level)
+        const WHO_PROCESS: libc::c_int = 1;
+        let ioprio = match io_class {
+            IoClass::Idle => 3 << 13,
+            IoClass::BestEffort => (2 << 13) | 7,
+        };
+
+        // SAFETY: plain syscalls on the calling process, no pointers involved.
+        unsafe {
+            if libc::setpriority(libc::PRIO_PROCESS, 0, nice.clamp(0, 19)) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            if libc::syscall(libc::SYS_ioprio_set, WHO_PROCESS, 0, ioprio) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// PID of another process whose command name is one of `names`.
+    pub fn find_process(names: &[&str]) -> Option<i32> {
+        let own_pid = std::process::id() as i32;
+
+        std::fs::read_dir("/proc")
+            .ok()?
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<i32>().ok())
+            .filter(|pid| *pid != own_pid)
+            .find(|pid| {
+                std::fs::read_to_string(format!("/proc/{}/comm", pid))
+                    .is_ok_and(|comm| names.contains(&comm.trim()))
+            })
+    }
+
+    /// Human users with their own Flatpak installation: accounts with a UID
+    /// from 1000 up and a login shell, plus anyone logind has a session for
+    /// (systemd-homed or LDAP users that aren't enumerable).
+    pub async fn flatpak_users() -> Vec<String> {
+        async fn getent(args: &[&str]) -> String {
+            tokio::process::Command::new("getent")
+                .args(args)
+                .output()
+                .await
+                .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+                .unwrap_or_default()
+        }
+
+        let mut entries = getent(&["passwd"]).await;
+        if command_exists("loginctl")
+            && let Ok(output) = tokio::process::Command::new("loginctl")
+                .args(["list-users", "--no-legend"])
+                .output()
+                .await
+        {
+            for user in String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|l| l.split_whitespace().nth(1))
+            {
+                entries.push_str(&getent(&["passwd", user]).await);
+            }
+        }
+
+        let mut users: Vec<String> = entries
+            .lines()
+            .filter_map(|line| {
+                // name:password:uid:gid:gecos:home:shell
+                let fields: Vec<&str> = line.split(':').collect();
+                let [name, _, uid, _, _, home, shell] = fields[..] else {
+                    return None;
+                };
+                let uid: u32 = uid.parse().ok()?;
+                let human = (1000..65534).contains(&uid)
+                    && !shell.ends_with("nologin")
+                    && !shell.ends_with("false");
+                (human && Path::new(home).join(".local/share/flatpak").is_dir())
+                    .then(|| name.to_string())
+            })
+            .collect();
+        users.sort();
+        users.dedup();
+        users
+    }
+
+    async fn packagekit_locked() -> bool {
+        if !command_exists("busctl") {
+            return false;
+        }
+
+        tokio::process::Command::new("busctl")
+            .args([
+                "--auto-start=no",
+                "get-property",
+                "org.freedesktop.PackageKit",
+                "/org/freedesktop/PackageKit",
+                "org.freedesktop.PackageKit",
+                "Locked",
+            ])
+            .output()
+            .await
+            .is_ok_and(|o| {
+                o.status.success() && String::from_utf8_lossy(&o.stdout).trim() == "b true"
+            })
+    }
+
+    /// A logind inhibitor lock against sleep, idle and shutdown. It is held by a
+    /// `systemd-inhibit` child that exits as soon as this value is dropped (or
+    /// fup dies and its stdin pipe closes).
+    pub struct InhibitorLock {
+        _child: tokio::process::Child,
+    }
+
+    impl InhibitorLock {
+        pub fn acquire(why: &str) -> Option<Self> {
+            if !command_exists("systemd-inhibit") {
+                debug!("systemd-inhibit not available, not taking an inhibitor lock");
+                return None;
+            }
+
+            let child = tokio::process::Command::new("systemd-inhibit")
+                .args([
+                    "--what=sleep:idle:shutdown",
+                    "--who=fup",
+                    &format!("--why={}", why),
+                    "--mode=block",
+                    "cat",
+                ])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .process_group(0)
+                .kill_on_drop(true)
+                .spawn();
+
+            match child {
+                Ok(child) => {
+                    info!("Holding sleep/shutdown inhibitor lock");
+                    Some(Self { _child: child })
+                }
+                Err(e) => {
+                    warn!("Failed to take inhibitor lock: {}", e);
+                    None
+                }
+            }
+        }
+    }
+
+    fn describe_pid(pid: i32) -> String {
+        match std::fs::read_to_string(format!("/proc/{}/comm", pid)) {
+            Ok(comm) => format!("{} (pid {})", comm.trim(), pid),
+            Err(_) => format!("pid {}", pid),
+        }
+    }
+}
+
+mod ui {
+    use colored::{Color, ColoredString, Colorize};
+    use serde::{Deserialize, Serialize};
+    use std::io::IsTerminal;
+    use std::sync::OnceLock;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static ACCESSIBLE: AtomicBool = AtomicBool::new(false);
+    static PLAIN: AtomicBool = AtomicBool::new(false);
+
+    /// When to color output (`--color`).
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+    pub enum ColorChoice {
+        /// Color when stdout is a terminal and `NO_COLOR` is not set
+        #[default]
+        Auto,
+        Always,
+        Never,
+    }
+
+    impl ColorChoice {
+        pub fn enabled(self) -> bool {
+            match self {
+                Self::Always => true,
+                Self::Never => false,
+                Self::Auto => {
+                    std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                        && std::io::stdout().is_terminal()
+                }
+            }
+        }
+    }
+
+    /// Applies `--color` to everything that prints: our own output, progress
+    /// bars and the stderr log.
+    pub fn set_color(choice: ColorChoice) {
+        let enabled = choice.enabled();
+        colored::control::set_override(enabled);
+        console::set_colors_enabled(enabled);
+        console::set_colors_enabled_stderr(enabled);
+    }
+
+    pub fn color() -> bool {
+        colored::control::SHOULD_COLORIZE.should_colorize()
+    }
+
+    /// Switches to ASCII-only output for logs captured by cron or CI: no box
+    /// drawing, spinner braille or symbols, just `[ok]`-style markers.
+    pub fn set_plain(enabled: bool) {
+        PLAIN.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn plain() -> bool {
+        PLAIN.load(Ordering::Relaxed)
+    }
+
+    /// The ASCII stand-in for a status symbol in plain mode, or the symbol
+    /// itself (or its `[ui]` replacement) otherwise.
+    pub fn sym(symbol: &'static str) -> &'static str {
+        if !plain() {
+            return theme().glyph(symbol);
+        }
+        match symbol {
+            "✓" => "[ok]",
+            "○" => "[--]",
+            "✗" => "[FAIL]",
+            "⚠" => "[!]",
+            "⟳" => "[reboot]",
+            "↑" => "[new]",
+            "•" => "-",
+            "→" => "->",
+            "═" => "=",
+            "─" | "──" => "-",
+            other => other,
+        }
+    }
+
+    /// Colors for `[ui] palette`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum Palette {
+        /// Green for success, yellow for warnings, red for failures
+        #[default]
+        Default,
+        /// Blue for success and magenta for failures, told apart with
+        /// red-green color blindness
+        Colorblind,
+    }
+
+    /// A terminal color by name ("cyan", "bright blue"), checked when the
+    /// config is read.
+    #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(try_from = "String", into = "String")]
+    pub struct ColorName(String);
+
+    impl TryFrom<String> for ColorName {
+        type Error = String;
+
+        fn try_from(name: String) -> Result<Self, String> {
+            match name.parse::<Color>() {
+                Ok(_) => Ok(Self(name)),
+                Err(()) => Err(format!(
+                    "unknown color `{}` (black, red, green, yellow, blue, magenta, cyan, white, optionally \"bright ...\")",
+                    name
+                )),
+            }
+        }
+    }
+
+    impl From<ColorName> for String {
+        fn from(name: ColorName) -> Self {
+            name.0
+        }
+    }
+
+    impl ColorName {
+        pub fn new(name: &str) -> Self {
+            Self(name.into())
+        }
+
+        pub fn color(&self) -> Color {
+            self.0.parse().unwrap_or(Color::Cyan)
+        }
+
+        /// The same color as an indicatif template style ("cyan.bright").
+        pub fn dotted(&self) -> String {
+            let name = self.0.to_lowercase().replace("purple", "magenta");
+            match name.strip_prefix("bright ") {
+                Some(base) => format!("{}.bright", base.trim()),
+                None => name,
+            }
+        }
+    }
+
+    /// Colors and symbols from `[ui]`.
+    #[derive(Debug)]
+    pub struct Theme {
+        pub accent: ColorName,
+        pub success: Color,
+        pub warning: Color,
+        pub failure: Color,
+        pub success_glyph: String,
+        pub warning_glyph: String,
+        pub failure_glyph: String,
+    }
+
+    impl Theme {
+        pub fn new(config: &crate::config::UiConfig) -> Self {
+            let (success, warning, failure) = match config.palette {
+                Palette::Default => (Color::Green, Color::Yellow, Color::Red),
+                Palette::Colorblind => {
+                    (Color::BrightBlue, Color::BrightYellow, Color::BrightMagenta)
+                }
+            };
+            Self {
+                accent: config.accent.clone(),
+                success,
+                warning,
+                failure,
+                success_glyph: config.success_glyph.clone(),
+                warning_glyph: config.warning_glyph.clone(),
+                failure_glyph: config.failure_glyph.clone(),
+            }
+        }
+
+        pub fn glyph<'a>(&'a self, symbol: &'a str) -> &'a str {
+            match symbol {
+                "✓" => &self.success_glyph,
+                "⚠" => &self.warning_glyph,
+                "✗" => &self.failure_glyph,
+                other => other,
+            }
+        }
+    }
+
+    static THEME: OnceLock<Theme> = OnceLock::new();
+
+    /// Applies `[ui]`; output printed before the config is loaded uses the
+    /// defaults.
+    pub fn set_theme(config: &crate::config::UiConfig) {
+        let _ = THEME.set(Theme::new(config));
+    }
+
+    fn theme() -> &'static Theme {
+        THEME.get_or_init(|| Theme::new(&Default::default()))
+    }
+
+    /// Template style for progress bars and spinners in the accent color.
+    pub fn accent_style() -> String {
+        theme().accent.dotted()
+    }
+
+    /// Semantic colors, so `[ui]` can restyle every message at once.
+    pub trait Themed: Colorize + Sized {
+        fn accent(self) -> ColoredString {
+            self.color(theme().accent.color())
+        }
+
+        fn success(self) -> ColoredString {
+            self.color(theme().success)
+        }
+
+        fn warning(self) -> ColoredString {
+            self.color(theme().warning)
+        }
+
+        fn failure(self) -> ColoredString {
+            self.color(theme().failure)
+        }
+    }
+
+    impl<T: Colorize> Themed for T {}
+
+    /// Spinner frames for indicatif.
+    pub fn ticks() -> &'static str {
+        if plain() {
+            "|/-\\ "
+        } else {
+            "⠁⠂⠄⡀⢀⠠⠐⠈ "
+        }
+    }
+
+    /// Switches all output to linear, uncolored status sentences suitable for
+    /// screen readers and braille displays.
+    pub fn set_accessible(enabled: bool) {
+        ACCESSIBLE.store(enabled, Ordering::Relaxed);
+        if enabled {
+            colored::control::set_override(false);
+            console::set_colors_enabled(false);
+            console::set_colors_enabled_stderr(false);
+        }
+    }
+
+    pub fn accessible() -> bool {
+        ACCESSIBLE.load(Ordering::Relaxed)
+    }
+
+    /// Prints a complete sentence, used instead of transient spinner messages.
+    pub fn announce(msg: &str) {
+        println!("{}", msg);
+    }
+}
+
+/// The single owner of the terminal while updates run. Spinners and
+/// progress bars are drawn by one `MultiProgress`, and everything else
+/// printed meanwhile (command output, log events, prompts) goes above them
+/// instead of being drawn over.
+mod render {
+    use indicatif::{MultiProgress, ProgressBar};
+    use std::io::{self, Write};
+    use std::sync::LazyLock;
+
+    static BARS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+
+    /// Puts a spinner or progress bar on screen, below the others.
+    pub fn add(bar: ProgressBar) -> ProgressBar {
+        BARS.add(bar)
+    }
+
+    /// Clears the bars, runs `f` (to print or ask something) and redraws
+    /// them below what it printed.
+    pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+        BARS.suspend(f)
+    }
+
+    pub fn println(text: &str) {
+        suspend(|| println!("{}", text));
+    }
+
+    pub fn eprintln(text: &str) {
+        suspend(|| eprintln!("{}", text));
+    }
+
+    /// Console writer for log events. fmt writes each event in one call,
+    /// so a line is never split by a redraw.
+    pub struct Console;
+
+    impl Write for Console {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            suspend(|| io::stdout().write_all(buf))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            io::stdout().flush()
+        }
+    }
+}
+
+mod i18n {
+    use std::collections::HashMap;
+    use std::sync::LazyLock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Bundled message catalogs, a subset of Fluent: one `key = value` per
+    /// line with `{ $name }` placeholders. English comes first and fills in
+    /// any message a translation lacks.
+    pub const LOCALES: &[(&str, &str)] = &[
+        ("en", include_str!("../locales/en.ftl")),
+        ("de", include_str!("../locales/de.ftl")),
+    ];
+
+    static CATALOGS: LazyLock<Vec<HashMap<&'static str, &'static str>>> =
+        LazyLock::new(|| LOCALES.iter().map(|(_, source)| parse(source)).collect());
+
+    thread_local! {
+        static ENGLISH: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    }
+
+    const UNSET: usize = usize::MAX;
+    static SELECTED: AtomicUsize = AtomicUsize::new(UNSET);
+
+    pub fn parse(source: &str) -> HashMap<&str, &str> {
+        source
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect()
+    }
+
+    /// Picks the catalog for `[ui] language`; "auto" follows the environment
+    /// like gettext does.
+    pub fn set_language(language: &str) {
+        SELECTED.store(
+            resolve(language, |name| std::env::var(name).ok()),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// The catalog index for a language, falling back to English. For
+    /// "auto", the first of LC_ALL, LC_MESSAGES and LANG that is set decides,
+    /// with LANGUAGE's preference list consulted first unless that is "C".
+    pub fn resolve(language: &str, env: impl Fn(&str) -> Option<String>) -> usize {
+        if language != "auto" {
+            return index(language).unwrap_or(0);
+        }
+        let set = |name: &str| env(name).filter(|v| !v.is_empty());
+        let Some(locale) = set("LC_ALL")
+            .or_else(|| set("LC_MESSAGES"))
+            .or_else(|| set("LANG"))
+        else {
+            return 0;
+        };
+        if locale == "C" || locale == "POSIX" || locale.starts_with("C.") {
+            return 0;
+        }
+        let preferred = set("LANGUAGE").unwrap_or_default();
+        preferred
+            .split(':')
+            .chain([locale.as_str()])
+            .find_map(index)
+            .unwrap_or(0)
+    }
+
+    /// "de_DE.UTF-8" and "de" both select the German catalog.
+    fn index(tag: &str) -> Option<usize> {
+        let code = tag.split(['_', '-', '.', '@']).next()?.to_lowercase();
+        LOCALES.iter().position(|(name, _)| *name == code)
+    }
+
+    fn selected() -> usize {
+        match SELECTED.load(Ordering::Relaxed) {
+            UNSET => {
+                let index = resolve("auto", |name| std::env::var(name).ok());
+                SELECTED.store(index, Ordering::Relaxed);
+                index
+            }
+            index => index,
+        }
+    }
+
+    /// The language messages are shown in.
+    pub fn language() -> &'static str {
+        LOCALES[selected()].0
+    }
+
+    /// Use `t!` rather than calling this directly.
+    pub fn tr(key: &str, args: &[(&str, String)]) -> String {
+        let locale = if ENGLISH.get() { 0 } else { selected() };
+        translate(locale, key, args)
+    }
+
+    /// Runs `f` with messages in English whatever the user's language, for
+    /// text that programs read back.
+    pub fn english<T>(f: impl FnOnce() -> T) -> T {
+        let previous = ENGLISH.replace(true);
+        let value = f();
+        ENGLISH.set(previous);
+        value
+    }
+
+    pub fn translate(locale: usize, key: &str, args: &[(&str, String)]) -> String {
+        let catalogs = &*CATALOGS;
+        let template = catalogs[locale]
+            .get(key)
+            .or_else(|| catalogs[0].get(key))
+            .copied()
+            .unwrap_or(key);
+        args.iter()
+            .fold(template.to_string(), |text, (name, value)| {
+                text.replace(&format!("{{ ${} }}", name), value)
+            })
+    }
+}
+
+mod events {
+    use crate::history::RunRecord;
+    use crate::updater::{Stage, StageStats};
+    use chrono::{DateTime, Local};
+    use serde::Serialize;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static ENABLED: AtomicBool = AtomicBool::new(false);
+
+    /// `--porcelain`: stdout carries only these events, one JSON object per
+    /// line, for GUIs and wrappers that draw their own progress.
+    pub fn set_enabled(enabled: bool) {
+        ENABLED.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn enabled() -> bool {
+        ENABLED.load(Ordering::Relaxed)
+    }
+
+    #[derive(Debug, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Stream {
+        Stdout,
+        Stderr,
+    }
+
+    #[derive(Debug, Serialize)]
+    #[serde(tag = "event", rename_all = "kebab-case")]
+    pub enum Event<'a> {
+        PhaseStart {
+            stage: Stage,
+        },
+        CommandOutput {
+            stage: Stage,
+            command: &'a str,
+            stream: Stream,
+            line: &'a str,
+        },
+        PhaseEnd {
+            stage: Stage,
+            #[serde(flatten)]
+            stats: &'a StageStats,
+            error: Option<&'a str>,
+        },
+        Summary {
+            system_updated: bool,
+            flatpak_updated: bool,
+            firmware_updated: bool,
+            cancelled: bool,
+            halted: &'a [Stage],
+            #[serde(flatten)]
+            run: RunRecord,
+        },
+    }
+
+    #[derive(Serialize)]
+    struct Line<'a> {
+        time: DateTime<Local>,
+        run: &'static str,
+        #[serde(flatten)]
+        event: &'a Event<'a>,
+    }
+
+    /// Writes `event` to stdout in porcelain mode; does nothing otherwise.
+    pub fn emit(event: Event) {
+        if !enabled() {
+            return;
+        }
+        let Ok(json) = serde_json::to_string(&Line {
+            time: Local::now(),
+            run: crate::artifacts::run_id(),
+            event: &event,
+        }) else {
+            return;
+        };
+        let mut stdout = std::io::stdout().lock();
+        let _ = writeln!(stdout, "{}", json);
+        let _ = stdout.flush();
+    }
+}
+
+mod help {
+    use crate::ui::Themed;
+    use clap::CommandFactory;
+    use colored::Colorize;
+
+    pub struct Example {
+        pub topic: &'static str,
+        pub title: &'static str,
+        pub commands: &'static [&'static str],
+        pub note: &'static str,
+    }
+
+    pub const EXAMPLES: &[Example] = &[
+        Example {
+            topic: "basics",
+            title: "Show what updates are available",
+            commands: &["sudo fup --refresh"],
+            note: "Checks dnf5, Flatpak and fwupd without changing anything.",
+        },
+        Example {
+            topic: "basics",
+            title: "Update system packages and Flatpak applications",
+            commands: &["sudo fup --update-all"],
+            note: "",
+        },
+        Example {
+            topic: "basics",
+            title: "Preview a full update",
+            commands: &["sudo fup --update-all --dry-run"],
+            note: "Prints the commands that would run without executing them.",
+        },
+        Example {
+            topic: "basics",
+            title: "Update only specific packages or apps",
+            commands: &[
+                "sudo fup --update-system kernel firefox",
+                "sudo fup --update-flatpak org.mozilla.firefox",
+            ],
+            note: "Skips the autoremove/unused-runtime cleanup that a full update does.",
+        },
+        Example {
+            topic: "firmware",
+            title: "Update everything including firmware",
+            commands: &["sudo fup --update-all --firmware"],
+            note: "Firmware is listed with any warnings and flashed only once you confirm, even with -y; \
+                   unattended runs need --firmware-force. Keep the machine on AC power meanwhile.",
+        },
+        Example {
+            topic: "firmware",
+            title: "Update only firmware",
+            commands: &["sudo fup --update-firmware"],
+            note: "Requires fwupdmgr.",
+        },
+        Example {
+            topic: "scripting",
+            title: "Unattended run for cron or scripts",
+            commands: &["sudo fup --update-all --assume-yes --quiet --no-reboot-prompt"],
+            note: "Only errors are printed; the exit code reports success or failure.",
+        },
+        Example {
+            topic: "timers",
+            title: "Run nightly from a systemd timer",
+            commands: &[
+                "# /etc/systemd/system/fup.service",
+                "[Service]",
+                "Type=oneshot",
+                "ExecStart=/usr/local/bin/fup --update-all --assume-yes --quiet --no-reboot-prompt",
+                "",
+                "# /etc/systemd/system/fup.timer",
+                "[Timer]",
+                "OnCalendar=*-*-* 03:30",
+                "Persistent=true",
+                "",
+                "[Install]",
+                "WantedBy=timers.target",
+                "",
+                "sudo systemctl enable --now fup.timer",
+            ],
+            note: "Check results later with `journalctl -u fup.service`.",
+        },
+        Example {
+            topic: "fleet",
+            title: "Update several hosts over SSH, one after another",
+            commands: &[
+                "for host in web1 web2 db1; do",
+                "    ssh \"$host\" sudo fup --update-all --assume-yes --quiet --no-reboot-prompt || break",
+                "done",
+            ],
+            note: "Stops at the first host that fails so problems do not spread.",
+        },
+        Example {
+            topic: "snapshots",
+            title: "Take a btrfs snapshot with snapper before updating",
+            commands: &[
+                "sudo snapper create --description \"before fup\" --cleanup-algorithm number",
+                "sudo fup --update-all",
+            ],
+            note: "Roll back with `snapper rollback` if the update breaks the system.",
+        },
+        Example {
+            topic: "accessibility",
+            title: "Screen-reader friendly output",
+            commands: &["sudo fup --update-all --accessible"],
+            note: "Replaces spinners and box drawing with plain status sentences.",
+        },
+    ];
+
+    /// Prints curated examples and the options whose name or help mentions the
+    /// query. Without a query, prints every example grouped by topic.
+    pub fn print_examples(query: Option<&str>) {
+        let query = query.map(str::to_lowercase);
+        let matches: Vec<&Example> = EXAMPLES
+            .iter()
+            .filter(|e| query.as_deref().is_none_or(|q| example_matches(e, q)))
+            .collect();
+
+        let mut topic = "";
+        for example in &matches {
+            if example.topic != topic {
+                topic = example.topic;
+                println!("{}\n", topic.to_uppercase().warning().bold());
+            }
+            println!("  {}", example.title.bold());
+            for line in example.commands {
+                println!("    {}", line.success());
+            }
+            if !example.note.is_empty() {
+                println!("    {}", format!("# {}", example.note).dimmed());
+            }
+            println!();
+        }
+
+        let Some(query) = query else {
+            return;
+        };
+
+        let options = matching_options(&query);
+        if !options.is_empty() {
+            println!("{}\n", "RELATED OPTIONS".warning().bold());
+            for (flag, help) in &options {
+                println!("  {:<24}  {}", flag.accent(), help);
+            }
+            println!();
+        }
+
+        if matches.is_empty() && options.is_empty() {
+            let topics: Vec<&str> = EXAMPLES.iter().fold(Vec::new(), |mut acc, e| {
+                if !acc.contains(&e.topic) {
+                    acc.push(e.topic);
+                }
+                acc
+            });
+            println!(
+                "No examples match '{}'. Topics: {}",
+                query,
+                topics.join(", ")
+            );
+        }
+    }
+
+    fn example_matches(example: &Example, query: &str) -> bool {
+        example.topic.contains(query)
+            || example.title.to_lowercase().contains(query)
+            || example.note.to_lowercase().contains(query)
+            || example
+                .commands
+                .iter()
+                .any(|c| c.to_lowercase().contains(query))
+    }
+
+    /// Options from the real CLI definition whose flag or help text mentions the query.
+    fn matching_options(query: &str) -> Vec<(String, String)> {
+        crate::cli::Args::command()
+            .get_arguments()
+            .filter_map(|arg| {
+                let long = arg.get_long()?;
+                let help = arg.get_help().map(|h| h.to_string()).unwrap_or_default();
+                if !long.contains(query) && !help.to_lowercase().contains(query) {
+                    return None;
+                }
+                let flag = match arg.get_short() {
+                    Some(short) => format!("-{}, --{}", short, long),
+                    None => format!("    --{}", long),
+                };
+                Some((flag, help))
+            })
+            .collect()
+    }
+}
+
+mod news {
+    use crate::ui::Themed;
+    use colored::Colorize;
+    use std::path::Path;
+
+    const CHANGELOG: &str = include_str!("../CHANGELOG.md");
+    const SEEN_FILE: &str = "/var/lib/fup/news-seen";
+    const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+    struct Section<'a> {
+        version: &'a str,
+        body: &'a str,
+    }
+
+    /// Splits the embedded changelog into `## [version]` sections, newest first.
+    fn sections() -> Vec<Section<'static>> {
+        CHANGELOG
+            .split("\n## ")
+            .skip(1)
+            .filter_map(|chunk| {
+                let (heading, body) = chunk.split_once('\n')?;
+                let version = heading.trim().trim_start_matches('[').split(']').next()?;
+                Some(Section {
+                    version,
+                    body: body.trim(),
+                })
+            })
+            .collect()
+    }
+
+    /// Prints the notes for the installed version (and anything unreleased
+    /// built into this binary), or every release with `all`.
+    pub fn print_news(all: bool) {
+        let sections = sections();
+        let shown = if all {
+            sections.len()
+        } else {
+            sections
+                .iter()
+                .position(|s| s.version == VERSION)
+                .map_or(sections.len(), |i| i + 1)
+        };
+
+        for section in sections.iter().take(shown).filter(|s| !s.body.is_empty()) {
+            println!("\n{}", format!("fup {}", section.version).accent().bold());
+            print_body(section.body);
+        }
+        println!();
+
+        mark_seen();
+    }
+
+    fn print_body(body: &str) {
+        let mut config_options = false;
+        for line in body.lines() {
+            if let Some(heading) = line.strip_prefix("### ") {
+                config_options = heading.eq_ignore_ascii_case("New config options");
+                println!("\n  {}", heading.warning().bold());
+            } else if let Some(item) = line.strip_prefix("- ") {
+                if config_options {
+                    println!("    {} {}", "+".success().bold(), item.accent());
+                } else {
+                    println!("    {} {}", crate::ui::sym("•"), item);
+                }
+            } else if !line.trim().is_empty() {
+                println!("      {}", line.trim());
+            }
+        }
+    }
+
+    /// One-line hint shown after fup itself was upgraded, until `fup news` is run.
+    pub fn upgrade_notice() -> Option<String> {
+        let seen = std::fs::read_to_string(SEEN_FILE).ok();
+        if seen.as_deref().map(str::trim) == Some(VERSION) {
+            return None;
+        }
+        Some(format!(
+            "fup was updated to {}. Run `fup news` to see what's new.",
+            VERSION
+        ))
+    }
+
+    fn mark_seen() {
+        let path = Path::new(SEEN_FILE);
+        if let Some(parent) = path.parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        // Best effort: non-root users simply keep seeing the notice
+        let _ = std::fs::write(path, VERSION);
+    }
+}
+
+mod self_update {
+    use crate::config::SelfUpdateConfig;
+    use crate::error::{Result, UpdateError};
+    use crate::ui::Themed;
+    use base64::Engine;
+    use colored::Colorize;
+    use serde::Deserialize;
+    use sha2::{Digest, Sha256};
+    use std::{io::Write, os::unix::fs::OpenOptionsExt, path::Path, time::Duration};
+    use tracing::{debug, info};
+
+    const VERSION: &str = env!("CARGO_PKG_VERSION");
+    pub const CHECKSUMS: &str = "SHA256SUMS";
+    pub const SIGNATURE: &str = "SHA256SUMS.sig";
+
+    /// A release as GitHub's `releases/latest` API describes it.
+    #[derive(Debug, Deserialize)]
+    pub struct Release {
+        pub tag_name: String,
+        #[serde(default)]
+        pub assets: Vec<Asset>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct Asset {
+        pub name: String,
+        pub browser_download_url: String,
+    }
+
+    impl Release {
+        pub fn version(&self) -> &str {
+            self.tag_name.trim_start_matches('v')
+        }
+
+        fn asset(&self, name: &str) -> Option<&Asset> {
+            self.assets.iter().find(|a| a.name == name)
+        }
+    }
+
+    fn failed(message: impl std::fmt::Display) -> UpdateError {
+        UpdateError::SelfUpdate(message.to_string())
+    }
+
+    fn client(timeout: Duration) -> Result<reqwest::Client> {
+        // GitHub's API turns away requests without a User-Agent
+        reqwest::Client::builder()
+            .timeout(timeout)
+            .user_agent(concat!("fup/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| UpdateError::Config(e.to_string()))
+    }
+
+    async fn download(client: &reqwest::Client, url: &str) -> Result<Vec<u8>> {
+        debug!("Downloading {}", url);
+        let response = client
+            .get(url)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| failed(format!("{}: {}", url, e)))?;
+        Ok(response
+            .bytes()
+            .await
+            .map_err(|e| failed(format!("{}: {}", url, e)))?
+            .to_vec())
+    }
+
+    pub async fn latest(config: &SelfUpdateConfig, timeout: Duration) -> Result<Release> {
+        let body = download(&client(timeout)?, &config.url).await?;
+        serde_json::from_slice(&body).map_err(|e| {
+            failed(format!(
+                "unexpected release data from {}: {}",
+                config.url, e
+            ))
+        })
+    }
+
+    /// Whether `candidate` is a later version than `current`, comparing the
+    /// dot-separated numbers ("2.10.0" is newer than "2.9.1").
+    pub fn is_newer(candidate: &str, current: &str) -> bool {
+        fn numbers(version: &str) -> Vec<u64> {
+            version
+                .split(['.', '-'])
+                .map_while(|p| p.parse().ok())
+                .collect()
+        }
+        numbers(candidate) > numbers(current)
+    }
+
+    /// `name`'s digest in a `sha256sum`-style list.
+    pub fn expected_digest<'a>(sums: &'a str, name: &str) -> Option<&'a str> {
+        sums.lines().find_map(|line| {
+            let (digest, file) = line.split_once(char::is_whitespace)?;
+            (file.trim().trim_start_matches('*') == name).then_some(digest)
+        })
+    }
+
+    /// Lowercase hex SHA-256, as `sha256sum` prints it.
+    pub fn digest(data: &[u8]) -> String {
+        Sha256::digest(data)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Checks a base64 ed25519 `signature` of a checksum list against a
+    /// base64 `public_key`, naming the config key it came from in errors.
+    pub fn verify_signature(
+        sums: &str,
+        signature: &str,
+        public_key: &str,
+        setting: &str,
+    ) -> std::result::Result<(), String> {
+        let engine = base64::engine::general_purpose::STANDARD;
+        let key = engine
+            .decode(public_key.trim())
+            .map_err(|e| format!("{} is not base64: {}", setting, e))?;
+        let signature = engine
+            .decode(signature.trim())
+            .map_err(|e| format!("{} is not base64: {}", SIGNATURE, e))?;
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, key)
+            .verify(sums.as_bytes(), &signature)
+            .map_err(|_| format!("the {} signature does not match {}", CHECKSUMS, setting))
+    }
+
+    /// Checks the binary against the release's checksum list and the list
+    /// against its ed25519 signature. Without a public key only
+    /// `allow_unsigned` lets the checksum alone do.
+    pub fn verify(
+        binary: &[u8],
+        name: &str,
+        sums: &str,
+        signature: Option<&str>,
+        public_key: &str,
+        allow_unsigned: bool,
+    ) -> Result<()> {
+        if !public_key.is_empty() {
+            let signature = signature.ok_or_else(|| {
+                failed(format!(
+                    "the release has no {} but [self_update] public_key is set",
+                    SIGNATURE
+                ))
+            })?;
+            verify_signature(sums, signature, public_key, "[self_update] public_key")
+                .map_err(failed)?;
+        } else if !allow_unsigned {
+            return Err(failed(
+                "no [self_update] public_key is set to check the release signature with; \
+                 set it, or allow_unsigned = true to rely on the checksum alone",
+            ));
+        }
+
+        let expected = expected_digest(sums, name)
+            .ok_or_else(|| failed(format!("{} has no entry for {}", CHECKSUMS, name)))?;
+        let actual = digest(binary);
+        if !expected.eq_ignore_ascii_case(&actual) {
+            return Err(failed(format!(
+                "checksum mismatch for {}: expected {}, got {}",
+                name, expected, actual
+            )));
+        }
+        Ok(())
+    }
+
+    /// Writes the new binary next to the running one and renames it into
+    /// place, so an interrupted update leaves the old binary intact.
+    fn replace(exe: &Path, binary: &[u8]) -> std::io::Result<()> {
+        let dir = exe.parent().unwrap_or(Path::new("/"));
+        let name = exe.file_name().and_then(|n| n.to_str()).unwrap_or("fup");
+        let staged = dir.join(format!(".{}.new-{}", name, std::process::id()));
+        let result = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o755)
+            .open(&staged)
+            .and_then(|mut file| {
+                file.write_all(binary)?;
+                file.sync_all()
+            })
+            .and_then(|()| std::fs::rename(&staged, exe));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&staged);
+        }
+        result
+    }
+
+    /// Installs the latest release over the running binary, unless it is
+    /// already current or the binary belongs to an RPM package.
+    pub async fn run(config: &SelfUpdateConfig, check_only: bool) -> Result<()> {
+        let release = latest(config, Duration::from_secs(30)).await?;
+        if !is_newer(release.version(), VERSION) {
+            println!(
+                "{}",
+                format!("fup {} is the latest release", VERSION).success()
+            );
+            return Ok(());
+        }
+        println!(
+            "fup {} is available (installed: {})",
+            release.version().bold(),
+            VERSION
+        );
+        if check_only {
+            return Ok(());
+        }
+
+        let exe = std::env::current_exe()?.canonicalize()?;
+        if let Ok(out) = tokio::process::Command::new("rpm")
+            .arg("-qf")
+            .arg(&exe)
+            .output()
+            .await
+            && out.status.success()
+        {
+            return Err(failed(format!(
+                "{} belongs to {}; update it with dnf instead",
+                exe.display(),
+                String::from_utf8_lossy(&out.stdout).trim()
+            )));
+        }
+
+        let asset = release.asset(&config.asset).ok_or_else(|| {
+            failed(format!(
+                "release {} has no asset named {}",
+                release.tag_name, config.asset
+            ))
+        })?;
+        let sums = release.asset(CHECKSUMS).ok_or_else(|| {
+            failed(format!(
+                "release {} publishes no {}, so the download can't be verified",
+                release.tag_name, CHECKSUMS
+            ))
+        })?;
+
+        let client = client(Duration::from_secs(300))?;
+        let binary = download(&client, &asset.browser_download_url).await?;
+        let sums = String::from_utf8_lossy(&download(&client, &sums.browser_download_url).await?)
+            .into_owned();
+        let signature = match release.asset(SIGNATURE) {
+            Some(sig) => Some(
+                String::from_utf8_lossy(&download(&client, &sig.browser_download_url).await?)
+                    .into_owned(),
+            ),
+            None => None,
+        };
+        verify(
+            &binary,
+            &asset.name,
+            &sums,
+            signature.as_deref(),
+            &config.public_key,
+            config.allow_unsigned,
+        )?;
+
+        replace(&exe, &binary)
+            .map_err(|e| failed(format!("cannot replace {}: {}", exe.display(), e)))?;
+        info!("Replaced {} with fup {}", exe.display(), release.version());
+        println!(
+            "{}",
+            format!(
+                "Updated fup {} {} {}",
+                VERSION,
+                crate::ui::sym("→"),
+                release.version()
+            )
+            .success()
+        );
+        Ok(())
+    }
+
+    /// A one-line notice for `--refresh` when a newer release is out. Any
+    /// failure to check is only logged.
+    pub async fn notice(config: &SelfUpdateConfig) -> Option<String> {
+        if !config.notify {
+            return None;
+        }
+        match latest(config, Duration::from_secs(5)).await {
+            Ok(release) if is_newer(release.version(), VERSION) => Some(format!(
+                "fup {} is available (installed: {}); run `sudo fup self-update` to install it",
+                release.version(),
+                VERSION
+            )),
+            Ok(_) => None,
+            Err(e) => {
+                debug!("Cannot check for a newer fup: {}", e);
+                None
+            }
+        }
+    }
+}
+
+mod config_cmd {
+    use crate::config::{Config, Source, TEMPLATE};
+    use crate::error::Result;
+    use crate::ui::Themed;
+    use colored::Colorize;
+    use std::path::PathBuf;
+
+    const DEFAULT_PATH: &str = "/etc/fup.toml";
+
+    /// Settings `config show` doesn't print, since its output ends up in
+    /// terminals, logs and bug reports: `[section] key`.
+    const SECRETS: &[(&str, &str)] = &[("notify", "mqtt_password"), ("telemetry", "headers")];
+
+    /// Writes the commented default config, refusing to replace an existing
+    /// file unless `force` is set.
+    pub fn init(path: Option<&PathBuf>, force: bool) -> std::io::Result<PathBuf> {
+        let path = path.cloned().unwrap_or_else(|| PathBuf::from(DEFAULT_PATH));
+        if path.exists() && !force {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} already exists (use --force to overwrite)",
+                    path.display()
+                ),
+            ));
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, TEMPLATE)?;
+        println!("{} Wrote {}", crate::ui::sym("✓").success(), path.display());
+        Ok(path)
+    }
+
+    /// Parses the config file that would be used and reports the result.
+    /// Returns false if it has errors.
+    pub fn validate(path: Option<&PathBuf>) -> bool {
+        match Config::load_with_source(path, None) {
+            Ok((
+                _,
+                Source {
+                    file: Some(file),
+                    profiles,
+                    ..
+                },
+            )) => {
+                let mut valid = true;
+                for name in &profiles {
+                    if let Err(e) = Config::load_with_source(path, Some(name)) {
+                        println!("{} {}", crate::ui::sym("✗").failure(), e);
+                        valid = false;
+                    }
+                }
+                if valid {
+                    println!(
+                        "{} {} is valid",
+                        crate::ui::sym("✓").success(),
+                        file.display()
+                    );
+                    if !profiles.is_empty() {
+                        println!("  profiles: {}", profiles.join(", "));
+                    }
+                }
+                valid
+            }
+            Ok((_, Source { file: None, .. })) => {
+                println!(
+                    "{} No config file found; built-in defaults are used",
+                    crate::ui::sym("○").warning()
+                );
+                true
+            }
+            Err(e) => {
+                println!("{} {}", crate::ui::sym("✗").failure(), e);
+                false
+            }
+        }
+    }
+
+    /// Prints every setting with its effective value and whether it came from
+    /// the config file, the selected profile, the environment or the
+    /// built-in default.
+    pub fn show(path: Option<&PathBuf>, profile: Option<&str>) -> Result<()> {
+        let (config, source) = Config::load_with_source(path, profile)?;
+        let Ok(toml::Value::Table(sections)) = toml::Value::try_from(&config) else {
+            return Ok(());
+        };
+
+        match &source.file {
+            Some(file) => println!(
+                "# Effective configuration ({} over built-in defaults)",
+                file.display()
+            ),
+            None => println!("# Effective configuration (built-in defaults, no config file found)"),
+        }
+        if let Some((name, _)) = &source.profile {
+            println!("# Profile: {}", name);
+        }
+
+        for (section, values) in &sections {
+            let toml::Value::Table(values) = values else {
+                continue;
+            };
+            println!("\n[{}]", section.accent().bold());
+            for (key, value) in values {
+                let from_env = source.env.get(&(section.clone(), key.clone()));
+                let from_file = source
+                    .file
+                    .as_ref()
+                    .filter(|_| source.raw.get(section).and_then(|t| t.get(key)).is_some());
+                let origin = match (from_env, source.profile_setting(section, key), from_file) {
+                    (Some(var), _, _) => var.warning(),
+                    (None, Some(name), _) => format!("profile {}", name).magenta(),
+                    (None, None, Some(file)) => file.display().to_string().success(),
+                    (None, None, None) => "default".dimmed(),
+                };
+                let line = format!("{} = {}", key, redacted(section, key, value));
+                println!("{:<48} # {}", line, origin);
+            }
+        }
+        Ok(())
+    }
+
+    /// `value` with secrets replaced by "<set>"; empty ones stay visible, so
+    /// it still shows whether one is configured.
+    pub fn redacted(section: &str, key: &str, value: &toml::Value) -> toml::Value {
+        if !SECRETS.contains(&(section, key)) {
+            return value.clone();
+        }
+        match value {
+            toml::Value::String(s) if !s.is_empty() => toml::Value::String("<set>".into()),
+            toml::Value::Table(table) => toml::Value::Table(
+                table
+                    .iter()
+                    .map(|(name, value)| (name.clone(), redacted(section, key, value)))
+                    .collect(),
+            ),
+            value => value.clone(),
+        }
+    }
+}
+
+mod pending {
+    use crate::updater::RestartCheck;
+    use chrono::{DateTime, Local};
+    use serde::{Deserialize, Serialize};
+    use std::path::Path;
+    use tokio::process::Command;
+    use tracing::{debug, warn};
+
+    /// Lives on tmpfs, so a reboot clears it.
+    pub const MARKER: &str = "/run/fup/reboot-required.json";
+    /// Login message about the pending reboot. pam_motd reads /run/motd.d
+    /// too, and like the marker it is gone after the reboot.
+    pub const MOTD: &str = "/run/motd.d/fup";
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct RebootMarker {
+        pub reboot_required: bool,
+        pub detected: DateTime<Local>,
+        pub reason: String,
+        /// Updated packages that need the reboot (kernel, glibc, systemd, ...).
+        pub packages: Vec<String>,
+    }
+
+    /// Writes the marker when `check` found a pending reboot and removes a
+    /// stale one (and login message) otherwise. Failures are logged, never
+    /// fatal.
+    pub fn record(check: &RestartCheck) -> Option<RebootMarker> {
+        let path = Path::new(MARKER);
+        let Some(reason) = &check.reboot else {
+            for stale in [MARKER, MOTD] {
+                if Path::new(stale).exists()
+                    && let Err(e) = std::fs::remove_file(stale)
+                {
+                    warn!("Failed to remove {}: {}", stale, e);
+                }
+            }
+            return None;
+        };
+
+        let marker = RebootMarker {
+            reboot_required: true,
+            detected: Local::now(),
+            reason: reason.lines().next().unwrap_or_default().trim().to_string(),
+            packages: reason
+                .lines()
+                .filter_map(|l| l.trim().strip_prefix("* "))
+                .map(|p| p.trim().to_string())
+                .collect(),
+        };
+
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                let json = serde_json::to_string_pretty(&marker).map_err(std::io::Error::other)?;
+                std::fs::write(path, json)
+            });
+        match result {
+            Ok(()) => debug!("Wrote {}", MARKER),
+            Err(e) => warn!("Failed to write {}: {}", MARKER, e),
+        }
+        Some(marker)
+    }
+
+    /// What users are told about the pending reboot.
+    pub fn notice(marker: &RebootMarker) -> String {
+        let mut text = format!(
+            "fup: a reboot is pending to finish installing updates since {}: {}",
+            marker.detected.format("%Y-%m-%d %H:%M"),
+            marker.reason
+        );
+        if !marker.packages.is_empty() {
+            text.push_str(&format!("\nUpdated: {}", marker.packages.join(", ")));
+        }
+        text
+    }
+
+    /// Broadcasts the notice with `wall` and/or leaves it as the login
+    /// message, for runs nobody watched.
+    pub async fn announce(marker: &RebootMarker, wall: bool, motd: bool) {
+        let text = notice(marker);
+        if wall {
+            match Command::new("wall").arg(&text).status().await {
+                Ok(status) if status.success() => debug!("Broadcast the pending reboot"),
+                Ok(status) => warn!("wall exited with {}", status),
+                Err(e) => warn!("Failed to run wall: {}", e),
+            }
+        }
+        if motd {
+            let path = Path::new(MOTD);
+            let result = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| std::fs::write(path, text + "\n"));
+            match result {
+                Ok(()) => debug!("Wrote {}", MOTD),
+                Err(e) => warn!("Failed to write {}: {}", MOTD, e),
+            }
+        }
+    }
+
+    pub fn read() -> Option<RebootMarker> {
+        let content = std::fs::read_to_string(MARKER).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+}
+
+mod notify {
+    use crate::config::NotifyConfig;
+    use crate::system::command_exists;
+    use std::time::Duration;
+    use tokio::process::Command;
+    use tracing::{debug, warn};
+
+    /// Desktop session types logind reports for graphical logins.
+    const GRAPHICAL: &[&str] = &["x11", "wayland", "mir"];
+
+    /// Users logged in to a graphical session. A root run has no session
+    /// bus of its own, so each user's is reached through
+    /// `busctl --machine=USER@.host --user`.
+    pub async fn graphical_users() -> Vec<String> {
+        if !command_exists("loginctl") {
+            return Vec::new();
+        }
+        let Ok(output) = Command::new("loginctl")
+            .args(["list-sessions", "--no-legend"])
+            .output()
+            .await
+        else {
+            return Vec::new();
+        };
+        let mut users = Vec::new();
+        for id in String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|l| l.split_whitespace().next())
+        {
+            let Ok(output) = Command::new("loginctl")
+                .args([
+                    "show-session",
+                    id,
+                    "-p",
+                    "Name",
+                    "-p",
+                    "Type",
+                    "-p",
+                    "Class",
+                ])
+                .output()
+                .await
+            else {
+                continue;
+            };
+            if let Some(user) = graphical_user(&String::from_utf8_lossy(&output.stdout))
+                && !users.contains(&user)
+            {
+                users.push(user);
+            }
+        }
+        users
+    }
+
+    /// The user of a `loginctl show-session` property list, if it is a
+    /// graphical user session rather than a greeter, tty or ssh login.
+    pub fn graphical_user(properties: &str) -> Option<String> {
+        let property = |key: &str| {
+            properties
+                .lines()
+                .find_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+                .map(str::trim)
+        };
+        (property("Class") == Some("user")
+            && property("Type").is_some_and(|t| GRAPHICAL.contains(&t)))
+        .then(|| property("Name").map(String::from))
+        .flatten()
+    }
+
+    /// Arguments of the org.freedesktop.Notifications.Notify call for `user`.
+    pub fn notify_args(user: &str, title: &str, body: &str, urgent: bool) -> Vec<String> {
+        let (icon, urgency) = if urgent {
+            ("software-update-urgent", "2")
+        } else {
+            ("software-update-available", "1")
+        };
+        let machine = format!("--machine={}@.host", user);
+        [
+            "--user",
+            &machine,
+            "call",
+            "org.freedesktop.Notifications",
+            "/org/freedesktop/Notifications",
+            "org.freedesktop.Notifications",
+            "Notify",
+            "susssasa{sv}i",
+            "fup",
+            "0",
+            icon,
+            title,
+            body,
+            "0",
+            "1",
+            "urgency",
+            "y",
+            urgency,
+            "-1",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect()
+    }
+
+    /// Publishes `(subtopic, JSON)` messages under `[notify] mqtt_topic`,
+    /// retained so a dashboard shows the latest state as soon as it
+    /// subscribes. Waits for the broker to acknowledge them, at most 10
+    /// seconds; failures are logged, never fatal.
+    pub async fn publish(config: &NotifyConfig, messages: &[(&str, serde_json::Value)]) {
+        use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+
+        let Ok(Some((host, port))) = config.mqtt_address() else {
+            return;
+        };
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        let hostname = hostname.trim();
+        let topic = config.mqtt_topic.replace("{host}", hostname);
+
+        let mut options = MqttOptions::new(
+            format!("fup-{}-{}", hostname, std::process::id()),
+            host,
+            port,
+        );
+        options.set_keep_alive(Duration::from_secs(10));
+        if !config.mqtt_username.is_empty() {
+            options.set_credentials(&config.mqtt_username, &config.mqtt_password);
+        }
+        let (client, mut eventloop) = AsyncClient::new(options, messages.len().max(1));
+        for (subtopic, payload) in messages {
+            let topic = format!("{}/{}", topic.trim_end_matches('/'), subtopic);
+            if let Err(e) = client.try_publish(&topic, QoS::AtLeastOnce, true, payload.to_string())
+            {
+                warn!("Failed to queue MQTT message for {}: {}", topic, e);
+                return;
+            }
+        }
+
+        let delivered = tokio::time::timeout(Duration::from_secs(10), async {
+            let mut acked = 0;
+            while acked < messages.len() {
+                match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::PubAck(_))) => acked += 1,
+                    Ok(_) => {}
+                    Err(e) => return Err(e.to_string()),
+                }
+            }
+            let _ = client.try_disconnect();
+            let _ = eventloop.poll().await;
+            Ok(())
+        })
+        .await;
+        match delivered {
+            Ok(Ok(())) => debug!(
+                "Published {} MQTT message(s) under {}",
+                messages.len(),
+                topic
+            ),
+            Ok(Err(e)) => warn!(
+                "Failed to publish to MQTT broker {}: {}",
+                config.mqtt_broker, e
+            ),
+            Err(_) => warn!(
+                "MQTT broker {} didn't acknowledge within 10s",
+                config.mqtt_broker
+            ),
+        }
+    }
+
+    /// Shows the notification to every graphical user. Failures, such as
+    /// a session without a notification daemon, are logged, never fatal.
+    pub async fn send(title: &str, body: &str, urgent: bool) {
+        if !command_exists("busctl") {
+            debug!("busctl not found; no desktop notification");
+            return;
+        }
+        for user in graphical_users().await {
+            match Command::new("busctl")
+                .args(notify_args(&user, title, body, urgent))
+                .output()
+                .await
+            {
+                Ok(output) if output.status.success() => debug!("Notified {}", user),
+                Ok(output) => warn!(
+                    "Failed to notify {}: {}",
+                    user,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+                Err(e) => warn!("Failed to run busctl: {}", e),
+            }
+        }
+    }
+}
+
+mod cache {
+    use crate::updater::AvailableUpdates;
+    use chrono::{DateTime, Local, TimeDelta};
+    use serde::{Deserialize, Serialize};
+    use std::path::Path;
+    use tracing::debug;
+
+    pub const FILE: &str = "/var/cache/fup/updates.json";
+
+    /// The result of the last `--refresh` on this machine.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct CachedCheck {
+        pub checked: DateTime<Local>,
+        pub updates: AvailableUpdates,
+        /// Set once updates were applied; the lists are kept as the
+        /// baseline for what is new at the next check.
+        #[serde(default)]
+        pub outdated: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub new: Option<NewUpdates>,
+    }
+
+    /// Updates that weren't pending at the check before.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct NewUpdates {
+        pub since: DateTime<Local>,
+        pub updates: AvailableUpdates,
+    }
+
+    impl CachedCheck {
+        pub fn age(&self) -> TimeDelta {
+            Local::now() - self.checked
+        }
+
+        pub fn is_fresh(&self, max_age: TimeDelta) -> bool {
+            !self.outdated && self.age() <= max_age
+        }
+    }
+
+    pub fn load() -> Option<CachedCheck> {
+        let content = std::fs::read_to_string(FILE).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    /// Saves a check of the running system, along with what is new compared
+    /// to the previous one. Failures only cost a re-check later.
+    pub fn store(updates: &AvailableUpdates) -> CachedCheck {
+        let check = CachedCheck {
+            checked: Local::now(),
+            updates: updates.clone(),
+            outdated: false,
+            new: load().map(|previous| NewUpdates {
+                since: previous.checked,
+                updates: updates.new_since(&previous.updates),
+            }),
+        };
+        write(&check);
+        check
+    }
+
+    /// Marks the cached check as no longer describing the system, once
+    /// updates have been applied.
+    pub fn mark_outdated() {
+        if let Some(mut check) = load() {
+            check.outdated = true;
+            write(&check);
+        }
+    }
+
+    fn write(check: &CachedCheck) {
+        let path = Path::new(FILE);
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                let json = serde_json::to_string_pretty(check).map_err(std::io::Error::other)?;
+                std::fs::write(path, json)
+            });
+        if let Err(e) = result {
+            debug!("Failed to write {}: {}", FILE, e);
+        }
+    }
+
+    /// Parses an age like "90s", "30m", "6h" or "2d"; a bare number is seconds.
+    pub fn parse_age(text: &str) -> std::result::Result<TimeDelta, String> {
+        let text = text.trim();
+        let (number, unit) = text.split_at(
+            text.find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(text.len()),
+        );
+        let n: i64 = number
+            .parse()
+            .map_err(|_| format!("expected a duration like 30m, 6h or 2d, got {:?}", text))?;
+        match unit {
+            "" | "s" => Ok(TimeDelta::seconds(n)),
+            "m" => Ok(TimeDelta::minutes(n)),
+            "h" => Ok(TimeDelta::hours(n)),
+            "d" => Ok(TimeDelta::days(n)),
+            _ => Err(format!(
+                "unknown unit {:?} in {:?}; use s, m, h or d",
+                unit, text
+            )),
+        }
+    }
+}
+
+mod doctor {
+    use crate::config::Config;
+    use crate::ui::Themed;
+    use colored::Colorize;
+    use nix::unistd::AccessFlags;
+    use std::path::{Path, PathBuf};
+    use tokio::process::Command;
+
+    const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+    /// Backends whose version is worth quoting in a bug report.
+    const VERSIONED: &[&str] = &["dnf5", "dnf", "flatpak", "fwupdmgr"];
+
+    #[derive(Default)]
+    struct Report {
+        problems: usize,
+    }
+
+    impl Report {
+        fn heading(&self, title: &str) {
+            if crate::ui::accessible() {
+                println!("\n{}:", title);
+            } else {
+                println!("\n{}", title.bold());
+            }
+        }
+
+        fn ok(&self, text: impl std::fmt::Display) {
+            self.line(crate::ui::sym("✓").success().to_string(), "OK", text);
+        }
+
+        fn note(&self, text: impl std::fmt::Display) {
+            self.line(crate::ui::sym("○").warning().to_string(), "Note", text);
+        }
+
+        fn problem(&mut self, text: impl std::fmt::Display) {
+            self.problems += 1;
+            self.line(crate::ui::sym("✗").failure().to_string(), "Problem", text);
+        }
+
+        fn line(&self, mark: String, word: &str, text: impl std::fmt::Display) {
+            if crate::ui::accessible() {
+                println!("  {}: {}", word, text);
+            } else {
+                println!("  {} {}", mark, text);
+            }
+        }
+    }
+
+    /// Checks everything a bug report usually has to ask about. Returns
+    /// false when a problem was found.
+    pub async fn run(config_path: Option<&PathBuf>, profile: Option<&str>) -> bool {
+        let mut report = Report::default();
+        let root = nix::unistd::Uid::effective().is_root();
+
+        report.heading("fup");
+        let build = if cfg!(debug_assertions) {
+            "debug"
+        } else {
+            "release"
+        };
+        report.ok(format!(
+            "version {} ({}, {} build)",
+            VERSION,
+            std::env::consts::ARCH,
+            build
+        ));
+        if root {
+            report.ok("running as root");
+        } else {
+            report.note(
+                "not running as root; updates need sudo, and path checks below are for this user",
+            );
+        }
+
+        report.heading("System");
+        let os_release = std::fs::read_to_string("/etc/os-release").unwrap_or_default();
+        match os_release
+            .lines()
+            .find_map(|l| l.strip_prefix("PRETTY_NAME="))
+        {
+            Some(name) => report.ok(name.trim_matches('"')),
+            None => report.problem("/etc/os-release has no PRETTY_NAME"),
+        }
+        let kernel = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+        report.ok(format!("kernel {}", kernel.trim()));
+        if Path::new("/run/ostree-booted").exists() {
+            report.note("ostree-based system; only --refresh and --target checks apply");
+        }
+        if !Path::new("/run/systemd/system").exists() {
+            report.note("systemd is not running as init; inhibitor locks, scopes and timers are unavailable");
+        }
+
+        report.heading("Tools");
+        let tools = std::iter::once(("dnf", "dnf4 fallback checks"))
+            .chain(crate::system::CAPABILITIES.iter().copied());
+        for (cmd, enables) in tools {
+            match crate::system::find_command(cmd) {
+                Some(path) if VERSIONED.contains(&cmd) => report.ok(format!(
+                    "{} {} ({})",
+                    cmd,
+                    tool_version(&path).await,
+                    path.display()
+                )),
+                Some(path) => report.ok(format!("{} ({})", cmd, path.display())),
+                None if cmd == "dnf5" => report.problem("dnf5 not found; system updates need it"),
+                None => report.note(format!("{} not found; no {}", cmd, enables)),
+            }
+        }
+
+        report.heading("Configuration");
+        for path in Config::search_paths(config_path) {
+            let Ok(content) = std::fs::read_to_string(&path) else {
+                if path.exists() {
+                    report.problem(format!("{} is not readable", path.display()));
+                } else {
+                    report.note(format!("{} not present", path.display()));
+                }
+                continue;
+            };
+            match Config::parse(&path, &content) {
+                Ok(_) => report.ok(format!("{} parsed", path.display())),
+                Err(e) => report.problem(e),
+            }
+        }
+        let config = match Config::load_with_source(config_path, profile) {
+            Ok((config, source)) => {
+                let file = source
+                    .file
+                    .map_or("built-in defaults".to_string(), |f| f.display().to_string());
+                report.ok(format!("effective settings from {}", file));
+                if let Some((name, _)) = &source.profile {
+                    report.ok(format!("profile {}", name));
+                }
+                for ((section, key), var) in &source.env {
+                    report.note(format!("{} overrides [{}] {}", var, section, key));
+                }
+                crate::i18n::set_language(&config.ui.language);
+                report.ok(format!(
+                    "messages in {} ([ui] language = {:?})",
+                    crate::i18n::language(),
+                    config.ui.language
+                ));
+                config
+            }
+            Err(e) => {
+                report.problem(e);
+                Config::default()
+            }
+        };
+
+        report.heading("Paths");
+        let paths = [
+            ("log file", Some(config.logging.file.as_path())),
+            (
+                "run history",
+                config
+                    .history
+                    .enabled
+                    .then_some(config.history.file.as_path()),
+            ),
+            ("update cache", Some(Path::new(crate::cache::FILE))),
+            ("reboot marker", Some(Path::new(crate::pending::MARKER))),
+            (
+                "login message",
+                config
+                    .reboot
+                    .motd
+                    .then_some(Path::new(crate::pending::MOTD)),
+            ),
+            (
+                "audit trail",
+                config.audit.enabled.then_some(config.audit.file.as_path()),
+            ),
+            (
+                "artifacts",
+                config
+                    .artifacts
+                    .enabled
+                    .then_some(config.artifacts.dir.as_path()),
+            ),
+            (
+                "/etc backups",
+                config.backup.etc.then_some(config.backup.dir.as_path()),
+            ),
+        ];
+        for (what, path) in paths
+            .into_iter()
+            .filter_map(|(what, path)| Some((what, path?)))
+        {
+            if writable(path) {
+                report.ok(format!("{}: {}", what, path.display()));
+            } else if root {
+                report.problem(format!("{}: {} is not writable", what, path.display()));
+            } else {
+                report.note(format!(
+                    "{}: {} is not writable by this user",
+                    what,
+                    path.display()
+                ));
+            }
+        }
+
+        report.heading("Package lock");
+        match crate::system::package_lock_holder().await {
+            Some(holder) => report.note(holder),
+            None => report.ok("free"),
+        }
+
+        report.heading("Network");
+        match crate::system::check_network(&config.network).await {
+            Ok(()) => report.ok("connectivity check passed"),
+            Err(e) => report.problem(e),
+        }
+
+        println!();
+        if report.problems == 0 {
+            println!("{}", "No problems found.".success());
+        } else {
+            println!(
+                "{}",
+                format!("{} problem(s) found.", report.problems).failure()
+            );
+        }
+        report.problems == 0
+    }
+
+    /// The version line of `<tool> --version`; fwupdmgr prints a table
+    /// where the daemon's row is the one that matters.
+    async fn tool_version(path: &Path) -> String {
+        let Ok(out) = Command::new(path).arg("--version").output().await else {
+            return "(version unknown)".into();
+        };
+        let text = String::from_utf8_lossy(&out.stdout);
+        let line = text
+            .lines()
+            .find(|l| l.contains("org.freedesktop.fwupd"))
+            .or_else(|| text.lines().find(|l| !l.trim().is_empty()))
+            .unwrap_or("(version unknown)");
+        line.split_whitespace().collect::<Vec<_>>().join(" ")
+    }
+
+    /// Whether `path` can be written, or created in its nearest existing
+    /// ancestor directory.
+    fn writable(path: &Path) -> bool {
+        path.ancestors()
+            .find(|p| p.exists())
+            .is_some_and(|p| nix::unistd::access(p, AccessFlags::W_OK).is_ok())
+    }
+}
+
+mod status {
+    use crate::cache::CachedCheck;
+    use crate::config::Config;
+    use crate::history::RunRecord;
+    use crate::pending::RebootMarker;
+    use crate::ui::Themed;
+    use chrono::{DateTime, Local};
+    use colored::Colorize;
+    use serde::{Deserialize, Serialize};
+    use std::path::Path;
+    use tokio::process::Command;
+    use tracing::warn;
+
+    /// The unit suggested by `fup examples timers`.
+    const TIMER: &str = "fup.timer";
+
+    /// Everything `fup status` knows about this machine without running a check.
+    #[derive(Debug, Serialize)]
+    pub struct Status {
+        pub reboot_required: bool,
+        pub reboot: Option<RebootMarker>,
+        pub last_run: Option<LastRun>,
+        pub last_check: Option<CachedCheck>,
+        pub timer: Option<Timer>,
+        /// Whoever holds the RPM or PackageKit lock right now.
+        pub package_lock: Option<String>,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct LastRun {
+        #[serde(skip_serializing_if = "String::is_empty")]
+        pub id: String,
+        pub timestamp: DateTime<Local>,
+        /// "success", "failed" or "cancelled".
+        pub result: &'static str,
+        pub packages: usize,
+        pub errors: Vec<String>,
+    }
+
+    impl LastRun {
+        fn from_record(record: &RunRecord) -> Self {
+            let result = if record.stages.values().any(|s| s.cancelled) {
+                "cancelled"
+            } else if !record.errors.is_empty()
+                || !record.verification.is_empty()
+                || record.stages.values().any(|s| s.failed)
+            {
+                "failed"
+            } else {
+                "success"
+            };
+            Self {
+                id: record.id.clone(),
+                timestamp: record.timestamp,
+                result,
+                packages: record.stages.values().map(|s| s.packages).sum(),
+                errors: record
+                    .errors
+                    .iter()
+                    .chain(&record.verification)
+                    .cloned()
+                    .collect(),
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Timer {
+        pub active: bool,
+        /// Next and previous activation, as systemd prints them.
+        pub next: Option<String>,
+        pub last: Option<String>,
+    }
+
+    pub async fn collect(config: &Config) -> Status {
+        let reboot = crate::pending::read();
+        let last_run = config
+            .history
+            .enabled
+            .then(|| crate::history::load(&config.history.file))
+            .and_then(|records| records.last().map(LastRun::from_record));
+
+        Status {
+            reboot_required: reboot.is_some(),
+            reboot,
+            last_run,
+            last_check: crate::cache::load(),
+            timer: timer().await,
+            package_lock: crate::system::package_lock_holder().await,
+        }
+    }
+
+    /// State of `fup.timer`, or None if it isn't installed.
+    async fn timer() -> Option<Timer> {
+        let output = Command::new("systemctl")
+            .args([
+                "show",
+                TIMER,
+                "--property=LoadState,ActiveState,NextElapseUSecRealtime,LastTriggerUSec",
+            ])
+            .output()
+            .await
+            .ok()?;
+        let text = String::from_utf8_lossy(&output.stdout);
+        let property = |name: &str| {
+            text.lines()
+                .find_map(|l| l.strip_prefix(name)?.strip_prefix('='))
+                .map(str::trim)
+                .filter(|v| !v.is_empty() && *v != "n/a")
+                .map(str::to_string)
+        };
+
+        if property("LoadState").as_deref() != Some("loaded") {
+            return None;
+        }
+        Some(Timer {
+            active: property("ActiveState").as_deref() == Some("active"),
+            next: property("NextElapseUSecRealtime"),
+            last: property("LastTriggerUSec"),
+        })
+    }
+
+    /// What `[notify] status_file` holds, for bar widgets and dashboards to
+    /// poll. Fields are only ever added, never renamed or removed.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct StatusFile {
+        pub version: u32,
+        pub updated: Option<DateTime<Local>>,
+        /// Pending updates as of `checked`; `outdated` once updates were
+        /// applied since.
+        pub pending: Option<PendingCounts>,
+        pub checked: Option<DateTime<Local>>,
+        pub outdated: bool,
+        pub last_run: Option<DateTime<Local>>,
+        /// "ok", "partial", "failed" or "cancelled".
+        pub last_result: Option<String>,
+        pub last_success: Option<DateTime<Local>>,
+        pub reboot_required: bool,
+    }
+
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default)]
+    pub struct PendingCounts {
+        pub total: usize,
+        pub system: usize,
+        pub flatpak: usize,
+        pub flatpak_runtimes: usize,
+        pub firmware: usize,
+    }
+
+    impl StatusFile {
+        /// `previous` brought up to date with the last check, the reboot
+        /// marker and, after a run, how it went.
+        pub fn update(
+            previous: Option<StatusFile>,
+            check: Option<&CachedCheck>,
+            reboot_required: bool,
+            run: Option<&str>,
+            now: DateTime<Local>,
+        ) -> Self {
+            let mut status = previous.unwrap_or_default();
+            status.version = 1;
+            status.updated = Some(now);
+            if let Some(check) = check {
+                let updates = &check.updates;
+                status.pending = Some(PendingCounts {
+                    total: updates.total_count(),
+                    system: updates.system.len(),
+                    flatpak: updates.flatpak.len(),
+                    flatpak_runtimes: updates.flatpak_runtimes.len(),
+                    firmware: updates.firmware.len(),
+                });
+                status.checked = Some(check.checked);
+                status.outdated = check.outdated;
+            }
+            if let Some(result) = run {
+                status.last_run = Some(now);
+                status.last_result = Some(result.to_string());
+                if result == "ok" {
+                    status.last_success = Some(now);
+                }
+            }
+            status.reboot_required = reboot_required;
+            status
+        }
+    }
+
+    /// Rewrites the status file at `path` after a check or a run (`run` is
+    /// its result). The file is replaced whole, so pollers never read half
+    /// of it.
+    pub fn write_file(path: &str, run: Option<&str>) {
+        if path.is_empty() {
+            return;
+        }
+        let path = Path::new(path);
+        let previous = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|c| serde_json::from_str(&c).ok());
+        let check = crate::cache::load();
+        let status = StatusFile::update(
+            previous,
+            check.as_ref(),
+            crate::pending::read().is_some(),
+            run,
+            Local::now(),
+        );
+
+        let partial = path.with_extension("tmp");
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| {
+                let json = serde_json::to_string_pretty(&status).map_err(std::io::Error::other)?;
+                std::fs::write(&partial, json + "\n")
+            })
+            .and_then(|_| std::fs::rename(&partial, path));
+        if let Err(e) = result {
+            warn!("Failed to write status file {}: {}", path.display(), e);
+        }
+    }
+
+    pub async fn print(config: &Config, json: bool) {
+        let status = collect(config).await;
+
+        if json {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&status).unwrap_or_default()
+            );
+            return;
+        }
+
+        if crate::ui::accessible() {
+            print_accessible(&status);
+            return;
+        }
+
+        match &status.last_run {
+            Some(run) => {
+                let when = run.timestamp.format("%Y-%m-%d %H:%M");
+                match run.result {
+                    "success" => println!(
+                        "{} Last run {}: succeeded, {} package(s) changed",
+                        crate::ui::sym("✓").success(),
+                        when,
+                        run.packages
+                    ),
+                    "cancelled" => println!(
+                        "{} Last run {}: cancelled",
+                        crate::ui::sym("○").warning(),
+                        when
+                    ),
+                    _ => println!(
+                        "{} Last run {}: failed",
+                        crate::ui::sym("✗").failure(),
+                        when
+                    ),
+                }
+                for error in &run.errors {
+                    println!("    {} {}", crate::ui::sym("•").dimmed(), error);
+                }
+                if !run.id.is_empty() {
+                    println!("    {}", format!("Run {}", run.id).dimmed());
+                }
+            }
+            None => println!("{} No runs recorded", crate::ui::sym("○").warning()),
+        }
+
+        match &status.last_check {
+            Some(check) => {
+                let updates = &check.updates;
+                let total = updates.total_count();
+                let mark = if total == 0 {
+                    crate::ui::sym("✓").success()
+                } else {
+                    crate::ui::sym("↑").accent().bold()
+                };
+                println!(
+                    "{} {} pending update(s) ({} system, {} Flatpak, {} firmware), checked {}{}",
+                    mark,
+                    total,
+                    updates.system.len(),
+                    updates.flatpak_count(),
+                    updates.firmware.len(),
+                    check.checked.format("%Y-%m-%d %H:%M"),
+                    stale_note(&status),
+                );
+            }
+            None => println!(
+                "{} No update check recorded; run `fup --refresh`",
+                crate::ui::sym("○").warning()
+            ),
+        }
+
+        match &status.reboot {
+            Some(marker) => {
+                println!(
+                    "{} Reboot pending since {}",
+                    crate::ui::sym("⟳").warning().bold(),
+                    marker.detected.format("%Y-%m-%d %H:%M")
+                );
+                if !marker.reason.is_empty() {
+                    println!("  {}", marker.reason.dimmed());
+                }
+                for package in &marker.packages {
+                    println!("    {} {}", crate::ui::sym("•").dimmed(), package);
+                }
+            }
+            None => println!("{} No reboot pending", crate::ui::sym("✓").success()),
+        }
+
+        match &status.timer {
+            Some(timer) if timer.active => println!(
+                "{} {} active, next run {}",
+                crate::ui::sym("✓").success(),
+                TIMER,
+                timer.next.as_deref().unwrap_or("not scheduled")
+            ),
+            Some(_) => println!(
+                "{} {} is installed but not active",
+                crate::ui::sym("○").warning(),
+                TIMER
+            ),
+            None => println!(
+                "{} No {} installed (see `fup examples timers`)",
+                crate::ui::sym("○").warning(),
+                TIMER
+            ),
+        }
+
+        match &status.package_lock {
+            Some(holder) => println!(
+                "{} Package manager busy: {}",
+                crate::ui::sym("⚠").warning(),
+                holder
+            ),
+            None => println!(
+                "{} Package manager lock is free",
+                crate::ui::sym("✓").success()
+            ),
+        }
+    }
+
+    fn print_accessible(status: &Status) {
+        use crate::ui::announce;
+
+        match &status.last_run {
+            Some(run) => {
+                let when = run.timestamp.format("%Y-%m-%d %H:%M");
+                announce(&match run.result {
+                    "success" => format!(
+                        "Last run {} succeeded, {} package(s) changed.",
+                        when, run.packages
+                    ),
+                    result => format!("Last run {} {}.", when, result),
+                });
+                for error in &run.errors {
+                    announce(&format!("Error: {}", error));
+                }
+            }
+            None => announce("No runs recorded."),
+        }
+
+        match &status.last_check {
+            Some(check) => announce(&format!(
+                "{} pending update(s): {} system, {} Flatpak, {} firmware, checked {}{}.",
+                check.updates.total_count(),
+                check.updates.system.len(),
+                check.updates.flatpak_count(),
+                check.updates.firmware.len(),
+                check.checked.format("%Y-%m-%d %H:%M"),
+                stale_note(status),
+            )),
+            None => announce("No update check recorded. Run fup --refresh."),
+        }
+
+        match &status.reboot {
+            Some(marker) => {
+                announce(&format!(
+                    "Reboot pending since {}. {}",
+                    marker.detected.format("%Y-%m-%d %H:%M"),
+                    marker.reason
+                ));
+                if !marker.packages.is_empty() {
+                    announce(&format!("Packages: {}.", marker.packages.join(", ")));
+                }
+            }
+            None => announce("No reboot pending."),
+        }
+
+        announce(&match &status.timer {
+            Some(timer) if timer.active => format!(
+                "{} is active, next run {}.",
+                TIMER,
+                timer.next.as_deref().unwrap_or("not scheduled")
+            ),
+            Some(_) => format!("{} is installed but not active.", TIMER),
+            None => format!("No {} installed.", TIMER),
+        });
+
+        announce(&match &status.package_lock {
+            Some(holder) => format!("Package manager busy: {}.", holder),
+            None => "Package manager lock is free.".to_string(),
+        });
+    }
+
+    /// Counts from before the last run are likely out of date.
+    fn stale_note(status: &Status) -> &'static str {
+        match (&status.last_check, &status.last_run) {
+            (Some(check), Some(run)) if run.timestamp > check.checked => ", before the last run",
+            _ => "",
+        }
+    }
+}
+
+mod updater {
+    use crate::artifacts::ArtifactStore;
+    use crate::bundle::Manifest;
+    use crate::config::Config;
+    use crate::error::{Result, UpdateError};
+    use crate::security::{Advisory, Severity};
+    use crate::ui::Themed;
+    use colored::Colorize;
+    use indicatif::{ProgressBar, ProgressStyle};
+    use nix::{
+        sys::signal::{Signal, killpg},
+        unistd::Pid,
+    };
+    use serde::{Deserialize, Serialize};
+    use std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        future::Future,
+        io::IsTerminal,
+        path::Path,
+        pin::Pin,
+        process::{ExitStatus, Stdio},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncRead, BufReader},
+        process::{Child, Command},
+        sync::{Mutex, watch},
+    };
+    use tracing::{Instrument, debug, error, info, warn};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Stage {
+        System,
+        Flatpak,
+        Firmware,
+    }
+
+    impl Stage {
+        pub fn label(self) -> &'static str {
+            match self {
+                Stage::System => "System",
+                Stage::Flatpak => "Flatpak",
+                Stage::Firmware => "Firmware",
+            }
+        }
+
+        /// Prefix for streamed command output lines.
+        pub fn prefix(self) -> &'static str {
+            match self {
+                Stage::System => "[DNF5]",
+                Stage::Flatpak => "[Flatpak]",
+                Stage::Firmware => "[Firmware]",
+            }
+        }
+    }
+
+    /// Groups stages for --parallel: each wave holds stages that may all
+    /// overlap with one another, and waves run one after the other in the
+    /// given order.
+    pub fn parallel_waves(stages: &[Stage], overlap: &[[Stage; 2]]) -> Vec<Vec<Stage>> {
+        let may_overlap = |a: Stage, b: Stage| {
+            overlap
+                .iter()
+                .any(|&[x, y]| (x, y) == (a, b) || (y, x) == (a, b))
+        };
+        let mut waves: Vec<Vec<Stage>> = Vec::new();
+        for &stage in stages {
+            match waves.last_mut() {
+                Some(wave) if wave.iter().all(|&other| may_overlap(stage, other)) => {
+                    wave.push(stage)
+                }
+                _ => waves.push(vec![stage]),
+            }
+        }
+        waves
+    }
+
+    /// Outcome of a single backend within one run.
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    pub struct StageStats {
+        pub duration_secs: f64,
+        pub packages: usize,
+        pub failed: bool,
+        #[serde(default)]
+        pub cancelled: bool,
+        #[serde(default)]
+        pub downloaded_bytes: u64,
+        /// Time spent in each backend command, in the order they first ran.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub phases: Vec<PhaseTiming>,
+        /// End-of-run retries before this outcome; the stats are the last attempt's.
+        #[serde(default)]
+        pub retries: u32,
+        #[serde(skip)]
+        pub error: Option<String>,
+        /// What to do about the failure, when its cause is known.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub hint: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    pub struct PhaseTiming {
+        pub name: String,
+        pub duration_secs: f64,
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct UpdateSummary {
+        pub system_updated: bool,
+        pub flatpak_updated: bool,
+        pub firmware_updated: bool,
+        pub cancelled: bool,
+        pub errors: Vec<String>,
+        pub stages: BTreeMap<Stage, StageStats>,
+        pub backup: Option<String>,
+        pub artifacts: Option<String>,
+        pub download_limit: Option<String>,
+        /// Differences between the installed package sets before and after.
+        pub changes: Vec<crate::pkgdiff::PackageChange>,
+        /// Packages the update, obsoletes and autoremove took away.
+        pub removed: Vec<TransactionItem>,
+        /// Regressions found by the post-update checks.
+        pub verification: Vec<String>,
+        /// What happened to each fwupd device with an update.
+        pub firmware_devices: Vec<FirmwareOutcome>,
+        /// Per-user Flatpak results with `[flatpak] all_users`.
+        pub flatpak_users: Vec<FlatpakUserOutcome>,
+        /// Installed Flatpaks that are end-of-life or run on an end-of-life runtime.
+        pub flatpak_eol: Vec<EolFlatpak>,
+        /// Backends not run under fail-fast, and the one whose failure stopped them.
+        pub halted: Vec<Stage>,
+        pub halted_by: Option<Stage>,
+    }
+
+    impl UpdateSummary {
+        /// The remediation hints of the failed backends, each once.
+        pub fn hints(&self) -> Vec<&str> {
+            let mut hints: Vec<&str> = Vec::new();
+            for hint in self.stages.values().filter_map(|s| s.hint.as_deref()) {
+                if !hints.contains(&hint) {
+                    hints.push(hint);
+                }
+            }
+            hints
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct FlatpakUserOutcome {
+        pub user: String,
+        /// Refs updated in the user's installation.
+        pub refs: usize,
+        pub error: Option<String>,
+    }
+
+    impl FlatpakUserOutcome {
+        pub fn describe(&self) -> String {
+            match &self.error {
+                Some(error) => format!("failed: {}", error),
+                None => format!("{} ref(s) updated", self.refs),
+            }
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct FirmwareOutcome {
+        pub name: String,
+        pub from: Option<String>,
+        pub to: Option<String>,
+        pub result: FirmwareResult,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum FirmwareResult {
+        Updated,
+        /// Matched `[firmware] skip_devices`.
+        Skipped,
+        /// Left out by `--firmware-device`.
+        NotSelected,
+        /// Held back by a safety check; override with --firmware-force.
+        Blocked(String),
+        /// The update wasn't confirmed, or there was no one to confirm it.
+        NotConfirmed,
+        Failed(String),
+    }
+
+    impl FirmwareResult {
+        pub fn describe(&self) -> String {
+            match self {
+                FirmwareResult::Updated => "updated".into(),
+                FirmwareResult::Skipped => "skipped by [firmware] skip_devices".into(),
+                FirmwareResult::NotSelected => "not selected".into(),
+                FirmwareResult::Blocked(reason) => format!("blocked: {}", reason),
+                FirmwareResult::NotConfirmed => "not confirmed".into(),
+                FirmwareResult::Failed(reason) => format!("failed: {}", reason),
+            }
+        }
+    }
+
+    /// The answer to `ask_after_failure`.
+    enum FailureChoice {
+        Retry,
+        Skip,
+        Abort,
+    }
+
+    /// How firmware updates get the go-ahead once the devices are known.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum FirmwareApproval {
+        /// List the devices and warnings on the terminal and ask.
+        Ask,
+        /// Nobody to ask, so firmware is left alone.
+        Unattended,
+        /// --firmware-force: flash without asking, even past fwupd's problems.
+        Forced,
+    }
+
+    /// `fwupdmgr get-updates --json`.
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct FirmwareUpdates {
+        #[serde(default)]
+        devices: Vec<FirmwareDevice>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct FirmwareDevice {
+        device_id: String,
+        name: String,
+        #[serde(default)]
+        guid: Vec<String>,
+        #[serde(default)]
+        version: Option<String>,
+        #[serde(default)]
+        plugin: Option<String>,
+        /// Only set for devices on a branch other than the vendor's default.
+        #[serde(default)]
+        branch: Option<String>,
+        /// e.g. "needs-reboot", "needs-shutdown", "usable-during-update".
+        #[serde(default)]
+        flags: Vec<String>,
+        /// Why fwupd thinks the device can't be updated now, e.g.
+        /// "require-ac-power" or "lid-is-closed".
+        #[serde(default)]
+        problems: Vec<String>,
+        #[serde(default)]
+        releases: Vec<FirmwareRelease>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct FirmwareRelease {
+        version: String,
+        /// Of the cabinet file, in bytes.
+        #[serde(default)]
+        size: u64,
+        /// Download URLs of the cabinet file; older fwupd has a single `Uri`.
+        #[serde(default)]
+        locations: Vec<String>,
+        #[serde(default)]
+        uri: Option<String>,
+    }
+
+    impl FirmwareRelease {
+        fn location(&self) -> Option<&str> {
+            self.locations
+                .first()
+                .or(self.uri.as_ref())
+                .map(String::as_str)
+        }
+    }
+
+    /// `fwupdmgr get-remotes --json`.
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct FirmwareRemotes {
+        #[serde(default)]
+        remotes: Vec<FirmwareRemote>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct FirmwareRemote {
+        id: String,
+        #[serde(default)]
+        enabled: bool,
+    }
+
+    const LVFS_TESTING: &str = "lvfs-testing";
+
+    /// What flashing a device means: its pending update, moving it to the
+    /// branch it is pinned to, or installing a cabinet from a bundle.
+    #[derive(Debug)]
+    enum FirmwareAction {
+        Update,
+        SwitchBranch(String),
+        /// Flash a cabinet file from an update bundle.
+        Install(String),
+    }
+
+    impl FirmwareDevice {
+        fn is_capsule(&self) -> bool {
+            self.plugin.as_deref() == Some("uefi_capsule")
+        }
+
+        fn has_flag(&self, flag: &str) -> bool {
+            self.flags.iter().any(|f| f == flag)
+        }
+
+        /// What stops this device from being flashed safely right now.
+        fn blockers(&self) -> Vec<String> {
+            let mut blockers: Vec<String> = self
+                .problems
+                .iter()
+                .map(|p| format!("fwupd reports {}", p))
+                .collect();
+            if self.is_capsule()
+                && crate::preflight::secure_boot_enabled() == Some(true)
+                && !crate::preflight::signed_fwupd_efi()
+            {
+                blockers.push("Secure Boot is on but no signed fwupd EFI binary is installed (install fwupd-efi)".into());
+            }
+            blockers
+        }
+
+        /// A GUID or device ID matches exactly (ignoring case); anything else
+        /// is a glob on the device name.
+        fn matches(&self, pattern: &str) -> bool {
+            self.device_id.eq_ignore_ascii_case(pattern)
+                || self.guid.iter().any(|g| g.eq_ignore_ascii_case(pattern))
+                || glob_match(&pattern.to_lowercase(), &self.name.to_lowercase())
+        }
+    }
+
+    /// Shell-style matching with `*` and `?`.
+    fn glob_match(pattern: &str, text: &str) -> bool {
+        let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+        let (mut pi, mut ti) = (0, 0);
+        let mut backtrack = None;
+        while ti < t.len() {
+            match p.get(pi) {
+                Some('*') => {
+                    backtrack = Some((pi, ti));
+                    pi += 1;
+                }
+                Some(&c) if c == '?' || c == t[ti] => {
+                    pi += 1;
+                    ti += 1;
+                }
+                _ => match backtrack {
+                    Some((star, matched)) => {
+                        pi = star + 1;
+                        ti = matched + 1;
+                        backtrack = Some((star, matched + 1));
+                    }
+                    None => return false,
+                },
+            }
+        }
+        p[pi..].iter().all(|&c| c == '*')
+    }
+
+    /// How long a signalled child process group gets to exit before SIGKILL.
+    const KILL_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+    /// How often running commands are checked for hangs.
+    const HANG_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+    /// Short name for a command's timing: the program and its subcommand
+    /// ("dnf5 update", "fwupdmgr refresh"), skipping options and a
+    /// `runuser -u USER --` prefix.
+    fn phase_name(full_cmd: &str) -> String {
+        let cmd = full_cmd
+            .strip_prefix("runuser ")
+            .and_then(|rest| rest.split_once(" -- "))
+            .map_or(full_cmd, |(_, cmd)| cmd);
+        cmd.split_whitespace()
+            .filter(|w| !w.starts_with('-'))
+            .take(2)
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Received shutdown signal, shared between the signal handler and running commands.
+    pub type ShutdownSignal = watch::Receiver<Option<Signal>>;
+
+    /// A command's stdout or stderr.
+    pub type Output = Box<dyn AsyncRead + Send + Unpin>;
+    pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+    /// Starts the backend commands. `TokioRunner` spawns real processes;
+    /// tests script their output and exit codes instead.
+    pub trait CommandRunner: Send + Sync {
+        fn exists(&self, cmd: &str) -> bool;
+        fn spawn(&self, cmd: &str, args: &[&str]) -> std::io::Result<Box<dyn RunningCommand>>;
+    }
+
+    /// A started command: its output, and a way to wait for or stop it.
+    pub trait RunningCommand: Send {
+        fn stdout(&mut self) -> Output;
+        fn stderr(&mut self) -> Output;
+        fn wait(&mut self) -> BoxFuture<'_, std::io::Result<ExitStatus>>;
+        /// Sends `signal` to the command and everything it started, killing
+        /// them if they don't exit in time.
+        fn terminate(&mut self, signal: Signal) -> BoxFuture<'_, ()>;
+    }
+
+    pub struct TokioRunner;
+
+    impl CommandRunner for TokioRunner {
+        fn exists(&self, cmd: &str) -> bool {
+            crate::system::command_exists(cmd)
+        }
+
+        /// Runs the command in its own process group, so signals can be
+        /// forwarded to everything it starts.
+        fn spawn(&self, cmd: &str, args: &[&str]) -> std::io::Result<Box<dyn RunningCommand>> {
+            let child = Command::new(cmd)
+                .args(args)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .process_group(0)
+                .spawn()?;
+            Ok(Box::new(child))
+        }
+    }
+
+    impl RunningCommand for Child {
+        fn stdout(&mut self) -> Output {
+            Box::new(self.stdout.take().expect("stdout piped"))
+        }
+
+        fn stderr(&mut self) -> Output {
+            Box::new(self.stderr.take().expect("stderr piped"))
+        }
+
+        fn wait(&mut self) -> BoxFuture<'_, std::io::Result<ExitStatus>> {
+            Box::pin(Child::wait(self))
+        }
+
+        fn terminate(&mut self, signal: Signal) -> BoxFuture<'_, ()> {
+            Box::pin(terminate_process_group(self, signal))
+        }
+    }
+
+    /// Tracks when a child last produced output, for hang detection.
+    #[derive(Clone)]
+    struct Activity(Arc<std::sync::Mutex<Instant>>);
+
+    impl Activity {
+        fn new() -> Self {
+            Self(Arc::new(std::sync::Mutex::new(Instant::now())))
+        }
+
+        fn touch(&self) {
+            *self.0.lock().unwrap() = Instant::now();
+        }
+
+        fn idle(&self) -> Duration {
+            self.0.lock().unwrap().elapsed()
+        }
+    }
+
+    /// Output of one backend in parallel mode: a live pane showing its latest
+    /// line, with the full log held back and printed per section at the end.
+    #[derive(Clone)]
+    struct ParallelOutput {
+        /// False in accessible mode, where only the buffered logs are printed.
+        live: bool,
+        panes: Arc<std::sync::Mutex<BTreeMap<Stage, ProgressBar>>>,
+        logs: Arc<std::sync::Mutex<BTreeMap<Stage, Vec<LogLine>>>>,
+    }
+
+    struct LogLine {
+        stderr: bool,
+        text: String,
+    }
+
+    impl ParallelOutput {
+        fn new(live: bool) -> Self {
+            Self {
+                live,
+                panes: Arc::default(),
+                logs: Arc::default(),
+            }
+        }
+
+        fn start(&self, stage: Stage) {
+            if !self.live {
+                return;
+            }
+            let pane = crate::render::add(ProgressBar::new_spinner());
+            pane.set_style(
+                ProgressStyle::default_spinner()
+                    .tick_chars(crate::ui::ticks())
+                    .template(&format!(
+                        "{{spinner:.{}}} {{prefix:.bold}} {{wide_msg}}",
+                        crate::ui::accent_style()
+                    ))
+                    .unwrap(),
+            );
+            pane.set_prefix(format!("{:<10}", stage.prefix()));
+            pane.set_message("starting...");
+            pane.enable_steady_tick(Duration::from_millis(80));
+            self.panes.lock().unwrap().insert(stage, pane);
+        }
+
+        fn line(&self, stage: Stage, stderr: bool, line: &str) {
+            if let Some(pane) = self.panes.lock().unwrap().get(&stage) {
+                match crate::progress::parse(stage, line) {
+                    Some(p) if p.total > 0 => pane
+                        .set_message(format!("{} {}/{} {}", p.phase, p.current, p.total, p.name)),
+                    _ => pane.set_message(line.trim().to_string()),
+                }
+            }
+            self.logs
+                .lock()
+                .unwrap()
+                .entry(stage)
+                .or_default()
+                .push(LogLine {
+                    stderr,
+                    text: line.to_string(),
+                });
+        }
+
+        fn finish(&self, stage: Stage, outcome: &str) {
+            if let Some(pane) = self.panes.lock().unwrap().get(&stage) {
+                pane.set_style(
+                    ProgressStyle::with_template("  {prefix:.bold} {wide_msg}").unwrap(),
+                );
+                pane.finish_with_message(outcome.to_string());
+            }
+        }
+
+        /// Prints every backend's full log as its own section.
+        fn flush(&self) {
+            for (stage, lines) in std::mem::take(&mut *self.logs.lock().unwrap()) {
+                println!(
+                    "\n{} {} {}",
+                    crate::ui::sym("──").accent(),
+                    stage.label().accent().bold(),
+                    crate::ui::sym("─").repeat(40).accent()
+                );
+                for line in lines {
+                    let prefix = if line.stderr {
+                        stage.prefix().failure().bold()
+                    } else {
+                        stage.prefix().white().bold()
+                    };
+                    println!("{} {}", prefix, line.text);
+                }
+            }
+        }
+    }
+
+    /// Percentage bar driven by a backend's own progress lines, replacing
+    /// them on screen. Other output is printed above it.
+    #[derive(Default)]
+    struct ProgressTracker {
+        bar: Option<(&'static str, ProgressBar)>,
+    }
+
+    impl ProgressTracker {
+        /// Returns false if the line is not progress and should be printed.
+        fn update(&mut self, stage: Stage, line: &str) -> bool {
+            let Some(p) = crate::progress::parse(stage, line) else {
+                return false;
+            };
+            if p.total == 0 {
+                if let Some((_, bar)) = &self.bar {
+                    bar.set_message(p.name);
+                }
+                return true;
+            }
+
+            let bar = match &self.bar {
+                Some((phase, bar)) if *phase == p.phase => bar,
+                _ => {
+                    if let Some((_, done)) = self.bar.take() {
+                        done.finish_and_clear();
+                    }
+                    let bar = crate::render::add(ProgressBar::new(p.total));
+                    bar.set_style(
+                        ProgressStyle::with_template(
+                            &format!(
+                                "{{prefix:>12.{0}.bold}} [{{bar:30.{0}/blue}}] {{pos}}/{{len}} {{wide_msg}}",
+                                crate::ui::accent_style()
+                            ),
+                        )
+                        .unwrap()
+                        .progress_chars("=> "),
+                    );
+                    bar.set_prefix(p.phase);
+                    &self.bar.insert((p.phase, bar)).1
+                }
+            };
+            bar.set_length(p.total);
+            bar.set_position(p.current);
+            bar.set_message(p.name);
+            true
+        }
+
+        fn println(&self, text: String, stderr: bool) {
+            if stderr {
+                crate::render::eprintln(&text)
+            } else {
+                crate::render::println(&text)
+            }
+        }
+
+        fn finish(&mut self) {
+            if let Some((_, bar)) = self.bar.take() {
+                bar.finish_and_clear();
+            }
+        }
+    }
+
+    /// How to deal with a dnf transaction that never finished.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Recovery {
+        /// Ask on the terminal; unattended runs stop instead.
+        Ask,
+        /// Remove the old halves of half-done upgrades and carry on.
+        Complete,
+        /// Undo the interrupted transaction with `dnf5 history undo`.
+        Rollback,
+        /// Warn and update anyway.
+        Ignore,
+    }
+
+    /// How system updates reach dnf5.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum DnfBackend {
+        /// Spawn the `dnf5` command and parse its output.
+        Cli,
+        /// Call dnf5daemon over D-Bus, when built with the `dnf5daemon`
+        /// feature and the daemon answers; the CLI otherwise.
+        Daemon,
+    }
+
+    /// What to do when a transaction would remove a `[system] protected` package.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ProtectedRemoval {
+        /// Refuse the transaction and say which packages it would take.
+        Abort,
+        /// Ask on the terminal; unattended runs refuse instead.
+        Ask,
+    }
+
+    /// A dnf transaction cut short by a crash or power loss, found before
+    /// starting a new one.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct InterruptedTransaction {
+        /// dnf5 history ID of the last transaction, when it never finished.
+        pub id: Option<String>,
+        pub status: Option<String>,
+        /// Packages installed twice, the old version next to its upgrade.
+        pub duplicates: Vec<String>,
+    }
+
+    impl InterruptedTransaction {
+        pub fn describe(&self) -> String {
+            let mut parts = Vec::new();
+            if let Some(id) = &self.id {
+                parts.push(format!(
+                    "dnf5 transaction {} did not finish (status {})",
+                    id,
+                    self.status.as_deref().unwrap_or("unknown")
+                ));
+            }
+            if !self.duplicates.is_empty() {
+                parts.push(format!(
+                    "{} package(s) are installed twice",
+                    self.duplicates.len()
+                ));
+            }
+            parts.join("; ")
+        }
+    }
+
+    /// One package in a transaction resolved by `dnf5 update --assumeno`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct TransactionItem {
+        /// The heading dnf5 lists it under, e.g. "Upgrading" or "Installing
+        /// dependencies", or "Obsoleted" for a package another one replaces.
+        pub action: String,
+        pub name: String,
+        pub version: String,
+        /// The installed version an upgrade or downgrade replaces.
+        pub from: Option<String>,
+        pub repo: String,
+        pub size: u64,
+    }
+
+    impl TransactionItem {
+        /// Whether the transaction takes the package away rather than
+        /// installing or updating it.
+        pub fn is_removal(&self) -> bool {
+            self.action.starts_with("Removing") || self.action == "Obsoleted"
+        }
+
+        /// Why it goes: "removed", "obsoleted", "unused dependencies"...
+        pub fn removal_reason(&self) -> String {
+            match self.action.as_str() {
+                "Removing" => "removed".to_string(),
+                "Obsoleted" => "obsoleted".to_string(),
+                other => other.trim_start_matches("Removing ").to_lowercase(),
+            }
+        }
+    }
+
+    /// What an update run would do, resolved by the backends themselves
+    /// without changing anything. Shown by `--dry-run`.
+    #[derive(Debug, Clone, Default)]
+    pub struct Preview {
+        pub transaction: Vec<TransactionItem>,
+        pub download_bytes: u64,
+        /// Packages `dnf5 autoremove` would take away afterwards.
+        pub autoremove: Vec<String>,
+        /// `AvailableUpdates::flatpak` rows with their download size.
+        pub flatpak: Vec<(String, u64)>,
+        pub flatpak_unused: Vec<String>,
+        pub firmware: Vec<String>,
+        /// Removed packages, from the transaction or autoremove, matching
+        /// `[system] protected`.
+        pub protected: Vec<String>,
+    }
+
+    impl Preview {
+        pub fn is_empty(&self) -> bool {
+            self.transaction.is_empty()
+                && self.autoremove.is_empty()
+                && self.flatpak.is_empty()
+                && self.flatpak_unused.is_empty()
+                && self.firmware.is_empty()
+        }
+    }
+
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    pub struct AvailableUpdates {
+        pub system: Vec<String>,
+        /// Flatpak apps, one row per app and branch whatever its arches.
+        pub flatpak: Vec<String>,
+        /// Flatpak runtimes and extensions, in the same rows.
+        #[serde(default)]
+        pub flatpak_runtimes: Vec<String>,
+        pub firmware: Vec<String>,
+        /// Sizes of the rows above, where the backend reports them.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        pub sizes: BTreeMap<String, ItemSize>,
+    }
+
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+    pub struct ItemSize {
+        pub download: u64,
+        #[serde(default)]
+        pub installed: u64,
+    }
+
+    impl AvailableUpdates {
+        pub fn total_count(&self) -> usize {
+            self.system.len() + self.flatpak_count() + self.firmware.len()
+        }
+
+        pub fn flatpak_count(&self) -> usize {
+            self.flatpak.len() + self.flatpak_runtimes.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.total_count() == 0
+        }
+
+        /// What the rows of `items` add up to; all of them without.
+        pub fn total_size<'a>(&self, items: impl IntoIterator<Item = &'a String>) -> ItemSize {
+            items
+                .into_iter()
+                .filter_map(|row| self.sizes.get(row))
+                .fold(ItemSize::default(), |total, size| ItemSize {
+                    download: total.download + size.download,
+                    installed: total.installed + size.installed,
+                })
+        }
+
+        pub fn all_rows(&self) -> impl Iterator<Item = &String> {
+            self.system
+                .iter()
+                .chain(&self.flatpak)
+                .chain(&self.flatpak_runtimes)
+                .chain(&self.firmware)
+        }
+
+        /// Entries that weren't pending in `previous`; a new version of an
+        /// already pending package counts as new.
+        pub fn new_since(&self, previous: &AvailableUpdates) -> AvailableUpdates {
+            fn normalize(line: &str) -> String {
+                line.split_whitespace().collect::<Vec<_>>().join(" ")
+            }
+            fn unseen(now: &[String], before: &[String]) -> Vec<String> {
+                let seen: HashSet<String> = before.iter().map(|l| normalize(l)).collect();
+                now.iter()
+                    .filter(|l| !seen.contains(&normalize(l)))
+                    .cloned()
+                    .collect()
+            }
+
+            let mut new = AvailableUpdates {
+                system: unseen(&self.system, &previous.system),
+                flatpak: unseen(&self.flatpak, &previous.flatpak),
+                flatpak_runtimes: unseen(&self.flatpak_runtimes, &previous.flatpak_runtimes),
+                firmware: unseen(&self.firmware, &previous.firmware),
+                sizes: BTreeMap::new(),
+            };
+            new.sizes = self
+                .sizes
+                .iter()
+                .filter(|(row, _)| new.all_rows().any(|r| r == *row))
+                .map(|(r, s)| (r.clone(), *s))
+                .collect();
+            new
+        }
+
+        /// Pending system updates per repository, from the last column of
+        /// the check-upgrade rows, busiest first.
+        pub fn by_repo(&self) -> Vec<(&str, usize)> {
+            let mut repos: BTreeMap<&str, usize> = BTreeMap::new();
+            for repo in self
+                .system
+                .iter()
+                .filter_map(|l| l.split_whitespace().nth(2))
+            {
+                *repos.entry(repo).or_default() += 1;
+            }
+            let mut repos: Vec<_> = repos.into_iter().collect();
+            repos.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+            repos
+        }
+
+        /// Pending Flatpak updates per remote, with remotes of per-user
+        /// installations marked with the user, busiest first.
+        pub fn flatpak_by_remote(&self) -> Vec<(String, usize)> {
+            let mut remotes: BTreeMap<String, usize> = BTreeMap::new();
+            for (origin, installation) in self
+                .flatpak
+                .iter()
+                .chain(&self.flatpak_runtimes)
+                .filter_map(|l| flatpak_origin(l))
+            {
+                *remotes.entry(installation.tag(origin)).or_default() += 1;
+            }
+            let mut remotes: Vec<_> = remotes.into_iter().collect();
+            remotes.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+            remotes
+        }
+
+        /// Drops Flatpak updates from remotes other than `remotes`; empty
+        /// keeps everything.
+        pub fn retain_flatpak_remotes(&mut self, remotes: &[String]) {
+            if !remotes.is_empty() {
+                for list in [&mut self.flatpak, &mut self.flatpak_runtimes] {
+                    list.retain(|l| {
+                        flatpak_origin(l)
+                            .is_some_and(|(origin, _)| remotes.iter().any(|r| r == origin))
+                    });
+                }
+            }
+        }
+    }
+
+    /// How `--refresh --sort` orders each section.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+    pub enum SortKey {
+        Name,
+        /// Largest download first.
+        Size,
+        /// Repository, or Flatpak remote.
+        Repo,
+        /// Most severe security advisory first; packages without one last.
+        Severity,
+    }
+
+    impl AvailableUpdates {
+        /// Narrows each section to the rows whose name matches one of
+        /// `filter` (case-insensitive globs), ordered by `sort` and cut to
+        /// `limit` rows. `severities` maps package names for
+        /// [`SortKey::Severity`].
+        pub fn slice(
+            &mut self,
+            filter: &[String],
+            sort: Option<SortKey>,
+            limit: Option<usize>,
+            severities: &BTreeMap<String, Severity>,
+        ) {
+            let filter: Vec<String> = filter.iter().map(|f| f.to_lowercase()).collect();
+            let sizes = &self.sizes;
+            // Firmware rows are "Device name: old → new"; the others start with the name
+            fn name(row: &str, firmware: bool) -> &str {
+                if firmware {
+                    row.split(':').next().unwrap_or(row).trim()
+                } else {
+                    row.split_whitespace().next().unwrap_or(row)
+                }
+            }
+            let sections = [
+                (&mut self.system, false),
+                (&mut self.flatpak, false),
+                (&mut self.flatpak_runtimes, false),
+                (&mut self.firmware, true),
+            ];
+            for (rows, firmware) in sections {
+                if !filter.is_empty() {
+                    rows.retain(|row| {
+                        let name = name(row, firmware).to_lowercase();
+                        // "bash" as well as "bash.x86_64" for packages
+                        let package = name
+                            .rsplit_once('.')
+                            .map_or(name.as_str(), |(package, _)| package);
+                        filter
+                            .iter()
+                            .any(|f| glob_match(f, &name) || glob_match(f, package))
+                    });
+                }
+                match sort {
+                    Some(SortKey::Name) => {
+                        rows.sort_by_key(|row| name(row, firmware).to_lowercase())
+                    }
+                    Some(SortKey::Size) => rows.sort_by_key(|row| {
+                        std::cmp::Reverse(sizes.get(row).map_or(0, |s| s.download))
+                    }),
+                    Some(SortKey::Repo) => {
+                        rows.sort_by_key(|row| row.split_whitespace().nth(2).map(str::to_string))
+                    }
+                    Some(SortKey::Severity) => rows.sort_by_key(|row| {
+                        std::cmp::Reverse(severities.get(package_name(row)).copied())
+                    }),
+                    None => {}
+                }
+                if let Some(limit) = limit {
+                    rows.truncate(limit);
+                }
+            }
+        }
+    }
+
+    /// Flatpak installations fup updates: the system-wide one, and per-user
+    /// ones of whoever ran fup through sudo or, with `[flatpak] all_users`,
+    /// of every user.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum Installation {
+        System,
+        /// The named user's, or that of the user fup runs as.
+        User(Option<String>),
+    }
+
+    impl Installation {
+        /// "system", "user" or "user:alice"; the last column of an
+        /// `AvailableUpdates::flatpak` row.
+        fn label(&self) -> String {
+            match self {
+                Installation::System => "system".into(),
+                Installation::User(None) => "user".into(),
+                Installation::User(Some(user)) => format!("user:{}", user),
+            }
+        }
+
+        fn parse(label: &str) -> Option<Self> {
+            match label.split_once(':') {
+                Some(("user", user)) => Some(Installation::User(Some(user.to_string()))),
+                None if label == "system" => Some(Installation::System),
+                None if label == "user" => Some(Installation::User(None)),
+                _ => None,
+            }
+        }
+
+        fn flag(&self) -> &'static str {
+            match self {
+                Installation::System => "--system",
+                Installation::User(_) => "--user",
+            }
+        }
+
+        /// `name` marked with the installation it is in, if it's per-user.
+        pub fn tag(&self, name: &str) -> String {
+            match self {
+                Installation::System => name.to_string(),
+                Installation::User(None) => format!("{} (user)", name),
+                Installation::User(Some(user)) => format!("{} ({})", name, user),
+            }
+        }
+    }
+
+    /// A pending Flatpak update, as listed by `remote-ls --updates`.
+    #[derive(Debug)]
+    struct FlatpakUpdate {
+        app: String,
+        branch: String,
+        origin: String,
+        /// Of every arch pending, e.g. the i386 GL extension next to x86_64.
+        download_bytes: u64,
+        installed_bytes: u64,
+        installation: Installation,
+        /// A runtime or extension (such as a .Locale) rather than an app.
+        runtime: bool,
+    }
+
+    impl FlatpakUpdate {
+        /// The `AvailableUpdates::flatpak` row: "org.mozilla.firefox stable flathub system".
+        fn row(&self) -> String {
+            format!(
+                "{} {} {} {}",
+                self.app,
+                self.branch,
+                self.origin,
+                self.installation.label()
+            )
+        }
+    }
+
+    /// An installed Flatpak that its remote marks end-of-life, or an app
+    /// still running on an end-of-life runtime. Updating these keeps them
+    /// current with a branch nobody maintains any more.
+    #[derive(Debug, Clone)]
+    pub struct EolFlatpak {
+        /// The ref as `flatpak list` shows it ("org.gnome.Platform/x86_64/43").
+        pub name: String,
+        pub installation: Installation,
+        /// The end-of-life runtime, when the app itself isn't end-of-life.
+        pub runtime: Option<String>,
+        pub reason: String,
+        /// What the remote says replaces it (`eol-rebase`).
+        pub replacement: Option<String>,
+    }
+
+    impl EolFlatpak {
+        pub fn suggestion(&self) -> String {
+            match (&self.replacement, &self.runtime) {
+                (Some(replacement), None) => format!("replaced by {}", replacement),
+                (_, Some(_)) => "needs an update to a supported runtime; otherwise look for a maintained alternative".into(),
+                (None, None) => "no replacement named; uninstall it or look for an alternative".into(),
+            }
+        }
+    }
+
+    /// The end-of-life reason and replacement from a remote-ls options
+    /// column ("eol=Use the 44 branch,eol-rebase=org.gnome.Platform/x86_64/44").
+    fn parse_eol(options: &str) -> Option<(String, Option<String>)> {
+        let rebase_at = options.find("eol-rebase=");
+        let replacement = rebase_at.map(|i| {
+            options[i + "eol-rebase=".len()..]
+                .split(',')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .to_string()
+        });
+        let eol_at = options
+            .match_indices("eol=")
+            .map(|(i, _)| i)
+            .find(|&i| i == 0 || options[..i].ends_with(','));
+        let reason = match eol_at {
+            Some(at) => {
+                let end = rebase_at.filter(|&r| r > at).unwrap_or(options.len());
+                options[at + "eol=".len()..end]
+                    .trim_end_matches(',')
+                    .trim()
+                    .to_string()
+            }
+            None => format!("renamed to {}", replacement.as_deref()?),
+        };
+        Some((reason, replacement))
+    }
+
+    /// Remote and installation of an `AvailableUpdates::flatpak` row.
+    pub fn flatpak_origin(row: &str) -> Option<(&str, Installation)> {
+        let mut cols = row.split_whitespace().skip(2);
+        let origin = cols.next()?;
+        Some((origin, Installation::parse(cols.next()?)?))
+    }
+
+    /// Repositories Fedora itself ships packages from. `anaconda` is what
+    /// dnf5 reports for packages that came with the installer.
+    const DISTRO_REPOS: &[&str] = &[
+        "fedora",
+        "updates",
+        "updates-testing",
+        "fedora-cisco-openh264",
+        "anaconda",
+    ];
+
+    /// Anything outside Fedora's own repositories: RPM Fusion, COPRs and
+    /// vendor repos.
+    pub fn is_third_party(repo: &str) -> bool {
+        let repo = repo.trim_start_matches('@');
+        let base = repo
+            .strip_suffix("-debuginfo")
+            .or_else(|| repo.strip_suffix("-source"))
+            .unwrap_or(repo);
+        !DISTRO_REPOS.contains(&base)
+    }
+
+    /// Name of the package in a check-upgrade row such as
+    /// "kernel.x86_64  6.9.1-200.fc40  updates".
+    fn package_name(row: &str) -> &str {
+        let package = row.split_whitespace().next().unwrap_or(row);
+        package
+            .rsplit_once('.')
+            .map_or(package, |(name, _arch)| name)
+    }
+
+    /// A package as printed by dnf5's `--json` options. Field names have
+    /// moved between dnf5 releases, hence the aliases.
+    #[derive(Debug, Deserialize)]
+    pub struct JsonPackage {
+        pub name: String,
+        #[serde(default)]
+        pub arch: String,
+        /// A number or a string, depending on the command.
+        #[serde(default)]
+        pub epoch: serde_json::Value,
+        #[serde(default)]
+        pub version: String,
+        #[serde(default)]
+        pub release: String,
+        #[serde(default)]
+        pub evr: Option<String>,
+        #[serde(default, alias = "repo_id", alias = "repository")]
+        pub repo: String,
+        #[serde(default)]
+        pub from_repo: Option<String>,
+    }
+
+    impl JsonPackage {
+        pub fn evr(&self) -> String {
+            if let Some(evr) = &self.evr {
+                return evr.clone();
+            }
+            let epoch = match &self.epoch {
+                serde_json::Value::Number(n) => n.to_string(),
+                serde_json::Value::String(s) => s.clone(),
+                _ => String::new(),
+            };
+            match epoch.as_str() {
+                "" | "0" => format!("{}-{}", self.version, self.release),
+                epoch => format!("{}:{}-{}", epoch, self.version, self.release),
+            }
+        }
+
+        /// The same row `dnf5 check-upgrade` prints as text.
+        pub fn row(&self) -> String {
+            format!("{}.{}  {}  {}", self.name, self.arch, self.evr(), self.repo)
+        }
+    }
+
+    /// Reads the packages out of dnf5 `--json` output: a bare array, or an
+    /// object holding one. None when the output isn't JSON at all, which is
+    /// what a dnf5 too old for `--json` leaves on stdout.
+    pub fn json_packages(lines: &[String]) -> Option<Vec<JsonPackage>> {
+        let value: serde_json::Value = serde_json::from_str(&lines.join("\n")).ok()?;
+        let array = match value {
+            serde_json::Value::Array(_) => value,
+            serde_json::Value::Object(map) => {
+                map.into_iter().map(|(_, v)| v).find(|v| v.is_array())?
+            }
+            _ => return None,
+        };
+        serde_json::from_value(array).ok()
+    }
+
+    /// check-upgrade's text rows, "name.arch  evr  repo", without the
+    /// metadata expiration notices and headings around them.
+    pub fn parse_check_upgrade(lines: &[String]) -> Vec<String> {
+        lines
+            .iter()
+            .filter(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                fields.len() == 3
+                    && fields[0].contains('.')
+                    && fields[1].chars().next().is_some_and(|c| c.is_ascii_digit())
+                    && !fields[1].ends_with(':')
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// A distro package that a third-party repository is about to replace.
+    #[derive(Debug, Clone)]
+    pub struct RepoTakeover {
+        pub package: String,
+        pub repo: String,
+        /// Where the installed version came from.
+        pub installed_from: String,
+    }
+
+    pub struct Updater {
+        config: Config,
+        installroot: Option<String>,
+        /// Global dnf5 options: the download throttle and package excludes.
+        dnf_opts: Vec<String>,
+        security_filter: Option<String>,
+        /// Packages or Flatpak refs named on the command line; empty means everything.
+        only: Vec<String>,
+        kernel_only: bool,
+        /// Flatpak remotes named with --flatpak-remote; empty means all of them.
+        flatpak_remotes: Vec<String>,
+        /// Whose per-user Flatpak installation to update: the sudo caller's.
+        flatpak_user: Option<String>,
+        /// fwupd devices named with --firmware-device; empty means all of them.
+        firmware_devices: Vec<String>,
+        firmware_approval: FirmwareApproval,
+        auto_remove: bool,
+        remove_unused: bool,
+        /// Show what autoremove and unused-runtime removal would take and ask first.
+        confirm_removals: bool,
+        /// Questions can be asked at the terminal, even under -y.
+        interactive: bool,
+        /// After a backend fails, ask whether to retry, skip it or abort.
+        ask_on_failure: bool,
+        /// Low CPU and I/O weights for the systemd scopes, as with --background.
+        background: bool,
+        /// Numbers the systemd scopes of this run.
+        scopes: Arc<std::sync::atomic::AtomicUsize>,
+        dry_run: bool,
+        quiet: bool,
+        shutdown: ShutdownSignal,
+        runner: Arc<dyn CommandRunner>,
+        artifacts: Option<ArtifactStore>,
+        parallel: Option<ParallelOutput>,
+        /// Phase timings and download totals of the stages in progress.
+        running: Arc<Mutex<BTreeMap<Stage, StageStats>>>,
+        summary: Arc<Mutex<UpdateSummary>>,
+    }
+
+    impl Updater {
+        pub fn new(config: &Config, dry_run: bool, quiet: bool, shutdown: ShutdownSignal) -> Self {
+            // Checked when the config is loaded
+            let download_limit = config
+                .network
+                .max_download_rate
+                .as_deref()
+                .map(str::trim)
+                .filter(|rate| is_valid_rate(rate))
+                .map(str::to_string);
+
+            let mut dnf_opts: Vec<String> = download_limit
+                .iter()
+                .map(|r| format!("--setopt=throttle={}", r))
+                .collect();
+            if !config.system.exclude.is_empty() {
+                dnf_opts.push(format!("--exclude={}", config.system.exclude.join(",")));
+            }
+            dnf_opts.extend(crate::mirror::dnf_options(&config.mirror, Path::new("/")));
+
+            Self {
+                config: config.clone(),
+                installroot: None,
+                dnf_opts,
+                security_filter: None,
+                only: Vec::new(),
+                kernel_only: false,
+                flatpak_remotes: Vec::new(),
+                flatpak_user: std::env::var("SUDO_USER")
+                    .ok()
+                    .filter(|u| !u.is_empty() && u != "root"),
+                firmware_devices: Vec::new(),
+                firmware_approval: FirmwareApproval::Unattended,
+                auto_remove: config.system.auto_remove,
+                remove_unused: config.flatpak.remove_unused,
+                confirm_removals: false,
+                interactive: false,
+                ask_on_failure: false,
+                background: config.system.background,
+                scopes: Arc::default(),
+                dry_run,
+                quiet,
+                shutdown,
+                runner: Arc::new(TokioRunner),
+                artifacts: None,
+                parallel: None,
+                running: Arc::default(),
+                summary: Arc::new(Mutex::new(UpdateSummary {
+                    download_limit,
+                    ..Default::default()
+                })),
+            }
+        }
+
+        #[cfg(test)]
+        pub fn with_runner(mut self, runner: Arc<dyn CommandRunner>) -> Self {
+            self.runner = runner;
+            self
+        }
+
+        /// Points dnf5 at an alternate root. Flatpak and firmware always refer to
+        /// the running system, so their checks are skipped for a target.
+        pub fn with_target(mut self, root: &Path) -> Self {
+            self.installroot = Some(format!("--installroot={}", root.display()));
+            self
+        }
+
+        /// Adds package globs to exclude on top of `[system] exclude`.
+        pub fn with_excludes(mut self, patterns: &[String]) -> Self {
+            if !patterns.is_empty() {
+                self.dnf_opts
+                    .push(format!("--exclude={}", patterns.join(",")));
+            }
+            self
+        }
+
+        /// Limits the update to the named packages (system) or refs (Flatpak).
+        pub fn with_only(mut self, items: &[String]) -> Self {
+            self.only = items.to_vec();
+            self
+        }
+
+        pub fn with_flatpak_remotes(mut self, remotes: &[String]) -> Self {
+            self.flatpak_remotes = remotes.to_vec();
+            self
+        }
+
+        pub fn with_firmware_devices(mut self, devices: &[String]) -> Self {
+            self.firmware_devices = devices.to_vec();
+            self
+        }
+
+        /// Lets fup ask at the terminal before removing `[system] protected`
+        /// packages; otherwise such removals are refused.
+        pub fn with_prompts(mut self, interactive: bool) -> Self {
+            self.interactive = interactive;
+            self
+        }
+
+        /// Also gives the systemd scopes low CPU and I/O weights.
+        pub fn with_background(mut self) -> Self {
+            self.background = true;
+            self
+        }
+
+        pub fn with_firmware_approval(mut self, approval: FirmwareApproval) -> Self {
+            self.firmware_approval = approval;
+            self
+        }
+
+        /// Updates only kernel packages; old kernels are still cleaned up.
+        pub fn with_kernel_only(mut self) -> Self {
+            self.only = vec!["kernel*".into()];
+            self.kernel_only = true;
+            self
+        }
+
+        /// Restricts system updates to security fixes of at least `min` severity.
+        pub fn with_min_severity(mut self, min: Severity) -> Self {
+            self.security_filter = Some(min.dnf_filter());
+            self
+        }
+
+        /// Pending security advisories, most severe first.
+        pub async fn security_advisories(&self) -> Result<Vec<Advisory>> {
+            if !self.runner.exists("dnf5") {
+                return Err(UpdateError::CommandNotFound("dnf5".into()));
+            }
+            let spinner = self.create_spinner("Checking security advisories...");
+            let advisories = async {
+                let lines = self
+                    .run_command_silent(
+                        Stage::System,
+                        "dnf5",
+                        &self.dnf_args(&["advisory", "list", "--security", "--with-cve", "--json"]),
+                    )
+                    .await?;
+                let json = lines.join("\n");
+                if json.trim_start().starts_with(['[', '{']) {
+                    return crate::security::parse(&json);
+                }
+                // Without --json the list has no CVEs, but still names the advisories
+                debug!("dnf5 advisory list --json unsupported; reading its text output");
+                let lines = self
+                    .run_command_silent(
+                        Stage::System,
+                        "dnf5",
+                        &self.dnf_args(&["advisory", "list", "--security"]),
+                    )
+                    .await?;
+                Ok(crate::security::parse_text(&lines))
+            }
+            .await;
+            spinner.finish_and_clear();
+            advisories
+        }
+
+        /// dnf5 arguments, prefixed with `--installroot` when updating a target
+        /// and the configured throttle and excludes.
+        fn dnf_args<'a>(&'a self, args: &[&'a str]) -> Vec<&'a str> {
+            let mut all: Vec<&str> = self
+                .installroot
+                .iter()
+                .chain(&self.dnf_opts)
+                .map(String::as_str)
+                .collect();
+            all.extend_from_slice(args);
+            all
+        }
+
+        /// `dnf5 update` with the security filter and named packages, answered
+        /// with `-y` or, to only resolve the transaction, `--assumeno`.
+        fn update_args<'a>(&'a self, answer: &'a str) -> Vec<&'a str> {
+            let mut args = self.dnf_args(&["update", "--refresh", answer]);
+            if let Some(filter) = &self.security_filter {
+                args.extend(["--security", filter.as_str()]);
+            }
+            args.extend(self.only.iter().map(String::as_str));
+            args
+        }
+
+        /// Keeps command output and device reports in the given run directory.
+        pub fn with_artifacts(mut self, store: ArtifactStore) -> Self {
+            self.artifacts = Some(store);
+            self
+        }
+
+        /// Overrides `[system] auto_remove` and `[flatpak] remove_unused`, and
+        /// whether to ask before removing anything.
+        pub fn with_removals(
+            mut self,
+            auto_remove: Option<bool>,
+            remove_unused: Option<bool>,
+            confirm: bool,
+        ) -> Self {
+            self.auto_remove = auto_remove.unwrap_or(self.auto_remove);
+            self.remove_unused = remove_unused.unwrap_or(self.remove_unused);
+            self.confirm_removals = confirm;
+            self
+        }
+
+        /// Asks at the terminal what to do about a failed backend instead of
+        /// moving on; only for backends run one after another.
+        pub fn with_failure_prompts(mut self, ask: bool) -> Self {
+            self.ask_on_failure = ask;
+            self
+        }
+
+        /// Gives each backend its own live pane and holds back full command
+        /// output until `flush_output`, for running stages concurrently.
+        pub fn with_parallel_output(mut self) -> Self {
+            if !self.quiet {
+                self.parallel = Some(ParallelOutput::new(!crate::ui::accessible()));
+            }
+            self
+        }
+
+        /// Prints the output held back in parallel mode, one section per backend.
+        pub fn flush_output(&self) {
+            if let Some(output) = &self.parallel {
+                output.flush();
+            }
+        }
+
+        pub async fn summary(&self) -> UpdateSummary {
+            self.summary.lock().await.clone()
+        }
+
+        /// Estimates how much the pending system and Flatpak updates will download.
+        pub async fn pending_download_bytes(&self, system: bool, flatpak: bool) -> u64 {
+            let mut total = 0;
+
+            if system && self.runner.exists("dnf5") {
+                let spinner = self.create_spinner("Calculating download size...");
+                // --assumeno resolves the transaction and exits without applying it
+                if let Ok(lines) = self
+                    .run_command_silent(
+                        Stage::System,
+                        "dnf5",
+                        &self.dnf_args(&["update", "--assumeno"]),
+                    )
+                    .await
+                {
+                    total += dnf_download_size(&lines);
+                }
+                spinner.finish_and_clear();
+            }
+
+            if flatpak && self.runner.exists("flatpak") {
+                for installation in self.flatpak_installations().await {
+                    let pending = self
+                        .pending_flatpaks(&installation)
+                        .await
+                        .unwrap_or_default();
+                    total += pending.iter().map(|u| u.download_bytes).sum::<u64>();
+                }
+            }
+
+            total
+        }
+
+        /// Runs backends one after the other until Ctrl-C or, with `fail_fast`,
+        /// the first failure. Aborting at a failure prompt returns that failure.
+        pub async fn run_stages(&self, stages: &[Stage], fail_fast: bool) -> Result<()> {
+            for (i, &stage) in stages.iter().enumerate() {
+                if self.shutdown.borrow().is_some() {
+                    break;
+                }
+                let mut result = self.run_stage(stage).await;
+                let mut retries = 0;
+                let mut choice = None;
+                while self.ask_on_failure
+                    && let Err(e) = &result
+                    && !matches!(e, UpdateError::Cancelled)
+                {
+                    error!("{} update failed: {}", stage.label(), e);
+                    match self.ask_after_failure(stage, e).await {
+                        FailureChoice::Retry => {
+                            retries += 1;
+                            self.forget_error(stage).await;
+                            result = self.run_stage(stage).await;
+                            if let Some(stats) = self.summary.lock().await.stages.get_mut(&stage) {
+                                stats.retries = retries;
+                            }
+                        }
+                        other => {
+                            choice = Some(other);
+                            break;
+                        }
+                    }
+                }
+                match (result, choice) {
+                    (Ok(()) | Err(UpdateError::Cancelled), _)
+                    | (Err(_), Some(FailureChoice::Skip)) => {}
+                    (Err(e), Some(_)) => return Err(e),
+                    (Err(e), None) => {
+                        error!("{} update failed: {}", stage.label(), e);
+                        if fail_fast {
+                            self.halt_after(stage, &stages[i + 1..]).await;
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        /// Asks what to do about a backend that failed, showing the failed
+        /// command's whole output in a pager on request.
+        async fn ask_after_failure(&self, stage: Stage, e: &UpdateError) -> FailureChoice {
+            if let Some(hint) = e.hint() {
+                crate::render::println(&format!("{} {}", t!("hint-prefix").accent().bold(), hint));
+            }
+            loop {
+                let answer = crate::render::suspend(|| {
+                    use std::io::Write;
+
+                    let log = if e.output().is_some() {
+                        ", show the [l]og"
+                    } else {
+                        ""
+                    };
+                    print!(
+                        "\n{} failed. [r]etry, [s]kip it and continue{} or [a]bort? ",
+                        stage.label(),
+                        log
+                    );
+                    let _ = std::io::stdout().flush();
+                    let mut input = String::new();
+                    match std::io::stdin().read_line(&mut input) {
+                        Ok(n) if n > 0 => input.trim().to_lowercase(),
+                        // Nobody left to answer
+                        _ => "a".to_string(),
+                    }
+                });
+                match answer.as_str() {
+                    "r" | "retry" => return FailureChoice::Retry,
+                    "s" | "skip" => return FailureChoice::Skip,
+                    "a" | "abort" => return FailureChoice::Abort,
+                    "l" | "log" => {
+                        if let Some(output) = e.output() {
+                            crate::show_in_pager(&format!("{}\n", output.trim_end())).await;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        /// Drops a backend's error from the summary before it is run again.
+        async fn forget_error(&self, stage: Stage) {
+            let mut summary = self.summary.lock().await;
+            let previous = summary.stages.get(&stage).and_then(|s| s.error.clone());
+            if let Some(i) = summary
+                .errors
+                .iter()
+                .position(|e| Some(e) == previous.as_ref())
+            {
+                summary.errors.remove(i);
+            }
+        }
+
+        /// Runs failed backends again, up to `attempts` times each after
+        /// `delay`, dropping the earlier error when a retry gets through.
+        /// Backends that fail-fast held back behind one are run once it does,
+        /// and aborting at a failure prompt among those returns that failure.
+        pub async fn retry_failed(
+            &self,
+            attempts: u32,
+            delay: Duration,
+            fail_fast: bool,
+        ) -> Result<()> {
+            let mut retried = Vec::new();
+            loop {
+                let summary = self.summary.lock().await;
+                let next = summary
+                    .stages
+                    .iter()
+                    .find(|(stage, stats)| stats.failed && !retried.contains(*stage))
+                    .map(|(stage, _)| *stage);
+                drop(summary);
+                let Some(stage) = next else {
+                    return Ok(());
+                };
+                retried.push(stage);
+
+                for retry in 1..=attempts {
+                    info!(
+                        "Retrying {} update in {} (attempt {} of {})",
+                        stage.label(),
+                        crate::history::format_duration(delay.as_secs_f64()),
+                        retry,
+                        attempts
+                    );
+                    let mut shutdown = self.shutdown.clone();
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {}
+                        Ok(_) = shutdown.wait_for(Option::is_some) => return Ok(()),
+                    }
+
+                    self.forget_error(stage).await;
+                    let result = self.run_stage(stage).await;
+                    if let Some(stats) = self.summary.lock().await.stages.get_mut(&stage) {
+                        stats.retries = retry;
+                    }
+                    match result {
+                        Ok(()) => {
+                            info!("{} update succeeded on retry {}", stage.label(), retry);
+                            let halted = {
+                                let mut summary = self.summary.lock().await;
+                                if summary.halted_by == Some(stage) {
+                                    summary.halted_by = None;
+                                    std::mem::take(&mut summary.halted)
+                                } else {
+                                    Vec::new()
+                                }
+                            };
+                            self.run_stages(&halted, fail_fast).await?;
+                            break;
+                        }
+                        Err(UpdateError::Cancelled) => return Ok(()),
+                        Err(_) => {}
+                    }
+                }
+            }
+        }
+
+        /// Runs the waves from `parallel_waves`, the stages of each at the
+        /// same time. Under fail-fast a failure skips the later waves.
+        pub async fn run_parallel(&self, waves: &[Vec<Stage>], fail_fast: bool) {
+            for (i, wave) in waves.iter().enumerate() {
+                if self.shutdown.borrow().is_some() {
+                    break;
+                }
+                let run = |stage: Stage| async move {
+                    if wave.contains(&stage) {
+                        self.run_stage(stage).await
+                    } else {
+                        Ok(())
+                    }
+                };
+                let (sys_res, flat_res, fw_res) = tokio::join!(
+                    run(Stage::System),
+                    run(Stage::Flatpak),
+                    run(Stage::Firmware)
+                );
+
+                let mut failed = None;
+                for (stage, res) in [
+                    (Stage::System, sys_res),
+                    (Stage::Flatpak, flat_res),
+                    (Stage::Firmware, fw_res),
+                ] {
+                    if let Err(e) = res {
+                        error!("Update failed: {}", e);
+                        if !matches!(e, UpdateError::Cancelled) {
+                            failed.get_or_insert(stage);
+                        }
+                    }
+                }
+                if fail_fast && let Some(stage) = failed {
+                    let rest: Vec<Stage> = waves[i + 1..].iter().flatten().copied().collect();
+                    self.halt_after(stage, &rest).await;
+                    break;
+                }
+            }
+        }
+
+        /// Records backends left out because `failed` failed under fail-fast.
+        pub async fn halt_after(&self, failed: Stage, stages: &[Stage]) {
+            if stages.is_empty() {
+                return;
+            }
+            let labels: Vec<&str> = stages.iter().map(|s| s.label()).collect();
+            warn!(
+                "{} failed; skipping {} (fail-fast)",
+                failed.label(),
+                labels.join(", ")
+            );
+            let mut summary = self.summary.lock().await;
+            summary.halted.extend_from_slice(stages);
+            summary.halted_by = Some(failed);
+        }
+
+        /// Records a stage that was not run because a check ruled it out.
+        pub async fn skip_stage(&self, stage: Stage, reason: &str) {
+            warn!("{} update skipped: {}", stage.label(), reason);
+            self.summary
+                .lock()
+                .await
+                .errors
+                .push(format!("{} skipped: {}", stage.label(), reason));
+        }
+
+        fn create_spinner(&self, msg: &str) -> ProgressBar {
+            if self.quiet {
+                return ProgressBar::hidden();
+            }
+            if crate::ui::accessible() {
+                crate::ui::announce(msg);
+                return ProgressBar::hidden();
+            }
+            let pb = crate::render::add(ProgressBar::new_spinner());
+            pb.set_style(
+                ProgressStyle::default_spinner()
+                    .tick_chars(crate::ui::ticks())
+                    .template(&format!(
+                        "{{spinner:.{}}} {{msg}}",
+                        crate::ui::accent_style()
+                    ))
+                    .unwrap(),
+            );
+            pb.set_message(msg.to_string());
+            pb.enable_steady_tick(Duration::from_millis(80));
+            pb
+        }
+
+        fn set_step(&self, spinner: &ProgressBar, msg: &str) {
+            if crate::ui::accessible() && !self.quiet {
+                crate::ui::announce(msg);
+            }
+            spinner.set_message(msg.to_string());
+        }
+
+        async fn run_command(&self, stage: Stage, cmd: &str, args: &[&str]) -> Result<Vec<String>> {
+            self.run_command_in(stage, cmd, args, None).await
+        }
+
+        /// Like `run_command`, but with the output shown as the latest line
+        /// of `pane` instead of printed, for commands running side by side.
+        async fn run_command_in(
+            &self,
+            stage: Stage,
+            cmd: &str,
+            args: &[&str],
+            pane: Option<ProgressBar>,
+        ) -> Result<Vec<String>> {
+            let full_cmd = format!("{} {}", cmd, args.join(" "));
+            let prefix = stage.prefix();
+            info!(backend = stage.label(), "Executing: {}", full_cmd);
+
+            if self.dry_run {
+                match &self.parallel {
+                    Some(output) => output.line(stage, false, &format!("[DRY RUN] {}", full_cmd)),
+                    None if crate::events::enabled() => {
+                        crate::events::emit(crate::events::Event::CommandOutput {
+                            stage,
+                            command: &full_cmd,
+                            stream: crate::events::Stream::Stdout,
+                            line: &format!("[DRY RUN] {}", full_cmd),
+                        })
+                    }
+                    None => crate::render::println(&format!(
+                        "{} [DRY RUN] {}",
+                        prefix.accent().bold(),
+                        full_cmd
+                    )),
+                }
+                return Ok(vec![]);
+            }
+
+            let mut child = self.spawn_in_scope(stage, cmd, args)?;
+            let stdout = child.stdout();
+            let stderr = child.stderr();
+
+            let prefix_out = format!("{}", prefix.white().bold());
+            let prefix_err = format!("{}", prefix.failure().bold());
+            let quiet = self.quiet;
+            let parallel_out = self.parallel.clone();
+            let parallel_err = self.parallel.clone();
+            let pane_out = pane.clone();
+            let pane_err = pane;
+            // Bars replace the progress lines only where they can be drawn
+            let tracker = Arc::new(std::sync::Mutex::new(ProgressTracker::default()));
+            let track = !quiet && !crate::ui::accessible() && std::io::stderr().is_terminal();
+            let tracker_out = tracker.clone();
+            let tracker_err = tracker.clone();
+            let output_lines = Arc::new(Mutex::new(Vec::new()));
+            let lines_clone = output_lines.clone();
+            let error_lines = Arc::new(Mutex::new(Vec::new()));
+            let errors_clone = error_lines.clone();
+            let activity = Activity::new();
+            let stdout_activity = activity.clone();
+            let stderr_activity = activity.clone();
+            let command_out = Arc::new(full_cmd.clone());
+            let command_err = command_out.clone();
+
+            let stdout_handle = tokio::spawn(async move {
+                let mut reader = BufReader::new(stdout).lines();
+                while let Ok(Some(line)) = reader.next_line().await {
+                    stdout_activity.touch();
+                    crate::events::emit(crate::events::Event::CommandOutput {
+                        stage,
+                        command: &command_out,
+                        stream: crate::events::Stream::Stdout,
+                        line: &line,
+                    });
+                    if let Some(pane) = &pane_out {
+                        pane.set_message(line.trim().to_string());
+                    }
+                    match &parallel_out {
+                        Some(output) => output.line(stage, false, &line),
+                        None if quiet || pane_out.is_some() => {}
+                        None => {
+                            let mut tracker = tracker_out.lock().unwrap();
+                            if !(track && tracker.update(stage, &line)) {
+                                tracker.println(format!("{} {}", prefix_out, line), false);
+                            }
+                        }
+                    }
+                    lines_clone.lock().await.push(line);
+                }
+            });
+
+            let stderr_handle = tokio::spawn(async move {
+                let mut reader = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = reader.next_line().await {
+                    stderr_activity.touch();
+                    crate::events::emit(crate::events::Event::CommandOutput {
+                        stage,
+                        command: &command_err,
+                        stream: crate::events::Stream::Stderr,
+                        line: &line,
+                    });
+                    if let Some(pane) = &pane_err {
+                        pane.set_message(line.trim().to_string());
+                    }
+                    match &parallel_err {
+                        Some(output) => output.line(stage, true, &line),
+                        None if quiet || pane_err.is_some() => {}
+                        None => {
+                            let mut tracker = tracker_err.lock().unwrap();
+                            if !(track && tracker.update(stage, &line)) {
+                                tracker.println(format!("{} {}", prefix_err, line), true);
+                            }
+                        }
+                    }
+                    debug!("stderr: {}", line);
+                    errors_clone.lock().await.push(line);
+                }
+            });
+
+            let status = self
+                .wait_child(child.as_mut(), stage, (cmd, args), &activity)
+                .await;
+            let _ = tokio::join!(stdout_handle, stderr_handle);
+            tracker.lock().unwrap().finish();
+            let status = status?;
+            let lines = output_lines.lock().await.clone();
+
+            if !status.success() {
+                let code = status.code().unwrap_or(-1);
+                let mut details = lines;
+                details.extend(error_lines.lock().await.drain(..));
+                return Err(UpdateError::from_output(full_cmd, code, details.join("\n")));
+            }
+
+            Ok(lines)
+        }
+
+        /// Like `run_command`, but also keeps the output as a run artifact,
+        /// whether the command succeeds or fails.
+        async fn run_command_kept(
+            &self,
+            stage: Stage,
+            cmd: &str,
+            args: &[&str],
+            artifact: &str,
+        ) -> Result<Vec<String>> {
+            let result = self.run_command(stage, cmd, args).await;
+            if let Some(store) = &self.artifacts {
+                match &result {
+                    Ok(lines) => store.write(artifact, &lines.join("\n")),
+                    Err(e) => {
+                        if let Some(output) = e.output() {
+                            store.write(artifact, output)
+                        }
+                    }
+                }
+            }
+            result
+        }
+
+        /// Saves the backend's own record of what just happened: the dnf5
+        /// transaction and the fwupd device state and history.
+        async fn collect_artifacts(&self, stage: Stage) {
+            let Some(store) = &self.artifacts else {
+                return;
+            };
+            if self.dry_run {
+                return;
+            }
+
+            let reports: Vec<(&str, Vec<&str>, &str)> = match stage {
+                Stage::System => vec![(
+                    "dnf5",
+                    self.dnf_args(&["history", "info", "last", "--json"]),
+                    "dnf5-transaction.json",
+                )],
+                Stage::Firmware => vec![
+                    (
+                        "fwupdmgr",
+                        vec!["get-devices", "--json"],
+                        "fwupd-devices.json",
+                    ),
+                    (
+                        "fwupdmgr",
+                        vec!["get-history", "--json"],
+                        "fwupd-history.json",
+                    ),
+                ],
+                Stage::Flatpak => vec![],
+            };
+
+            for (cmd, args, name) in reports {
+                if let Ok(lines) = self.run_command_silent(stage, cmd, &args).await
+                    && !lines.is_empty()
+                {
+                    store.write(name, &lines.join("\n"));
+                }
+            }
+        }
+
+        async fn run_command_silent(
+            &self,
+            stage: Stage,
+            cmd: &str,
+            args: &[&str],
+        ) -> Result<Vec<String>> {
+            let (_, lines, _) = self.run_command_captured(stage, cmd, args).await?;
+            Ok(lines)
+        }
+
+        /// Like `run_command_silent`, but exiting with anything other than 0
+        /// or one of `ok_codes` is an error carrying the output.
+        async fn run_command_checked(
+            &self,
+            stage: Stage,
+            cmd: &str,
+            args: &[&str],
+            ok_codes: &[i32],
+        ) -> Result<Vec<String>> {
+            let (status, mut lines, errors) = self.run_command_captured(stage, cmd, args).await?;
+            match status.code() {
+                Some(code) if code == 0 || ok_codes.contains(&code) => Ok(lines),
+                code => {
+                    lines.extend(errors);
+                    let full_cmd = format!("{} {}", cmd, args.join(" "));
+                    Err(UpdateError::from_output(
+                        full_cmd,
+                        code.unwrap_or(-1),
+                        lines.join("\n"),
+                    ))
+                }
+            }
+        }
+
+        /// Runs a command without showing its output: the exit status, then
+        /// what it wrote to stdout and to stderr.
+        async fn run_command_captured(
+            &self,
+            stage: Stage,
+            cmd: &str,
+            args: &[&str],
+        ) -> Result<(ExitStatus, Vec<String>, Vec<String>)> {
+            let mut child = self.spawn(cmd, args)?;
+            let activity = Activity::new();
+            let read = |output: Output| {
+                let activity = activity.clone();
+                tokio::spawn(async move {
+                    let mut lines = Vec::new();
+                    let mut reader = BufReader::new(output).lines();
+                    while let Ok(Some(line)) = reader.next_line().await {
+                        activity.touch();
+                        lines.push(line);
+                    }
+                    lines
+                })
+            };
+            let stdout = read(child.stdout());
+            let stderr = read(child.stderr());
+
+            let status = self
+                .wait_child(child.as_mut(), stage, (cmd, args), &activity)
+                .await?;
+            Ok((
+                status,
+                stdout.await.unwrap_or_default(),
+                stderr.await.unwrap_or_default(),
+            ))
+        }
+
+        /// Spawns a backend command in its own transient systemd scope when
+        /// `[scope] enabled`: it gets a fup-named cgroup for systemd-cgtop,
+        /// leaves the invoking terminal's session and is held to the limits.
+        fn spawn_in_scope(
+            &self,
+            stage: Stage,
+            cmd: &str,
+            args: &[&str],
+        ) -> Result<Box<dyn RunningCommand>> {
+            let scope = &self.config.scope;
+            if !scope.enabled {
+                return self.spawn(cmd, args);
+            }
+            if !self.runner.exists("systemd-run") {
+                debug!("systemd-run not available, running {} without a scope", cmd);
+                return self.spawn(cmd, args);
+            }
+
+            let n = self
+                .scopes
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+                + 1;
+            let mut wrapped = vec![
+                "--scope".to_string(),
+                "--quiet".to_string(),
+                "--collect".to_string(),
+                format!(
+                    "--unit=fup-{}-{}-{}",
+                    stage.label().to_lowercase(),
+                    std::process::id(),
+                    n
+                ),
+                format!(
+                    "--description=fup: {}",
+                    phase_name(&format!("{} {}", cmd, args.join(" ")))
+                ),
+            ];
+            if !scope.slice.is_empty() {
+                wrapped.push(format!("--slice={}", scope.slice));
+            }
+            for (property, value) in [
+                ("MemoryMax", &scope.memory_max),
+                ("CPUQuota", &scope.cpu_quota),
+            ] {
+                if !value.trim().is_empty() {
+                    wrapped.push(format!("--property={}={}", property, value.trim()));
+                }
+            }
+            if self.background {
+                wrapped.push("--property=CPUWeight=20".into());
+                wrapped.push("--property=IOWeight=20".into());
+            }
+            wrapped.push("--".into());
+            wrapped.push(cmd.into());
+            let mut all: Vec<&str> = wrapped.iter().map(String::as_str).collect();
+            all.extend_from_slice(args);
+            self.spawn("systemd-run", &all)
+        }
+
+        fn spawn(&self, cmd: &str, args: &[&str]) -> Result<Box<dyn RunningCommand>> {
+            self.runner.spawn(cmd, args).map_err(|e| {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    UpdateError::CommandNotFound(cmd.to_string())
+                } else {
+                    UpdateError::Io(e)
+                }
+            })
+        }
+
+        /// Waits for a child spawned in its own process group. If a shutdown
+        /// signal arrives first, it is forwarded to the whole group, which is
+        /// killed outright if it hasn't exited after `KILL_GRACE_PERIOD`. A
+        /// child that stays silent for longer than the stage's timeout is
+        /// treated as hung and terminated the same way.
+        async fn wait_child(
+            &self,
+            child: &mut dyn RunningCommand,
+            stage: Stage,
+            (cmd, args): (&str, &[&str]),
+            activity: &Activity,
+        ) -> Result<ExitStatus> {
+            let full_cmd = format!("{} {}", cmd, args.join(" "));
+            let span = tracing::info_span!(
+                target: crate::telemetry::TARGET,
+                "command",
+                otel.name = %phase_name(&full_cmd),
+                command = %full_cmd,
+                exit_code = tracing::field::Empty,
+                outcome = tracing::field::Empty,
+                otel.status_code = tracing::field::Empty,
+                otel.status_message = tracing::field::Empty,
+            );
+            let started = Instant::now();
+            let result = self
+                .wait_child_inner(child, stage, &full_cmd, activity)
+                .instrument(span.clone())
+                .await;
+            if let Some(code) = result.as_ref().ok().and_then(|s| s.code()) {
+                span.record("exit_code", code);
+            }
+            crate::telemetry::record_outcome(&span, &result);
+            self.record_phase(stage, phase_name(&full_cmd), started.elapsed())
+                .await;
+            crate::audit::command(cmd, args, result.as_ref().ok().and_then(|s| s.code()));
+            result
+        }
+
+        async fn wait_child_inner(
+            &self,
+            child: &mut dyn RunningCommand,
+            stage: Stage,
+            full_cmd: &str,
+            activity: &Activity,
+        ) -> Result<ExitStatus> {
+            let mut shutdown = self.shutdown.clone();
+            let idle_limit = self.stage_timeout(stage);
+
+            let watchdog = async {
+                let Some(limit) = idle_limit else {
+                    return std::future::pending().await;
+                };
+                loop {
+                    tokio::time::sleep(HANG_CHECK_INTERVAL).await;
+                    if activity.idle() >= limit {
+                        return limit;
+                    }
+                }
+            };
+
+            tokio::select! {
+                status = child.wait() => Ok(status?),
+                Ok(signal) = shutdown.wait_for(Option::is_some) => {
+                    let signal = signal.unwrap_or(Signal::SIGTERM);
+                    child.terminate(signal).await;
+                    Err(UpdateError::Cancelled)
+                }
+                limit = watchdog => {
+                    let mins = limit.as_secs() / 60;
+                    warn!("{} produced no output for {} min, assuming it hung", full_cmd, mins);
+                    child.terminate(Signal::SIGTERM).await;
+                    Err(UpdateError::Timeout { cmd: full_cmd.to_string(), mins })
+                }
+            }
+        }
+
+        /// Adds to the time spent in a phase of a running stage. Repeated
+        /// commands (retries) accumulate under one name.
+        async fn record_phase(&self, stage: Stage, name: String, elapsed: Duration) {
+            debug!(
+                backend = stage.label(),
+                phase = %name,
+                duration_secs = elapsed.as_secs_f64(),
+                "Phase finished"
+            );
+            let mut running = self.running.lock().await;
+            let phases = &mut running.entry(stage).or_default().phases;
+            match phases.iter_mut().find(|p| p.name == name) {
+                Some(phase) => phase.duration_secs += elapsed.as_secs_f64(),
+                None => phases.push(PhaseTiming {
+                    name,
+                    duration_secs: elapsed.as_secs_f64(),
+                }),
+            }
+        }
+
+        async fn record_download(&self, stage: Stage, bytes: u64) {
+            self.running
+                .lock()
+                .await
+                .entry(stage)
+                .or_default()
+                .downloaded_bytes += bytes;
+        }
+
+        /// Idle limit for a stage's commands; `timeout_mins = 0` disables it.
+        fn stage_timeout(&self, stage: Stage) -> Option<Duration> {
+            let mins = match stage {
+                Stage::System => self.config.system.timeout_mins,
+                Stage::Flatpak => self.config.flatpak.timeout_mins,
+                Stage::Firmware => self.config.firmware.timeout_mins,
+            };
+            (mins > 0).then(|| Duration::from_secs(mins * 60))
+        }
+
+        /// `dnf5 check-upgrade --refresh -q` plus `extra`, which exits with 100
+        /// when updates are pending. The refresh fetches metadata, so network
+        /// trouble gets retried like any other download.
+        async fn check_upgrade(&self, extra: &[&str]) -> Result<Vec<String>> {
+            let mut args = self.dnf_args(&["check-upgrade", "--refresh", "-q"]);
+            args.extend_from_slice(extra);
+            crate::retry::with_backoff(
+                &self.config.retry,
+                &self.shutdown,
+                "dnf5 check-upgrade",
+                crate::retry::is_transient_failure,
+                || self.run_command_checked(Stage::System, "dnf5", &args, &[100]),
+            )
+            .await
+        }
+
+        pub async fn check_available_updates(&self) -> Result<AvailableUpdates> {
+            let mut updates = AvailableUpdates::default();
+
+            // Check system updates
+            if self.runner.exists("dnf5") {
+                let spinner = self.create_spinner("Checking system updates...");
+                let json = self.check_upgrade(&["--json"]).await;
+                match json.as_deref().map(json_packages) {
+                    Ok(Some(packages)) => {
+                        updates.system = packages.iter().map(JsonPackage::row).collect()
+                    }
+                    Err(e) if crate::retry::is_transient_failure(e) => {
+                        warn!("Could not check for system updates: {}", e)
+                    }
+                    _ => {
+                        debug!("dnf5 check-upgrade --json unsupported; reading its text output");
+                        if let Ok(lines) = self.check_upgrade(&[]).await {
+                            updates.system = parse_check_upgrade(&lines);
+                        }
+                    }
+                }
+                if !updates.system.is_empty() {
+                    let sizes = self.system_update_sizes().await;
+                    for row in &updates.system {
+                        if let Some(size) = row
+                            .split_whitespace()
+                            .next()
+                            .and_then(|name| sizes.get(name))
+                        {
+                            updates.sizes.insert(row.clone(), *size);
+                        }
+                    }
+                }
+                spinner.finish_and_clear();
+            }
+
+            // Check flatpak updates
+            if self.installroot.is_none() && self.runner.exists("flatpak") {
+                let spinner = self.create_spinner("Checking Flatpak updates...");
+                let skip = &self.config.flatpak.skip;
+                for installation in self.flatpak_installations().await {
+                    match self.pending_flatpaks(&installation).await {
+                        Ok(pending) => {
+                            for update in pending.iter().filter(|u| !skip.contains(&u.app)) {
+                                let list = if update.runtime {
+                                    &mut updates.flatpak_runtimes
+                                } else {
+                                    &mut updates.flatpak
+                                };
+                                list.push(update.row());
+                                let size = ItemSize {
+                                    download: update.download_bytes,
+                                    installed: update.installed_bytes,
+                                };
+                                if size != ItemSize::default() {
+                                    updates.sizes.insert(update.row(), size);
+                                }
+                            }
+                        }
+                        Err(e) => debug!(
+                            "Cannot list {} Flatpak updates: {}",
+                            installation.label(),
+                            e
+                        ),
+                    }
+                }
+                spinner.finish_and_clear();
+            }
+
+            // Check firmware updates
+            if self.installroot.is_none() && self.runner.exists("fwupdmgr") {
+                let spinner = self.create_spinner("Checking firmware updates...");
+                let _ = self
+                    .run_command_silent(Stage::Firmware, "fwupdmgr", &["refresh", "--force"])
+                    .await;
+                updates.firmware = self.pending_firmware().await;
+                if !updates.firmware.is_empty() {
+                    let releases = self
+                        .run_command_silent(Stage::Firmware, "fwupdmgr", &["get-updates", "--json"])
+                        .await
+                        .unwrap_or_default();
+                    if let Ok(pending) =
+                        serde_json::from_str::<FirmwareUpdates>(&releases.join("\n"))
+                    {
+                        for device in &pending.devices {
+                            let download = device.releases.first().map_or(0, |r| r.size);
+                            if let Some(row) = updates
+                                .firmware
+                                .iter()
+                                .find(|row| row.contains(&device.name))
+                                && download > 0
+                            {
+                                updates.sizes.insert(
+                                    row.clone(),
+                                    ItemSize {
+                                        download,
+                                        installed: 0,
+                                    },
+                                );
+                            }
+                        }
+                    }
+                }
+                spinner.finish_and_clear();
+            }
+
+            Ok(updates)
+        }
+
+        /// Download and installed size of each pending system update, keyed
+        /// by "name.arch" as in the check-upgrade rows.
+        async fn system_update_sizes(&self) -> HashMap<String, ItemSize> {
+            let lines = self
+                .run_command_silent(
+                    Stage::System,
+                    "dnf5",
+                    &self.dnf_args(&[
+                        "repoquery",
+                        "--upgrades",
+                        "--latest-limit=1",
+                        "--queryformat",
+                        "%{name}.%{arch} %{downloadsize} %{installsize}\n",
+                    ]),
+                )
+                .await
+                .unwrap_or_default();
+            lines
+                .iter()
+                .filter_map(|line| {
+                    let mut cols = line.split_whitespace();
+                    let name = cols.next()?.to_string();
+                    let download = cols.next()?.parse().ok()?;
+                    let installed = cols.next()?.parse().ok()?;
+                    Some((
+                        name,
+                        ItemSize {
+                            download,
+                            installed,
+                        },
+                    ))
+                })
+                .collect()
+        }
+
+        /// Looks for traces of a dnf transaction that never finished: a last
+        /// history entry still "Started" or "Running", and packages left
+        /// installed twice. A transaction that failed cleanly ("Error") left
+        /// nothing half done.
+        pub async fn interrupted_transaction(&self) -> Option<InterruptedTransaction> {
+            if !self.runner.exists("dnf5") {
+                return None;
+            }
+            let mut found = InterruptedTransaction::default();
+
+            let history = self
+                .run_command_silent(
+                    Stage::System,
+                    "dnf5",
+                    &self.dnf_args(&["history", "info", "last", "--json"]),
+                )
+                .await
+                .unwrap_or_default()
+                .join("\n");
+            if let Ok(json) = serde_json::from_str::<serde_json::Value>(&history) {
+                let last = json.as_array().and_then(|a| a.first()).unwrap_or(&json);
+                let status = last.get("status").and_then(|s| s.as_str());
+                if let Some(status) = status.filter(|s| {
+                    s.eq_ignore_ascii_case("started") || s.eq_ignore_ascii_case("running")
+                }) {
+                    found.id = last
+                        .get("id")
+                        .map(|id| id.to_string().trim_matches('"').to_string());
+                    found.status = Some(status.to_string());
+                }
+            }
+
+            let check = self
+                .run_command_silent(
+                    Stage::System,
+                    "dnf5",
+                    &self.dnf_args(&["check", "--duplicates"]),
+                )
+                .await
+                .unwrap_or_default();
+            found.duplicates = duplicate_packages(&check);
+
+            (found != InterruptedTransaction::default()).then_some(found)
+        }
+
+        /// Completes or rolls back an interrupted transaction before the
+        /// update starts. Completing removes the superseded duplicates; the
+        /// update itself then brings the rest up to date.
+        pub async fn recover_transaction(
+            &self,
+            found: &InterruptedTransaction,
+            recovery: Recovery,
+        ) -> Result<()> {
+            match recovery {
+                Recovery::Complete if !found.duplicates.is_empty() => {
+                    info!(
+                        "Removing {} superseded duplicate package(s)",
+                        found.duplicates.len()
+                    );
+                    self.run_command(
+                        Stage::System,
+                        "dnf5",
+                        &self.dnf_args(&["remove", "--duplicates", "-y"]),
+                    )
+                    .await?;
+                }
+                Recovery::Rollback => {
+                    let Some(id) = &found.id else {
+                        return Err(UpdateError::Preflight(
+                            "dnf5 history has no unfinished transaction to roll back; \
+                             complete it instead (`dnf5 remove --duplicates`)"
+                                .into(),
+                        ));
+                    };
+                    info!("Rolling back dnf5 transaction {}", id);
+                    self.run_command(
+                        Stage::System,
+                        "dnf5",
+                        &self.dnf_args(&["history", "undo", id, "-y"]),
+                    )
+                    .await?;
+                }
+                Recovery::Ignore => warn!(
+                    "Updating despite the interrupted transaction ([system] interrupted_transaction = \"ignore\")"
+                ),
+                Recovery::Complete | Recovery::Ask => {}
+            }
+            Ok(())
+        }
+
+        /// Runs a `fwupdmgr` query that prints devices as JSON. fwupdmgr exits
+        /// with 2 when nothing applies, which is no devices rather than an error.
+        async fn firmware_query(&self, args: &[&str]) -> Result<FirmwareUpdates> {
+            let cmd = format!("fwupdmgr {}", args.join(" "));
+            let (status, mut lines, errors) = self
+                .run_command_captured(Stage::Firmware, "fwupdmgr", args)
+                .await?;
+            match status.code() {
+                Some(0) => serde_json::from_str(&lines.join("\n")).map_err(|e| {
+                    UpdateError::UnexpectedOutput {
+                        cmd,
+                        details: e.to_string(),
+                    }
+                }),
+                Some(2) => Ok(FirmwareUpdates {
+                    devices: Vec::new(),
+                }),
+                code => {
+                    lines.extend(errors);
+                    Err(UpdateError::from_output(
+                        cmd,
+                        code.unwrap_or(-1),
+                        lines.join("\n"),
+                    ))
+                }
+            }
+        }
+
+        /// The "old → new" lines of `fwupdmgr get-updates`.
+        async fn pending_firmware(&self) -> Vec<String> {
+            self.run_command_silent(Stage::Firmware, "fwupdmgr", &["get-updates", "-y"])
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|l| l.contains("→") || l.contains("New version"))
+                .collect()
+        }
+
+        /// Resolves what updating the given backends would change: the dnf5
+        /// transaction and what autoremove would take, pending Flatpaks and
+        /// unused runtimes, and firmware, all without applying anything.
+        pub async fn preview(&self, system: bool, flatpak: bool, firmware: bool) -> Preview {
+            let mut preview = Preview::default();
+
+            if system && self.runner.exists("dnf5") {
+                let spinner = self.create_spinner("Resolving system transaction...");
+                match self.daemon_resolve().await {
+                    Some(transaction) => {
+                        preview.download_bytes +=
+                            transaction.iter().map(|item| item.size).sum::<u64>();
+                        preview.transaction = transaction;
+                    }
+                    None => {
+                        let lines = self
+                            .run_command_silent(
+                                Stage::System,
+                                "dnf5",
+                                &self.update_args("--assumeno"),
+                            )
+                            .await
+                            .unwrap_or_default();
+                        preview.transaction = parse_transaction(&lines);
+                        preview.download_bytes += dnf_download_size(&lines);
+                    }
+                }
+                if self.only.is_empty() && self.auto_remove {
+                    let lines = self
+                        .run_command_silent(
+                            Stage::System,
+                            "dnf5",
+                            &self.dnf_args(&["autoremove", "--assumeno"]),
+                        )
+                        .await
+                        .unwrap_or_default();
+                    preview.autoremove = dnf_removals(&lines);
+                }
+                let removed = lost_packages(&preview.transaction)
+                    .into_iter()
+                    .map(|i| &i.name);
+                preview.protected = self
+                    .protected(removed.chain(&preview.autoremove))
+                    .into_iter()
+                    .map(String::from)
+                    .collect();
+                spinner.finish_and_clear();
+            }
+
+            if flatpak && self.installroot.is_none() && self.runner.exists("flatpak") {
+                let spinner = self.create_spinner("Resolving Flatpak updates...");
+                let skip = &self.config.flatpak.skip;
+                for installation in self.flatpak_installations().await {
+                    let pending = self
+                        .pending_flatpaks(&installation)
+                        .await
+                        .unwrap_or_default();
+                    preview.flatpak.extend(
+                        pending
+                            .iter()
+                            .filter(|u| !skip.contains(&u.app))
+                            .filter(|u| {
+                                self.flatpak_remotes.is_empty()
+                                    || self.flatpak_remotes.contains(&u.origin)
+                            })
+                            .filter(|u| {
+                                self.only.is_empty()
+                                    || self.only.iter().any(|r| r.split('/').any(|p| p == u.app))
+                            })
+                            .map(|u| (u.row(), u.download_bytes)),
+                    );
+                }
+                preview.download_bytes +=
+                    preview.flatpak.iter().map(|(_, bytes)| bytes).sum::<u64>();
+                if self.only.is_empty() && self.remove_unused {
+                    preview.flatpak_unused = crate::clean::unused_flatpak_refs().await;
+                }
+                spinner.finish_and_clear();
+            }
+
+            if firmware && self.installroot.is_none() && self.runner.exists("fwupdmgr") {
+                let spinner = self.create_spinner("Checking firmware updates...");
+                let lines = self
+                    .run_command_silent(Stage::Firmware, "fwupdmgr", &["get-updates", "--json"])
+                    .await
+                    .unwrap_or_default();
+                preview.firmware = match serde_json::from_str::<FirmwareUpdates>(&lines.join("\n"))
+                {
+                    Ok(updates) => updates
+                        .devices
+                        .iter()
+                        .map(|device| {
+                            let to = device.releases.first().map_or("?", |r| r.version.as_str());
+                            format!(
+                                "{}: {} {} {}{}",
+                                device.name,
+                                device.version.as_deref().unwrap_or("?"),
+                                crate::ui::sym("→"),
+                                to,
+                                self.firmware_hold(device)
+                            )
+                        })
+                        .collect(),
+                    Err(_) => self.pending_firmware().await,
+                };
+                for (device, branch) in self.branch_switches().await {
+                    preview.firmware.push(format!(
+                        "{}: switch to branch {}{}",
+                        device.name,
+                        branch,
+                        self.firmware_hold(&device)
+                    ));
+                }
+                spinner.finish_and_clear();
+            }
+
+            preview
+        }
+
+        /// Why the firmware stage would leave a device alone, as a suffix
+        /// for the dry-run preview.
+        fn firmware_hold(&self, device: &FirmwareDevice) -> String {
+            if self
+                .config
+                .firmware
+                .skip_devices
+                .iter()
+                .any(|p| device.matches(p))
+            {
+                " (skipped by [firmware] skip_devices)".into()
+            } else if !self.firmware_devices.is_empty()
+                && !self.firmware_devices.iter().any(|p| device.matches(p))
+            {
+                " (not selected)".into()
+            } else if !device.blockers().is_empty() {
+                format!(" (held back: {})", device.blockers().join("; "))
+            } else {
+                String::new()
+            }
+        }
+
+        /// Pending updates from third-party repositories to packages that
+        /// were installed from Fedora's own, e.g. a COPR shipping its own
+        /// glibc.
+        pub async fn repo_takeovers(&self, updates: &AvailableUpdates) -> Vec<RepoTakeover> {
+            let candidates: Vec<(&str, &str)> = updates
+                .system
+                .iter()
+                .filter_map(|l| Some((package_name(l), l.split_whitespace().nth(2)?)))
+                .filter(|(_, repo)| is_third_party(repo))
+                .collect();
+            if candidates.is_empty() {
+                return Vec::new();
+            }
+
+            let names = candidates.iter().map(|(name, _)| *name);
+            let mut args = vec!["repoquery", "--installed", "--json"];
+            args.extend(names.clone());
+            let json = self
+                .run_command_silent(Stage::System, "dnf5", &self.dnf_args(&args))
+                .await;
+            // Older dnf5 lists no from_repo in its JSON, so it takes the text query
+            let from_json: Option<HashMap<String, String>> = json
+                .ok()
+                .as_deref()
+                .and_then(json_packages)
+                .and_then(|packages| {
+                    packages
+                        .into_iter()
+                        .map(|p| Some((p.name, p.from_repo?)))
+                        .collect()
+                });
+            let installed = match from_json {
+                Some(installed) => installed,
+                None => {
+                    let mut args = vec![
+                        "repoquery",
+                        "--installed",
+                        "--queryformat",
+                        "%{name} %{from_repo}\n",
+                    ];
+                    args.extend(names);
+                    self.run_command_silent(Stage::System, "dnf5", &self.dnf_args(&args))
+                        .await
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|l| {
+                            let (name, repo) = l.trim().split_once(' ')?;
+                            Some((name.to_string(), repo.to_string()))
+                        })
+                        .collect()
+                }
+            };
+
+            candidates
+                .into_iter()
+                .filter_map(|(name, repo)| {
+                    let from = installed.get(name)?;
+                    (!is_third_party(from)).then(|| RepoTakeover {
+                        package: name.to_string(),
+                        repo: repo.to_string(),
+                        installed_from: from.clone(),
+                    })
+                })
+                .collect()
+        }
+
+        /// Collects what the pending updates change: dnf5 advisories and
+        /// package changelogs, and the commit log of each Flatpak ref.
+        pub async fn fetch_changelogs(&self, updates: &AvailableUpdates) -> String {
+            let mut sections = Vec::new();
+
+            if !updates.system.is_empty() {
+                let spinner = self.create_spinner("Fetching advisories and changelogs...");
+                if let Ok(lines) = self
+                    .run_command_silent(
+                        Stage::System,
+                        "dnf5",
+                        &self.dnf_args(&["advisory", "info", "--updates"]),
+                    )
+                    .await
+                    && !lines.is_empty()
+                {
+                    sections.push(("Advisories".to_string(), lines));
+                }
+
+                // check-upgrade rows look like "kernel.x86_64  6.9.1-200.fc40  updates"
+                let names: Vec<&str> = updates.system.iter().map(|l| package_name(l)).collect();
+                let mut args = vec!["changelog", "--upgrades"];
+                args.extend(names);
+                if let Ok(lines) = self
+                    .run_command_silent(Stage::System, "dnf5", &self.dnf_args(&args))
+                    .await
+                    && !lines.is_empty()
+                {
+                    sections.push(("Package changelogs".to_string(), lines));
+                }
+                spinner.finish_and_clear();
+            }
+
+            if !updates.flatpak.is_empty() {
+                let spinner = self.create_spinner("Fetching Flatpak release notes...");
+                let refs = self
+                    .run_command_silent(
+                        Stage::Flatpak,
+                        "flatpak",
+                        &["remote-ls", "--updates", "--columns=ref,origin"],
+                    )
+                    .await
+                    .unwrap_or_default();
+                for row in refs {
+                    let mut cols = row.split_whitespace();
+                    let (Some(flatpak_ref), Some(origin)) = (cols.next(), cols.next()) else {
+                        continue;
+                    };
+                    if let Ok(lines) = self
+                        .run_command_silent(
+                            Stage::Flatpak,
+                            "flatpak",
+                            &["remote-info", "--log", origin, flatpak_ref],
+                        )
+                        .await
+                        && !lines.is_empty()
+                    {
+                        sections.push((flatpak_ref.to_string(), lines));
+                    }
+                }
+                spinner.finish_and_clear();
+            }
+
+            sections
+                .into_iter()
+                .map(|(title, lines)| format!("=== {} ===\n\n{}\n", title, lines.join("\n")))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+
+        /// Runs one backend, recording its duration, package count and outcome
+        /// in the summary.
+        pub async fn run_stage(&self, stage: Stage) -> Result<()> {
+            let announce = crate::ui::accessible() && !self.quiet;
+            if announce {
+                crate::ui::announce(&format!(
+                    "Starting {} update.",
+                    stage.label().to_lowercase()
+                ));
+            }
+
+            if let Some(output) = &self.parallel {
+                output.start(stage);
+            }
+            crate::events::emit(crate::events::Event::PhaseStart { stage });
+
+            // Drop timings of checks that ran before the stage itself
+            self.running.lock().await.remove(&stage);
+            let started = Instant::now();
+            let span = tracing::info_span!(
+                target: crate::telemetry::TARGET,
+                "stage",
+                otel.name = %format!("{} update", stage.label()),
+                backend = stage.label(),
+                packages = tracing::field::Empty,
+                outcome = tracing::field::Empty,
+                otel.status_code = tracing::field::Empty,
+                otel.status_message = tracing::field::Empty,
+            );
+            let result = async {
+                match stage {
+                    Stage::System => self.update_system().await,
+                    Stage::Flatpak => self.update_flatpak().await,
+                    Stage::Firmware => self.update_firmware().await,
+                }
+            }
+            .instrument(span.clone())
+            .await;
+            if let Ok(packages) = &result {
+                span.record("packages", packages);
+            }
+            crate::telemetry::record_outcome(&span, &result);
+
+            if let Some(output) = &self.parallel {
+                output.finish(
+                    stage,
+                    &match &result {
+                        Ok(n) => {
+                            format!("{} done, {} package(s)", crate::ui::sym("✓").success(), n)
+                        }
+                        Err(UpdateError::Cancelled) => {
+                            format!("{} cancelled", crate::ui::sym("○").warning())
+                        }
+                        Err(_) => format!("{} failed", crate::ui::sym("✗").failure()),
+                    },
+                );
+            }
+
+            let cancelled = matches!(result, Err(UpdateError::Cancelled));
+            let stats = StageStats {
+                duration_secs: started.elapsed().as_secs_f64(),
+                packages: *result.as_ref().unwrap_or(&0),
+                failed: result.is_err() && !cancelled,
+                cancelled,
+                error: result
+                    .as_ref()
+                    .err()
+                    .filter(|_| !cancelled)
+                    .map(UpdateError::english),
+                hint: result.as_ref().err().and_then(UpdateError::hint),
+                ..self.running.lock().await.remove(&stage).unwrap_or_default()
+            };
+
+            crate::events::emit(crate::events::Event::PhaseEnd {
+                stage,
+                stats: &stats,
+                error: stats.error.as_deref(),
+            });
+
+            if announce {
+                crate::ui::announce(&match &result {
+                    Ok(_) => format!(
+                        "{} update finished: {} package(s) changed in {}.",
+                        stage.label(),
+                        stats.packages,
+                        crate::history::format_duration(stats.duration_secs)
+                    ),
+                    Err(UpdateError::Cancelled) => format!("{} update cancelled.", stage.label()),
+                    Err(e) => format!("{} update failed: {}", stage.label(), e),
+                });
+            }
+
+            info!(
+                backend = stage.label(),
+                packages = stats.packages,
+                downloaded_bytes = stats.downloaded_bytes,
+                duration_secs = stats.duration_secs,
+                failed = stats.failed,
+                cancelled = stats.cancelled,
+                "{} update finished",
+                stage.label()
+            );
+
+            self.collect_artifacts(stage).await;
+
+            let mut summary = self.summary.lock().await;
+            summary.stages.insert(stage, stats);
+            match &result {
+                Err(UpdateError::Cancelled) => summary.cancelled = true,
+                Err(e) => summary.errors.push(e.english()),
+                Ok(_) => {}
+            }
+
+            result.map(|_| ())
+        }
+
+        /// With `confirm_removals`, lists what autoremove or the unused-runtime
+        /// cleanup would take away and asks. Nothing to remove is a no, and so
+        /// is autoremove taking a `[system] protected` package unattended.
+        async fn removal_confirmed(&self, stage: Stage) -> bool {
+            let guarded =
+                stage == Stage::System && !self.config.system.protected.is_empty() && !self.dry_run;
+            if !self.confirm_removals && !guarded {
+                return true;
+            }
+            let (what, items) = match stage {
+                Stage::System => {
+                    let lines = self
+                        .run_command_silent(
+                            stage,
+                            "dnf5",
+                            &self.dnf_args(&["autoremove", "--assumeno"]),
+                        )
+                        .await
+                        .unwrap_or_default();
+                    ("unneeded package(s)", dnf_removals(&lines))
+                }
+                Stage::Flatpak => (
+                    "unused Flatpak runtime(s)",
+                    crate::clean::unused_flatpak_refs().await,
+                ),
+                Stage::Firmware => return true,
+            };
+            if items.is_empty() {
+                debug!("No {} to remove", what);
+                return false;
+            }
+            let protected = if stage == Stage::System {
+                self.protected(&items)
+            } else {
+                Vec::new()
+            };
+            if protected.is_empty() && !self.confirm_removals {
+                return true;
+            }
+            if !protected.is_empty() && !self.may_ask_protected() {
+                warn!(
+                    "Skipping autoremove: it would remove protected package(s) {} (see [system] protected)",
+                    protected.join(", ")
+                );
+                return false;
+            }
+            self.ask_removal(what, &items, &protected)
+        }
+
+        /// Names among `removed` that match `[system] protected`.
+        fn protected<'a>(&self, removed: impl IntoIterator<Item = &'a String>) -> Vec<&'a str> {
+            removed
+                .into_iter()
+                .filter(|name| {
+                    self.config
+                        .system
+                        .protected
+                        .iter()
+                        .any(|p| glob_match(p, name))
+                })
+                .map(String::as_str)
+                .collect()
+        }
+
+        /// Whether a protected removal may be put to the user rather than refused.
+        fn may_ask_protected(&self) -> bool {
+            self.config.system.protected_removal == ProtectedRemoval::Ask && self.interactive
+        }
+
+        /// Stops the update before it starts if the resolved transaction
+        /// removes or obsoletes a `[system] protected` package, unless
+        /// `protected_removal = "ask"` and it is confirmed at the terminal.
+        fn check_protected_removals(&self, transaction: &[TransactionItem]) -> Result<()> {
+            let removed = lost_packages(transaction);
+            let names: Vec<String> = removed.iter().map(|item| item.name.clone()).collect();
+            let protected = self.protected(&names);
+            if protected.is_empty()
+                || self.may_ask_protected()
+                    && self.ask_removal("package(s) the update would remove", &names, &protected)
+            {
+                return Ok(());
+            }
+            let reasons: Vec<String> = removed
+                .iter()
+                .filter(|item| protected.contains(&item.name.as_str()))
+                .map(|item| format!("{} {} ({})", item.name, item.version, item.removal_reason()))
+                .collect();
+            Err(UpdateError::Preflight(format!(
+                "the system update would remove protected package(s): {}; nothing was changed (see [system] protected)",
+                reasons.join(", ")
+            )))
+        }
+
+        /// Adds what a finished transaction removed or obsoleted to the summary.
+        async fn record_removals(&self, transaction: Vec<TransactionItem>) {
+            let removed = transaction.into_iter().filter(TransactionItem::is_removal);
+            self.summary.lock().await.removed.extend(removed);
+        }
+
+        /// Lists packages about to go, marking the protected ones, and asks
+        /// whether to remove them.
+        fn ask_removal(&self, what: &str, items: &[String], protected: &[&str]) -> bool {
+            crate::render::suspend(|| {
+                use std::io::Write;
+
+                println!("\n{} {}:", items.len(), what);
+                for item in items {
+                    if protected.contains(&item.as_str()) {
+                        println!("  {} {} {}", "-".failure(), item, "(protected)".warning());
+                    } else {
+                        println!("  {} {}", "-".failure(), item);
+                    }
+                }
+                print!("Remove them? [y/N]: ");
+                let _ = std::io::stdout().flush();
+                let mut input = String::new();
+                let _ = std::io::stdin().read_line(&mut input);
+                let yes = matches!(input.trim().to_lowercase().as_str(), "y" | "yes");
+                if !yes {
+                    println!("{}", "Keeping them.".warning());
+                }
+                yes
+            })
+        }
+
+        /// Waits for other package managers to release the RPM lock, up to
+        /// `[system] lock_timeout_secs`.
+        async fn wait_for_package_lock(&self, spinner: &ProgressBar) -> Result<()> {
+            // The lock probes only know about the running system's rpmdb
+            if self.dry_run || self.installroot.is_some() {
+                return Ok(());
+            }
+
+            let timeout = Duration::from_secs(self.config.system.lock_timeout_secs);
+            let started = Instant::now();
+            let mut waited_any = false;
+
+            while let Some(holder) = crate::system::package_lock_holder().await {
+                let waited = started.elapsed();
+                if waited >= timeout {
+                    return Err(UpdateError::LockHeld(format!(
+                        "{} (gave up after {}s; close GNOME Software or wait for the other update to finish)",
+                        holder,
+                        waited.as_secs()
+                    )));
+                }
+
+                let msg = format!(
+                    "Waiting for package manager lock: {} ({}s)...",
+                    holder,
+                    waited.as_secs()
+                );
+                if waited_any {
+                    spinner.set_message(msg);
+                } else {
+                    warn!("Waiting for package manager lock: {}", holder);
+                    self.set_step(spinner, &msg);
+                    waited_any = true;
+                }
+                tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+            }
+
+            if waited_any {
+                self.set_step(spinner, "Updating system packages...");
+            }
+            Ok(())
+        }
+
+        /// Downloads what this host has pending into `dir`, for `fup
+        /// import-bundle` on a host without internet access: the packages
+        /// of the dnf5 update, the system Flatpak updates as a sideload
+        /// repository, and firmware cabinets from the LVFS.
+        pub async fn export_bundle(
+            &self,
+            dir: &Path,
+            system: bool,
+            flatpak: bool,
+            firmware: bool,
+        ) -> Result<Manifest> {
+            let mut manifest = Manifest::for_this_host();
+
+            if system && self.runner.exists("dnf5") {
+                let spinner = self.create_spinner("Downloading system packages...");
+                let rpms = dir.join(crate::bundle::RPMS);
+                let destdir = format!("--destdir={}", rpms.display());
+                let mut args = self.update_args("-y");
+                args.extend(["--downloadonly", destdir.as_str()]);
+                self.run_command_silent(Stage::System, "dnf5", &args)
+                    .await?;
+                manifest.packages = crate::bundle::file_names(&rpms, ".rpm");
+                spinner.finish_and_clear();
+            }
+
+            if flatpak && self.installroot.is_none() && self.runner.exists("flatpak") {
+                let spinner = self.create_spinner("Downloading Flatpak updates...");
+                let skip = &self.config.flatpak.skip;
+                let pending = self.pending_flatpaks(&Installation::System).await?;
+                let apps: Vec<&str> = pending
+                    .iter()
+                    .filter(|u| !skip.contains(&u.app))
+                    .filter(|u| {
+                        self.flatpak_remotes.is_empty() || self.flatpak_remotes.contains(&u.origin)
+                    })
+                    .filter(|u| self.only.is_empty() || self.only.contains(&u.app))
+                    .map(|u| u.app.as_str())
+                    .collect();
+                if !apps.is_empty() {
+                    // Pulled into the local repository without deploying, then
+                    // copied out with the remotes' collection IDs for sideloading
+                    let mut pull = vec![
+                        "update",
+                        "--system",
+                        "--no-deploy",
+                        "--noninteractive",
+                        "-y",
+                    ];
+                    pull.extend(&apps);
+                    self.run_command_silent(Stage::Flatpak, "flatpak", &pull)
+                        .await?;
+                    let target = dir.join(crate::bundle::FLATPAK);
+                    std::fs::create_dir_all(&target)?;
+                    let target = target.display().to_string();
+                    let mut export =
+                        vec!["create-usb", "--system", "--allow-partial", target.as_str()];
+                    export.extend(&apps);
+                    self.run_command_silent(Stage::Flatpak, "flatpak", &export)
+                        .await?;
+                    manifest.flatpaks = apps.iter().map(|app| app.to_string()).collect();
+                }
+                spinner.finish_and_clear();
+            }
+
+            if firmware && self.installroot.is_none() && self.runner.exists("fwupdmgr") {
+                let spinner = self.create_spinner("Downloading firmware...");
+                let updates = match self.firmware_query(&["get-updates", "--json"]).await {
+                    Ok(updates) => Some(updates),
+                    Err(UpdateError::UnexpectedOutput { .. }) => None,
+                    Err(e) => return Err(e),
+                };
+                let cabinets = dir.join(crate::bundle::FIRMWARE);
+                for device in updates.iter().flat_map(|u| &u.devices) {
+                    if !self.firmware_devices.is_empty()
+                        && !self.firmware_devices.iter().any(|p| device.matches(p))
+                    {
+                        continue;
+                    }
+                    if self
+                        .config
+                        .firmware
+                        .skip_devices
+                        .iter()
+                        .any(|p| device.matches(p))
+                    {
+                        info!(
+                            "Leaving the {} firmware out of the bundle ([firmware] skip_devices)",
+                            device.name
+                        );
+                        continue;
+                    }
+                    let Some(url) = device.releases.first().and_then(FirmwareRelease::location)
+                    else {
+                        warn!(
+                            "No download location for the {} firmware; leaving it out",
+                            device.name
+                        );
+                        continue;
+                    };
+                    self.set_step(
+                        &spinner,
+                        &format!("Downloading firmware for {}...", device.name),
+                    );
+                    manifest
+                        .firmware
+                        .push(crate::bundle::download(url, &cabinets).await?);
+                }
+                spinner.finish_and_clear();
+            }
+
+            Ok(manifest)
+        }
+
+        /// Installs a bundle `export_bundle` made, from the files alone: dnf5
+        /// with every repository disabled (package signatures are still
+        /// checked), Flatpak from the sideload repository and fwupd from the
+        /// cabinets. Returns the number of packages, Flatpaks and devices.
+        pub async fn import_bundle(&self, dir: &Path, manifest: &Manifest) -> Result<usize> {
+            let mut count = 0;
+
+            if !manifest.packages.is_empty() {
+                if !self.runner.exists("dnf5") {
+                    return Err(UpdateError::CommandNotFound("dnf5".into()));
+                }
+                let spinner = self.create_spinner("Installing system packages from the bundle...");
+                self.wait_for_package_lock(&spinner).await?;
+                spinner.finish_and_clear();
+                let rpms = dir.join(crate::bundle::RPMS);
+                let files: Vec<String> = manifest
+                    .packages
+                    .iter()
+                    .map(|f| rpms.join(f).display().to_string())
+                    .collect();
+                let mut args = self.dnf_args(&[
+                    "install",
+                    "-y",
+                    "--disablerepo=*",
+                    "--setopt=localpkg_gpgcheck=1",
+                ]);
+                args.extend(files.iter().map(String::as_str));
+                let lines = self
+                    .run_command_kept(Stage::System, "dnf5", &args, "dnf5-bundle.log")
+                    .await?;
+                count += count_dnf_packages(&lines);
+                self.record_removals(parse_transaction(&lines)).await;
+                self.summary.lock().await.system_updated = true;
+            }
+
+            if !manifest.flatpaks.is_empty() {
+                let repo = format!(
+                    "--sideload-repo={}",
+                    dir.join(crate::bundle::FLATPAK)
+                        .join(".ostree/repo")
+                        .display()
+                );
+                let mut args = vec![
+                    "update",
+                    "--system",
+                    "--noninteractive",
+                    "-y",
+                    repo.as_str(),
+                ];
+                args.extend(manifest.flatpaks.iter().map(String::as_str));
+                self.run_command(Stage::Flatpak, "flatpak", &args).await?;
+                count += manifest.flatpaks.len();
+                self.summary.lock().await.flatpak_updated = true;
+            }
+
+            if !manifest.firmware.is_empty() {
+                if !self.runner.exists("fwupdmgr") {
+                    return Err(UpdateError::CommandNotFound("fwupdmgr".into()));
+                }
+                // Each cabinet goes to the devices here it has firmware for,
+                // past the same skip list, safety checks and approval as
+                // firmware from LVFS
+                let spinner = self.create_spinner("Checking the bundled firmware...");
+                let mut plan = Vec::new();
+                for cabinet in &manifest.firmware {
+                    let file = dir
+                        .join(crate::bundle::FIRMWARE)
+                        .join(cabinet)
+                        .display()
+                        .to_string();
+                    let devices = match self.firmware_query(&["get-details", &file, "--json"]).await
+                    {
+                        Ok(updates) => updates.devices,
+                        Err(e) => {
+                            spinner.finish_and_clear();
+                            return Err(e);
+                        }
+                    };
+                    if devices.is_empty() {
+                        info!(
+                            "No device here takes the firmware in {}; leaving it alone",
+                            cabinet
+                        );
+                    }
+                    plan.extend(
+                        devices
+                            .into_iter()
+                            .map(|device| (device, FirmwareAction::Install(file.clone()))),
+                    );
+                }
+                let result = self.update_firmware_devices(&spinner, plan).await;
+                spinner.finish_and_clear();
+                count += result?;
+            }
+
+            Ok(count)
+        }
+
+        pub async fn update_system(&self) -> Result<usize> {
+            if !self.runner.exists("dnf5") {
+                return Err(UpdateError::CommandNotFound("dnf5".into()));
+            }
+
+            let spinner = self.create_spinner("Updating system packages...");
+
+            self.wait_for_package_lock(&spinner).await?;
+
+            let mut packages = match self.daemon_update(&spinner).await {
+                Some(updated) => updated?,
+                None => {
+                    if !self.config.system.protected.is_empty() && !self.dry_run {
+                        let lines = self
+                            .run_command_silent(
+                                Stage::System,
+                                "dnf5",
+                                &self.update_args("--assumeno"),
+                            )
+                            .await
+                            .unwrap_or_default();
+                        self.check_protected_removals(&parse_transaction(&lines))?;
+                    }
+                    let update_args = self.update_args("-y");
+                    let lines = crate::retry::with_backoff(
+                        &self.config.retry,
+                        &self.shutdown,
+                        "dnf5 update",
+                        crate::retry::is_transient_failure,
+                        || {
+                            self.run_command_kept(
+                                Stage::System,
+                                "dnf5",
+                                &update_args,
+                                "dnf5-update.log",
+                            )
+                        },
+                    )
+                    .await?;
+                    self.record_download(Stage::System, dnf_download_size(&lines))
+                        .await;
+                    self.record_removals(parse_transaction(&lines)).await;
+                    count_dnf_packages(&lines)
+                }
+            };
+
+            // A targeted update shouldn't also prune unrelated packages
+            if self.only.is_empty()
+                && self.auto_remove
+                && self.removal_confirmed(Stage::System).await
+            {
+                self.set_step(&spinner, "Removing unused packages...");
+                let lines = self
+                    .run_command_kept(
+                        Stage::System,
+                        "dnf5",
+                        &self.dnf_args(&["autoremove", "-y"]),
+                        "dnf5-autoremove.log",
+                    )
+                    .await?;
+                packages += count_dnf_packages(&lines);
+                let mut removed = parse_transaction(&lines);
+                for item in &mut removed {
+                    item.action = "Removing unused dependencies".into();
+                }
+                self.record_removals(removed).await;
+            }
+
+            if self.config.kernel.keep > 0 && (self.only.is_empty() || self.kernel_only) {
+                self.set_step(&spinner, "Removing old kernels...");
+                self.cleanup_old_kernels().await?;
+            }
+
+            spinner.finish_with_message(
+                format!("System update complete {}", crate::ui::sym("✓"))
+                    .success()
+                    .to_string(),
+            );
+            self.summary.lock().await.system_updated = true;
+            Ok(packages)
+        }
+
+        /// Opens a dnf5daemon session for `[system] backend = "daemon"`, or
+        /// returns None to use the dnf5 CLI: for the default backend, for
+        /// security-only updates, which the daemon can't filter, and when the
+        /// daemon doesn't answer.
+        #[cfg(feature = "dnf5daemon")]
+        async fn daemon_session(&self) -> Option<crate::dnf5daemon::Session> {
+            if self.config.system.backend != DnfBackend::Daemon {
+                return None;
+            }
+            if self.security_filter.is_some() {
+                debug!("dnf5daemon can't filter by advisory; using the dnf5 CLI");
+                return None;
+            }
+            if !self.config.mirror.url.is_empty() {
+                debug!("dnf5daemon can't add the [mirror] repositories; using the dnf5 CLI");
+                return None;
+            }
+            // The same settings the CLI gets as options
+            let mut settings = Vec::new();
+            let mut excludes = Vec::new();
+            for opt in self.installroot.iter().chain(&self.dnf_opts) {
+                if let Some(root) = opt.strip_prefix("--installroot=") {
+                    settings.push(("installroot", root.to_string()));
+                } else if let Some(rate) = opt.strip_prefix("--setopt=throttle=") {
+                    settings.push(("throttle", rate.to_string()));
+                } else if let Some(globs) = opt.strip_prefix("--exclude=") {
+                    excludes.push(globs);
+                }
+            }
+            if !excludes.is_empty() {
+                settings.push(("excludepkgs", excludes.join(",")));
+            }
+            match crate::dnf5daemon::Session::open(&settings, self.config.system.refresh).await {
+                Ok(session) => Some(session),
+                Err(e) => {
+                    warn!("dnf5daemon unavailable ({}); using the dnf5 CLI", e);
+                    None
+                }
+            }
+        }
+
+        /// Resolves the system update through dnf5daemon, without applying
+        /// it. None means the CLI has to do it.
+        #[cfg(feature = "dnf5daemon")]
+        async fn daemon_resolve(&self) -> Option<Vec<TransactionItem>> {
+            let session = self.daemon_session().await?;
+            let resolved = session.resolve_upgrade(&self.only).await;
+            session.close().await;
+            resolved
+                .inspect_err(|e| warn!("dnf5daemon couldn't resolve the update: {}", e))
+                .ok()
+        }
+
+        #[cfg(not(feature = "dnf5daemon"))]
+        async fn daemon_resolve(&self) -> Option<Vec<TransactionItem>> {
+            None
+        }
+
+        /// Runs the system update through dnf5daemon, naming each package on
+        /// the spinner as the daemon gets to it. None means the CLI has to
+        /// do it.
+        #[cfg(feature = "dnf5daemon")]
+        async fn daemon_update(&self, spinner: &ProgressBar) -> Option<Result<usize>> {
+            let session = self.daemon_session().await?;
+            info!(
+                backend = Stage::System.label(),
+                "Updating through dnf5daemon"
+            );
+            let failed = |e: zbus::Error| UpdateError::CommandFailed {
+                cmd: "dnf5daemon upgrade".into(),
+                code: 1,
+                details: e.to_string(),
+            };
+            let result = async {
+                let transaction = session.resolve_upgrade(&self.only).await.map_err(failed)?;
+                self.check_protected_removals(&transaction)?;
+                if !transaction.is_empty() {
+                    session
+                        .apply(|nevra| {
+                            self.set_step(spinner, &format!("Updating system packages: {}", nevra))
+                        })
+                        .await
+                        .map_err(failed)?;
+                }
+                Ok(transaction)
+            }
+            .await;
+            session.close().await;
+
+            Some(match result {
+                Ok(transaction) => {
+                    if let Some(store) = &self.artifacts {
+                        let rows: Vec<String> = transaction
+                            .iter()
+                            .map(|item| {
+                                format!(
+                                    "{} {} {} {}",
+                                    item.action, item.name, item.version, item.repo
+                                )
+                            })
+                            .collect();
+                        store.write("dnf5-update.log", &rows.join("\n"));
+                    }
+                    self.record_download(
+                        Stage::System,
+                        transaction.iter().map(|item| item.size).sum(),
+                    )
+                    .await;
+                    let count = transaction.len();
+                    self.record_removals(transaction).await;
+                    Ok(count)
+                }
+                Err(e) => Err(e),
+            })
+        }
+
+        #[cfg(not(feature = "dnf5daemon"))]
+        async fn daemon_update(&self, _spinner: &ProgressBar) -> Option<Result<usize>> {
+            None
+        }
+
+        /// Removes installed kernels beyond the newest `[kernel] keep`. The
+        /// running kernel is never removed, whatever its age.
+        async fn cleanup_old_kernels(&self) -> Result<()> {
+            if self.installroot.is_some() {
+                debug!("Skipping kernel cleanup for --target");
+                return Ok(());
+            }
+
+            let installed = self
+                .run_command_silent(
+                    Stage::System,
+                    "rpm",
+                    &[
+                        "-q",
+                        "kernel-core",
+                        "--qf",
+                        "%{INSTALLTIME} %{VERSION}-%{RELEASE}.%{ARCH}\n",
+                    ],
+                )
+                .await?;
+            let running = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+            let old = old_kernels(&installed, running.trim(), self.config.kernel.keep);
+            if old.is_empty() {
+                debug!("No old kernels to remove");
+                return Ok(());
+            }
+
+            info!("Removing old kernels: {}", old.join(", "));
+            // Only the kernels themselves: `[system] exclude` would make dnf5
+            // refuse to remove a kernel it matches
+            let packages: Vec<String> = old.iter().map(|v| format!("kernel-core-{}", v)).collect();
+            let mut args = vec!["remove", "-y"];
+            args.extend(packages.iter().map(String::as_str));
+            self.run_command_kept(Stage::System, "dnf5", &args, "dnf5-kernel-cleanup.log")
+                .await?;
+            Ok(())
+        }
+
+        pub async fn update_flatpak(&self) -> Result<usize> {
+            if !self.runner.exists("flatpak") {
+                info!("Flatpak not installed, skipping");
+                return Ok(0);
+            }
+
+            let spinner = self.create_spinner("Updating Flatpak applications...");
+
+            // flatpak has no exclude option, so with a skip list, remote
+            // selection or refs from the command line the wanted refs are
+            // named explicitly.
+            let skip = &self.config.flatpak.skip;
+            let filtered =
+                !self.only.is_empty() || !self.flatpak_remotes.is_empty() || !skip.is_empty();
+            let mut lines = Vec::new();
+            let mut failed_users = Vec::new();
+            for installation in self.flatpak_installations().await {
+                let result = self
+                    .update_flatpak_installation(&installation, filtered)
+                    .await;
+                // With all_users one user's broken installation doesn't hold up the others
+                match &installation {
+                    Installation::User(Some(user)) if self.config.flatpak.all_users => {
+                        let (refs, error) = match result {
+                            Ok(user_lines) => {
+                                let refs = count_flatpak_refs(&user_lines);
+                                lines.extend(user_lines);
+                                (refs, None)
+                            }
+                            Err(e) => {
+                                warn!("Flatpak update for {} failed: {}", user, e);
+                                failed_users.push(user.clone());
+                                (0, Some(e.to_string()))
+                            }
+                        };
+                        self.summary
+                            .lock()
+                            .await
+                            .flatpak_users
+                            .push(FlatpakUserOutcome {
+                                user: user.clone(),
+                                refs,
+                                error,
+                            });
+                    }
+                    _ => lines.extend(result?),
+                }
+            }
+            if filtered && lines.is_empty() {
+                info!(
+                    "No pending Flatpak updates left after [flatpak] skip and the selected refs/remotes"
+                );
+            }
+
+            let packages = count_flatpak_refs(&lines);
+            self.record_download(Stage::Flatpak, flatpak_download_size(&lines))
+                .await;
+
+            if self.only.is_empty()
+                && self.flatpak_remotes.is_empty()
+                && self.remove_unused
+                && self.removal_confirmed(Stage::Flatpak).await
+            {
+                self.set_step(&spinner, "Removing unused Flatpak runtimes...");
+                self.run_command(Stage::Flatpak, "flatpak", &["uninstall", "--unused", "-y"])
+                    .await?;
+            }
+
+            let eol = self.flatpak_eol().await;
+            for flatpak in &eol {
+                warn!(
+                    "{} is end-of-life: {} ({})",
+                    flatpak.name,
+                    flatpak.reason,
+                    flatpak.suggestion()
+                );
+            }
+
+            spinner.finish_with_message(
+                format!("Flatpak update complete {}", crate::ui::sym("✓"))
+                    .success()
+                    .to_string(),
+            );
+            let mut summary = self.summary.lock().await;
+            summary.flatpak_updated = true;
+            summary.flatpak_eol = eol;
+            drop(summary);
+            if failed_users.is_empty() {
+                Ok(packages)
+            } else {
+                Err(UpdateError::CommandFailed {
+                    cmd: "flatpak update --user".into(),
+                    code: 1,
+                    details: format!("Flatpaks not updated for {}", failed_users.join(", ")),
+                })
+            }
+        }
+
+        /// Installed Flatpaks that are end-of-life on their remote, or whose
+        /// runtime is.
+        pub async fn flatpak_eol(&self) -> Vec<EolFlatpak> {
+            if self.installroot.is_some() || !self.runner.exists("flatpak") {
+                return Vec::new();
+            }
+            let mut found = Vec::new();
+            for installation in self.flatpak_installations().await {
+                let (cmd, args) = self.flatpak_command(
+                    &installation,
+                    &["remote-ls", installation.flag(), "--columns=ref,options"],
+                );
+                let Ok(remote) = self.run_command_silent(Stage::Flatpak, cmd, &args).await else {
+                    continue;
+                };
+                // Columns are tab-separated when not on a terminal; reasons contain spaces
+                let eol: HashMap<&str, (String, Option<String>)> = remote
+                    .iter()
+                    .filter_map(|line| {
+                        let (name, options) = line.split_once('\t')?;
+                        Some((name.trim(), parse_eol(options)?))
+                    })
+                    .collect();
+                if eol.is_empty() {
+                    continue;
+                }
+
+                let (cmd, args) = self.flatpak_command(
+                    &installation,
+                    &["list", installation.flag(), "--columns=ref,runtime"],
+                );
+                let Ok(installed) = self.run_command_silent(Stage::Flatpak, cmd, &args).await
+                else {
+                    continue;
+                };
+                for line in installed {
+                    let mut cols = line.split('\t').map(str::trim);
+                    let Some(name) = cols.next().filter(|n| !n.is_empty()) else {
+                        continue;
+                    };
+                    let runtime = cols.next().filter(|r| !r.is_empty());
+                    let (runtime, (reason, replacement)) =
+                        match (eol.get(name), runtime.and_then(|r| Some((r, eol.get(r)?)))) {
+                            (Some(own), _) => (None, own.clone()),
+                            (None, Some((runtime, its))) => {
+                                (Some(runtime.to_string()), its.clone())
+                            }
+                            (None, None) => continue,
+                        };
+                    found.push(EolFlatpak {
+                        name: name.to_string(),
+                        installation: installation.clone(),
+                        runtime,
+                        reason,
+                        replacement,
+                    });
+                }
+            }
+            found
+        }
+
+        /// Installations to check and update, system-wide first.
+        async fn flatpak_installations(&self) -> Vec<Installation> {
+            let mut all = vec![Installation::System];
+            if self.config.flatpak.all_users {
+                all.extend(
+                    crate::system::flatpak_users()
+                        .await
+                        .into_iter()
+                        .map(|u| Installation::User(Some(u))),
+                );
+            } else {
+                all.push(Installation::User(self.flatpak_user.clone()));
+            }
+            all
+        }
+
+        /// Updates one installation, naming the wanted refs when `filtered`.
+        async fn update_flatpak_installation(
+            &self,
+            installation: &Installation,
+            filtered: bool,
+        ) -> Result<Vec<String>> {
+            let skip = &self.config.flatpak.skip;
+            let concurrent = self.config.flatpak.jobs > 1;
+            let mut update_args = vec!["update", installation.flag(), "-y"];
+            let pending;
+            if filtered || concurrent {
+                pending = match self.pending_flatpaks(installation).await {
+                    Ok(pending) => pending,
+                    // The caller's installation failing to list mustn't cost the
+                    // system one its update; all_users reports failures per user
+                    Err(e)
+                        if matches!(installation, Installation::User(_))
+                            && !self.config.flatpak.all_users =>
+                    {
+                        warn!(
+                            "Skipping the {} Flatpak installation, its updates can't be listed: {}",
+                            installation.label(),
+                            e
+                        );
+                        return Ok(Vec::new());
+                    }
+                    Err(e) => return Err(e),
+                };
+                let selected: Vec<&FlatpakUpdate> = pending
+                    .iter()
+                    .filter(|u| !skip.contains(&u.app))
+                    .filter(|u| {
+                        self.flatpak_remotes.is_empty() || self.flatpak_remotes.contains(&u.origin)
+                    })
+                    .filter(|u| {
+                        self.only.is_empty()
+                            || self.only.iter().any(|r| r.split('/').any(|p| p == u.app))
+                    })
+                    .collect();
+                if selected.is_empty() {
+                    debug!("No {} Flatpak updates selected", installation.label());
+                    return Ok(Vec::new());
+                }
+                if concurrent {
+                    return self
+                        .update_flatpaks_concurrently(installation, &selected)
+                        .await;
+                }
+                update_args.extend(selected.iter().map(|u| u.app.as_str()));
+            }
+            let (cmd, args) = self.flatpak_command(installation, &update_args);
+            crate::retry::with_backoff(
+                &self.config.retry,
+                &self.shutdown,
+                "Flatpak update",
+                crate::retry::is_transient_failure,
+                || self.run_command(Stage::Flatpak, cmd, &args),
+            )
+            .await
+        }
+
+        /// Updates the runtimes among `selected` first, then the apps side
+        /// by side, up to `[flatpak] jobs` at a time: apps don't depend on one
+        /// another, but a single flatpak call works through them one by one.
+        /// A failed app doesn't stop the others.
+        async fn update_flatpaks_concurrently(
+            &self,
+            installation: &Installation,
+            selected: &[&FlatpakUpdate],
+        ) -> Result<Vec<String>> {
+            let (runtimes, apps): (Vec<&FlatpakUpdate>, Vec<&FlatpakUpdate>) =
+                selected.iter().partition(|u| u.runtime);
+            let runtimes: Vec<&str> = runtimes.iter().map(|u| u.app.as_str()).collect();
+            let apps: Vec<&str> = apps.iter().map(|u| u.app.as_str()).collect();
+
+            let mut lines = Vec::new();
+            if !runtimes.is_empty() {
+                lines = self
+                    .update_flatpak_refs(installation, &runtimes, None)
+                    .await?;
+            }
+
+            let slots = &tokio::sync::Semaphore::new(self.config.flatpak.jobs);
+            let done = &std::sync::atomic::AtomicUsize::new(0);
+            let total = apps.len();
+            let results = futures_util::future::join_all(apps.iter().map(|&app| async move {
+                let _slot = slots.acquire().await;
+                if self.shutdown.borrow().is_some() {
+                    return Err(UpdateError::Cancelled);
+                }
+                let pane = self.flatpak_pane(app);
+                let result = self
+                    .update_flatpak_refs(installation, &[app], Some(pane.clone()))
+                    .await;
+                let n = done.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+                pane.set_style(
+                    ProgressStyle::with_template("  {prefix:.bold} {wide_msg}").unwrap(),
+                );
+                match &result {
+                    Ok(_) => {
+                        info!(backend = "Flatpak", "Updated {} ({}/{})", app, n, total);
+                        pane.finish_with_message(
+                            format!("{} updated", crate::ui::sym("✓"))
+                                .success()
+                                .to_string(),
+                        );
+                    }
+                    Err(e) => {
+                        warn!("Flatpak update of {} failed: {}", app, e);
+                        pane.finish_with_message(
+                            format!("{} failed", crate::ui::sym("✗"))
+                                .failure()
+                                .to_string(),
+                        );
+                    }
+                }
+                result
+            }))
+            .await;
+
+            let mut failed = None;
+            for result in results {
+                match result {
+                    Ok(app_lines) => lines.extend(app_lines),
+                    Err(e) => {
+                        failed.get_or_insert(e);
+                    }
+                }
+            }
+            match failed {
+                Some(e) => Err(e),
+                None => Ok(lines),
+            }
+        }
+
+        /// `flatpak update` of the given refs, retried on transient failures.
+        async fn update_flatpak_refs(
+            &self,
+            installation: &Installation,
+            refs: &[&str],
+            pane: Option<ProgressBar>,
+        ) -> Result<Vec<String>> {
+            let mut update_args = vec!["update", installation.flag(), "-y"];
+            update_args.extend_from_slice(refs);
+            let (cmd, args) = self.flatpak_command(installation, &update_args);
+            crate::retry::with_backoff(
+                &self.config.retry,
+                &self.shutdown,
+                "Flatpak update",
+                crate::retry::is_transient_failure,
+                || self.run_command_in(Stage::Flatpak, cmd, &args, pane.clone()),
+            )
+            .await
+        }
+
+        /// A line showing the progress of one app's update.
+        fn flatpak_pane(&self, app: &str) -> ProgressBar {
+            if self.quiet || crate::ui::accessible() {
+                return ProgressBar::hidden();
+            }
+            let pane = crate::render::add(ProgressBar::new_spinner());
+            pane.set_style(
+                ProgressStyle::default_spinner()
+                    .tick_chars(crate::ui::ticks())
+                    .template(&format!(
+                        "{{spinner:.{}}} {{prefix:.bold}} {{wide_msg}}",
+                        crate::ui::accent_style()
+                    ))
+                    .unwrap(),
+            );
+            pane.set_prefix(app.to_string());
+            pane.set_message("waiting...");
+            pane.enable_steady_tick(Duration::from_millis(80));
+            pane
+        }
+
+        /// Pending updates in one Flatpak installation, apps first, then
+        /// runtimes and extensions. A ref pending for several arches is one
+        /// update.
+        async fn pending_flatpaks(
+            &self,
+            installation: &Installation,
+        ) -> Result<Vec<FlatpakUpdate>> {
+            let mut pending: Vec<FlatpakUpdate> = Vec::new();
+            for (kind, runtime) in [("--app", false), ("--runtime", true)] {
+                let (cmd, args) = self.flatpak_command(
+                    installation,
+                    &[
+                        "remote-ls",
+                        installation.flag(),
+                        "--updates",
+                        kind,
+                        "--columns=ref,origin,download-size,installed-size",
+                    ],
+                );
+                let lines = self
+                    .run_command_checked(Stage::Flatpak, cmd, &args, &[])
+                    .await?;
+                for update in lines
+                    .iter()
+                    .filter_map(|line| parse_flatpak_update(line, installation, runtime))
+                {
+                    match pending
+                        .iter_mut()
+                        .find(|p| p.app == update.app && p.branch == update.branch)
+                    {
+                        Some(seen) if seen.runtime == update.runtime => {
+                            seen.download_bytes += update.download_bytes;
+                            seen.installed_bytes += update.installed_bytes;
+                        }
+                        Some(_) => {}
+                        None => pending.push(update),
+                    }
+                }
+            }
+            Ok(pending)
+        }
+
+        /// `flatpak <args>`, run as the owner of a per-user installation since
+        /// root's `--user` is a different one.
+        fn flatpak_command<'a>(
+            &self,
+            installation: &'a Installation,
+            args: &[&'a str],
+        ) -> (&'static str, Vec<&'a str>) {
+            match installation {
+                Installation::User(Some(user)) => {
+                    let mut all = vec!["-u", user.as_str(), "--", "flatpak"];
+                    all.extend_from_slice(args);
+                    ("runuser", all)
+                }
+                _ => ("flatpak", args.to_vec()),
+            }
+        }
+
+        pub async fn update_firmware(&self) -> Result<usize> {
+            if !self.runner.exists("fwupdmgr") {
+                info!("fwupdmgr not installed, skipping firmware updates");
+                return Ok(0);
+            }
+
+            let spinner = self.create_spinner("Checking for firmware updates...");
+
+            if let Err(e) = self.sync_lvfs_testing().await {
+                warn!("Could not set the {} remote: {}", LVFS_TESTING, e);
+            }
+
+            let _ = crate::retry::with_backoff(
+                &self.config.retry,
+                &self.shutdown,
+                "Firmware metadata refresh",
+                crate::retry::is_transient,
+                || self.run_command(Stage::Firmware, "fwupdmgr", &["refresh", "--force"]),
+            )
+            .await;
+
+            let devices = match self.firmware_query(&["get-updates", "--json"]).await {
+                Ok(updates) => Some(updates),
+                Err(e) => {
+                    debug!("fwupdmgr get-updates --json failed: {}", e);
+                    None
+                }
+            };
+            if let Some(updates) = devices {
+                let mut plan: Vec<(FirmwareDevice, FirmwareAction)> = updates
+                    .devices
+                    .into_iter()
+                    .map(|d| (d, FirmwareAction::Update))
+                    .collect();
+                for (device, branch) in self.branch_switches().await {
+                    plan.retain(|(d, _)| d.device_id != device.device_id);
+                    plan.push((device, FirmwareAction::SwitchBranch(branch)));
+                }
+                let result = self.update_firmware_devices(&spinner, plan).await;
+                match &result {
+                    Ok(0) => spinner
+                        .finish_with_message("No firmware updates applied".warning().to_string()),
+                    Ok(_) => spinner.finish_with_message(
+                        format!("Firmware update complete {}", crate::ui::sym("✓"))
+                            .success()
+                            .to_string(),
+                    ),
+                    Err(_) => spinner.finish_and_clear(),
+                }
+                return result;
+            }
+
+            // Without a device list (older fwupd) only an update of everything is possible
+            if !self.firmware_devices.is_empty()
+                || !self.config.firmware.skip_devices.is_empty()
+                || !self.config.firmware.branches.is_empty()
+            {
+                spinner.finish_and_clear();
+                return Err(UpdateError::CommandFailed {
+                    cmd: "fwupdmgr get-updates --json".into(),
+                    code: 1,
+                    details: "cannot list firmware devices, so --firmware-device and [firmware] skip_devices \
+                              or branches can't be applied; nothing was updated"
+                        .into(),
+                });
+            }
+            if !self.firmware_approved(&[], &[]).await {
+                spinner.finish_with_message("Firmware left alone".warning().to_string());
+                return Ok(0);
+            }
+            self.set_step(&spinner, "Applying firmware updates...");
+            match self
+                .run_command_kept(
+                    Stage::Firmware,
+                    "fwupdmgr",
+                    &["update", "-y"],
+                    "fwupdmgr-update.log",
+                )
+                .await
+            {
+                Ok(lines) => {
+                    spinner.finish_with_message(
+                        format!("Firmware update complete {}", crate::ui::sym("✓"))
+                            .success()
+                            .to_string(),
+                    );
+                    self.summary.lock().await.firmware_updated = true;
+                    Ok(lines
+                        .iter()
+                        .filter(|l| l.contains("Successfully installed firmware"))
+                        .count())
+                }
+                Err(UpdateError::CommandFailed { code: 2, .. }) => {
+                    spinner
+                        .finish_with_message("No firmware updates available".warning().to_string());
+                    Ok(0)
+                }
+                Err(e) => Err(e),
+            }
+        }
+
+        /// Enables or disables the lvfs-testing remote to match `[firmware]
+        /// lvfs_testing`, so beta firmware is only offered where wanted.
+        async fn sync_lvfs_testing(&self) -> Result<()> {
+            let Some(wanted) = self.config.firmware.lvfs_testing else {
+                return Ok(());
+            };
+            let lines = self
+                .run_command_silent(Stage::Firmware, "fwupdmgr", &["get-remotes", "--json"])
+                .await?;
+            let enabled = serde_json::from_str::<FirmwareRemotes>(&lines.join("\n"))
+                .ok()
+                .and_then(|r| r.remotes.into_iter().find(|r| r.id == LVFS_TESTING))
+                .map(|r| r.enabled);
+            match enabled {
+                None => warn!(
+                    "fwupd has no {} remote to {}",
+                    LVFS_TESTING,
+                    if wanted { "enable" } else { "disable" }
+                ),
+                Some(enabled) if enabled == wanted => {
+                    debug!("{} remote already {}", LVFS_TESTING, enabled)
+                }
+                Some(_) => {
+                    let verb = if wanted {
+                        "enable-remote"
+                    } else {
+                        "disable-remote"
+                    };
+                    info!(
+                        "Running fwupdmgr {} {} per [firmware] lvfs_testing",
+                        verb, LVFS_TESTING
+                    );
+                    self.run_command(Stage::Firmware, "fwupdmgr", &[verb, LVFS_TESTING, "-y"])
+                        .await?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Devices that are on a different release branch than `[firmware]
+        /// branches` pins them to, with the branch to switch to.
+        async fn branch_switches(&self) -> Vec<(FirmwareDevice, String)> {
+            let pins = &self.config.firmware.branches;
+            if pins.is_empty() {
+                return Vec::new();
+            }
+            let devices = match self
+                .run_command_silent(Stage::Firmware, "fwupdmgr", &["get-devices", "--json"])
+                .await
+            {
+                Ok(lines) => serde_json::from_str::<FirmwareUpdates>(&lines.join("\n"))
+                    .map(|u| u.devices)
+                    .unwrap_or_default(),
+                Err(e) => {
+                    warn!(
+                        "Cannot list firmware devices to apply [firmware] branches: {}",
+                        e
+                    );
+                    return Vec::new();
+                }
+            };
+            devices
+                .into_iter()
+                .filter_map(|device| {
+                    let (_, branch) = pins.iter().find(|(pattern, _)| device.matches(pattern))?;
+                    let current = device.branch.as_deref().unwrap_or("default");
+                    (current != branch).then(|| (device, branch.clone()))
+                })
+                .collect()
+        }
+
+        /// Updates each device with an update that isn't skipped, left out by
+        /// --firmware-device or blocked by a safety check, once the whole
+        /// set is approved. Devices are flashed one at a time so one failing
+        /// doesn't keep the others back, and each outcome goes in the summary.
+        async fn update_firmware_devices(
+            &self,
+            spinner: &ProgressBar,
+            devices: Vec<(FirmwareDevice, FirmwareAction)>,
+        ) -> Result<usize> {
+            let forced = self.firmware_approval == FirmwareApproval::Forced;
+            let plan: Vec<(FirmwareDevice, FirmwareAction, Option<FirmwareResult>)> = devices
+                .into_iter()
+                .map(|(device, action)| {
+                    let decided = if self
+                        .config
+                        .firmware
+                        .skip_devices
+                        .iter()
+                        .any(|p| device.matches(p))
+                    {
+                        Some(FirmwareResult::Skipped)
+                    } else if !self.firmware_devices.is_empty()
+                        && !self.firmware_devices.iter().any(|p| device.matches(p))
+                    {
+                        Some(FirmwareResult::NotSelected)
+                    } else {
+                        let blockers = device.blockers();
+                        if blockers.is_empty() {
+                            None
+                        } else if forced {
+                            warn!(
+                                "Flashing {} despite: {} (--firmware-force)",
+                                device.name,
+                                blockers.join("; ")
+                            );
+                            None
+                        } else {
+                            Some(FirmwareResult::Blocked(blockers.join("; ")))
+                        }
+                    };
+                    (device, action, decided)
+                })
+                .collect();
+
+            let to_flash: Vec<(&FirmwareDevice, &FirmwareAction)> = plan
+                .iter()
+                .filter(|(.., r)| r.is_none())
+                .map(|(d, a, _)| (d, a))
+                .collect();
+            let approved = to_flash.is_empty()
+                || self
+                    .firmware_approved(&to_flash, &firmware_warnings(&to_flash))
+                    .await;
+
+            let mut updated = 0;
+            let mut failed = Vec::new();
+            for (device, action, decided) in plan {
+                let result = match decided {
+                    Some(result) => result,
+                    None if !approved => FirmwareResult::NotConfirmed,
+                    None => {
+                        self.set_step(spinner, &format!("Updating {}...", device.name));
+                        let short_id = &device.device_id[..device.device_id.len().min(8)];
+                        let (args, artifact) = match &action {
+                            FirmwareAction::Update => (
+                                vec!["update", device.device_id.as_str(), "-y"],
+                                format!("fwupdmgr-update-{}.log", short_id),
+                            ),
+                            FirmwareAction::SwitchBranch(branch) => (
+                                vec![
+                                    "switch-branch",
+                                    device.device_id.as_str(),
+                                    branch.as_str(),
+                                    "-y",
+                                ],
+                                format!("fwupdmgr-switch-branch-{}.log", short_id),
+                            ),
+                            FirmwareAction::Install(file) => (
+                                vec![
+                                    "local-install",
+                                    file.as_str(),
+                                    device.device_id.as_str(),
+                                    "-y",
+                                ],
+                                format!("fwupdmgr-local-install-{}.log", short_id),
+                            ),
+                        };
+                        match self
+                            .run_command_kept(Stage::Firmware, "fwupdmgr", &args, &artifact)
+                            .await
+                        {
+                            Ok(_) => {
+                                updated += 1;
+                                FirmwareResult::Updated
+                            }
+                            Err(UpdateError::Cancelled) => return Err(UpdateError::Cancelled),
+                            Err(UpdateError::CommandFailed { code, details, .. }) => {
+                                let reason = details
+                                    .lines()
+                                    .rev()
+                                    .find(|l| !l.trim().is_empty())
+                                    .unwrap_or_default();
+                                FirmwareResult::Failed(format!(
+                                    "exit code {}: {}",
+                                    code,
+                                    reason.trim()
+                                ))
+                            }
+                            Err(e) => FirmwareResult::Failed(e.english()),
+                        }
+                    }
+                };
+                if matches!(
+                    result,
+                    FirmwareResult::Failed(_) | FirmwareResult::Blocked(_)
+                ) {
+                    failed.push(device.name.clone());
+                }
+                if result != FirmwareResult::Updated {
+                    info!("Firmware for {}: {}", device.name, result.describe());
+                }
+                let to = match action {
+                    FirmwareAction::Update | FirmwareAction::Install(_) => {
+                        device.releases.into_iter().next().map(|r| r.version)
+                    }
+                    FirmwareAction::SwitchBranch(branch) => Some(format!("{} branch", branch)),
+                };
+                self.summary
+                    .lock()
+                    .await
+                    .firmware_devices
+                    .push(FirmwareOutcome {
+                        name: device.name,
+                        from: device.version,
+                        to,
+                        result,
+                    });
+            }
+
+            if updated > 0 {
+                self.summary.lock().await.firmware_updated = true;
+            }
+            if failed.is_empty() {
+                Ok(updated)
+            } else {
+                Err(UpdateError::CommandFailed {
+                    cmd: "fwupdmgr update".into(),
+                    code: 1,
+                    details: format!("firmware not updated for {}", failed.join(", ")),
+                })
+            }
+        }
+
+        /// Gets the go-ahead to flash `devices`, even under -y: a blind yes
+        /// is fine for packages but not for firmware. An empty list means
+        /// fwupd couldn't list devices and everything it has is flashed.
+        async fn firmware_approved(
+            &self,
+            devices: &[(&FirmwareDevice, &FirmwareAction)],
+            warnings: &[String],
+        ) -> bool {
+            match self.firmware_approval {
+                FirmwareApproval::Forced => {
+                    for warning in warnings {
+                        warn!("{}", warning);
+                    }
+                    true
+                }
+                FirmwareApproval::Unattended => {
+                    self.skip_stage(
+                        Stage::Firmware,
+                        "firmware updates need confirmation; use --firmware-force to flash unattended",
+                    )
+                    .await;
+                    false
+                }
+                FirmwareApproval::Ask => crate::render::suspend(|| {
+                    use std::io::Write;
+
+                    if devices.is_empty() {
+                        println!(
+                            "\n{}",
+                            "Firmware for every device with an update will be flashed.".bold()
+                        );
+                    } else {
+                        println!("\n{}", "Firmware to flash:".bold());
+                    }
+                    for (device, action) in devices {
+                        let from = device.version.as_deref().unwrap_or("?");
+                        let to = match action {
+                            FirmwareAction::Update | FirmwareAction::Install(_) => device
+                                .releases
+                                .first()
+                                .map_or("?".into(), |r| r.version.clone()),
+                            FirmwareAction::SwitchBranch(branch) => format!("{} branch", branch),
+                        };
+                        println!(
+                            "  {} {} {}",
+                            crate::ui::sym("•").accent(),
+                            device.name,
+                            format!("{} {} {}", from, crate::ui::sym("→"), to).dimmed()
+                        );
+                    }
+                    for warning in warnings {
+                        println!("  {} {}", crate::ui::sym("⚠").warning(), warning.warning());
+                    }
+                    print!("Keep the machine powered until it finishes. Flash firmware? [y/N]: ");
+                    let _ = std::io::stdout().flush();
+                    let mut input = String::new();
+                    let _ = std::io::stdin().read_line(&mut input);
+                    let yes = matches!(input.trim().to_lowercase().as_str(), "y" | "yes");
+                    if !yes {
+                        println!("{}", "Leaving firmware alone.".warning());
+                    }
+                    yes
+                }),
+            }
+        }
+    }
+
+    /// Things worth knowing before flashing: when the update actually
+    /// happens, and what a changed firmware measurement will lock out.
+    fn firmware_warnings(devices: &[(&FirmwareDevice, &FirmwareAction)]) -> Vec<String> {
+        let devices: Vec<&FirmwareDevice> = devices.iter().map(|(d, _)| *d).collect();
+        let mut warnings = Vec::new();
+        for device in &devices {
+            if device.has_flag("needs-shutdown") {
+                warnings.push(format!(
+                    "{} is flashed at the next shutdown; don't cut the power",
+                    device.name
+                ));
+            } else if device.has_flag("needs-reboot") {
+                warnings.push(format!(
+                    "{} is flashed during the next reboot, which can take several minutes",
+                    device.name
+                ));
+            }
+        }
+        if devices.iter().any(|d| d.is_capsule()) {
+            let bitlocker = crate::preflight::bitlocker_volumes();
+            if !bitlocker.is_empty() {
+                warnings.push(format!(
+                    "BitLocker volume(s) on {}: Windows may ask for the recovery key after a UEFI update, \
+                     so have it at hand",
+                    bitlocker.join(", ")
+                ));
+            }
+            if crate::preflight::tpm2_bound_luks() {
+                warnings.push(
+                    "LUKS volumes are unlocked with the TPM2: expect to enter the passphrase after the update \
+                     and re-enrol with systemd-cryptenroll"
+                        .into(),
+                );
+            }
+        }
+        warnings
+    }
+
+    const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+    /// Accepts a byte rate with an optional k/M/G suffix, as dnf5's `throttle`
+    /// option does. Percentages are left out since they need `bandwidth` too.
+    pub fn is_valid_rate(rate: &str) -> bool {
+        let number = rate.strip_suffix(['k', 'K', 'M', 'G']).unwrap_or(rate);
+        number
+            .parse::<f64>()
+            .is_ok_and(|n| n.is_finite() && n > 0.0)
+    }
+
+    async fn terminate_process_group(child: &mut Child, signal: Signal) {
+        let Some(pid) = child.id() else {
+            return;
+        };
+        let group = Pid::from_raw(pid as i32);
+
+        warn!("Forwarding {} to process group {}", signal, pid);
+        let _ = killpg(group, signal);
+
+        if tokio::time::timeout(KILL_GRACE_PERIOD, child.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Process group {} still running after {}s, sending SIGKILL",
+                pid,
+                KILL_GRACE_PERIOD.as_secs()
+            );
+            let _ = killpg(group, Signal::SIGKILL);
+            let _ = child.wait().await;
+        }
+    }
+
+    /// Picks the kernels to remove from `rpm -q kernel-core` rows of
+    /// "<installtime> <version-release.arch>": everything but `running` and
+    /// the `keep` most recently installed besides it. dnf5 takes the rest of
+    /// each kernel's packages along since they depend on kernel-core.
+    pub fn old_kernels(installed: &[String], running: &str, keep: usize) -> Vec<String> {
+        let mut kernels: Vec<(u64, &str)> = installed
+            .iter()
+            .filter_map(|l| {
+                let (time, version) = l.trim().split_once(' ')?;
+                Some((time.parse().ok()?, version))
+            })
+            .collect();
+        kernels.sort_by(|a, b| b.cmp(a));
+        kernels
+            .into_iter()
+            .filter(|(_, version)| *version != running)
+            .skip(keep)
+            .map(|(_, version)| version.to_string())
+            .collect()
+    }
+
+    /// Sums the package counts from the "Transaction Summary" block of dnf5 output.
+    fn count_dnf_packages(lines: &[String]) -> usize {
+        lines
+            .iter()
+            .filter_map(|l| {
+                let (op, rest) = l.trim().split_once(':')?;
+                if !matches!(
+                    op,
+                    "Installing" | "Upgrading" | "Downgrading" | "Reinstalling" | "Removing"
+                ) {
+                    return None;
+                }
+                rest.split_whitespace().next()?.parse::<usize>().ok()
+            })
+            .sum()
+    }
+
+    /// Counts the numbered ref rows ("1. org.foo.App ...") flatpak prints for a transaction.
+    fn count_flatpak_refs(lines: &[String]) -> usize {
+        flatpak_ref_rows(lines).count()
+    }
+
+    fn flatpak_ref_rows(lines: &[String]) -> impl Iterator<Item = &String> {
+        lines.iter().filter(|l| {
+            let t = l.trim_start();
+            let digits = t.chars().take_while(char::is_ascii_digit).count();
+            digits > 0 && t[digits..].starts_with('.')
+        })
+    }
+
+    /// A tab-separated `remote-ls --columns=ref,origin,download-size,installed-size`
+    /// row: "org.mozilla.firefox/x86_64/stable  flathub  95.2 MB  262.1 MB".
+    fn parse_flatpak_update(
+        line: &str,
+        installation: &Installation,
+        runtime: bool,
+    ) -> Option<FlatpakUpdate> {
+        let mut cols = line.split('\t').map(str::trim);
+        let full_ref = cols.next().filter(|r| !r.is_empty())?;
+        let full_ref = full_ref
+            .strip_prefix("app/")
+            .or_else(|| full_ref.strip_prefix("runtime/"))
+            .unwrap_or(full_ref);
+        let mut parts = full_ref.split('/');
+        let app = parts.next()?.to_string();
+        let branch = parts.nth(1).unwrap_or_default().to_string();
+        Some(FlatpakUpdate {
+            app,
+            branch,
+            origin: cols.next()?.to_string(),
+            download_bytes: cols.next().and_then(crate::size::parse).unwrap_or(0),
+            installed_bytes: cols.next().and_then(crate::size::parse).unwrap_or(0),
+            installation: installation.clone(),
+            runtime,
+        })
+    }
+
+    /// The packages `dnf5 check --duplicates` reports: each is printed
+    /// unindented, followed by an indented "duplicate with ..." line.
+    fn duplicate_packages(lines: &[String]) -> Vec<String> {
+        let mut packages = Vec::new();
+        let mut current: Option<&str> = None;
+        for line in lines {
+            if !line.starts_with(char::is_whitespace) {
+                current = Some(line.trim());
+            } else if line.contains("duplicate")
+                && let Some(package) = current.take()
+            {
+                packages.push(package.to_string());
+            }
+        }
+        packages
+    }
+
+    /// The packages of a dnf5 transaction table, with the version each
+    /// upgrade replaces taken from its "replacing" line.
+    fn parse_transaction(lines: &[String]) -> Vec<TransactionItem> {
+        const ACTIONS: [&str; 6] = [
+            "Installing",
+            "Upgrading",
+            "Downgrading",
+            "Reinstalling",
+            "Removing",
+            "Replacing",
+        ];
+        let mut action: Option<String> = None;
+        let mut items: Vec<TransactionItem> = Vec::new();
+        for line in lines {
+            if !line.starts_with(char::is_whitespace) {
+                action = line
+                    .trim_end()
+                    .strip_suffix(':')
+                    .filter(|h| ACTIONS.iter().any(|a| h.starts_with(a)))
+                    .map(str::to_string);
+                continue;
+            }
+            let Some(action) = &action else {
+                continue;
+            };
+            let cols: Vec<&str> = line.split_whitespace().collect();
+            if cols.first() == Some(&"replacing") {
+                let Some(item) = items.last_mut() else {
+                    continue;
+                };
+                // A different name is an obsolete, not the old version
+                if let [_, name, _arch, version, repo, ..] = cols.as_slice()
+                    && *name != item.name
+                {
+                    let obsoleted = TransactionItem {
+                        action: "Obsoleted".into(),
+                        name: name.to_string(),
+                        version: version.to_string(),
+                        from: None,
+                        repo: repo.to_string(),
+                        size: 0,
+                    };
+                    items.push(obsoleted);
+                } else if item.from.is_none() {
+                    item.from = cols.get(3).map(|v| v.to_string());
+                }
+                continue;
+            }
+            let [name, _arch, version, repo, size @ ..] = cols.as_slice() else {
+                continue;
+            };
+            items.push(TransactionItem {
+                action: action.clone(),
+                name: name.to_string(),
+                version: version.to_string(),
+                from: None,
+                repo: repo.to_string(),
+                size: crate::size::parse(&size.join(" ")).unwrap_or(0),
+            });
+        }
+        items
+    }
+
+    /// Removals and obsoletes a transaction leaves the system without: an
+    /// old kernel dropped while a newer one is installed is not one.
+    fn lost_packages(transaction: &[TransactionItem]) -> Vec<&TransactionItem> {
+        let kept: Vec<&str> = transaction
+            .iter()
+            .filter(|item| !item.is_removal())
+            .map(|item| item.name.as_str())
+            .collect();
+        transaction
+            .iter()
+            .filter(|item| item.is_removal() && !kept.contains(&item.name.as_str()))
+            .collect()
+    }
+
+    /// Package names under dnf5's "Removing ...:" headings in a transaction table.
+    fn dnf_removals(lines: &[String]) -> Vec<String> {
+        parse_transaction(lines)
+            .into_iter()
+            .filter(|item| item.action.starts_with("Removing"))
+            .map(|item| item.name)
+            .collect()
+    }
+
+    /// dnf5's "Need to download 123 MiB." line, before a transaction.
+    fn dnf_download_size(lines: &[String]) -> u64 {
+        lines
+            .iter()
+            .find_map(|l| {
+                let size = l.split("Need to download ").nth(1)?;
+                crate::size::parse(size.trim_end_matches('.'))
+            })
+            .unwrap_or(0)
+    }
+
+    /// Sums the "< 150.3 MB" column of flatpak's transaction table. These are
+    /// upper bounds, as deltas are often smaller.
+    fn flatpak_download_size(lines: &[String]) -> u64 {
+        flatpak_ref_rows(lines)
+            .filter_map(|l| crate::size::parse(l.rsplit_once('<')?.1))
+            .sum()
+    }
+
+    /// What it takes for the updates to take effect.
+    #[derive(Debug, Clone, Default)]
+    pub struct RestartCheck {
+        /// Why a reboot is needed, if it is.
+        pub reboot: Option<String>,
+        /// Services still running with outdated libraries or binaries.
+        pub services: Vec<String>,
+    }
+
+    pub async fn check_reboot_required() -> Result<RestartCheck> {
+        let mut check = RestartCheck::default();
+        if !crate::system::command_exists("dnf5") {
+            return Ok(check);
+        }
+
+        let output = Command::new("dnf5")
+            .args(["needs-restarting", "-r"])
+            .output()
+            .await?;
+
+        if output.status.code() == Some(1) {
+            check.reboot = Some(String::from_utf8_lossy(&output.stdout).to_string());
+        }
+
+        let output = Command::new("dnf5")
+            .args(["needs-restarting", "--services"])
+            .output()
+            .await?;
+        if output.status.success() {
+            check.services = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::trim)
+                .filter(|l| l.ends_with(".service"))
+                .map(str::to_string)
+                .collect();
+        }
+
+        Ok(check)
+    }
+}
+
+mod retry {
+    use crate::config::RetryConfig;
+    use crate::error::{Result, UpdateError};
+    use crate::updater::ShutdownSignal;
+    use std::{future::Future, time::Duration};
+    use tracing::warn;
+
+    /// Delay before retry number `attempt` (1-based): exponential growth from
+    /// `initial_delay_secs`, capped at `max_delay_secs`, then spread by `jitter`.
+    pub fn delay(policy: &RetryConfig, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = policy.initial_delay_secs * policy.multiplier.powi(exponent);
+        let capped = base.min(policy.max_delay_secs);
+        let spread = capped * policy.jitter * (fastrand::f64() * 2.0 - 1.0);
+        Duration::from_secs_f64((capped + spread).max(0.0))
+    }
+
+    /// Runs `op` until it succeeds, fails with an error `retryable` rejects, or
+    /// `max_attempts` is reached. A shutdown signal cuts the wait short.
+    pub async fn with_backoff<T, F, Fut>(
+        policy: &RetryConfig,
+        shutdown: &ShutdownSignal,
+        what: &str,
+        retryable: fn(&UpdateError) -> bool,
+        mut op: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let max_attempts = policy.max_attempts.max(1);
+        let mut attempt = 1;
+
+        loop {
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < max_attempts && retryable(&e) => {
+                    let wait = delay(policy, attempt);
+                    warn!(
+                        "{} failed (attempt {}/{}): {}; retrying in {:.1}s",
+                        what,
+                        attempt,
+                        max_attempts,
+                        e,
+                        wait.as_secs_f64()
+                    );
+
+                    let mut shutdown = shutdown.clone();
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        Ok(_) = shutdown.wait_for(Option::is_some) => return Err(UpdateError::Cancelled),
+                    }
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Failures that may go away on their own: network blips, failed or hung
+    /// commands, and a busy package manager.
+    pub fn is_transient(e: &UpdateError) -> bool {
+        matches!(
+            e,
+            UpdateError::NoNetwork
+                | UpdateError::CommandFailed { .. }
+                | UpdateError::MetadataFetchFailed { .. }
+                | UpdateError::Timeout { .. }
+                | UpdateError::LockHeld(_)
+        )
+    }
+
+    /// Output from dnf5/librepo, flatpak/ostree and fwupd that points at a
+    /// mirror or network problem rather than a broken transaction.
+    const TRANSIENT_MARKERS: &[&str] = &[
+        "curl error",
+        "librepo error",
+        "failed to download",
+        "cannot download",
+        "could not resolve host",
+        "temporary failure in name resolution",
+        "connection timed out",
+        "connection refused",
+        "connection reset",
+        "operation timed out",
+        "timeout was reached",
+        "failed to connect",
+        "unable to connect",
+        "error while fetching",
+    ];
+
+    /// Like `is_transient`, but only retries a failed command when its output
+    /// looks like a network or mirror blip (or the package manager was busy). Re-running a transaction that
+    /// failed on a dependency problem or a scriptlet would just fail again.
+    pub fn is_transient_failure(e: &UpdateError) -> bool {
+        match e {
+            UpdateError::CommandFailed { details, .. } => {
+                let details = details.to_lowercase();
+                TRANSIENT_MARKERS.iter().any(|m| details.contains(m))
+            }
+            other => is_transient(other),
+        }
+    }
+}
+
+mod progress {
+    use crate::updater::Stage;
+
+    /// A position in a backend's download or transaction, as parsed from
+    /// its plain-text output.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Progress {
+        pub phase: &'static str,
+        pub current: u64,
+        pub total: u64,
+        pub name: String,
+    }
+
+    pub fn parse(stage: Stage, line: &str) -> Option<Progress> {
+        // Redrawn progress lines arrive as one line with carriage returns
+        let line = line.rsplit('\r').next()?.trim();
+        match stage {
+            Stage::System => parse_dnf(line),
+            Stage::Flatpak => parse_flatpak(line),
+            Stage::Firmware => None,
+        }
+    }
+
+    /// dnf5 numbers both downloads and transaction items:
+    /// `[ 3/41] firefox-130.0-1.fc40.x86_64  100% | 9.1 MiB/s | ...` and
+    /// `[12/84] Upgrading firefox-0:130.0-1.fc40.x86_64  100% | ...`.
+    fn parse_dnf(line: &str) -> Option<Progress> {
+        let (counter, rest) = line.strip_prefix('[')?.split_once(']')?;
+        let (current, total) = counter.split_once('/')?;
+        let current = current.trim().parse().ok()?;
+        let total = total.trim().parse().ok()?;
+
+        let mut words = rest.split_whitespace();
+        let first = words.next()?;
+        let (phase, name) = match first {
+            "Installing" | "Upgrading" | "Downgrading" | "Reinstalling" | "Removing"
+            | "Erasing" => ("Installing", words.next()?),
+            "Cleaning" => ("Installing", words.find(|w| *w != "up")?),
+            // "Verify package files", "Prepare transaction", ...
+            _ if !first.contains('-') => ("Preparing", first),
+            _ => ("Downloading", first),
+        };
+        Some(Progress {
+            phase,
+            current,
+            total,
+            name: name.to_string(),
+        })
+    }
+
+    /// flatpak counts operations (`Updating 2/5…`) and names the ref being
+    /// worked on (`Updating app/org.mozilla.firefox/x86_64/stable`).
+    fn parse_flatpak(line: &str) -> Option<Progress> {
+        let (verb, rest) = line.split_once(' ')?;
+        let phase = match verb {
+            "Updating" => "Updating",
+            "Installing" => "Installing",
+            "Uninstalling" => "Removing",
+            _ => return None,
+        };
+        let token = rest.split_whitespace().next()?.trim_end_matches(['…', '.']);
+        match token.split_once('/') {
+            Some((current, total)) if current.chars().all(|c| c.is_ascii_digit()) => {
+                Some(Progress {
+                    phase,
+                    current: current.parse().ok()?,
+                    total: total.parse().ok()?,
+                    name: String::new(),
+                })
+            }
+            _ => {
+                // app/<id>/<arch>/<branch> or a bare application ID
+                let name = token.split('/').nth(1).unwrap_or(token);
+                Some(Progress {
+                    phase,
+                    current: 0,
+                    total: 0,
+                    name: name.to_string(),
+                })
+            }
+        }
+    }
+}
+
+mod size {
+    const UNITS: &[(&str, u64)] = &[
+        ("b", 1),
+        ("bytes", 1),
+        ("k", 1000),
+        ("kb", 1000),
+        ("kib", 1 << 10),
+        ("m", 1000 * 1000),
+        ("mb", 1000 * 1000),
+        ("mib", 1 << 20),
+        ("g", 1000 * 1000 * 1000),
+        ("gb", 1000 * 1000 * 1000),
+        ("gib", 1 << 30),
+        ("tb", 1000 * 1000 * 1000 * 1000),
+        ("tib", 1 << 40),
+    ];
+
+    /// Parses sizes as printed by dnf5 ("1.2 GiB") and flatpak ("< 845.3 kB").
+    pub fn parse(text: &str) -> Option<u64> {
+        let text = text.trim().trim_start_matches('<').trim();
+        let split = text
+            .find(|c: char| !c.is_ascii_digit() && c != '.' && c != ',')
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let number: f64 = number.replace(',', ".").parse().ok()?;
+
+        let unit = unit.trim().to_lowercase();
+        let multiplier = if unit.is_empty() {
+            1
+        } else {
+            UNITS.iter().find(|(u, _)| *u == unit)?.1
+        };
+
+        Some((number * multiplier as f64) as u64)
+    }
+
+    pub fn format(bytes: u64) -> String {
+        const STEPS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut value = bytes as f64;
+        let mut step = 0;
+        while value >= 1024.0 && step < STEPS.len() - 1 {
+            value /= 1024.0;
+            step += 1;
+        }
+        if step == 0 {
+            format!("{} B", bytes)
+        } else {
+            format!("{:.1} {}", value, STEPS[step])
+        }
+    }
+}
+
+mod security {
+    use crate::error::{Result, UpdateError};
+    use serde_json::Value;
+    use std::collections::BTreeMap;
+
+    /// Fedora advisory severities, lowest first.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum)]
+    pub enum Severity {
+        Low,
+        Moderate,
+        Important,
+        Critical,
+    }
+
+    impl Severity {
+        pub const ALL: [Severity; 4] = [
+            Severity::Low,
+            Severity::Moderate,
+            Severity::Important,
+            Severity::Critical,
+        ];
+
+        /// The spelling used by updateinfo and dnf5's `--advisory-severities`.
+        pub fn label(self) -> &'static str {
+            match self {
+                Severity::Low => "Low",
+                Severity::Moderate => "Moderate",
+                Severity::Important => "Important",
+                Severity::Critical => "Critical",
+            }
+        }
+
+        fn parse(text: &str) -> Option<Self> {
+            Self::ALL
+                .into_iter()
+                .find(|s| s.label().eq_ignore_ascii_case(text))
+        }
+
+        /// dnf5 arguments restricting a transaction to security fixes of at
+        /// least this severity.
+        pub fn dnf_filter(self) -> String {
+            let levels: Vec<&str> = Self::ALL
+                .into_iter()
+                .filter(|s| *s >= self)
+                .map(Severity::label)
+                .collect();
+            format!("--advisory-severities={}", levels.join(","))
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Advisory {
+        pub id: String,
+        /// None for advisories published without a severity rating.
+        pub severity: Option<Severity>,
+        pub packages: Vec<String>,
+        pub cves: Vec<String>,
+    }
+
+    /// The most severe advisory fixing each package, by package name.
+    pub fn package_severities(advisories: &[Advisory]) -> BTreeMap<String, Severity> {
+        let mut severities: BTreeMap<String, Severity> = BTreeMap::new();
+        for advisory in advisories {
+            let Some(severity) = advisory.severity else {
+                continue;
+            };
+            // "openssl-libs-1:3.2.1-2.fc40.x86_64": name-version-release.arch
+            for name in advisory
+                .packages
+                .iter()
+                .filter_map(|nevra| nevra.rsplitn(3, '-').nth(2))
+            {
+                let entry = severities.entry(name.to_string()).or_insert(severity);
+                *entry = (*entry).max(severity);
+            }
+        }
+        severities
+    }
+
+    /// Parses `dnf5 advisory list --security --with-cve --json`. dnf5 emits one
+    /// row per affected package, so rows are merged by advisory ID. CVE IDs are
+    /// picked out of every string in the row, since their key varies between
+    /// dnf5 releases. Output that isn't a list of rows is an error rather
+    /// than no advisories, so a truncated reply can't hide pending fixes.
+    pub fn parse(json: &str) -> Result<Vec<Advisory>> {
+        let unreadable = |details: String| UpdateError::UnexpectedOutput {
+            cmd: "dnf5 advisory list --json".into(),
+            details,
+        };
+        let rows = match serde_json::from_str::<Value>(json) {
+            Ok(Value::Array(rows)) => rows,
+            Ok(_) => return Err(unreadable("expected a list of advisories".into())),
+            Err(e) => return Err(unreadable(e.to_string())),
+        };
+
+        let mut advisories: BTreeMap<String, Advisory> = BTreeMap::new();
+        for row in &rows {
+            let field = |keys: &[&str]| {
+                keys.iter()
+                    .find_map(|k| row.get(*k).and_then(Value::as_str))
+                    .map(str::to_string)
+            };
+            let Some(id) = field(&["advisory_name", "name"]) else {
+                continue;
+            };
+            let severity =
+                field(&["advisory_severity", "severity"]).and_then(|s| Severity::parse(&s));
+            let advisory = advisories.entry(id.clone()).or_insert_with(|| Advisory {
+                id,
+                severity,
+                packages: Vec::new(),
+                cves: Vec::new(),
+            });
+
+            if let Some(nevra) = field(&["nevra", "package"])
+                && !advisory.packages.contains(&nevra)
+            {
+                advisory.packages.push(nevra);
+            }
+            let mut strings = Vec::new();
+            collect_strings(row, &mut strings);
+            for cve in strings.iter().flat_map(|s| find_cves(s)) {
+                if !advisory.cves.contains(&cve) {
+                    advisory.cves.push(cve);
+                }
+            }
+        }
+
+        let mut list: Vec<Advisory> = advisories.into_values().collect();
+        list.sort_by(|a, b| b.severity.cmp(&a.severity).then_with(|| a.id.cmp(&b.id)));
+        Ok(list)
+    }
+
+    /// Parses the text table of `dnf5 advisory list --security`, for dnf5
+    /// releases without `--json`: "FEDORA-2024-1a2b3c  security  Important
+    /// openssl-1:3.2.1-2.fc40.x86_64  2024-03-01 ...".
+    pub fn parse_text(lines: &[String]) -> Vec<Advisory> {
+        let mut advisories: BTreeMap<String, Advisory> = BTreeMap::new();
+        for line in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [id, kind, severity, package, ..] = fields[..] else {
+                continue;
+            };
+            if !kind.eq_ignore_ascii_case("security") {
+                continue;
+            }
+            let advisory = advisories
+                .entry(id.to_string())
+                .or_insert_with(|| Advisory {
+                    id: id.to_string(),
+                    severity: Severity::parse(severity),
+                    packages: Vec::new(),
+                    cves: Vec::new(),
+                });
+            if !advisory.packages.iter().any(|p| p == package) {
+                advisory.packages.push(package.to_string());
+            }
+        }
+
+        let mut list: Vec<Advisory> = advisories.into_values().collect();
+        list.sort_by(|a, b| b.severity.cmp(&a.severity).then_with(|| a.id.cmp(&b.id)));
+        list
+    }
+
+    fn collect_strings<'a>(value: &'a Value, out: &mut Vec<&'a str>) {
+        match value {
+            Value::String(s) => out.push(s),
+            Value::Array(items) => items.iter().for_each(|v| collect_strings(v, out)),
+            Value::Object(map) => map.values().for_each(|v| collect_strings(v, out)),
+            _ => {}
+        }
+    }
+
+    /// Extracts "CVE-YYYY-NNNN" identifiers from free text.
+    fn find_cves(text: &str) -> Vec<String> {
+        text.split(|c: char| !c.is_ascii_alphanumeric() && c != '-')
+            .filter(|word| {
+                let mut parts = word.split('-');
+                parts.next() == Some("CVE")
+                    && parts
+                        .next()
+                        .is_some_and(|y| y.len() == 4 && y.chars().all(|c| c.is_ascii_digit()))
+                    && parts
+                        .next()
+                        .is_some_and(|n| n.len() >= 4 && n.chars().all(|c| c.is_ascii_digit()))
+                    && parts.next().is_none()
+            })
+            .map(str::to_string)
+            .collect()
+    }
+}
+mod repos {
+    use crate::config::NetworkConfig;
+    use crate::error::{Result, UpdateError};
+    use crate::ui::Themed;
+    use chrono::{DateTime, Local, TimeDelta};
+    use colored::Colorize;
+    use serde::{Deserialize, Serialize};
+    use std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    };
+    use tracing::debug;
+
+    /// Where dnf5 reads repository definitions, highest precedence first: a
+    /// .repo file in /etc hides one of the same name shipped under /usr.
+    const REPO_DIRS: &[&str] = &[
+        "etc/yum.repos.d",
+        "etc/distro.repos.d",
+        "usr/share/dnf5/repos.d",
+    ];
+
+    const CACHE_DIR: &str = "/var/cache/libdnf5";
+    const HEALTH_FILE: &str = "/var/lib/fup/repo-health.json";
+
+    /// dnf5's default when a repo doesn't set `metadata_expire`.
+    const DEFAULT_METADATA_EXPIRE: Duration = Duration::from_secs(48 * 3600);
+
+    /// Consecutive failed checks after which a repo is worth disabling.
+    const FLAKY_AFTER: u32 = 3;
+
+    /// A repository as configured in a .repo file.
+    #[derive(Debug, Clone)]
+    pub struct Repo {
+        pub id: String,
+        pub enabled: bool,
+        pub gpgcheck: bool,
+        pub baseurl: Vec<String>,
+        pub mirrorlist: Option<String>,
+        pub metalink: Option<String>,
+        /// None means metadata never expires.
+        pub metadata_expire: Option<Duration>,
+    }
+
+    /// Reads every repository definition under `root`.
+    pub fn load(root: &Path) -> Vec<Repo> {
+        // Repos that don't say inherit [main]; libdnf5 itself defaults to off
+        let main = std::fs::read_to_string(root.join("etc/dnf/dnf.conf")).unwrap_or_default();
+        let default_check = ini_sections(&main)
+            .into_iter()
+            .find(|(name, _)| name == "main")
+            .and_then(|(_, keys)| gpgcheck(&keys))
+            .unwrap_or(false);
+
+        let mut seen_files = HashSet::new();
+        let mut seen_repos = HashSet::new();
+        let mut repos = Vec::new();
+        for dir in REPO_DIRS {
+            let mut files: Vec<_> = std::fs::read_dir(root.join(dir))
+                .into_iter()
+                .flatten()
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|ext| ext == "repo"))
+                .collect();
+            files.sort();
+            for file in files {
+                if !seen_files.insert(file.file_name().map(|n| n.to_owned())) {
+                    continue;
+                }
+                let content = std::fs::read_to_string(&file).unwrap_or_default();
+                for (id, keys) in ini_sections(&content) {
+                    if !seen_repos.insert(id.clone()) {
+                        continue;
+                    }
+                    let get = |key: &str| keys.get(key).filter(|v| !v.is_empty()).cloned();
+                    repos.push(Repo {
+                        enabled: keys.get("enabled").is_none_or(|v| truthy(v)),
+                        gpgcheck: gpgcheck(&keys).unwrap_or(default_check),
+                        baseurl: keys
+                            .get("baseurl")
+                            .map(|v| {
+                                v.split([',', ' '])
+                                    .filter(|u| !u.is_empty())
+                                    .map(String::from)
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                        mirrorlist: get("mirrorlist"),
+                        metalink: get("metalink"),
+                        metadata_expire: get("metadata_expire")
+                            .map_or(Some(DEFAULT_METADATA_EXPIRE), |v| parse_expire(&v)),
+                        id,
+                    });
+                }
+            }
+        }
+        repos
+    }
+
+    /// `pkg_gpgcheck` is dnf5's name for it; `gpgcheck` is the older alias.
+    fn gpgcheck(keys: &HashMap<String, String>) -> Option<bool> {
+        keys.get("pkg_gpgcheck")
+            .or_else(|| keys.get("gpgcheck"))
+            .map(|v| truthy(v))
+    }
+
+    fn truthy(value: &str) -> bool {
+        matches!(
+            value.to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        )
+    }
+
+    /// `metadata_expire` is in seconds unless suffixed with m, h or d;
+    /// -1 or "never" turns expiry off.
+    pub fn parse_expire(value: &str) -> Option<Duration> {
+        if value == "-1" || value.eq_ignore_ascii_case("never") {
+            return None;
+        }
+        let (number, unit) = match value.char_indices().last() {
+            Some((i, 'm')) => (&value[..i], 60),
+            Some((i, 'h')) => (&value[..i], 3600),
+            Some((i, 'd')) => (&value[..i], 86400),
+            _ => (value, 1),
+        };
+        Some(
+            number
+                .parse::<u64>()
+                .map_or(DEFAULT_METADATA_EXPIRE, |n| Duration::from_secs(n * unit)),
+        )
+    }
+
+    /// Sections of an INI-style dnf config file with their keys.
+    pub fn ini_sections(content: &str) -> Vec<(String, HashMap<String, String>)> {
+        let mut sections: Vec<(String, HashMap<String, String>)> = Vec::new();
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with(['#', ';']) {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                sections.push((name.trim().to_string(), HashMap::new()));
+            } else if let Some((key, value)) = line.split_once('=')
+                && let Some((_, keys)) = sections.last_mut()
+            {
+                keys.insert(key.trim().to_string(), value.trim().to_string());
+            }
+        }
+        sections
+    }
+
+    /// Values for the `$releasever`/`$basearch` style variables in repo
+    /// URLs, with /etc/dnf/vars taking precedence.
+    fn url_vars() -> HashMap<String, String> {
+        let mut vars = HashMap::new();
+        let os_release = std::fs::read_to_string("/etc/os-release").unwrap_or_default();
+        if let Some(version) = os_release
+            .lines()
+            .find_map(|l| l.strip_prefix("VERSION_ID="))
+        {
+            vars.insert(
+                "releasever".to_string(),
+                version.trim_matches('"').to_string(),
+            );
+        }
+        vars.insert("basearch".to_string(), std::env::consts::ARCH.to_string());
+        vars.insert("arch".to_string(), std::env::consts::ARCH.to_string());
+        for entry in std::fs::read_dir("/etc/dnf/vars")
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            if let (Some(name), Ok(value)) = (
+                entry.file_name().to_str(),
+                std::fs::read_to_string(entry.path()),
+            ) {
+                vars.insert(name.to_string(), value.trim().to_string());
+            }
+        }
+        vars
+    }
+
+    /// Substitutes `$name` and `${name}`; unknown variables are left as is.
+    pub fn expand(url: &str, vars: &HashMap<String, String>) -> String {
+        let mut out = String::new();
+        let mut rest = url;
+        while let Some(i) = rest.find('$') {
+            out.push_str(&rest[..i]);
+            let after = &rest[i + 1..];
+            let (name, consumed) = match after.strip_prefix('{') {
+                Some(braced) => match braced.find('}') {
+                    Some(end) => (&braced[..end], end + 2),
+                    None => ("", 0),
+                },
+                None => {
+                    let end = after
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(after.len());
+                    (&after[..end], end)
+                }
+            };
+            match vars.get(name) {
+                Some(value) if !name.is_empty() => out.push_str(value),
+                _ => out.push_str(&rest[i..i + 1 + consumed]),
+            }
+            rest = &after[consumed..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Consecutive failures per repo id, kept across `repo-check` runs.
+    #[derive(Debug, Clone, Default, Serialize, Deserialize)]
+    struct RepoHealth {
+        failures: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_ok: Option<DateTime<Local>>,
+    }
+
+    /// What checking one repository found.
+    struct Report {
+        id: String,
+        source: &'static str,
+        url: String,
+        latency: Option<Duration>,
+        problem: Option<String>,
+        metadata_age: Option<TimeDelta>,
+        expire: Option<Duration>,
+    }
+
+    /// Checks every enabled repository: that its URLs resolve, that the
+    /// metadata (or mirror list) can be fetched, and how old the cached
+    /// copy is. Repos that keep failing are flagged for disabling.
+    pub async fn check(config: &NetworkConfig) -> Result<()> {
+        let repos: Vec<Repo> = load(Path::new("/"))
+            .into_iter()
+            .filter(|r| r.enabled)
+            .collect();
+        if repos.is_empty() {
+            println!("No enabled repositories found.");
+            return Ok(());
+        }
+
+        let timeout = Duration::from_secs(config.timeout_secs);
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| UpdateError::Config(e.to_string()))?;
+        let vars = url_vars();
+
+        let mut tasks = tokio::task::JoinSet::new();
+        for repo in repos {
+            let client = client.clone();
+            let vars = vars.clone();
+            tasks.spawn(async move { check_repo(repo, &client, &vars, timeout).await });
+        }
+        let mut reports = Vec::new();
+        while let Some(report) = tasks.join_next().await {
+            if let Ok(report) = report {
+                reports.push(report);
+            }
+        }
+        reports.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let mut health = load_health();
+        for report in &reports {
+            let entry = health.entry(report.id.clone()).or_default();
+            match report.problem {
+                Some(_) => entry.failures += 1,
+                None => {
+                    entry.failures = 0;
+                    entry.last_ok = Some(Local::now());
+                }
+            }
+        }
+        save_health(&health);
+
+        print_reports(&reports, &health);
+
+        let failed = reports.iter().filter(|r| r.problem.is_some()).count();
+        if failed == 0 {
+            Ok(())
+        } else {
+            Err(UpdateError::CommandFailed {
+                cmd: "fup repo-check".into(),
+                code: 1,
+                details: format!(
+                    "{} of {} repositories failed the check",
+                    failed,
+                    reports.len()
+                ),
+            })
+        }
+    }
+
+    async fn check_repo(
+        repo: Repo,
+        client: &reqwest::Client,
+        vars: &HashMap<String, String>,
+        timeout: Duration,
+    ) -> Report {
+        let (source, url) = if let Some(url) = &repo.metalink {
+            ("metalink", expand(url, vars))
+        } else if let Some(url) = &repo.mirrorlist {
+            ("mirrorlist", expand(url, vars))
+        } else if let Some(url) = repo.baseurl.first() {
+            (
+                "baseurl",
+                format!(
+                    "{}/repodata/repomd.xml",
+                    expand(url, vars).trim_end_matches('/')
+                ),
+            )
+        } else {
+            ("", String::new())
+        };
+
+        let mut report = Report {
+            id: repo.id.clone(),
+            source,
+            url: url.clone(),
+            latency: None,
+            problem: None,
+            metadata_age: cached_metadata_age(&repo.id),
+            expire: repo.metadata_expire,
+        };
+        if url.is_empty() {
+            report.problem = Some("no baseurl, mirrorlist or metalink".into());
+            return report;
+        }
+        if url.contains('$') {
+            report.problem = Some(format!("unresolved variable in {}", url));
+            return report;
+        }
+
+        let Ok(parsed) = reqwest::Url::parse(&url) else {
+            report.problem = Some(format!("invalid URL {}", url));
+            return report;
+        };
+        if parsed.scheme() == "file" {
+            if !Path::new(parsed.path()).exists() {
+                report.problem = Some(format!("{} does not exist", parsed.path()));
+            }
+            return report;
+        }
+        if !crate::system::resolves_any(&[url.as_str()], timeout).await {
+            report.problem = Some(format!(
+                "DNS lookup failed for {}",
+                parsed.host_str().unwrap_or_default()
+            ));
+            return report;
+        }
+
+        let started = Instant::now();
+        let response = match client.get(parsed).send().await {
+            Ok(response) => response,
+            Err(e) if e.is_timeout() => {
+                report.problem = Some(format!("timed out after {}s", timeout.as_secs()));
+                return report;
+            }
+            Err(e) => {
+                report.problem = Some(format!("request failed: {}", e));
+                return report;
+            }
+        };
+        report.latency = Some(started.elapsed());
+        if !response.status().is_success() {
+            report.problem = Some(format!("HTTP {}", response.status()));
+            return report;
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        report.problem = match source {
+            "metalink" if !body.contains("<url") => Some("metalink lists no mirrors".into()),
+            "mirrorlist" if !body.lines().any(|l| l.trim_start().contains("://")) => {
+                Some("mirrorlist is empty".into())
+            }
+            "baseurl" if !body.contains("<repomd") => {
+                Some("repomd.xml is not repository metadata".into())
+            }
+            _ => None,
+        };
+        debug!(
+            "Checked {} via {} in {:?}: {:?}",
+            repo.id, source, report.latency, report.problem
+        );
+        report
+    }
+
+    /// Age of the metadata dnf5 has cached for `id`, from its repomd.xml.
+    fn cached_metadata_age(id: &str) -> Option<TimeDelta> {
+        std::fs::read_dir(CACHE_DIR)
+            .ok()?
+            .flatten()
+            .filter(|e| e.file_name().to_str().is_some_and(|n| is_cache_dir(n, id)))
+            .filter_map(|e| {
+                e.path()
+                    .join("repodata/repomd.xml")
+                    .metadata()
+                    .ok()?
+                    .modified()
+                    .ok()
+            })
+            .max()
+            .map(|modified| Local::now() - DateTime::<Local>::from(modified))
+    }
+
+    /// Cache directories are named "<id>-<hash>". The hash has to follow
+    /// the id directly, or "fedora" would also match "fedora-cisco-openh264-…".
+    pub fn is_cache_dir(name: &str, id: &str) -> bool {
+        name.strip_prefix(id)
+            .and_then(|rest| rest.strip_prefix('-'))
+            .is_some_and(|hash| !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit()))
+    }
+
+    fn load_health() -> BTreeMap<String, RepoHealth> {
+        std::fs::read_to_string(HEALTH_FILE)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn save_health(health: &BTreeMap<String, RepoHealth>) {
+        let path = PathBuf::from(HEALTH_FILE);
+        let saved = (|| -> std::io::Result<()> {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, serde_json::to_string_pretty(health)?)
+        })();
+        if let Err(e) = saved {
+            // Not being root only costs the failure streaks
+            debug!(
+                "Could not save repository health to {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    fn print_reports(reports: &[Report], health: &BTreeMap<String, RepoHealth>) {
+        let width = reports.iter().map(|r| r.id.len()).max().unwrap_or(0);
+        if !crate::ui::accessible() {
+            println!("\n{}", "Repository health:".bold());
+        }
+
+        for report in reports {
+            let streak = health.get(&report.id).map_or(0, |h| h.failures);
+            let metadata = match (report.metadata_age, report.expire) {
+                (Some(age), Some(expire)) if age.to_std().is_ok_and(|age| age > expire) => {
+                    format!(
+                        "cached metadata {} old, expired",
+                        crate::history::format_duration(age.num_seconds() as f64)
+                    )
+                }
+                (Some(age), _) => {
+                    format!(
+                        "cached metadata {} old",
+                        crate::history::format_duration(age.num_seconds() as f64)
+                    )
+                }
+                (None, _) => "not cached".to_string(),
+            };
+
+            if crate::ui::accessible() {
+                match &report.problem {
+                    None => crate::ui::announce(&format!(
+                        "{}: OK via {} in {:.2} seconds, {}.",
+                        report.id,
+                        report.source,
+                        report.latency.map_or(0.0, |l| l.as_secs_f64()),
+                        metadata
+                    )),
+                    Some(problem) => {
+                        crate::ui::announce(&format!("{}: failed, {}.", report.id, problem))
+                    }
+                }
+            } else {
+                match &report.problem {
+                    None => println!(
+                        "  {} {:<width$}  {:<10} {:>6}  {}",
+                        crate::ui::sym("✓").success(),
+                        report.id,
+                        report.source,
+                        report
+                            .latency
+                            .map_or(String::new(), |l| format!("{:.2}s", l.as_secs_f64())),
+                        metadata.dimmed(),
+                        width = width
+                    ),
+                    Some(problem) => {
+                        println!(
+                            "  {} {:<width$}  {}",
+                            crate::ui::sym("✗").failure(),
+                            report.id,
+                            problem.failure(),
+                            width = width
+                        );
+                        if !report.url.is_empty() && !problem.contains(&report.url) {
+                            println!("    {:<width$}  {}", "", report.url.dimmed(), width = width);
+                        }
+                    }
+                }
+            }
+
+            if streak >= FLAKY_AFTER {
+                let disable = format!("sudo dnf5 config-manager setopt {}.enabled=0", report.id);
+                if crate::ui::accessible() {
+                    crate::ui::announce(&format!(
+                        "{} failed the last {} checks; consider disabling it with {}.",
+                        report.id, streak, disable
+                    ));
+                } else {
+                    println!(
+                        "    {:<width$}  {} consider disabling it: {}",
+                        "",
+                        format!("failed the last {} checks;", streak).warning(),
+                        disable.accent(),
+                        width = width
+                    );
+                }
+            }
+        }
+        println!();
+    }
+}
+
+mod preflight {
+    use crate::config::{DiskConfig, HealthConfig, PowerConfig};
+    use crate::error::{Result, UpdateError};
+    use serde::{Deserialize, Serialize};
+    use std::path::Path;
+    use tokio::process::Command;
+    use tracing::{debug, info, warn};
+
+    const MIB: u64 = 1024 * 1024;
+
+    const ROOT_HINTS: &[&str] = &[
+        "sudo dnf5 clean packages",
+        "find large files: sudo du -xh / | sort -h | tail -20",
+    ];
+    const VAR_HINTS: &[&str] = &[
+        "sudo dnf5 clean packages",
+        "sudo flatpak uninstall --unused",
+        "sudo journalctl --vacuum-size=200M",
+    ];
+    const BOOT_HINTS: &[&str] = &["remove old kernels: sudo dnf5 remove --oldinstallonly"];
+
+    /// Verifies free space on /, /var (including the pending download) and
+    /// /boot under `root` against the `[disk]` thresholds.
+    /// Keeps an unattended run from pulling a release upgrade's worth of
+    /// packages over a metered or slow link: past `[run] max_download` it
+    /// waits for someone to run fup by hand.
+    pub fn check_download_size(limit: u64, download_bytes: u64, force: bool) -> Result<()> {
+        if download_bytes <= limit {
+            return Ok(());
+        }
+        let problem = format!(
+            "updates would download {}, more than [run] max_download ({})",
+            crate::size::format(download_bytes),
+            crate::size::format(limit)
+        );
+        if force {
+            warn!("Ignoring the download limit (--force): {}", problem);
+            Ok(())
+        } else {
+            Err(UpdateError::Preflight(format!(
+                "{}\n  Run fup at a terminal to confirm, raise the limit, or pass --force",
+                problem
+            )))
+        }
+    }
+
+    pub fn check_disk_space(
+        config: &DiskConfig,
+        root: &Path,
+        download_bytes: u64,
+        force: bool,
+    ) -> Result<()> {
+        let requirements = [
+            ("/", config.min_free_root_mb * MIB, ROOT_HINTS),
+            (
+                "/var",
+                config.min_free_var_mb * MIB + download_bytes,
+                VAR_HINTS,
+            ),
+            ("/boot", config.min_free_boot_mb * MIB, BOOT_HINTS),
+        ];
+
+        let mut problems = Vec::new();
+        let mut hints: Vec<&str> = Vec::new();
+        for (mount, required, mount_hints) in requirements {
+            let Some(free) = free_bytes(&root.join(mount.trim_start_matches('/'))) else {
+                continue;
+            };
+            debug!("{}: {} free, {} required", mount, free, required);
+            if free < required {
+                problems.push(format!(
+                    "{}: {} free, {} required",
+                    mount,
+                    crate::size::format(free),
+                    crate::size::format(required)
+                ));
+                for hint in mount_hints {
+                    if !hints.contains(hint) {
+                        hints.push(hint);
+                    }
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let mut msg = String::from("not enough free disk space");
+        for problem in &problems {
+            msg.push_str(&format!("\n  {}", problem));
+        }
+        msg.push_str("\n  To free space:");
+        for hint in &hints {
+            msg.push_str(&format!("\n    {} {}", crate::ui::sym("•"), hint));
+        }
+
+        if force {
+            warn!(
+                "Ignoring failed disk space check (--force): {}",
+                problems.join("; ")
+            );
+            Ok(())
+        } else {
+            Err(UpdateError::Preflight(msg))
+        }
+    }
+
+    /// What to do when the health check finds a problem.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum OnProblem {
+        /// Refuse to update until it is fixed (--force overrides).
+        Abort,
+        /// Log it and update anyway.
+        Warn,
+    }
+
+    /// Refuses system updates on top of a damaged RPM database or with an
+    /// SELinux relabel still pending: a transaction makes either worse.
+    /// Packages left installed twice are dealt with by the interrupted
+    /// transaction check.
+    pub async fn check_health(config: &HealthConfig, root: &Path, force: bool) -> Result<()> {
+        info!("Checking the RPM database and SELinux labels...");
+        let problems = health_problems(root).await;
+        health_verdict(config, &problems, force)
+    }
+
+    /// What is wrong under `root`, with how to fix each.
+    pub async fn health_problems(root: &Path) -> Vec<String> {
+        let mut problems = Vec::new();
+        if root.join(".autorelabel").exists() {
+            problems.push(
+                "an SELinux relabel is pending (/.autorelabel); reboot to let it finish first"
+                    .to_string(),
+            );
+        }
+
+        if crate::system::command_exists("rpmdb") {
+            match Command::new("rpmdb")
+                .arg("--root")
+                .arg(root)
+                .arg("--verify")
+                .output()
+                .await
+            {
+                Ok(out) if !out.status.success() => {
+                    let stderr = String::from_utf8_lossy(&out.stderr);
+                    let reason = stderr
+                        .lines()
+                        .map(str::trim)
+                        .find(|l| !l.is_empty())
+                        .unwrap_or("no details");
+                    problems.push(format!(
+                        "the RPM database failed verification ({}); rebuild it with `sudo rpmdb --rebuilddb`",
+                        reason
+                    ));
+                }
+                Ok(_) => debug!("rpmdb --verify passed"),
+                Err(e) => debug!("rpmdb --verify: {}", e),
+            }
+        }
+        problems
+    }
+
+    pub fn health_verdict(config: &HealthConfig, problems: &[String], force: bool) -> Result<()> {
+        if problems.is_empty() {
+            return Ok(());
+        }
+        if config.on_problem == OnProblem::Warn || force {
+            for problem in problems {
+                warn!("Updating anyway: {}", problem);
+            }
+            return Ok(());
+        }
+        Err(UpdateError::Preflight(problems.join("\n  ")))
+    }
+
+    fn free_bytes(path: &Path) -> Option<u64> {
+        let stat = nix::sys::statvfs::statvfs(path).ok()?;
+        Some(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+    }
+
+    /// Refuses to update while an enabled repository under `root` has
+    /// package signature checking turned off, unless `allow_unsigned` is
+    /// set. Either way the decision goes into the audit trail.
+    pub fn check_signatures(root: &Path, allow_unsigned: bool) -> Result<()> {
+        let unsigned = unsigned_repos(root);
+        if unsigned.is_empty() {
+            debug!("All enabled repositories verify package signatures");
+            return Ok(());
+        }
+
+        let list = unsigned.join(", ");
+        if allow_unsigned {
+            warn!(
+                "Updating from repositories without signature checking (--allow-unsigned): {}",
+                list
+            );
+            crate::audit::policy("gpgcheck", &unsigned, "allowed");
+            Ok(())
+        } else {
+            crate::audit::policy("gpgcheck", &unsigned, "refused");
+            Err(UpdateError::Preflight(format!(
+                "package signature checking (gpgcheck) is disabled for: {}\n  \
+                 Set gpgcheck=1 in their .repo files, disable them, or use --allow-unsigned",
+                list
+            )))
+        }
+    }
+
+    /// Enabled repositories that install packages without verifying their
+    /// signatures, by repo id.
+    pub fn unsigned_repos(root: &Path) -> Vec<String> {
+        crate::repos::load(root)
+            .into_iter()
+            .filter(|r| r.enabled && !r.gpgcheck)
+            .map(|r| r.id)
+            .collect()
+    }
+
+    const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct PowerStatus {
+        pub on_ac: bool,
+        pub battery_percent: Option<u8>,
+        /// A UPS reports that mains power is gone and it is running on battery.
+        pub ups_on_battery: bool,
+    }
+
+    /// Reads AC and system battery state from sysfs. Machines without a
+    /// battery are reported as on AC power.
+    pub fn power_status() -> PowerStatus {
+        let mut mains_online = None;
+        let mut battery_percent: Option<u8> = None;
+        let mut discharging = false;
+        let mut ups_on_battery = false;
+
+        let entries = std::fs::read_dir(POWER_SUPPLY_DIR)
+            .into_iter()
+            .flatten()
+            .flatten();
+        for entry in entries {
+            let dir = entry.path();
+            match read_attr(&dir, "type").as_deref() {
+                Some("Mains") => {
+                    let online = read_attr(&dir, "online").as_deref() == Some("1");
+                    mains_online = Some(mains_online.unwrap_or(false) || online);
+                }
+                // Peripheral batteries (mice, headsets) report scope=Device
+                Some("Battery") if read_attr(&dir, "scope").as_deref() != Some("Device") => {
+                    if let Some(capacity) = read_attr(&dir, "capacity").and_then(|c| c.parse().ok())
+                    {
+                        battery_percent =
+                            Some(battery_percent.map_or(capacity, |b: u8| b.min(capacity)));
+                    }
+                    discharging |= read_attr(&dir, "status").as_deref() == Some("Discharging");
+                }
+                Some("UPS") => {
+                    ups_on_battery |= read_attr(&dir, "status").as_deref() == Some("Discharging");
+                }
+                _ => {}
+            }
+        }
+
+        let on_ac = !ups_on_battery
+            && match mains_online {
+                Some(online) => online,
+                None => battery_percent.is_none() || !discharging,
+            };
+        debug!(
+            "Power status: on_ac={} battery={:?} ups_on_battery={}",
+            on_ac, battery_percent, ups_on_battery
+        );
+
+        PowerStatus {
+            on_ac,
+            battery_percent,
+            ups_on_battery,
+        }
+    }
+
+    /// The SecureBoot EFI variable: 4 bytes of attributes, then 1 when enabled.
+    const SECURE_BOOT_VAR: &str =
+        "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+    /// None on legacy BIOS systems or when efivarfs isn't mounted.
+    pub fn secure_boot_enabled() -> Option<bool> {
+        let data = std::fs::read(SECURE_BOOT_VAR).ok()?;
+        Some(data.last() == Some(&1))
+    }
+
+    /// Whether fwupd's EFI helper is installed in a signed build, which a
+    /// capsule update needs to boot with Secure Boot on.
+    pub fn signed_fwupd_efi() -> bool {
+        std::fs::read_dir("/usr/libexec/fwupd/efi")
+            .into_iter()
+            .flatten()
+            .flatten()
+            .any(|e| e.file_name().to_string_lossy().ends_with(".efi.signed"))
+    }
+
+    /// Partitions holding BitLocker volumes, e.g. a Windows install on a
+    /// dual-boot machine, whose TPM protector a UEFI update can trip.
+    pub fn bitlocker_volumes() -> Vec<String> {
+        let Ok(output) = std::process::Command::new("lsblk")
+            .args(["-rno", "NAME,FSTYPE"])
+            .output()
+        else {
+            return Vec::new();
+        };
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|l| l.split_once(' '))
+            .filter(|(_, fstype)| fstype.trim() == "BitLocker")
+            .map(|(name, _)| format!("/dev/{}", name))
+            .collect()
+    }
+
+    /// Whether any LUKS volume in /etc/crypttab is unlocked through the TPM2,
+    /// which measures the firmware and stops unlocking after an update.
+    pub fn tpm2_bound_luks() -> bool {
+        std::fs::read_to_string("/etc/crypttab")
+            .unwrap_or_default()
+            .lines()
+            .filter(|l| !l.trim_start().starts_with('#'))
+            .any(|l| l.contains("tpm2-device"))
+    }
+
+    fn read_attr(dir: &Path, name: &str) -> Option<String> {
+        std::fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|v| v.trim().to_string())
+    }
+
+    /// Aborts the run when the battery is below `[power] min_battery_percent`.
+    pub fn check_battery(config: &PowerConfig, status: PowerStatus, force: bool) -> Result<()> {
+        let Some(percent) = status.battery_percent else {
+            return Ok(());
+        };
+        if status.on_ac || percent >= config.min_battery_percent {
+            return Ok(());
+        }
+
+        let msg = format!(
+            "battery at {}%, below the configured minimum of {}%; plug in the charger",
+            percent, config.min_battery_percent
+        );
+        if force {
+            warn!("Ignoring failed power check (--force): {}", msg);
+            Ok(())
+        } else {
+            Err(UpdateError::Preflight(format!("{} or use --force", msg)))
+        }
+    }
+
+    /// Returns why firmware must not be flashed right now, if anything.
+    pub fn firmware_power_problem(
+        config: &PowerConfig,
+        status: PowerStatus,
+        force: bool,
+    ) -> Option<String> {
+        if !config.require_ac_for_firmware || status.on_ac {
+            return None;
+        }
+
+        let source = if status.ups_on_battery {
+            "the UPS is on battery"
+        } else {
+            "running on battery power"
+        };
+        if force {
+            warn!("Flashing firmware while {} (--force)", source);
+            None
+        } else {
+            Some(format!(
+                "{}; firmware updates require AC (use --force to override)",
+                source
+            ))
+        }
+    }
+}
+
+mod artifacts {
+    use crate::config::ArtifactsConfig;
+    use crate::error::Result;
+    use crate::updater::{AvailableUpdates, Stage};
+    use chrono::Local;
+    use serde::Serialize;
+    use std::{
+        path::{Path, PathBuf},
+        sync::OnceLock,
+    };
+    use tracing::{debug, warn};
+
+    static RUN_ID: OnceLock<String> = OnceLock::new();
+
+    /// This invocation's ID. It names the run directory and is carried by
+    /// the summary, history, audit trail, porcelain events and trace, so
+    /// they can be matched up later.
+    pub fn run_id() -> &'static str {
+        RUN_ID.get_or_init(|| ulid(Local::now().timestamp_millis() as u64, fastrand::u128(..)))
+    }
+
+    /// A ULID: 48 bits of milliseconds since the epoch, then 80 random bits,
+    /// in Crockford's base32. IDs sort in the order runs started.
+    pub fn ulid(millis: u64, random: u128) -> String {
+        const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+        let value = (u128::from(millis) << 80) | (random & ((1 << 80) - 1));
+        (0..26)
+            .rev()
+            .map(|i| ALPHABET[((value >> (i * 5)) & 31) as usize] as char)
+            .collect()
+    }
+
+    /// What a run set out to do, saved as `plan.json`.
+    #[derive(Serialize)]
+    pub struct Plan<'a> {
+        pub stages: Vec<Stage>,
+        pub only: &'a [String],
+        pub download_bytes: u64,
+        /// The pending updates, when they were checked for the confirmation.
+        pub updates: Option<&'a AvailableUpdates>,
+    }
+
+    /// Per-run directory, named by the run ID, holding the plan, raw command
+    /// output, device reports and summary for debugging failures after the fact.
+    #[derive(Debug, Clone)]
+    pub struct ArtifactStore {
+        dir: PathBuf,
+    }
+
+    impl ArtifactStore {
+        /// Creates this run's directory and prunes old runs beyond `keep`.
+        pub fn create(config: &ArtifactsConfig) -> Result<Self> {
+            let dir = config.dir.join(run_id());
+            std::fs::create_dir_all(&dir)?;
+            prune(&config.dir, config.keep);
+            Ok(Self { dir })
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.dir
+        }
+
+        /// Writes an artifact. Failures are logged, never fatal to the run.
+        pub fn write(&self, name: &str, content: &str) {
+            let path = self.dir.join(name);
+            match std::fs::write(&path, content) {
+                Ok(()) => debug!("Saved artifact {}", path.display()),
+                Err(e) => warn!("Failed to save artifact {}: {}", path.display(), e),
+            }
+        }
+
+        pub fn write_json(&self, name: &str, value: &impl Serialize) {
+            match serde_json::to_string_pretty(value) {
+                Ok(json) => self.write(name, &(json + "\n")),
+                Err(e) => warn!("Failed to save artifact {}: {}", name, e),
+            }
+        }
+    }
+
+    fn prune(root: &Path, keep: usize) {
+        let Ok(entries) = std::fs::read_dir(root) else {
+            return;
+        };
+
+        let mut runs: Vec<PathBuf> = entries
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect();
+
+        // Run IDs sort chronologically; directories named by start time, from
+        // before runs had IDs, are older than any of them
+        runs.sort_by_key(|p| (p.file_name().is_some_and(|n| n.len() == 26), p.clone()));
+        let excess = runs.len().saturating_sub(keep);
+        for old in &runs[..excess] {
+            if let Err(e) = std::fs::remove_dir_all(old) {
+                warn!(
+                    "Failed to remove old run directory {}: {}",
+                    old.display(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+mod packagekit {
+    use crate::config::PackageKitConfig;
+    use crate::system::command_exists;
+    use std::time::Duration;
+    use tokio::process::Command;
+    use tracing::{debug, info, warn};
+
+    const SERVICE: &str = "packagekit.service";
+    const BUS: [&str; 3] = [
+        "org.freedesktop.PackageKit",
+        "/org/freedesktop/PackageKit",
+        "org.freedesktop.PackageKit",
+    ];
+
+    /// PackageKit kept from restarting for the length of the run. The
+    /// runtime mask is lifted by `release`, or on drop if that never ran.
+    pub struct Hold {
+        masked: bool,
+    }
+
+    /// Stops packagekitd from competing for the rpm lock and metadata while
+    /// fup updates: it is asked to quit once idle, and with `hold` masked
+    /// until the run ends so GNOME Software can't start it again.
+    pub async fn prepare(config: &PackageKitConfig) -> Option<Hold> {
+        if !config.quit && !config.hold {
+            return None;
+        }
+        // Only a suggestion: packagekitd finishes a running transaction first.
+        // Without auto-start, a daemon that isn't running stays that way.
+        if config.quit
+            && command_exists("busctl")
+            && busctl(&[
+                "--auto-start=no",
+                "call",
+                BUS[0],
+                BUS[1],
+                BUS[2],
+                "SuggestDaemonQuit",
+            ])
+            .await
+        {
+            info!("Asked PackageKit to quit for the update");
+        }
+        if !config.hold || !command_exists("systemctl") {
+            return None;
+        }
+        let masked = Command::new("systemctl")
+            .args(["mask", "--runtime", "--now", SERVICE])
+            .output()
+            .await
+            .is_ok_and(|o| o.status.success());
+        if masked {
+            info!("Holding {} until the update is done", SERVICE);
+        } else {
+            warn!(
+                "Could not hold {}; GNOME Software may start it during the update",
+                SERVICE
+            );
+        }
+        Some(Hold { masked })
+    }
+
+    impl Hold {
+        pub async fn release(mut self) {
+            if self.masked {
+                let _ = Command::new("systemctl")
+                    .args(["unmask", "--runtime", SERVICE])
+                    .status()
+                    .await;
+                debug!("Released {}", SERVICE);
+            }
+            self.masked = false;
+        }
+    }
+
+    impl Drop for Hold {
+        fn drop(&mut self) {
+            if self.masked {
+                let _ = std::process::Command::new("systemctl")
+                    .args(["unmask", "--runtime", SERVICE])
+                    .status();
+            }
+        }
+    }
+
+    /// What `refresh` runs: the signal dnf's own PackageKit plugin sends, and
+    /// with `refresh_cache` a full metadata download on top.
+    pub fn refresh_commands(config: &PackageKitConfig) -> Vec<(&'static str, Vec<&'static str>)> {
+        let mut commands = Vec::new();
+        if config.refresh_after {
+            commands.push((
+                "busctl",
+                vec![
+                    "call",
+                    BUS[0],
+                    BUS[1],
+                    BUS[2],
+                    "StateHasChanged",
+                    "s",
+                    "posttrans",
+                ],
+            ));
+        }
+        if config.refresh_cache {
+            commands.push(("pkcon", vec!["refresh", "force", "--noninteractive"]));
+        }
+        commands
+    }
+
+    /// Tells PackageKit the installed packages changed, so the pending list
+    /// GNOME Software shows matches what was just installed.
+    pub async fn refresh(config: &PackageKitConfig) {
+        for (cmd, args) in refresh_commands(config) {
+            if !command_exists(cmd) {
+                continue;
+            }
+            if cmd == "busctl" {
+                busctl(&args).await;
+            } else {
+                refresh_cache(&args, config.refresh_timeout_secs).await;
+            }
+        }
+    }
+
+    async fn refresh_cache(args: &[&str], timeout_secs: u64) {
+        let refresh = Command::new("pkcon").args(args).kill_on_drop(true).output();
+        match tokio::time::timeout(Duration::from_secs(timeout_secs), refresh).await {
+            Ok(Ok(output)) if output.status.success() => info!("Refreshed the PackageKit cache"),
+            Ok(Ok(output)) => warn!(
+                "pkcon refresh failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+                    .lines()
+                    .last()
+                    .unwrap_or("")
+                    .trim()
+            ),
+            Ok(Err(e)) => warn!("Could not run pkcon refresh: {}", e),
+            Err(_) => warn!("pkcon refresh did not finish within {}s", timeout_secs),
+        }
+    }
+
+    async fn busctl(args: &[&str]) -> bool {
+        Command::new("busctl")
+            .args(args)
+            .output()
+            .await
+            .is_ok_and(|o| o.status.success())
+    }
+}
+
+#[cfg(feature = "dnf5daemon")]
+mod dnf5daemon {
+    use crate::updater::TransactionItem;
+    use futures_util::StreamExt;
+    use std::collections::HashMap;
+    use tracing::debug;
+    use zbus::zvariant::{OwnedObjectPath, OwnedValue, Value};
+    use zbus::{Connection, Proxy};
+
+    const SERVICE: &str = "org.rpm.dnf.v0";
+    const MANAGER: (&str, &str) = ("/org/rpm/dnf/v0", "org.rpm.dnf.v0.SessionManager");
+    const BASE: &str = "org.rpm.dnf.v0.Base";
+    const RPM: &str = "org.rpm.dnf.v0.rpm.Rpm";
+    const GOAL: &str = "org.rpm.dnf.v0.Goal";
+    /// `Goal.resolve`'s result when the transaction can't be built.
+    const RESOLVE_ERROR: u32 = 2;
+
+    type Attrs = HashMap<String, OwnedValue>;
+    /// Object type, action, reason, item attributes and the package itself.
+    type Item = (String, String, String, Attrs, Attrs);
+
+    /// A dnf5daemon session: libdnf5 loaded in the daemon with fup's
+    /// settings, holding a goal until it is resolved or applied.
+    pub struct Session {
+        conn: Connection,
+        path: OwnedObjectPath,
+    }
+
+    impl Session {
+        /// Opens a session on the system bus with the given libdnf5
+        /// settings (installroot, throttle, excludepkgs), expiring the
+        /// metadata cache first when `refresh` is set, like `--refresh`.
+        pub async fn open(settings: &[(&str, String)], refresh: bool) -> zbus::Result<Self> {
+            let conn = Connection::system().await?;
+            let manager = Proxy::new(&conn, SERVICE, MANAGER.0, MANAGER.1).await?;
+            let config: HashMap<&str, &str> =
+                settings.iter().map(|(k, v)| (*k, v.as_str())).collect();
+            let options = HashMap::from([("config", Value::from(config))]);
+            let path: OwnedObjectPath = manager.call("open_session", &(options,)).await?;
+            let session = Self { conn, path };
+            if refresh {
+                let base = session.proxy(BASE).await?;
+                let (_, message): (bool, String) = base.call("clean", &("expire-cache",)).await?;
+                if !message.is_empty() {
+                    debug!("dnf5daemon: {}", message);
+                }
+            }
+            Ok(session)
+        }
+
+        async fn proxy(&self, interface: &'static str) -> zbus::Result<Proxy<'_>> {
+            Proxy::new(&self.conn, SERVICE, self.path.as_ref(), interface).await
+        }
+
+        /// Upgrades the named packages, or everything when none are named,
+        /// and resolves the transaction without applying it.
+        pub async fn resolve_upgrade(
+            &self,
+            packages: &[String],
+        ) -> zbus::Result<Vec<TransactionItem>> {
+            let none: HashMap<&str, Value> = HashMap::new();
+            self.proxy(RPM)
+                .await?
+                .call::<_, _, ()>("upgrade", &(packages, &none))
+                .await?;
+            let goal = self.proxy(GOAL).await?;
+            let (items, result): (Vec<Item>, u32) = goal.call("resolve", &(&none,)).await?;
+            if result == RESOLVE_ERROR {
+                let problems: Vec<String> =
+                    goal.call("get_transaction_problems_string", &()).await?;
+                return Err(zbus::Error::Failure(problems.join("; ")));
+            }
+            let items: Vec<TransactionItem> = items.iter().filter_map(transaction_item).collect();
+            // Old versions of upgraded packages are "Replaced" too; only
+            // those nothing in the transaction reinstalls are obsoletes
+            let kept: Vec<&str> = items
+                .iter()
+                .filter(|i| !i.is_removal())
+                .map(|i| i.name.as_str())
+                .collect();
+            Ok(items
+                .iter()
+                .filter(|i| i.action != "Obsoleted" || !kept.contains(&i.name.as_str()))
+                .cloned()
+                .collect())
+        }
+
+        /// Applies the resolved transaction, passing `progress` the NEVRA
+        /// of each package as the daemon starts on it.
+        pub async fn apply(&self, mut progress: impl FnMut(&str)) -> zbus::Result<()> {
+            let mut started = self
+                .proxy(RPM)
+                .await?
+                .receive_signal("transaction_action_start")
+                .await?;
+            let none = (HashMap::<&str, Value>::new(),);
+            let goal = self.proxy(GOAL).await?;
+            let transaction = goal.call::<_, _, ()>("do_transaction", &none);
+            tokio::pin!(transaction);
+            loop {
+                tokio::select! {
+                    result = &mut transaction => return result,
+                    Some(signal) = started.next() => {
+                        // Signals of every session arrive; only this one's matter
+                        if let Ok((session, nevra, _, _)) = signal.body().deserialize::<(OwnedObjectPath, String, u32, u64)>()
+                            && session == self.path
+                        {
+                            progress(&nevra);
+                        }
+                    }
+                }
+            }
+        }
+
+        /// Ends the session, releasing the daemon's libdnf5 instance.
+        pub async fn close(self) {
+            if let Ok(manager) = Proxy::new(&self.conn, SERVICE, MANAGER.0, MANAGER.1).await
+                && let Err(e) = manager
+                    .call::<_, _, bool>("close_session", &(&self.path,))
+                    .await
+            {
+                debug!("Couldn't close the dnf5daemon session: {}", e);
+            }
+        }
+    }
+
+    /// A package from `Goal.resolve`, in the shape `dnf5 update --assumeno`
+    /// is parsed into. Other objects, like groups, are left out.
+    fn transaction_item((object, action, _, _, package): &Item) -> Option<TransactionItem> {
+        if object != "Package" {
+            return None;
+        }
+        let text = |key: &str| {
+            package
+                .get(key)
+                .and_then(|v| <&str>::try_from(v).ok())
+                .unwrap_or_default()
+                .to_string()
+        };
+        let action = match action.as_str() {
+            "Install" => "Installing",
+            "Upgrade" => "Upgrading",
+            "Downgrade" => "Downgrading",
+            "Reinstall" => "Reinstalling",
+            "Remove" => "Removing",
+            "Replaced" => "Obsoleted",
+            other => other,
+        };
+        Some(TransactionItem {
+            action: action.to_string(),
+            name: text("name"),
+            version: text("evr"),
+            from: None,
+            repo: text("repo_id"),
+            size: package
+                .get("download_size")
+                .and_then(|v| u64::try_from(v).ok())
+                .unwrap_or(0),
+        })
+    }
+}
+
+mod backup {
+    use crate::config::BackupConfig;
+    use crate::error::{Result, UpdateError};
+    use tokio::process::Command;
+    use tracing::{info, warn};
+
+    const TARBALL_PREFIX: &str = "fup-etc-";
+
+    /// Snapshots /etc before a system update, via etckeeper when installed and
+    /// a timestamped tarball otherwise. Returns a reference to the snapshot.
+    pub async fn backup_etc(config: &BackupConfig) -> Result<String> {
+        if crate::system::command_exists("etckeeper") {
+            commit_etckeeper().await
+        } else {
+            let path = write_tarball(config).await?;
+            prune_tarballs(config);
+            Ok(path)
+        }
+    }
+
+    async fn commit_etckeeper() -> Result<String> {
+        let output = Command::new("etckeeper")
+            .args(["commit", "fup: pre-update snapshot"])
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            let details = format!(
+                "{}\n{}",
+                String::from_utf8_lossy(&output.stdout).trim(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            if !nothing_to_commit(&details) {
+                return Err(UpdateError::Backup(format!(
+                    "etckeeper commit failed: {}",
+                    details.trim()
+                )));
+            }
+            info!("etckeeper: nothing to commit, /etc already recorded");
+        }
+
+        let rev = Command::new("etckeeper")
+            .args(["vcs", "rev-parse", "--short", "HEAD"])
+            .output()
+            .await?;
+        let rev = String::from_utf8_lossy(&rev.stdout).trim().to_string();
+
+        info!("etckeeper snapshot of /etc at {}", rev);
+        Ok(if rev.is_empty() {
+            "etckeeper".to_string()
+        } else {
+            format!("etckeeper:{}", rev)
+        })
+    }
+
+    /// Whether a failed `etckeeper commit` only found nothing to commit,
+    /// which the VCS reports with a non-zero exit.
+    pub fn nothing_to_commit(output: &str) -> bool {
+        output
+            .lines()
+            .any(|l| l.trim() == "nothing to commit, working tree clean")
+    }
+
+    async fn write_tarball(config: &BackupConfig) -> Result<String> {
+        std::fs::create_dir_all(&config.dir)?;
+
+        let name = format!(
+            "{}{}.tar.gz",
+            TARBALL_PREFIX,
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        );
+        let path = config.dir.join(name);
+
+        let output = Command::new("tar")
+            .args([
+                "--create",
+                "--gzip",
+                "--xattrs",
+                "--acls",
+                "--selinux",
+                "--file",
+            ])
+            .arg(&path)
+            .args(["--directory", "/", "etc"])
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            let _ = std::fs::remove_file(&path);
+            return Err(UpdateError::Backup(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        info!("Backed up /etc to {}", path.display());
+        Ok(path.display().to_string())
+    }
+
+    /// Removes all but the newest `keep` tarballs written by fup.
+    fn prune_tarballs(config: &BackupConfig) {
+        let Ok(entries) = std::fs::read_dir(&config.dir) else {
+            return;
+        };
+
+        let mut tarballs: Vec<_> = entries
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with(TARBALL_PREFIX))
+            })
+            .collect();
+
+        // Timestamped names sort chronologically
+        tarballs.sort();
+        let excess = tarballs.len().saturating_sub(config.keep);
+        for old in &tarballs[..excess] {
+            if let Err(e) = std::fs::remove_file(old) {
+                warn!("Failed to remove old backup {}: {}", old.display(), e);
+            }
+        }
+    }
+}
+
+mod bundle {
+    use crate::error::{Result, UpdateError};
+    use crate::self_update::{CHECKSUMS, SIGNATURE, digest, expected_digest, verify_signature};
+    use base64::Engine;
+    use serde::{Deserialize, Serialize};
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use tracing::debug;
+
+    pub const MANIFEST: &str = "manifest.json";
+    /// Downloaded RPMs, the Flatpak sideload repository and firmware cabinets.
+    pub const RPMS: &str = "rpms";
+    pub const FLATPAK: &str = "flatpak";
+    pub const FIRMWARE: &str = "firmware";
+
+    /// What a bundle holds and where it was made. Packages only fit the
+    /// release and architecture they were resolved for.
+    #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+    pub struct Manifest {
+        pub created: String,
+        pub host: String,
+        pub release: String,
+        pub arch: String,
+        /// RPM file names under `rpms/`.
+        pub packages: Vec<String>,
+        /// Flatpak applications in the sideload repository under `flatpak/`.
+        pub flatpaks: Vec<String>,
+        /// Cabinet file names under `firmware/`.
+        pub firmware: Vec<String>,
+    }
+
+    impl Manifest {
+        pub fn for_this_host() -> Self {
+            Self {
+                created: chrono::Local::now().to_rfc3339(),
+                host: std::fs::read_to_string("/proc/sys/kernel/hostname")
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+                release: release(),
+                arch: std::env::consts::ARCH.to_string(),
+                ..Self::default()
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.packages.is_empty() && self.flatpaks.is_empty() && self.firmware.is_empty()
+        }
+    }
+
+    fn failed(message: impl std::fmt::Display) -> UpdateError {
+        UpdateError::Bundle(message.to_string())
+    }
+
+    /// `VERSION_ID` from /etc/os-release, e.g. "41".
+    fn release() -> String {
+        std::fs::read_to_string("/etc/os-release")
+            .unwrap_or_default()
+            .lines()
+            .find_map(|l| l.strip_prefix("VERSION_ID="))
+            .map(|v| v.trim_matches('"').to_string())
+            .unwrap_or_default()
+    }
+
+    /// Sorted names of the files in `dir` ending in `suffix`.
+    pub fn file_names(dir: &Path, suffix: &str) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|name| name.ends_with(suffix))
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Every file under `dir`, relative to it and sorted, apart from the
+    /// checksum list and its signature.
+    fn files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let mut found = Vec::new();
+        let mut pending = vec![PathBuf::new()];
+        while let Some(relative) = pending.pop() {
+            for entry in std::fs::read_dir(dir.join(&relative))? {
+                let entry = entry?;
+                let path = relative.join(entry.file_name());
+                if entry.file_type()?.is_dir() {
+                    pending.push(path);
+                } else if path != Path::new(CHECKSUMS) && path != Path::new(SIGNATURE) {
+                    found.push(path);
+                }
+            }
+        }
+        found.sort();
+        Ok(found)
+    }
+
+    /// Downloads a firmware cabinet into `dir`, returning its file name.
+    pub async fn download(url: &str, dir: &Path) -> Result<String> {
+        let name = url
+            .rsplit('/')
+            .next()
+            .filter(|n| n.ends_with(".cab"))
+            .unwrap_or("firmware.cab");
+        debug!("Downloading {}", url);
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(600))
+            .user_agent(concat!("fup/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .map_err(|e| failed(e.to_string()))?;
+        let body = async {
+            client
+                .get(url)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await
+        }
+        .await
+        .map_err(|e| failed(format!("{}: {}", url, e)))?;
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(dir.join(name), &body)?;
+        Ok(name.to_string())
+    }
+
+    /// Writes the manifest and a SHA256SUMS covering every file in the
+    /// bundle, signed with the ed25519 key in `signing_key` when given.
+    pub fn seal(dir: &Path, manifest: &Manifest, signing_key: Option<&Path>) -> Result<()> {
+        let json = serde_json::to_string_pretty(manifest).map_err(|e| failed(e.to_string()))?;
+        std::fs::write(dir.join(MANIFEST), json + "\n")?;
+
+        let mut sums = String::new();
+        for file in files(dir)? {
+            sums.push_str(&format!(
+                "{}  {}\n",
+                digest(&std::fs::read(dir.join(&file))?),
+                file.display()
+            ));
+        }
+        std::fs::write(dir.join(CHECKSUMS), &sums)?;
+
+        if let Some(path) = signing_key {
+            let key = ring::signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(&read_key(path)?)
+                .map_err(|e| {
+                    failed(format!(
+                        "{} is not a PKCS#8 ed25519 key: {}",
+                        path.display(),
+                        e
+                    ))
+                })?;
+            let signature =
+                base64::engine::general_purpose::STANDARD.encode(key.sign(sums.as_bytes()));
+            std::fs::write(dir.join(SIGNATURE), signature + "\n")?;
+        }
+        Ok(())
+    }
+
+    /// A private key as DER, or as the base64 or PEM text of it.
+    fn read_key(path: &Path) -> Result<Vec<u8>> {
+        let raw = std::fs::read(path).map_err(|e| failed(format!("{}: {}", path.display(), e)))?;
+        let text: String = String::from_utf8_lossy(&raw)
+            .lines()
+            .filter(|l| !l.starts_with("-----"))
+            .collect();
+        Ok(base64::engine::general_purpose::STANDARD
+            .decode(text.trim())
+            .unwrap_or(raw))
+    }
+
+    /// Checks SHA256SUMS against its signature when `public_key` is set,
+    /// then every file in the bundle against SHA256SUMS, and returns the
+    /// manifest if the bundle was made for this release and architecture.
+    pub fn open(dir: &Path, public_key: &str) -> Result<Manifest> {
+        let sums = std::fs::read_to_string(dir.join(CHECKSUMS))
+            .map_err(|e| failed(format!("{} has no {}: {}", dir.display(), CHECKSUMS, e)))?;
+        if !public_key.is_empty() {
+            let signature = std::fs::read_to_string(dir.join(SIGNATURE)).map_err(|_| {
+                failed(format!(
+                    "the bundle has no {} but [bundle] public_key is set",
+                    SIGNATURE
+                ))
+            })?;
+            verify_signature(&sums, &signature, public_key, "[bundle] public_key")
+                .map_err(failed)?;
+        }
+
+        let files = files(dir)?;
+        for file in &files {
+            let name = file.display().to_string();
+            let expected = expected_digest(&sums, &name)
+                .ok_or_else(|| failed(format!("{} is not listed in {}", name, CHECKSUMS)))?;
+            if !expected.eq_ignore_ascii_case(&digest(&std::fs::read(dir.join(file))?)) {
+                return Err(failed(format!("{} does not match its checksum", name)));
+            }
+        }
+        if sums.lines().filter(|l| !l.trim().is_empty()).count() != files.len() {
+            return Err(failed(format!("files listed in {} are missing", CHECKSUMS)));
+        }
+
+        let manifest: Manifest = std::fs::read_to_string(dir.join(MANIFEST))
+            .map_err(|e| e.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+            .map_err(|e| failed(format!("unreadable {}: {}", MANIFEST, e)))?;
+        let here = Manifest::for_this_host();
+        if (manifest.release.as_str(), manifest.arch.as_str())
+            != (here.release.as_str(), here.arch.as_str())
+        {
+            return Err(failed(format!(
+                "the bundle was made on release {} ({}) by {}, this host runs {} ({})",
+                manifest.release, manifest.arch, manifest.host, here.release, here.arch
+            )));
+        }
+        Ok(manifest)
+    }
+}
+
+mod mirror {
+    use crate::config::MirrorConfig;
+    use crate::error::{Result, UpdateError};
+    use crate::ui::Themed;
+    use colored::Colorize;
+    use std::{
+        collections::HashMap,
+        path::{Path, PathBuf},
+        time::SystemTime,
+    };
+    use tracing::debug;
+
+    /// Prefix of the repositories clients add for `[mirror] url`, so they
+    /// don't clash with the ones they stand in for.
+    const REPO_PREFIX: &str = "fup-mirror-";
+
+    fn failed(message: impl std::fmt::Display) -> UpdateError {
+        UpdateError::Mirror(message.to_string())
+    }
+
+    fn mirror_dir(config: &MirrorConfig) -> Result<&Path> {
+        if config.dir.is_empty() {
+            return Err(failed("[mirror] dir is not set"));
+        }
+        Ok(Path::new(&config.dir))
+    }
+
+    /// `[mirror] repos`, or every repository enabled under `root`.
+    fn repo_ids(config: &MirrorConfig, root: &Path) -> Vec<String> {
+        if !config.repos.is_empty() {
+            return config.repos.clone();
+        }
+        crate::repos::load(root)
+            .into_iter()
+            .filter(|r| r.enabled)
+            .map(|r| r.id)
+            .collect()
+    }
+
+    /// dnf5 options that swap the repositories for their copies under
+    /// `[mirror] url`. Packages are still checked against the keys in the
+    /// RPM database.
+    pub fn dnf_options(config: &MirrorConfig, root: &Path) -> Vec<String> {
+        let ids = repo_ids(config, root);
+        if config.url.is_empty() || ids.is_empty() {
+            return Vec::new();
+        }
+        let base = config.url.trim_end_matches('/');
+        let mut options = Vec::new();
+        for id in &ids {
+            options.push(format!(
+                "--repofrompath={}{},{}/{}",
+                REPO_PREFIX, id, base, id
+            ));
+            options.push(format!("--setopt={}{}.gpgcheck=1", REPO_PREFIX, id));
+        }
+        let mirrored: Vec<String> = ids
+            .iter()
+            .map(|id| format!("{}{}", REPO_PREFIX, id))
+            .collect();
+        options.push(format!("--repo={}", mirrored.join(",")));
+        options
+    }
+
+    /// Brings each mirrored repository up to date with `dnf5 reposync`,
+    /// prunes versions beyond `[mirror] keep` and rebuilds its metadata,
+    /// keeping the upstream advisories and groups.
+    pub async fn sync(config: &MirrorConfig) -> Result<()> {
+        let dir = mirror_dir(config)?;
+        for tool in ["dnf5", "createrepo_c"] {
+            if !crate::system::command_exists(tool) {
+                return Err(UpdateError::CommandNotFound(tool.into()));
+            }
+        }
+        let ids = repo_ids(config, Path::new("/"));
+        if ids.is_empty() {
+            return Err(failed("no repository is enabled"));
+        }
+        std::fs::create_dir_all(dir)?;
+
+        let mut failures = Vec::new();
+        for id in &ids {
+            println!("{} {}...", "Syncing".bold(), id);
+            match sync_repo(config, dir, id).await {
+                Ok((packages, pruned)) => println!(
+                    "{} {}: {} package(s){}",
+                    crate::ui::sym("✓").success(),
+                    id,
+                    packages,
+                    if pruned > 0 {
+                        format!(", {} old version(s) pruned", pruned)
+                    } else {
+                        String::new()
+                    }
+                ),
+                Err(e) => {
+                    println!("{} {}: {}", crate::ui::sym("✗").failure(), id, e);
+                    failures.push(id.as_str());
+                }
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(failed(format!("could not sync {}", failures.join(", "))))
+        }
+    }
+
+    /// Returns the number of packages the repository holds afterwards and
+    /// how many were pruned.
+    async fn sync_repo(config: &MirrorConfig, dir: &Path, id: &str) -> Result<(usize, usize)> {
+        let repoid = format!("--repoid={}", id);
+        let download = format!("--download-path={}", dir.display());
+        // --remote-time gives each file its upstream time, which pruning goes by
+        let mut args = vec![
+            "reposync",
+            repoid.as_str(),
+            download.as_str(),
+            "--download-metadata",
+            "--remote-time",
+        ];
+        if config.keep == 1 {
+            args.push("--newest-only");
+        }
+        crate::clean::command("dnf5", &args).await?;
+
+        let repo = dir.join(id);
+        let packages = packages(&repo);
+        let old = old_packages(&packages, config.keep);
+        for path in &old {
+            debug!("Removing {}", path.display());
+            std::fs::remove_file(path)?;
+        }
+        let repo = repo.display().to_string();
+        crate::clean::command(
+            "createrepo_c",
+            &["--update", "--keep-all-metadata", repo.as_str()],
+        )
+        .await?;
+        Ok((packages.len() - old.len(), old.len()))
+    }
+
+    /// Every RPM under `dir` with its modification time.
+    fn packages(dir: &Path) -> Vec<(PathBuf, SystemTime)> {
+        let mut found = Vec::new();
+        for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            match entry.metadata() {
+                Ok(meta) if meta.is_dir() => found.extend(packages(&path)),
+                Ok(meta) if path.extension().is_some_and(|e| e == "rpm") => {
+                    found.push((path, meta.modified().unwrap_or(SystemTime::UNIX_EPOCH)))
+                }
+                _ => {}
+            }
+        }
+        found
+    }
+
+    /// "name.arch" of an RPM file named "name-version-release.arch.rpm".
+    fn name_arch(path: &Path) -> Option<String> {
+        let stem = path.file_name()?.to_str()?.strip_suffix(".rpm")?;
+        let (nvr, arch) = stem.rsplit_once('.')?;
+        let (nv, _release) = nvr.rsplit_once('-')?;
+        let (name, _version) = nv.rsplit_once('-')?;
+        Some(format!("{}.{}", name, arch))
+    }
+
+    /// The files beyond the `keep` newest of each package and architecture.
+    /// Goes by file time rather than comparing versions, like old kernels
+    /// go by install time.
+    pub fn old_packages(packages: &[(PathBuf, SystemTime)], keep: usize) -> Vec<PathBuf> {
+        if keep == 0 {
+            return Vec::new();
+        }
+        let mut by_name: HashMap<String, Vec<&(PathBuf, SystemTime)>> = HashMap::new();
+        for package in packages {
+            if let Some(key) = name_arch(&package.0) {
+                by_name.entry(key).or_default().push(package);
+            }
+        }
+        let mut old: Vec<PathBuf> = by_name
+            .into_values()
+            .flat_map(|mut versions| {
+                versions.sort_by_key(|(_, time)| std::cmp::Reverse(*time));
+                versions
+                    .into_iter()
+                    .skip(keep)
+                    .map(|(path, _)| path.clone())
+            })
+            .collect();
+        old.sort();
+        old
+    }
+
+    /// Lists the mirrored repositories with their package count, size and
+    /// when their metadata was last rebuilt.
+    pub fn status(config: &MirrorConfig) -> Result<()> {
+        let dir = mirror_dir(config)?;
+        println!("\n{}", format!("Mirror at {}:", dir.display()).bold());
+        for id in repo_ids(config, Path::new("/")) {
+            let repo = dir.join(&id);
+            let packages = packages(&repo);
+            let bytes: u64 = packages
+                .iter()
+                .filter_map(|(p, _)| p.metadata().ok())
+                .map(|m| m.len())
+                .sum();
+            let synced = std::fs::metadata(repo.join("repodata/repomd.xml"))
+                .and_then(|m| m.modified())
+                .map(|t| {
+                    chrono::DateTime::<chrono::Local>::from(t)
+                        .format("synced %Y-%m-%d %H:%M")
+                        .to_string()
+                })
+                .unwrap_or_else(|_| "never synced".into());
+            println!(
+                "  {:<28} {:>6} package(s) {:>10}  {}",
+                id,
+                packages.len(),
+                crate::size::format(bytes),
+                synced.dimmed()
+            );
+        }
+        if config.url.is_empty() {
+            println!(
+                "\nServe {} over HTTP and set [mirror] url on the hosts updating from it.",
+                dir.display()
+            );
+        } else {
+            println!("\nUpdates come from {}.", config.url.accent());
+        }
+        Ok(())
+    }
+}
+
+mod kmods {
+    use crate::config::KernelConfig;
+    use std::{
+        path::Path,
+        time::{Duration, Instant},
+    };
+    use tokio::process::Command;
+    use tracing::{debug, info, warn};
+
+    const BUILD_POLL_INTERVAL: Duration = Duration::from_secs(5);
+    const AKMODS_PROCESSES: &[&str] = &["akmods", "akmodsbuild"];
+
+    /// Checks that every akmod and DKMS module has been built for the newest
+    /// installed kernel. An akmods build that is still running is waited for,
+    /// and a build is started if none ran. Returns one line per missing module.
+    pub async fn verify(config: &KernelConfig) -> Vec<String> {
+        let Some(kernel) = newest_kernel().await else {
+            return Vec::new();
+        };
+        let running = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+        if kernel == running.trim() {
+            return Vec::new();
+        }
+
+        let akmods = akmod_names().await;
+        let dkms = dkms_modules().await;
+        if akmods.is_empty() && dkms.is_empty() {
+            return Vec::new();
+        }
+        info!("Verifying kernel modules for {}", kernel);
+
+        let deadline = Instant::now() + Duration::from_secs(config.module_build_timeout_mins * 60);
+        let mut started_build = false;
+        let mut missing = missing_akmods(&akmods, &kernel);
+        while !missing.is_empty() && Instant::now() < deadline {
+            if crate::system::find_process(AKMODS_PROCESSES).is_some() {
+                debug!("akmods is still building, waiting");
+                tokio::time::sleep(BUILD_POLL_INTERVAL).await;
+            } else if !started_build {
+                info!("Building akmods for {}", kernel);
+                started_build = true;
+                let build = Command::new("akmods").args(["--kernels", &kernel]).status();
+                let status = tokio::time::timeout_at(deadline.into(), build).await;
+                let code = status.ok().and_then(|s| s.ok()).and_then(|s| s.code());
+                crate::audit::command("akmods", &["--kernels", &kernel], code);
+            } else {
+                break;
+            }
+            missing = missing_akmods(&akmods, &kernel);
+        }
+
+        let mut problems: Vec<String> = missing
+            .into_iter()
+            .map(|name| format!("akmod-{} has no module built for {}", name, kernel))
+            .collect();
+
+        let mut missing = missing_dkms(&dkms, &kernel).await;
+        if !missing.is_empty() {
+            info!("Running dkms autoinstall for {}", kernel);
+            let status = Command::new("dkms")
+                .args(["autoinstall", "-k", &kernel])
+                .status()
+                .await;
+            crate::audit::command(
+                "dkms",
+                &["autoinstall", "-k", &kernel],
+                status.ok().and_then(|s| s.code()),
+            );
+            missing = missing_dkms(&dkms, &kernel).await;
+        }
+        problems.extend(
+            missing
+                .into_iter()
+                .map(|module| format!("DKMS module {} is not installed for {}", module, kernel)),
+        );
+
+        for problem in &problems {
+            warn!("{}", problem);
+        }
+        problems
+    }
+
+    /// The most recently installed kernel, as `uname -r` would print it.
+    pub async fn newest_kernel() -> Option<String> {
+        let output = Command::new("rpm")
+            .args(["-q", "--last", "kernel-core"])
+            .output()
+            .await
+            .ok()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let first = stdout.lines().next()?.split_whitespace().next()?;
+        first.strip_prefix("kernel-core-").map(str::to_string)
+    }
+
+    /// Names of installed akmod packages ("nvidia" for akmod-nvidia).
+    async fn akmod_names() -> Vec<String> {
+        let Ok(output) = Command::new("rpm")
+            .args(["-qa", "--qf", "%{NAME}\n", "akmod-*"])
+            .output()
+            .await
+        else {
+            return Vec::new();
+        };
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|l| l.trim().strip_prefix("akmod-"))
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// akmods installs each built module under extra/<name> for its kernel.
+    fn missing_akmods(names: &[String], kernel: &str) -> Vec<String> {
+        let extra = Path::new("/usr/lib/modules").join(kernel).join("extra");
+        names
+            .iter()
+            .filter(|name| !extra.join(name.as_str()).is_dir())
+            .cloned()
+            .collect()
+    }
+
+    /// Modules registered with DKMS, as "name/version".
+    async fn dkms_modules() -> Vec<String> {
+        if !crate::system::command_exists("dkms") {
+            return Vec::new();
+        }
+        let mut modules: Vec<String> = dkms_status(None)
+            .await
+            .into_iter()
+            .filter_map(|l| Some(l.split([',', ':']).next()?.trim().to_string()))
+            .collect();
+        modules.sort();
+        modules.dedup();
+        modules
+    }
+
+    async fn missing_dkms(modules: &[String], kernel: &str) -> Vec<String> {
+        let installed = dkms_status(Some(kernel)).await;
+        modules
+            .iter()
+            .filter(|m| {
+                !installed
+                    .iter()
+                    .any(|l| l.starts_with(m.as_str()) && l.trim_end().ends_with("installed"))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Lines of `dkms status`, e.g. "nvidia/550.67, 6.9.1-200.fc40.x86_64, x86_64: installed".
+    async fn dkms_status(kernel: Option<&str>) -> Vec<String> {
+        let mut cmd = Command::new("dkms");
+        cmd.arg("status");
+        if let Some(kernel) = kernel {
+            cmd.args(["-k", kernel]);
+        }
+        match cmd.output().await {
+            Ok(output) => String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::to_string)
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+mod reboot {
+    use crate::config::RebootConfig;
+    use crate::error::{Result, UpdateError};
+    use chrono::{Local, NaiveTime};
+    use serde::{Deserialize, Serialize};
+    use tokio::process::Command;
+    use tracing::{info, warn};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum RebootMethod {
+        /// Firmware, bootloader and kernel: the full POST.
+        Full,
+        /// Restarts userspace only; the running kernel stays.
+        Soft,
+        /// Boots straight into the new kernel, skipping firmware and bootloader.
+        Kexec,
+        Auto,
+    }
+
+    impl RebootMethod {
+        pub fn label(self) -> &'static str {
+            match self {
+                RebootMethod::Full | RebootMethod::Auto => "Reboot now",
+                RebootMethod::Soft => "Soft reboot now (userspace only, keeps the running kernel)",
+                RebootMethod::Kexec => "Fast reboot into the new kernel now (kexec)",
+            }
+        }
+    }
+
+    /// The reboot methods that can finish this update, fastest first. A soft
+    /// reboot only helps when the kernel is unchanged; kexec only when it isn't.
+    pub async fn available_methods() -> Vec<RebootMethod> {
+        let running = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+        let new_kernel = crate::kmods::newest_kernel()
+            .await
+            .filter(|k| k != running.trim());
+
+        let mut methods = Vec::new();
+        match new_kernel {
+            Some(_) if crate::system::command_exists("kexec") => methods.push(RebootMethod::Kexec),
+            Some(_) => {}
+            None if supports_soft_reboot().await => methods.push(RebootMethod::Soft),
+            None => {}
+        }
+        methods.push(RebootMethod::Full);
+        methods
+    }
+
+    /// Turns the configured method into one that applies now, falling back to
+    /// a full reboot.
+    pub async fn resolve(method: RebootMethod) -> RebootMethod {
+        let available = available_methods().await;
+        match method {
+            RebootMethod::Auto => available[0],
+            m if available.contains(&m) => m,
+            m => {
+                warn!(
+                    "{:?} reboot doesn't apply to this update, doing a full reboot",
+                    m
+                );
+                RebootMethod::Full
+            }
+        }
+    }
+
+    /// `systemctl soft-reboot` arrived in systemd 254.
+    async fn supports_soft_reboot() -> bool {
+        let Ok(output) = Command::new("systemctl").arg("--version").output().await else {
+            return false;
+        };
+        String::from_utf8_lossy(&output.stdout)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|v| v.parse::<u32>().ok())
+            .is_some_and(|v| v >= 254)
+    }
+
+    /// Reboots right away with the given (resolved) method.
+    pub async fn reboot_now(method: RebootMethod) -> Result<()> {
+        info!("Rebooting: {:?}", method);
+        match method {
+            RebootMethod::Soft => systemctl("soft-reboot").await,
+            RebootMethod::Kexec => match load_kexec_kernel().await {
+                Ok(()) => systemctl("kexec").await,
+                Err(e) => {
+                    warn!(
+                        "Could not stage the new kernel for kexec ({}), doing a full reboot instead",
+                        e
+                    );
+                    systemctl("reboot").await
+                }
+            },
+            RebootMethod::Full | RebootMethod::Auto => systemctl("reboot").await,
+        }
+    }
+
+    async fn systemctl(verb: &str) -> Result<()> {
+        let status = Command::new("systemctl").arg(verb).status().await?;
+        crate::audit::command("systemctl", &[verb], status.code());
+        if status.success() {
+            Ok(())
+        } else {
+            Err(UpdateError::CommandFailed {
+                cmd: format!("systemctl {}", verb),
+                code: status.code().unwrap_or(-1),
+                details: String::new(),
+            })
+        }
+    }
+
+    /// Stages the newest kernel and its initramfs for `systemctl kexec`,
+    /// reusing the running kernel's command line. The file-based syscall
+    /// (`kexec -s`) goes first since it is the only one allowed under
+    /// Secure Boot lockdown; the classic load is the fallback.
+    async fn load_kexec_kernel() -> Result<()> {
+        let kernel = crate::kmods::newest_kernel()
+            .await
+            .ok_or_else(|| UpdateError::Preflight("no installed kernel found for kexec".into()))?;
+        let image = format!("/boot/vmlinuz-{}", kernel);
+        let initrd = format!("--initrd=/boot/initramfs-{}.img", kernel);
+        let mut code = -1;
+        for syscall in [Some("--kexec-file-syscall"), None] {
+            let args: Vec<&str> = ["--load"]
+                .into_iter()
+                .chain(syscall)
+                .chain([&*image, &initrd, "--reuse-cmdline"])
+                .collect();
+            let status = Command::new("kexec").args(&args).status().await?;
+            crate::audit::command("kexec", &args, status.code());
+            if status.success() {
+                return Ok(());
+            }
+            code = status.code().unwrap_or(-1);
+        }
+        Err(UpdateError::CommandFailed {
+            cmd: format!("kexec --load {}", image),
+            code,
+            details: String::new(),
+        })
+    }
+
+    /// Parses "HH:MM", as used by `--reboot-at` and `[reboot] window`.
+    pub fn parse_time(text: &str) -> std::result::Result<NaiveTime, String> {
+        NaiveTime::parse_from_str(text.trim(), "%H:%M")
+            .map_err(|_| format!("expected a time like 03:30, got {:?}", text))
+    }
+
+    /// "02:00-05:00" as (start, end). The window may wrap past midnight.
+    pub fn parse_window(text: &str) -> std::result::Result<(NaiveTime, NaiveTime), String> {
+        let (start, end) = text
+            .split_once('-')
+            .ok_or_else(|| format!("expected a window like 02:00-05:00, got {:?}", text))?;
+        Ok((parse_time(start)?, parse_time(end)?))
+    }
+
+    /// Whether `now` falls inside the window, which ends before `end`.
+    pub fn in_window((start, end): (NaiveTime, NaiveTime), now: NaiveTime) -> bool {
+        if start <= end {
+            now >= start && now < end
+        } else {
+            now >= start || now < end
+        }
+    }
+
+    /// When an automatic reboot should happen: right away (None) inside the
+    /// configured window or without one, otherwise at the window's start.
+    pub fn auto_reboot_time(config: &RebootConfig) -> Option<NaiveTime> {
+        if config.window.trim().is_empty() {
+            return None;
+        }
+        // Checked when the config is loaded
+        let window = parse_window(&config.window).ok()?;
+        (!in_window(window, Local::now().time())).then_some(window.0)
+    }
+
+    /// Schedules a reboot with `shutdown -r`, which also sends `message` to
+    /// logged-in users. Without a time the reboot happens in one minute.
+    /// Returns when it will happen, for display.
+    pub async fn schedule(at: Option<NaiveTime>, message: &str) -> Result<String> {
+        let when = at.map_or_else(|| "+1".to_string(), |t| t.format("%H:%M").to_string());
+        let args = ["-r", &when, message];
+        let status = Command::new("shutdown").args(args).status().await?;
+        crate::audit::command("shutdown", &args, status.code());
+        if !status.success() {
+            return Err(UpdateError::CommandFailed {
+                cmd: format!("shutdown -r {}", when),
+                code: status.code().unwrap_or(-1),
+                details: String::new(),
+            });
+        }
+
+        info!("Reboot scheduled for {}", when);
+        Ok(match at {
+            Some(t) => format!("at {}", t.format("%H:%M")),
+            None => "in 1 minute".to_string(),
+        })
+    }
+}
+
+mod schedule {
+    use crate::config::ScheduleConfig;
+    use crate::error::{Result, UpdateError};
+    use crate::ui::Themed;
+    use crate::updater::ShutdownSignal;
+    use chrono::{Datelike, Days, Local, NaiveDateTime, NaiveTime, Weekday};
+    use serde::{Deserialize, Serialize};
+    use tracing::info;
+
+    /// What an update run does outside `[schedule] allowed_windows`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum OutsideWindow {
+        /// Exit with `EXIT_CODE` before changing anything.
+        Abort,
+        /// Wait for the next window to open, then go ahead.
+        Defer,
+    }
+
+    /// Exit status of a run refused outside the maintenance window
+    /// (EX_TEMPFAIL), so timers and wrappers can tell it from a failure.
+    pub const EXIT_CODE: u8 = 75;
+
+    /// A maintenance window: "Sat 02:00-06:00", "Mon-Fri 22:00-01:00",
+    /// "Sat,Sun 03:00-05:00", or just "02:00-04:00" for every day. A window
+    /// that wraps past midnight belongs to the day it starts on.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Window {
+        /// Empty means every day.
+        days: Vec<Weekday>,
+        start: NaiveTime,
+        end: NaiveTime,
+    }
+
+    impl Window {
+        pub fn parse(text: &str) -> std::result::Result<Self, String> {
+            let text = text.trim();
+            let (days, times) = match text.rsplit_once(' ') {
+                Some((days, times)) => (parse_days(days.trim())?, times),
+                None => (Vec::new(), text),
+            };
+            let (start, end) = times
+                .split_once('-')
+                .ok_or_else(|| format!("expected [DAYS] HH:MM-HH:MM, got {:?}", text))?;
+            Ok(Self {
+                days,
+                start: crate::reboot::parse_time(start)?,
+                end: crate::reboot::parse_time(end)?,
+            })
+        }
+
+        fn on(&self, day: Weekday) -> bool {
+            self.days.is_empty() || self.days.contains(&day)
+        }
+
+        pub fn contains(&self, at: NaiveDateTime) -> bool {
+            let (day, time) = (at.weekday(), at.time());
+            if self.start < self.end {
+                self.on(day) && time >= self.start && time < self.end
+            } else {
+                (self.on(day) && time >= self.start) || (self.on(day.pred()) && time < self.end)
+            }
+        }
+
+        /// When the window next opens after `after`.
+        fn next_start(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+            (0..=7)
+                .filter_map(|d| after.date().checked_add_days(Days::new(d)))
+                .map(|date| date.and_time(self.start))
+                .find(|start| *start > after && self.on(start.weekday()))
+        }
+    }
+
+    /// "Sat", "Mon-Fri" or "Sat,Sun"; ranges may wrap ("Fri-Mon").
+    fn parse_days(text: &str) -> std::result::Result<Vec<Weekday>, String> {
+        let day = |name: &str| {
+            name.trim()
+                .parse::<Weekday>()
+                .map_err(|_| format!("unknown day {:?}", name.trim()))
+        };
+        let mut days = Vec::new();
+        for part in text.split(',') {
+            match part.split_once('-') {
+                Some((from, to)) => {
+                    let (mut day, last) = (day(from)?, day(to)?);
+                    days.push(day);
+                    while day != last {
+                        day = day.succ();
+                        days.push(day);
+                    }
+                }
+                None => days.push(day(part)?),
+            }
+        }
+        Ok(days)
+    }
+
+    /// None when an update may run at `now`, otherwise when the next
+    /// window opens.
+    pub fn next_opening(
+        config: &ScheduleConfig,
+        now: NaiveDateTime,
+    ) -> Result<Option<NaiveDateTime>> {
+        let windows = config
+            .allowed_windows
+            .iter()
+            .map(|w| {
+                Window::parse(w)
+                    .map_err(|e| UpdateError::Config(format!("[schedule] allowed_windows: {}", e)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if windows.is_empty() || windows.iter().any(|w| w.contains(now)) {
+            return Ok(None);
+        }
+        Ok(windows.iter().filter_map(|w| w.next_start(now)).min())
+    }
+
+    /// Holds an update run to the maintenance windows, refusing it or
+    /// waiting for the next one per `[schedule] outside_window`.
+    pub async fn enforce(config: &ScheduleConfig, shutdown: &ShutdownSignal) -> Result<()> {
+        let now = Local::now().naive_local();
+        let Some(opens) = next_opening(config, now)? else {
+            return Ok(());
+        };
+        let when = opens.format("%a %H:%M").to_string();
+        match config.outside_window {
+            OutsideWindow::Abort => Err(UpdateError::OutsideWindow(format!(
+                "the next one opens {}",
+                when
+            ))),
+            OutsideWindow::Defer => {
+                info!("Outside the maintenance window, deferring until {}", when);
+                println!(
+                    "{}",
+                    format!("Outside the maintenance window; waiting until {} (--ignore-window runs now)", when)
+                        .warning()
+                );
+                let wait = (opens - now).to_std().unwrap_or_default();
+                let mut shutdown = shutdown.clone();
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => Ok(()),
+                    Ok(_) = shutdown.wait_for(Option::is_some) => Err(UpdateError::Cancelled),
+                }
+            }
+        }
+    }
+}
+
+mod history {
+    use crate::error::Result;
+    use crate::pkgdiff::PackageChange;
+    use crate::updater::{Stage, StageStats, UpdateSummary};
+    use chrono::{DateTime, Local};
+    use serde::{Deserialize, Serialize};
+    use std::{collections::BTreeMap, io::Write, path::Path};
+    use tracing::warn;
+
+    /// Number of recent runs used as the baseline for "usual" stage durations.
+    const BASELINE_RUNS: usize = 10;
+    /// A stage is flagged when it takes this many times its usual duration.
+    const SLOWDOWN_FACTOR: f64 = 3.0;
+    /// Ignore slowdowns smaller than this, so a 2s stage taking 8s is not flagged.
+    const SLOWDOWN_MIN_SECS: f64 = 60.0;
+    /// Failures are only called out when the stage has been healthy at least this long.
+    const QUIET_FAILURE_DAYS: i64 = 30;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct RunRecord {
+        /// Empty in records from before runs had IDs.
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub id: String,
+        pub timestamp: DateTime<Local>,
+        pub stages: BTreeMap<Stage, StageStats>,
+        pub errors: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub backup: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub artifacts: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub download_limit: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub changes: Vec<PackageChange>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub verification: Vec<String>,
+    }
+
+    impl RunRecord {
+        pub fn from_summary(started: DateTime<Local>, summary: &UpdateSummary) -> Self {
+            Self {
+                id: crate::artifacts::run_id().to_string(),
+                timestamp: started,
+                stages: summary.stages.clone(),
+                errors: summary.errors.clone(),
+                backup: summary.backup.clone(),
+                artifacts: summary.artifacts.clone(),
+                download_limit: summary.download_limit.clone(),
+                changes: summary.changes.clone(),
+                verification: summary.verification.clone(),
+            }
+        }
+    }
+
+    /// Reads all records from the history file. A missing file is an empty history.
+    pub fn load(path: &Path) -> Vec<RunRecord> {
+        let Ok(content) = std::fs::read_to_string(path) else {
+            return Vec::new();
+        };
+
+        content
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .filter_map(|l| match serde_json::from_str(l) {
+                Ok(record) => Some(record),
+                Err(e) => {
+                    warn!(
+                        "Skipping malformed history entry in {}: {}",
+                        path.display(),
+                        e
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Appends a record, trimming the file to the newest `max_entries` records.
+    pub fn append(path: &Path, record: &RunRecord, max_entries: usize) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut records = load(path);
+        records.push(record.clone());
+
+        if records.len() > max_entries {
+            let excess = records.len() - max_entries;
+            records.drain(..excess);
+            let mut content = String::new();
+            for r in &records {
+                content.push_str(&serde_json::to_string(r).map_err(std::io::Error::other)?);
+                content.push('\n');
+            }
+            std::fs::write(path, content)?;
+        } else {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            writeln!(
+                file,
+                "{}",
+                serde_json::to_string(record).map_err(std::io::Error::other)?
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[derive(Debug, Clone, Default)]
+    pub struct Comparison {
+        pub previous: Option<RunRecord>,
+        pub anomalies: Vec<String>,
+    }
+
+    /// Compares the current run against the history of earlier runs.
+    pub fn compare(current: &RunRecord, history: &[RunRecord]) -> Comparison {
+        let mut comparison = Comparison {
+            previous: history.last().cloned(),
+            anomalies: Vec::new(),
+        };
+
+        for (stage, stats) in &current.stages {
+            if let Some(usual) = usual_duration(*stage, history) {
+                let ratio = stats.duration_secs / usual;
+                if !stats.failed
+                    && ratio >= SLOWDOWN_FACTOR
+                    && stats.duration_secs - usual >= SLOWDOWN_MIN_SECS
+                {
+                    comparison.anomalies.push(format!(
+                        "{} stage took {:.1}x longer than usual ({} vs ~{})",
+                        stage.label(),
+                        ratio,
+                        format_duration(stats.duration_secs),
+                        format_duration(usual)
+                    ));
+                }
+            }
+
+            if stats.failed {
+                let last_failure = history
+                    .iter()
+                    .rev()
+                    .find(|r| r.stages.get(stage).is_some_and(|s| s.failed));
+                let since = last_failure.or(history.first()).map(|r| r.timestamp);
+
+                if let Some(since) = since {
+                    let days = (current.timestamp - since).num_days();
+                    if days >= QUIET_FAILURE_DAYS {
+                        comparison.anomalies.push(format!(
+                            "First {} failure in {} days",
+                            stage.label().to_lowercase(),
+                            days
+                        ));
+                    }
+                }
+            }
+        }
+
+        comparison
+    }
+
+    /// Median duration of a stage over its recent successful runs.
+    fn usual_duration(stage: Stage, history: &[RunRecord]) -> Option<f64> {
+        let mut samples: Vec<f64> = history
+            .iter()
+            .rev()
+            .filter_map(|r| r.stages.get(&stage))
+            .filter(|s| !s.failed && !s.cancelled)
+            .take(BASELINE_RUNS)
+            .map(|s| s.duration_secs)
+            .collect();
+
+        if samples.len() < 3 {
+            return None;
+        }
+
+        samples.sort_by(f64::total_cmp);
+        Some(samples[samples.len() / 2])
+    }
+
+    pub fn format_duration(secs: f64) -> String {
+        let secs = secs.round() as u64;
+        match secs {
+            0..=59 => format!("{}s", secs),
+            60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+            _ => format!("{}h {:02}m", secs / 3600, (secs % 3600) / 60),
+        }
+    }
+}
+
+mod audit {
+    use crate::config::AuditConfig;
+    use crate::pkgdiff::PackageChange;
+    use chrono::{DateTime, Local};
+    use nix::fcntl::{FlockArg, flock};
+    use serde::Serialize;
+    use sha2::{Digest, Sha256};
+    use std::{
+        fs::File,
+        io::{Read, Seek, SeekFrom, Write},
+        os::fd::AsRawFd,
+        sync::{Mutex, OnceLock},
+    };
+    use tracing::{debug, warn};
+
+    /// The open audit file. Other fup processes append to it too, so the
+    /// line to chain to is read under a lock on every append.
+    static TRAIL: OnceLock<Mutex<File>> = OnceLock::new();
+
+    /// One line of the audit file. `prev` is the SHA-256 of the line before
+    /// it, so editing or deleting an entry breaks the chain from there on.
+    #[derive(Serialize)]
+    struct Entry<'a> {
+        timestamp: DateTime<Local>,
+        pid: u32,
+        run: &'static str,
+        #[serde(flatten)]
+        event: Event<'a>,
+        prev: String,
+    }
+
+    #[derive(Serialize)]
+    #[serde(tag = "event", rename_all = "lowercase")]
+    enum Event<'a> {
+        Start {
+            host: String,
+            uid: u32,
+            euid: u32,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            sudo_user: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            sudo_uid: Option<String>,
+            argv: Vec<String>,
+        },
+        Command {
+            argv: Vec<&'a str>,
+            /// None when the command was killed or never started.
+            exit_code: Option<i32>,
+        },
+        Changes {
+            changes: &'a [PackageChange],
+        },
+        /// A safety policy that failed, and whether the run went ahead anyway.
+        Policy {
+            check: &'a str,
+            subjects: &'a [String],
+            decision: &'a str,
+        },
+        Finish {
+            result: &'a str,
+        },
+    }
+
+    /// Opens the audit file and records who started this run and how.
+    /// Failing to open it (e.g. a non-root `fup clean`) only disables auditing.
+    pub fn start(config: &AuditConfig) {
+        if !config.enabled {
+            return;
+        }
+        let opened = (|| -> std::io::Result<File> {
+            if let Some(parent) = config.file.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::OpenOptions::new()
+                .create(true)
+                .read(true)
+                .append(true)
+                .open(&config.file)
+        })();
+
+        match opened {
+            Ok(file) => {
+                let _ = TRAIL.set(Mutex::new(file));
+            }
+            Err(e) => {
+                debug!(
+                    "Audit trail disabled, cannot open {}: {}",
+                    config.file.display(),
+                    e
+                );
+                return;
+            }
+        }
+
+        let host = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        append(Event::Start {
+            host: host.trim().to_string(),
+            uid: nix::unistd::getuid().as_raw(),
+            euid: nix::unistd::geteuid().as_raw(),
+            sudo_user: std::env::var("SUDO_USER").ok(),
+            sudo_uid: std::env::var("SUDO_UID").ok(),
+            argv: std::env::args().collect(),
+        });
+    }
+
+    /// Records a command that was run to change the system, with its exact arguments.
+    pub fn command(program: &str, args: &[&str], exit_code: Option<i32>) {
+        let argv = std::iter::once(program)
+            .chain(args.iter().copied())
+            .collect();
+        append(Event::Command { argv, exit_code });
+    }
+
+    pub fn changes(changes: &[PackageChange]) {
+        append(Event::Changes { changes });
+    }
+
+    pub fn policy(check: &str, subjects: &[String], decision: &str) {
+        append(Event::Policy {
+            check,
+            subjects,
+            decision,
+        });
+    }
+
+    pub fn finish(result: &str) {
+        append(Event::Finish { result });
+    }
+
+    fn append(event: Event) {
+        let Some(trail) = TRAIL.get() else {
+            return;
+        };
+        let mut file = trail.lock().unwrap();
+        // Held until the line is written, so a concurrent run can't chain
+        // to the same line and fork the chain
+        if let Err(e) = flock(file.as_raw_fd(), FlockArg::LockExclusive) {
+            warn!("Failed to lock the audit trail: {}", e);
+            return;
+        }
+        let written = last_line(&mut file).and_then(|last| {
+            let entry = Entry {
+                timestamp: Local::now(),
+                pid: std::process::id(),
+                run: crate::artifacts::run_id(),
+                event,
+                prev: last.as_deref().map(hash).unwrap_or_default(),
+            };
+            let line = serde_json::to_string(&entry).map_err(std::io::Error::other)?;
+            file.write_all(format!("{}\n", line).as_bytes())
+        });
+        let _ = flock(file.as_raw_fd(), FlockArg::Unlock);
+        if let Err(e) = written {
+            warn!("Failed to write the audit trail: {}", e);
+        }
+    }
+
+    /// The last non-empty line of `file`, read backwards from its end so the
+    /// never-rotated trail isn't loaded whole.
+    pub fn last_line(file: &mut File) -> std::io::Result<Option<String>> {
+        let len = file.seek(SeekFrom::End(0))?;
+        let mut tail = Vec::new();
+        let mut start = len;
+        while start > 0 {
+            let chunk = start.min(4096);
+            start -= chunk;
+            let mut buf = vec![0; chunk as usize];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut buf)?;
+            buf.extend_from_slice(&tail);
+            tail = buf;
+            // A newline before the last line's text means it's complete
+            let text = tail.trim_ascii_end();
+            if text.iter().rposition(|&b| b == b'\n').is_some() {
+                break;
+            }
+        }
+        let text = String::from_utf8_lossy(tail.trim_ascii_end()).into_owned();
+        Ok(text
+            .lines()
+            .last()
+            .filter(|l| !l.trim().is_empty())
+            .map(str::to_string))
+    }
+
+    fn hash(line: &str) -> String {
+        Sha256::digest(line.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+mod clean {
+    use crate::config::CleanConfig;
+    use crate::error::{Result, UpdateError};
+    use crate::ui::Themed;
+    use colored::Colorize;
+    use std::{path::Path, process::Stdio};
+    use tokio::{io::AsyncWriteExt, process::Command};
+    use tracing::{debug, info};
+
+    const DNF_CACHE: &str = "/var/cache/libdnf5";
+    const FWUPD_CACHE: &str = "/var/cache/fwupd";
+
+    #[derive(Clone, Copy)]
+    enum Kind {
+        DnfCache,
+        FlatpakUnused,
+        Journal,
+        FwupdDownloads,
+    }
+
+    impl Kind {
+        fn label(self) -> &'static str {
+            match self {
+                Kind::DnfCache => "dnf5 cache",
+                Kind::FlatpakUnused => "Unused Flatpak runtimes",
+                Kind::Journal => "Journal",
+                Kind::FwupdDownloads => "fwupd downloads",
+            }
+        }
+    }
+
+    /// One kind of reclaimable space.
+    struct Item {
+        kind: Kind,
+        /// None when the size can't be known in advance.
+        bytes: Option<u64>,
+        detail: String,
+    }
+
+    /// Lists what can be reclaimed, and removes it when `apply` is set.
+    pub async fn run(config: &CleanConfig, apply: bool) -> Result<()> {
+        let items = survey(config).await;
+        if items.is_empty() {
+            println!("Nothing to clean; every step is disabled in [clean].");
+            return Ok(());
+        }
+
+        println!(
+            "\n{}",
+            if apply {
+                "Cleaning:"
+            } else {
+                "Reclaimable space:"
+            }
+            .bold()
+        );
+        for item in &items {
+            let size = item
+                .bytes
+                .map(crate::size::format)
+                .unwrap_or_else(|| "?".into());
+            println!(
+                "  {:<28} {:>10}  {}",
+                item.kind.label(),
+                size,
+                item.detail.dimmed()
+            );
+        }
+        let total: u64 = items.iter().filter_map(|i| i.bytes).sum();
+        println!(
+            "  {:<28} {:>10}",
+            "Total".bold(),
+            crate::size::format(total).bold()
+        );
+
+        if !apply {
+            println!("\nRun {} to reclaim it.", "sudo fup clean --apply".accent());
+            return Ok(());
+        }
+
+        let mut failures = Vec::new();
+        for item in &items {
+            match reclaim(config, item.kind).await {
+                Ok(()) => println!("{} {}", crate::ui::sym("✓").success(), item.kind.label()),
+                Err(e) => {
+                    println!(
+                        "{} {}: {}",
+                        crate::ui::sym("✗").failure(),
+                        item.kind.label(),
+                        e
+                    );
+                    failures.push(item.kind.label());
+                }
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(UpdateError::CommandFailed {
+                cmd: "fup clean".into(),
+                code: 1,
+                details: format!("failed to clean {}", failures.join(", ")),
+            })
+        }
+    }
+
+    async fn survey(config: &CleanConfig) -> Vec<Item> {
+        let mut items = Vec::new();
+
+        if (config.dnf_packages || config.dnf_metadata) && crate::system::command_exists("dnf5") {
+            let what = match (config.dnf_packages, config.dnf_metadata) {
+                (true, true) => "packages and metadata",
+                (true, false) => "downloaded packages",
+                _ => "metadata",
+            };
+            // Only packages can be sized separately from the whole cache
+            let bytes = if config.dnf_metadata {
+                dir_size(Path::new(DNF_CACHE), &|_| true)
+            } else {
+                dir_size(Path::new(DNF_CACHE), &|p| {
+                    p.extension().is_some_and(|e| e == "rpm")
+                })
+            };
+            items.push(Item {
+                kind: Kind::DnfCache,
+                bytes: Some(bytes),
+                detail: what.into(),
+            });
+        }
+
+        if config.flatpak_unused && crate::system::command_exists("flatpak") {
+            let unused = unused_flatpak_refs().await;
+            items.push(Item {
+                kind: Kind::FlatpakUnused,
+                bytes: None,
+                detail: if unused.is_empty() {
+                    "none".into()
+                } else {
+                    unused.join(", ")
+                },
+            });
+        }
+
+        if !config.journal_max_age.is_empty() && crate::system::command_exists("journalctl") {
+            let usage = output("journalctl", &["--disk-usage"]).await;
+            let bytes = usage
+                .split("take up ")
+                .nth(1)
+                .and_then(|rest| crate::size::parse(rest.split_whitespace().next()?));
+            items.push(Item {
+                kind: Kind::Journal,
+                bytes: None,
+                detail: format!(
+                    "{} in total; entries older than {} are removed",
+                    bytes
+                        .map(crate::size::format)
+                        .unwrap_or_else(|| "unknown size".into()),
+                    config.journal_max_age
+                ),
+            });
+        }
+
+        if config.fwupd_downloads {
+            let bytes = dir_size(Path::new(FWUPD_CACHE), &|p| {
+                p.extension().is_some_and(|e| e == "cab")
+            });
+            items.push(Item {
+                kind: Kind::FwupdDownloads,
+                bytes: Some(bytes),
+                detail: FWUPD_CACHE.into(),
+            });
+        }
+
+        items
+    }
+
+    async fn reclaim(config: &CleanConfig, kind: Kind) -> Result<()> {
+        match kind {
+            Kind::DnfCache => {
+                if config.dnf_packages {
+                    command("dnf5", &["clean", "packages"]).await?;
+                }
+                if config.dnf_metadata {
+                    command("dnf5", &["clean", "metadata"]).await?;
+                }
+                Ok(())
+            }
+            Kind::FlatpakUnused => command("flatpak", &["uninstall", "--unused", "-y"]).await,
+            Kind::Journal => {
+                command(
+                    "journalctl",
+                    &[&format!("--vacuum-time={}", config.journal_max_age)],
+                )
+                .await
+            }
+            Kind::FwupdDownloads => {
+                remove_files(Path::new(FWUPD_CACHE), &|p| {
+                    p.extension().is_some_and(|e| e == "cab")
+                })?;
+                Ok(())
+            }
+        }
+    }
+
+    /// flatpak has no dry run for this, so the removal is declined at its prompt.
+    pub async fn unused_flatpak_refs() -> Vec<String> {
+        let Ok(mut child) = Command::new("flatpak")
+            .args(["uninstall", "--unused"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        else {
+            return Vec::new();
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(b"n\n").await;
+        }
+        let Ok(out) = child.wait_with_output().await else {
+            return Vec::new();
+        };
+        String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .filter_map(|l| {
+                let t = l.trim_start();
+                let digits = t.chars().take_while(char::is_ascii_digit).count();
+                if digits == 0 || !t[digits..].starts_with('.') {
+                    return None;
+                }
+                t[digits + 1..]
+                    .split_whitespace()
+                    .next()
+                    .map(str::to_string)
+            })
+            .collect()
+    }
+
+    async fn output(cmd: &str, args: &[&str]) -> String {
+        match Command::new(cmd).args(args).output().await {
+            Ok(out) => String::from_utf8_lossy(&out.stdout).into_owned(),
+            Err(e) => {
+                debug!("{} {}: {}", cmd, args.join(" "), e);
+                String::new()
+            }
+        }
+    }
+
+    pub async fn command(cmd: &str, args: &[&str]) -> Result<()> {
+        info!("Executing: {} {}", cmd, args.join(" "));
+        let out = Command::new(cmd)
+            .args(args)
+            .stdout(Stdio::null())
+            .output()
+            .await?;
+        crate::audit::command(cmd, args, out.status.code());
+        if out.status.success() {
+            Ok(())
+        } else {
+            Err(UpdateError::from_output(
+                format!("{} {}", cmd, args.join(" ")),
+                out.status.code().unwrap_or(-1),
+                String::from_utf8_lossy(&out.stderr).trim().to_string(),
+            ))
+        }
+    }
+
+    fn dir_size(dir: &Path, matches: &dyn Fn(&Path) -> bool) -> u64 {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return 0;
+        };
+        entries
+            .flatten()
+            .map(|entry| {
+                let path = entry.path();
+                match entry.metadata() {
+                    Ok(meta) if meta.is_dir() => dir_size(&path, matches),
+                    Ok(meta) if matches(&path) => meta.len(),
+                    _ => 0,
+                }
+            })
+            .sum()
+    }
+
+    fn remove_files(dir: &Path, matches: &dyn Fn(&Path) -> bool) -> std::io::Result<()> {
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return Ok(());
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                remove_files(&path, matches)?;
+            } else if matches(&path) {
+                debug!("Removing {}", path.display());
+                std::fs::remove_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+mod verify {
+    use crate::config::VerifyConfig;
+    use std::path::Path;
+    use tokio::process::Command;
+    use tracing::{debug, info};
+
+    /// Units systemd currently lists as failed.
+    pub async fn failed_units() -> Vec<String> {
+        let output = Command::new("systemctl")
+            .args(["--failed", "--plain", "--no-legend", "--no-pager"])
+            .output()
+            .await;
+        match output {
+            Ok(out) => String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .filter_map(|l| l.split_whitespace().next())
+                .map(str::to_string)
+                .collect(),
+            Err(e) => {
+                debug!("systemctl --failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// What was already wrong before the update, so it isn't blamed on it.
+    #[derive(Debug, Default)]
+    pub struct Baseline {
+        pub failed_units: Vec<String>,
+        pub dnf_problems: Vec<String>,
+    }
+
+    pub async fn baseline(config: &VerifyConfig, root: Option<&Path>) -> Baseline {
+        Baseline {
+            failed_units: if config.failed_units && root.is_none() {
+                failed_units().await
+            } else {
+                Vec::new()
+            },
+            dnf_problems: if config.dnf_check {
+                dnf_problems(root).await
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    /// What `dnf5 check` reports, one problem per line.
+    async fn dnf_problems(root: Option<&Path>) -> Vec<String> {
+        if !crate::system::command_exists("dnf5") {
+            return Vec::new();
+        }
+        let mut cmd = Command::new("dnf5");
+        if let Some(root) = root {
+            cmd.arg(format!("--installroot={}", root.display()));
+        }
+        match cmd.arg("check").output().await {
+            Ok(out) if !out.status.success() => {
+                let details = String::from_utf8_lossy(&out.stdout);
+                let lines: Vec<String> = details
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty())
+                    .map(|l| format!("dnf5 check: {}", l))
+                    .collect();
+                if lines.is_empty() {
+                    vec![format!("dnf5 check failed ({})", out.status)]
+                } else {
+                    lines
+                }
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Checks the updated system and returns the problems found. Failed
+    /// units and dependency problems already there before the update are
+    /// not counted against it.
+    pub async fn run(config: &VerifyConfig, before: &Baseline, root: Option<&Path>) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if config.dnf_check {
+            info!("Checking for broken dependencies...");
+            problems.extend(new_problems(dnf_problems(root).await, &before.dnf_problems));
+        }
+
+        // Services only run on the live system
+        if config.failed_units && root.is_none() {
+            problems.extend(
+                new_problems(failed_units().await, &before.failed_units)
+                    .map(|unit| format!("{} failed during the update", unit)),
+            );
+        }
+
+        if config.rpm_verify && !config.critical_packages.is_empty() {
+            info!("Verifying critical package files...");
+            let mut cmd = Command::new("rpm");
+            if let Some(root) = root {
+                cmd.arg("--root").arg(root);
+            }
+            cmd.args(["-V", "--nofiledigest"])
+                .args(&config.critical_packages);
+            if let Ok(out) = cmd.output().await {
+                problems.extend(
+                    String::from_utf8_lossy(&out.stdout)
+                        .lines()
+                        .filter(|l| is_file_problem(l))
+                        .map(|l| format!("rpm -V: {}", l.trim())),
+                );
+            }
+        }
+
+        problems
+    }
+
+    /// The entries of `after` that aren't in `before`.
+    pub fn new_problems(after: Vec<String>, before: &[String]) -> impl Iterator<Item = String> {
+        after
+            .into_iter()
+            .filter(|problem| !before.contains(problem))
+    }
+
+    /// `rpm -V` lines for changed or missing files. Config files are meant to
+    /// be edited, and packages that aren't installed aren't a regression.
+    fn is_file_problem(line: &str) -> bool {
+        let mut cols = line.split_whitespace();
+        let (Some(flags), Some(second)) = (cols.next(), cols.next()) else {
+            return false;
+        };
+        if line.starts_with("package ") {
+            return false;
+        }
+        let is_config = second == "c" || second == "g";
+        (flags == "missing" || flags.len() == 9) && !is_config
+    }
+}
+
+mod pkgdiff {
+    use crate::updater::Stage;
+    use serde::{Deserialize, Serialize};
+    use std::{collections::BTreeMap, path::Path};
+    use tokio::process::Command;
+    use tracing::debug;
+
+    /// Installed packages and Flatpak refs with their versions.
+    #[derive(Debug, Clone, Default)]
+    pub struct Snapshot {
+        pub packages: BTreeMap<(Stage, String), String>,
+    }
+
+    /// One package whose presence or version differs between snapshots.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct PackageChange {
+        pub backend: Stage,
+        pub name: String,
+        /// None if the package was added.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub from: Option<String>,
+        /// None if the package was removed.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub to: Option<String>,
+    }
+
+    impl PackageChange {
+        pub fn kind(&self) -> &'static str {
+            match (&self.from, &self.to) {
+                (None, _) => "added",
+                (_, None) => "removed",
+                _ => "upgraded",
+            }
+        }
+
+        /// "1.0-1 → 1.1-1", "+ 1.1-1" or "- 1.0-1".
+        pub fn transition(&self) -> String {
+            match (&self.from, &self.to) {
+                (Some(from), Some(to)) => format!("{} {} {}", from, crate::ui::sym("→"), to),
+                (None, Some(to)) => format!("+ {}", to),
+                (Some(from), None) => format!("- {}", from),
+                (None, None) => String::new(),
+            }
+        }
+    }
+
+    /// Lists what is installed. Flatpak is skipped for alternate roots, as
+    /// it only updates the running system.
+    pub async fn snapshot(root: Option<&Path>) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+
+        let mut rpm = Command::new("rpm");
+        if let Some(root) = root {
+            rpm.arg("--root").arg(root);
+        }
+        rpm.args([
+            "-qa",
+            "--qf",
+            "%{NAME}.%{ARCH} %{EPOCHNUM}:%{VERSION}-%{RELEASE}\n",
+        ]);
+        for line in output_lines(&mut rpm).await {
+            if let Some((name, version)) = line.split_once(' ') {
+                let version = version.strip_prefix("0:").unwrap_or(version);
+                // Install-only packages like the kernel have several versions
+                snapshot
+                    .packages
+                    .entry((Stage::System, name.to_string()))
+                    .and_modify(|v| {
+                        let mut versions: Vec<&str> = v.split(", ").chain([version]).collect();
+                        versions.sort_unstable();
+                        *v = versions.join(", ");
+                    })
+                    .or_insert_with(|| version.to_string());
+            }
+        }
+
+        if root.is_none() {
+            let mut flatpak = Command::new("flatpak");
+            flatpak.args(["list", "--columns=ref,version,active"]);
+            for line in output_lines(&mut flatpak).await {
+                let mut cols = line.split('\t');
+                let (Some(name), version, commit) = (cols.next(), cols.next(), cols.next()) else {
+                    continue;
+                };
+                // Apps without a version string are told apart by commit
+                let version = match (version.map(str::trim), commit.map(str::trim)) {
+                    (Some(v), Some(c)) if !v.is_empty() => format!("{} ({})", v, c),
+                    (_, Some(c)) => c.to_string(),
+                    (Some(v), None) => v.to_string(),
+                    (None, None) => String::new(),
+                };
+                snapshot
+                    .packages
+                    .insert((Stage::Flatpak, name.trim().to_string()), version);
+            }
+        }
+
+        snapshot
+    }
+
+    async fn output_lines(cmd: &mut Command) -> Vec<String> {
+        match cmd.output().await {
+            Ok(out) if out.status.success() => String::from_utf8_lossy(&out.stdout)
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(str::to_string)
+                .collect(),
+            Ok(out) => {
+                debug!("Package listing exited with {}", out.status);
+                Vec::new()
+            }
+            Err(e) => {
+                debug!("Package listing failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn diff(before: &Snapshot, after: &Snapshot) -> Vec<PackageChange> {
+        let mut changes = Vec::new();
+        for ((backend, name), from) in &before.packages {
+            match after.packages.get(&(*backend, name.clone())) {
+                Some(to) if to == from => {}
+                to => changes.push(PackageChange {
+                    backend: *backend,
+                    name: name.clone(),
+                    from: Some(from.clone()),
+                    to: to.cloned(),
+                }),
+            }
+        }
+        for ((backend, name), to) in &after.packages {
+            if !before.packages.contains_key(&(*backend, name.clone())) {
+                changes.push(PackageChange {
+                    backend: *backend,
+                    name: name.clone(),
+                    from: None,
+                    to: Some(to.clone()),
+                });
+            }
+        }
+        changes.sort_by(|a, b| (a.backend, &a.name).cmp(&(b.backend, &b.name)));
+        changes
+    }
+
+    /// "12 upgraded, 2 added, 1 removed".
+    pub fn counts(changes: &[PackageChange]) -> String {
+        let count = |kind| changes.iter().filter(|c| c.kind() == kind).count();
+        format!(
+            "{} upgraded, {} added, {} removed",
+            count("upgraded"),
+            count("added"),
+            count("removed")
+        )
+    }
+}
+
+mod report {
+    use crate::history::format_duration;
+    use crate::updater::{RestartCheck, UpdateSummary};
+    use chrono::{DateTime, Local};
+    use std::path::Path;
+
+    /// Everything known about a finished run, for `--report`.
+    pub struct Report<'a> {
+        pub started: DateTime<Local>,
+        pub finished: DateTime<Local>,
+        pub summary: &'a UpdateSummary,
+        /// None when the reboot check didn't run (dry run, --target, cancelled).
+        pub restart: Option<&'a RestartCheck>,
+        pub module_problems: &'a [String],
+        pub dry_run: bool,
+    }
+
+    enum Block {
+        Heading(String),
+        Table(Vec<&'static str>, Vec<Vec<String>>),
+        List(Vec<String>),
+        Text(String),
+    }
+
+    /// Writes the report as HTML for `.html`/`.htm` paths, Markdown otherwise.
+    pub fn write(path: &Path, report: &Report) -> std::io::Result<()> {
+        let blocks = build(report);
+        let html = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e.eq_ignore_ascii_case("html") || e.eq_ignore_ascii_case("htm"));
+        let title = format!("fup update report: {}", hostname());
+        let content = if html {
+            to_html(&title, &blocks)
+        } else {
+            to_markdown(&title, &blocks)
+        };
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, content)
+    }
+
+    fn hostname() -> String {
+        std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .unwrap_or_else(|_| "unknown host".into())
+    }
+
+    fn build(report: &Report) -> Vec<Block> {
+        let summary = report.summary;
+        let result = if summary.cancelled {
+            "Cancelled"
+        } else if summary.errors.is_empty() && summary.verification.is_empty() {
+            "Succeeded"
+        } else {
+            "Completed with errors"
+        };
+        let duration = (report.finished - report.started).num_milliseconds() as f64 / 1000.0;
+
+        let mut overview = vec![
+            vec!["Run".to_string(), crate::artifacts::run_id().to_string()],
+            vec!["Host".to_string(), hostname()],
+            vec![
+                "Started".to_string(),
+                report.started.format("%Y-%m-%d %H:%M:%S %Z").to_string(),
+            ],
+            vec!["Duration".to_string(), format_duration(duration)],
+            vec!["Result".to_string(), result.to_string()],
+            vec![
+                "fup version".to_string(),
+                env!("CARGO_PKG_VERSION").to_string(),
+            ],
+        ];
+        if report.dry_run {
+            overview.push(vec![
+                "Mode".to_string(),
+                "Dry run (nothing was changed)".to_string(),
+            ]);
+        }
+        if let Some(backup) = &summary.backup {
+            overview.push(vec!["/etc backup".to_string(), backup.clone()]);
+        }
+        if let Some(artifacts) = &summary.artifacts {
+            overview.push(vec!["Artifacts".to_string(), artifacts.clone()]);
+        }
+        if let Some(limit) = &summary.download_limit {
+            overview.push(vec!["Download limit".to_string(), format!("{}/s", limit)]);
+        }
+
+        let mut blocks = vec![
+            Block::Table(vec!["", ""], overview),
+            Block::Heading("Updates".into()),
+        ];
+        if summary.stages.is_empty() {
+            blocks.push(Block::Text("No updates were run.".into()));
+        } else {
+            let rows = summary
+                .stages
+                .iter()
+                .map(|(stage, stats)| {
+                    let mut state = if stats.cancelled {
+                        "Cancelled"
+                    } else if stats.failed {
+                        "Failed"
+                    } else {
+                        "Updated"
+                    }
+                    .to_string();
+                    if stats.retries > 0 {
+                        state.push_str(&format!(" after {} retry(s)", stats.retries));
+                    }
+                    let phases = stats
+                        .phases
+                        .iter()
+                        .map(|p| format!("{} {}", p.name, format_duration(p.duration_secs)))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    vec![
+                        stage.label().to_string(),
+                        state.to_string(),
+                        stats.packages.to_string(),
+                        crate::size::format(stats.downloaded_bytes),
+                        format_duration(stats.duration_secs),
+                        phases,
+                    ]
+                })
+                .collect();
+            blocks.push(Block::Table(
+                vec![
+                    "Backend",
+                    "Result",
+                    "Packages",
+                    "Downloaded",
+                    "Duration",
+                    "Phases",
+                ],
+                rows,
+            ));
+        }
+        if let Some(by) = summary.halted_by {
+            let labels: Vec<&str> = summary.halted.iter().map(|s| s.label()).collect();
+            blocks.push(Block::Text(format!(
+                "Not run because {} failed (fail-fast): {}.",
+                by.label(),
+                labels.join(", ")
+            )));
+        }
+
+        blocks.push(Block::Heading("Package changes".into()));
+        if summary.changes.is_empty() {
+            blocks.push(Block::Text(if report.dry_run {
+                "Not recorded for dry runs.".into()
+            } else {
+                "No packages changed.".into()
+            }));
+        } else {
+            blocks.push(Block::Text(crate::pkgdiff::counts(&summary.changes)));
+            let rows = summary
+                .changes
+                .iter()
+                .map(|c| {
+                    vec![
+                        c.backend.label().to_string(),
+                        c.name.clone(),
+                        c.kind().to_string(),
+                        c.from.clone().unwrap_or_default(),
+                        c.to.clone().unwrap_or_default(),
+                    ]
+                })
+                .collect();
+            blocks.push(Block::Table(
+                vec!["Backend", "Package", "Change", "Before", "After"],
+                rows,
+            ));
+        }
+
+        if !summary.firmware_devices.is_empty() {
+            blocks.push(Block::Heading("Firmware devices".into()));
+            let rows = summary
+                .firmware_devices
+                .iter()
+                .map(|d| {
+                    vec![
+                        d.name.clone(),
+                        d.from.clone().unwrap_or_default(),
+                        d.to.clone().unwrap_or_default(),
+                        d.result.describe(),
+                    ]
+                })
+                .collect();
+            blocks.push(Block::Table(
+                vec!["Device", "Installed", "Available", "Result"],
+                rows,
+            ));
+        }
+
+        if !summary.flatpak_users.is_empty() {
+            blocks.push(Block::Heading("Flatpak users".into()));
+            let rows = summary
+                .flatpak_users
+                .iter()
+                .map(|u| vec![u.user.clone(), u.describe()])
+                .collect();
+            blocks.push(Block::Table(vec!["User", "Result"], rows));
+        }
+
+        if !summary.flatpak_eol.is_empty() {
+            blocks.push(Block::Heading("End-of-life Flatpaks".into()));
+            let rows = summary
+                .flatpak_eol
+                .iter()
+                .map(|f| {
+                    vec![
+                        f.installation.tag(&f.name),
+                        f.runtime.clone().unwrap_or_default(),
+                        f.reason.clone(),
+                        f.suggestion(),
+                    ]
+                })
+                .collect();
+            blocks.push(Block::Table(
+                vec!["Flatpak", "End-of-life runtime", "Reason", "Suggestion"],
+                rows,
+            ));
+        }
+
+        blocks.push(Block::Heading("Errors".into()));
+        if summary.errors.is_empty() {
+            blocks.push(Block::Text("None.".into()));
+        } else {
+            blocks.push(Block::List(summary.errors.clone()));
+        }
+
+        blocks.push(Block::Heading("Verification".into()));
+        if report.dry_run {
+            blocks.push(Block::Text("Not run for dry runs.".into()));
+        } else if summary.verification.is_empty() {
+            blocks.push(Block::Text("No problems found.".into()));
+        } else {
+            blocks.push(Block::List(summary.verification.clone()));
+        }
+
+        blocks.push(Block::Heading("Reboot".into()));
+        match report.restart {
+            None => blocks.push(Block::Text("Not checked.".into())),
+            Some(check) => {
+                blocks.push(Block::Text(match &check.reboot {
+                    Some(reason) => format!("Reboot required: {}", reason),
+                    None => "No reboot required.".into(),
+                }));
+                if !check.services.is_empty() {
+                    blocks.push(Block::Text("Services using outdated libraries:".into()));
+                    blocks.push(Block::List(check.services.clone()));
+                }
+            }
+        }
+        if !report.module_problems.is_empty() {
+            blocks.push(Block::Text(
+                "Kernel modules missing for the new kernel:".into(),
+            ));
+            blocks.push(Block::List(report.module_problems.to_vec()));
+        }
+        blocks
+    }
+
+    fn to_markdown(title: &str, blocks: &[Block]) -> String {
+        let cell = |s: &str| s.replace('|', "\\|").replace('\n', " ");
+        let mut out = format!("# {}\n", title);
+        for block in blocks {
+            out.push('\n');
+            match block {
+                Block::Heading(h) => out.push_str(&format!("## {}\n", h)),
+                Block::Text(t) => out.push_str(&format!("{}\n", t)),
+                Block::List(items) => {
+                    for item in items {
+                        out.push_str(&format!("- {}\n", item.replace('\n', " ")));
+                    }
+                }
+                Block::Table(header, rows) => {
+                    out.push_str(&format!("| {} |\n", header.join(" | ")));
+                    out.push_str(&format!("|{}\n", "---|".repeat(header.len())));
+                    for row in rows {
+                        let cells: Vec<String> = row.iter().map(|c| cell(c)).collect();
+                        out.push_str(&format!("| {} |\n", cells.join(" | ")));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn to_html(title: &str, blocks: &[Block]) -> String {
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{0}</title>\n\
+             <style>body{{font-family:sans-serif;max-width:60em;margin:2em auto}}\
+             table{{border-collapse:collapse}}td,th{{border:1px solid #ccc;padding:.3em .6em;text-align:left}}</style>\n\
+             </head>\n<body>\n<h1>{0}</h1>\n",
+            escape(title)
+        );
+        for block in blocks {
+            match block {
+                Block::Heading(h) => out.push_str(&format!("<h2>{}</h2>\n", escape(h))),
+                Block::Text(t) => out.push_str(&format!("<p>{}</p>\n", escape(t))),
+                Block::List(items) => {
+                    out.push_str("<ul>\n");
+                    for item in items {
+                        out.push_str(&format!("<li>{}</li>\n", escape(item)));
+                    }
+                    out.push_str("</ul>\n");
+                }
+                Block::Table(header, rows) => {
+                    out.push_str("<table>\n");
+                    if header.iter().any(|h| !h.is_empty()) {
+                        let cells: String = header
+                            .iter()
+                            .map(|h| format!("<th>{}</th>", escape(h)))
+                            .collect();
+                        out.push_str(&format!("<tr>{}</tr>\n", cells));
+                    }
+                    for row in rows {
+                        let cells: String = row
+                            .iter()
+                            .map(|c| format!("<td>{}</td>", escape(c)))
+                            .collect();
+                        out.push_str(&format!("<tr>{}</tr>\n", cells));
+                    }
+                    out.push_str("</table>\n");
+                }
+            }
+        }
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+
+    fn escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    }
+}
+
+mod logfile {
+    use crate::config::LoggingConfig;
+    use chrono::{DateTime, Local, NaiveDate};
+    use serde::{Deserialize, Serialize};
+    use std::{
+        fs::{File, OpenOptions},
+        io::{self, Write},
+        path::{Path, PathBuf},
+    };
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Format {
+        Text,
+        /// One JSON object per event, for Loki/Elasticsearch and friends.
+        Json,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Rotation {
+        Daily,
+        /// When the file reaches `max_size_mb`.
+        Size,
+        Never,
+    }
+
+    /// The log file, renamed to `<name>.<timestamp>` (and gzipped) when it
+    /// is due for rotation. Only `keep` rotated files are retained.
+    ///
+    /// Runs on tracing-appender's worker thread, so rotating and compressing
+    /// never block the update itself.
+    pub struct RotatingFile {
+        path: PathBuf,
+        rotation: Rotation,
+        max_bytes: u64,
+        keep: usize,
+        compress: bool,
+        file: File,
+        size: u64,
+        day: NaiveDate,
+    }
+
+    impl RotatingFile {
+        pub fn open(config: &LoggingConfig) -> io::Result<Self> {
+            if let Some(parent) = config.file.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let (file, size, day) = open_current(&config.file)?;
+            Ok(Self {
+                path: config.file.clone(),
+                rotation: config.rotation,
+                max_bytes: config.max_size_mb.saturating_mul(1024 * 1024),
+                keep: config.keep,
+                compress: config.compress,
+                file,
+                size,
+                day,
+            })
+        }
+
+        fn due(&self, incoming: usize) -> bool {
+            match self.rotation {
+                Rotation::Daily => self.day != Local::now().date_naive(),
+                Rotation::Size => self.size > 0 && self.size + incoming as u64 > self.max_bytes,
+                Rotation::Never => false,
+            }
+        }
+
+        fn rotate(&mut self) -> io::Result<()> {
+            let modified = std::fs::metadata(&self.path)
+                .and_then(|m| m.modified())
+                .map(DateTime::<Local>::from)
+                .unwrap_or_else(|_| Local::now());
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(modified.format(".%Y%m%d-%H%M%S").to_string());
+            let rotated = PathBuf::from(rotated);
+
+            std::fs::rename(&self.path, &rotated)?;
+            (self.file, self.size, self.day) = open_current(&self.path)?;
+
+            if self.compress {
+                // Best effort: without gzip the rotated file stays as it is
+                let _ = std::process::Command::new("gzip")
+                    .arg("--force")
+                    .arg(&rotated)
+                    .status();
+            }
+            prune(&self.path, self.keep);
+            Ok(())
+        }
+    }
+
+    impl Write for RotatingFile {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.due(buf.len()) {
+                // Keep logging to the old file rather than losing the line
+                let _ = self.rotate();
+            }
+            let written = self.file.write(buf)?;
+            self.size += written as u64;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    /// Opens the log file for appending, with its size and the day it was
+    /// last written to (today for a new file).
+    fn open_current(path: &Path) -> io::Result<(File, u64, NaiveDate)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let meta = file.metadata()?;
+        let day = match meta.modified() {
+            Ok(modified) if meta.len() > 0 => DateTime::<Local>::from(modified).date_naive(),
+            _ => Local::now().date_naive(),
+        };
+        Ok((file, meta.len(), day))
+    }
+
+    /// Removes all but the newest `keep` rotated files next to `path`.
+    fn prune(path: &Path, keep: usize) {
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str()))
+        else {
+            return;
+        };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        let prefix = format!("{}.", name);
+
+        let mut rotated: Vec<PathBuf> = entries
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| n.starts_with(&prefix))
+            })
+            .collect();
+
+        // Timestamped names sort chronologically
+        rotated.sort();
+        let excess = rotated.len().saturating_sub(keep);
+        for old in &rotated[..excess] {
+            let _ = std::fs::remove_file(old);
+        }
+    }
+}
+
+mod telemetry {
+    use crate::config::TelemetryConfig;
+    use crate::error::{Result, UpdateError};
+    use crate::ui::Themed;
+    use colored::Colorize;
+    use opentelemetry::{KeyValue, trace::TracerProvider};
+    use opentelemetry_otlp::{WithExportConfig, WithHttpConfig};
+    use opentelemetry_sdk::{
+        Resource,
+        trace::{SdkTracer, SdkTracerProvider},
+    };
+
+    /// Target of the run, backend and command spans. Only the OTLP layer
+    /// sees them; the terminal, file and journal output stay as they were.
+    pub const TARGET: &str = "fup::telemetry";
+
+    /// Sends the spans still queued when dropped, so it must live until fup exits.
+    pub struct Exporter(SdkTracerProvider);
+
+    impl Drop for Exporter {
+        fn drop(&mut self) {
+            if let Err(e) = self.0.shutdown() {
+                eprintln!(
+                    "{} Failed to export traces: {}",
+                    "Warning:".warning().bold(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Builds the OTLP/HTTP exporter. Spans are batched and sent from a
+    /// background thread, so a slow collector never holds up an update.
+    pub fn tracer(config: &TelemetryConfig) -> std::result::Result<(SdkTracer, Exporter), String> {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_http()
+            .with_endpoint(&config.endpoint)
+            .with_headers(config.headers.clone().into_iter().collect())
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let host = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+        let resource = Resource::builder()
+            .with_service_name(config.service_name.clone())
+            .with_attribute(KeyValue::new("host.name", host.trim().to_string()))
+            .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+            .build();
+
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource)
+            .build();
+        Ok((provider.tracer("fup"), Exporter(provider)))
+    }
+
+    /// Marks a span as finished with `result`; failures get an error status.
+    pub fn record_outcome<T>(span: &tracing::Span, result: &Result<T>) {
+        let outcome = match result {
+            Ok(_) => "ok",
+            Err(UpdateError::Cancelled) => "cancelled",
+            Err(_) => "failed",
+        };
+        span.record("outcome", outcome);
+        if let Err(e) = result
+            && !matches!(e, UpdateError::Cancelled)
+        {
+            span.record("otel.status_code", "error");
+            span.record("otel.status_message", e.english());
+        }
+    }
+}
+
+async fn setup_signal_handler() -> updater::ShutdownSignal {
+    use nix::sys::signal::Signal;
+
+    let (tx, rx) = tokio::sync::watch::channel(None);
+
+    tokio::spawn(async move {
+        let mut sigint = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
+            .expect("Failed to setup SIGINT handler");
+
+        let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to setup SIGTERM handler");
+
+        let signal = tokio::select! {
+            _ = sigint.recv() => Signal::SIGINT,
+            _ = sigterm.recv() => Signal::SIGTERM,
+        };
+        warn!("Received {}, shutting down...", signal);
+
+        let _ = tx.send(Some(signal));
+    });
+
+    rx
+}
+
+/// Installs the terminal, log file, journal and trace export layers. The
+/// returned guards flush the log file and the trace exporter when dropped,
+/// so they must live until fup exits.
+fn setup_logging(
+    verbose: u8,
+    quiet: bool,
+    config: &config::LoggingConfig,
+    telemetry_config: &config::TelemetryConfig,
+) -> (
+    Option<tracing_appender::non_blocking::WorkerGuard>,
+    Option<telemetry::Exporter>,
+) {
+    use tracing_subscriber::{EnvFilter, fmt, prelude::*};
+
+    // Trace spans would otherwise prefix every line with their fields
+    let without_spans = |filter: EnvFilter| {
+        filter.add_directive(
+            format!("{}=off", telemetry::TARGET)
+                .parse()
+                .expect("valid directive"),
+        )
+    };
+
+    // Quiet runs end with one status line instead
+    let level = if quiet {
+        LevelFilter::OFF
+    } else {
+        match verbose {
+            0 => LevelFilter::INFO,
+            1 => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        }
+    };
+
+    // -v raises the file's level too; otherwise `[logging] level` decides
+    let file_level = match verbose {
+        0 => config.level.as_str(),
+        1 => "debug",
+        _ => "trace",
+    };
+    let level_error = EnvFilter::try_new(file_level).err();
+    let file_filter =
+        || without_spans(EnvFilter::try_new(file_level).unwrap_or_else(|_| EnvFilter::new("info")));
+
+    let (file_writer, guard, file_error) = match logfile::RotatingFile::open(config) {
+        Ok(file) => {
+            let (writer, guard) = tracing_appender::non_blocking(file);
+            (Some(writer), Some(guard), None)
+        }
+        Err(e) => (None, None, Some(e)),
+    };
+    let json = config.format == logfile::Format::Json;
+
+    let text_file = file_writer.clone().filter(|_| !json).map(|writer| {
+        fmt::layer()
+            .with_target(true)
+            .with_ansi(false)
+            .with_writer(writer)
+            .with_filter(file_filter())
+    });
+    let json_file = file_writer.filter(|_| json).map(|writer| {
+        fmt::layer()
+            .json()
+            .with_target(true)
+            .with_writer(writer)
+            .with_filter(file_filter())
+    });
+
+    // Fields keep their names (BACKEND=, PHASE=) for `journalctl -t fup BACKEND=System`
+    let (journal, journal_error) = match config.journald.then(tracing_journald::layer) {
+        Some(Ok(layer)) => (
+            Some(
+                layer
+                    .with_syslog_identifier("fup".into())
+                    .with_field_prefix(None)
+                    .with_filter(without_spans(EnvFilter::new("info,fup=debug"))),
+            ),
+            None,
+        ),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+
+    let (traces, exporter, telemetry_error) = match telemetry_config
+        .enabled
+        .then(|| telemetry::tracer(telemetry_config))
+    {
+        Some(Ok((tracer, exporter))) => (
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(EnvFilter::new("info")),
+            ),
+            Some(exporter),
+            None,
+        ),
+        Some(Err(e)) => (None, None, Some(e)),
+        None => (None, None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(
+            fmt::layer()
+                .with_target(false)
+                .with_ansi(ui::color() && !ui::accessible())
+                .with_writer(|| render::Console)
+                .with_filter(without_spans(
+                    EnvFilter::from_default_env().add_directive(level.into()),
+                )),
+        )
+        .with(text_file)
+        .with(json_file)
+        .with(journal)
+        .with(traces)
+        .init();
+
+    if let Some(e) = level_error {
+        warn!(
+            "Invalid [logging] level {:?} ({}); logging info to the file",
+            config.level, e
+        );
+    }
+    if let Some(e) = file_error {
+        warn!("Cannot write {}: {}", config.file.display(), e);
+    }
+    if let Some(e) = journal_error {
+        warn!("Cannot log to the systemd journal: {}", e);
+    }
+    if let Some(e) = telemetry_error {
+        warn!(
+            "Cannot export traces to {}: {}",
+            telemetry_config.endpoint, e
+        );
+    }
+    (guard, exporter)
+}
+
+fn print_banner() {
+    if ui::plain() {
+        println!(
+            "FUP v{} - Fedora Utility Patchworker",
+            env!("CARGO_PKG_VERSION")
+        );
+        return;
+    }
+    println!(
+        "{}",
+        r#"
+╔═══════════════════════════════════════════╗
+║               FUP v2.1.2                  ║
+║     Fedora Utility Patchworker            ║
+╚═══════════════════════════════════════════╝"#
+            .accent()
+    );
+}
+
+fn print_usage() {
+    use clap::CommandFactory;
+
+    if !ui::accessible() {
+        print_banner();
+        println!();
+    }
+
+    let _ = cli::Args::command().print_help();
+}
+
+/// Lists pending updates. At a terminal a list longer than the screen goes
+/// to the pager whole; otherwise long sections are cut short.
+async fn print_available_updates(
+    updates: &updater::AvailableUpdates,
+    new: Option<&cache::NewUpdates>,
+    takeovers: &[updater::RepoTakeover],
+    pager: bool,
+) {
+    if ui::accessible() {
+        print_available_updates_accessible(updates, new, takeovers);
+        return;
+    }
+
+    let paged = pager && std::io::stdout().is_terminal();
+    let text = available_updates_text(updates, new, takeovers, !paged);
+    let rows = console::Term::stdout()
+        .size_checked()
+        .map_or(usize::MAX, |(rows, _)| rows as usize);
+    if paged && text.lines().count() >= rows {
+        show_in_pager(&text).await;
+    } else {
+        print!("{}", text);
+    }
+}
+
+fn available_updates_text(
+    updates: &updater::AvailableUpdates,
+    new: Option<&cache::NewUpdates>,
+    takeovers: &[updater::RepoTakeover],
+    truncate: bool,
+) -> String {
+    let mut out = String::new();
+    macro_rules! emit {
+        () => {
+            out.push('\n')
+        };
+        ($($arg:tt)*) => {{
+            out.push_str(&format!($($arg)*));
+            out.push('\n');
+        }};
+    }
+    let limit = |n: usize| if truncate { n } else { usize::MAX };
+
+    emit!("\n{}", ui::sym("═").repeat(50).accent());
+    emit!("{}", "         Available Updates".accent().bold());
+    emit!("{}\n", ui::sym("═").repeat(50).accent());
+
+    if let Some(new) = new {
+        let since = new.since.format("%Y-%m-%d %H:%M");
+        match new.updates.total_count() {
+            0 => emit!(
+                "  {} since the last check ({})\n",
+                "Nothing new".dimmed(),
+                since
+            ),
+            n => emit!(
+                "  {} since the last check ({})\n",
+                format!("{} new", n).success().bold(),
+                since
+            ),
+        }
+    }
+
+    if updates.is_empty() {
+        emit!(
+            "  {} Your system is up to date!\n",
+            ui::sym("✓").success().bold()
+        );
+        return out;
+    }
+
+    type List = fn(&updater::AvailableUpdates) -> &Vec<String>;
+    let tag = |list: List, item: &String| match new {
+        Some(new) if list(&new.updates).contains(item) => format!(" {}", "new".success().bold()),
+        _ => String::new(),
+    };
+    let size = |item: &String| match updates.sizes.get(item) {
+        Some(size) if size.download > 0 => format!(" {}", size::format(size.download).dimmed()),
+        _ => String::new(),
+    };
+    let total = |items: &Vec<String>| match updates.total_size(items).download {
+        0 => String::new(),
+        bytes => format!(", {} to download", size::format(bytes)),
+    };
+
+    // System packages
+    if !updates.system.is_empty() {
+        emit!(
+            "  {} {} package(s){}\n",
+            "System".warning().bold(),
+            updates.system.len().to_string().white().bold(),
+            total(&updates.system)
+        );
+        for pkg in updates.system.iter().take(limit(15)) {
+            let parts: Vec<&str> = pkg.split_whitespace().collect();
+            if let Some(name) = parts.first() {
+                let version = parts.get(1).unwrap_or(&"");
+                let new = tag(|u| &u.system, pkg);
+                emit!(
+                    "    {} {} {}{}{}",
+                    ui::sym("•").dimmed(),
+                    name,
+                    version.dimmed(),
+                    size(pkg),
+                    new
+                );
+            }
+        }
+        if updates.system.len() > limit(15) {
+            emit!(
+                "    {} ...and {} more",
+                ui::sym("•").dimmed(),
+                (updates.system.len() - 15).to_string().warning()
+            );
+        }
+        let repos: Vec<String> = updates
+            .by_repo()
+            .into_iter()
+            .map(|(repo, count)| {
+                let entry = format!("{} {}", repo, count);
+                if updater::is_third_party(repo) {
+                    entry.warning().to_string()
+                } else {
+                    entry
+                }
+            })
+            .collect();
+        if !repos.is_empty() {
+            emit!("\n    {} {}", "By repository:".dimmed(), repos.join(", "));
+        }
+        emit!();
+        out.push_str(&repo_takeovers_text(takeovers));
+    }
+
+    // Flatpak apps, then the runtimes and extensions they use
+    let flatpak_sections: [(&str, &str, List); 2] = [
+        ("Flatpak", "app(s)", |u| &u.flatpak),
+        ("Flatpak runtimes", "runtime(s)", |u| &u.flatpak_runtimes),
+    ];
+    for (label, unit, list) in flatpak_sections {
+        let items = list(updates);
+        if items.is_empty() {
+            continue;
+        }
+        emit!(
+            "  {} {} {}{}\n",
+            label.warning().bold(),
+            items.len().to_string().white().bold(),
+            unit,
+            total(items)
+        );
+        for item in items.iter().take(limit(10)) {
+            let mut cols = item.split_whitespace();
+            let name = cols.next().unwrap_or(item.as_str());
+            let branch = cols.next().unwrap_or_default();
+            let origin =
+                updater::flatpak_origin(item).map_or(String::new(), |(origin, i)| i.tag(origin));
+            emit!(
+                "    {} {} {} {}{}{}",
+                ui::sym("•").dimmed(),
+                name,
+                branch.dimmed(),
+                origin.dimmed(),
+                size(item),
+                tag(list, item)
+            );
+        }
+        if items.len() > limit(10) {
+            emit!(
+                "    {} ...and {} more",
+                ui::sym("•").dimmed(),
+                (items.len() - 10).to_string().warning()
+            );
+        }
+        emit!();
+    }
+    let remotes: Vec<String> = updates
+        .flatpak_by_remote()
+        .into_iter()
+        .map(|(remote, count)| format!("{} {}", remote, count))
+        .collect();
+    if remotes.len() > 1 {
+        emit!(
+            "  {} {}\n",
+            "Flatpak by remote:".dimmed(),
+            remotes.join(", ")
+        );
+    }
+
+    // Firmware
+    if !updates.firmware.is_empty() {
+        emit!(
+            "  {} {} device(s){}\n",
+            "Firmware".warning().bold(),
+            updates.firmware.len().to_string().white().bold(),
+            total(&updates.firmware)
+        );
+        for fw in &updates.firmware {
+            // fwupdmgr's own "old → new"
+            let line = fw.replace("→", ui::sym("→"));
+            emit!(
+                "    {} {}{}{}",
+                ui::sym("•").dimmed(),
+                line,
+                size(fw),
+                tag(|u| &u.firmware, fw)
+            );
+        }
+        emit!();
+    }
+
+    emit!("{}", ui::sym("═").repeat(50).accent());
+    emit!(
+        "  Total: {} update(s) available{}",
+        updates.total_count().to_string().success().bold(),
+        size_summary(updates.total_size(updates.all_rows()))
+    );
+    emit!("  Run {} to install\n", "sudo fup --update-all".accent());
+    out
+}
+
+/// ", 1.9 GiB to download, 3.2 GiB installed" for the sizes known.
+fn size_summary(total: updater::ItemSize) -> String {
+    match (total.download, total.installed) {
+        (0, 0) => String::new(),
+        (download, 0) => format!(", {} to download", size::format(download)),
+        (0, installed) => format!(", {} installed", size::format(installed)),
+        (download, installed) => {
+            format!(
+                ", {} to download, {} installed",
+                size::format(download),
+                size::format(installed)
+            )
+        }
+    }
+}
+
+fn print_available_updates_accessible(
+    updates: &updater::AvailableUpdates,
+    new: Option<&cache::NewUpdates>,
+    takeovers: &[updater::RepoTakeover],
+) {
+    if let Some(new) = new {
+        ui::announce(&format!(
+            "{} new since the last check on {}.",
+            new.updates.total_count(),
+            new.since.format("%Y-%m-%d %H:%M")
+        ));
+    }
+
+    if updates.is_empty() {
+        ui::announce("Your system is up to date.");
+        return;
+    }
+
+    ui::announce(&format!(
+        "{} update(s) available{}.",
+        updates.total_count(),
+        size_summary(updates.total_size(updates.all_rows()))
+    ));
+
+    let empty = updater::AvailableUpdates::default();
+    let fresh = new.map_or(&empty, |n| &n.updates);
+    let sections = [
+        ("System", "package", &updates.system, &fresh.system),
+        ("Flatpak", "app", &updates.flatpak, &fresh.flatpak),
+        (
+            "Flatpak runtimes",
+            "runtime",
+            &updates.flatpak_runtimes,
+            &fresh.flatpak_runtimes,
+        ),
+        ("Firmware", "device", &updates.firmware, &fresh.firmware),
+    ];
+    for (label, unit, items, fresh) in sections {
+        if items.is_empty() {
+            continue;
+        }
+        ui::announce(&format!(
+            "{}: {} {}(s){}.",
+            label,
+            items.len(),
+            unit,
+            size_summary(updates.total_size(items))
+        ));
+        for item in items {
+            let mut parts = item.split_whitespace();
+            let name = parts.next().unwrap_or(item.as_str());
+            let size = match updates.sizes.get(item) {
+                Some(size) if size.download > 0 => format!(", {}", size::format(size.download)),
+                _ => String::new(),
+            };
+            let new = if fresh.contains(item) {
+                format!("{}, new", size)
+            } else {
+                size
+            };
+            match parts.next() {
+                Some(version) if label == "System" => {
+                    ui::announce(&format!("{} version {}{}.", name, version, new))
+                }
+                _ if label.starts_with("Flatpak") => match updater::flatpak_origin(item) {
+                    Some((origin, updater::Installation::System)) => {
+                        ui::announce(&format!("{} from {}{}.", name, origin, new))
+                    }
+                    Some((origin, updater::Installation::User(user))) => ui::announce(&format!(
+                        "{} from {}, {} installation{}.",
+                        name,
+                        origin,
+                        user.map_or("user".into(), |u| format!("{}'s", u)),
+                        new
+                    )),
+                    None => ui::announce(&format!("{}{}.", item.trim(), new)),
+                },
+                _ => ui::announce(&format!("{}{}.", item.trim(), new)),
+            }
+        }
+        if label == "System" {
+            let repos: Vec<String> = updates
+                .by_repo()
+                .into_iter()
+                .map(|(repo, count)| {
+                    let third_party = if updater::is_third_party(repo) {
+                        " (third-party)"
+                    } else {
+                        ""
+                    };
+                    format!("{} {}{}", repo, count, third_party)
+                })
+                .collect();
+            if !repos.is_empty() {
+                ui::announce(&format!("By repository: {}.", repos.join(", ")));
+            }
+            print_repo_takeovers(takeovers);
+        }
+        // After the last Flatpak section, covering apps and runtimes alike
+        if label == "Flatpak runtimes"
+            || (label == "Flatpak" && updates.flatpak_runtimes.is_empty())
+        {
+            let remotes: Vec<String> = updates
+                .flatpak_by_remote()
+                .into_iter()
+                .map(|(remote, count)| format!("{} {}", remote, count))
+                .collect();
+            if remotes.len() > 1 {
+                ui::announce(&format!("By remote: {}.", remotes.join(", ")));
+            }
+        }
+    }
+
+    ui::announce("Run sudo fup --update-all to install.");
+}
+
+/// Lists end-of-life Flatpaks with what to do about them.
+fn print_flatpak_eol(eol: &[updater::EolFlatpak]) {
+    if eol.is_empty() {
+        return;
+    }
+    if ui::accessible() {
+        for f in eol {
+            let what = match &f.runtime {
+                Some(runtime) => format!("runs on end-of-life runtime {}", runtime),
+                None => "is end-of-life".into(),
+            };
+            ui::announce(&format!(
+                "Warning: {} {}: {}; {}.",
+                f.installation.tag(&f.name),
+                what,
+                f.reason,
+                f.suggestion()
+            ));
+        }
+        return;
+    }
+
+    println!(
+        "  {} {} installed Flatpak(s) are end-of-life:\n",
+        ui::sym("⚠").warning().bold(),
+        eol.len().to_string().warning().bold()
+    );
+    for f in eol {
+        let what = match &f.runtime {
+            Some(runtime) => format!("runtime {} is end-of-life", runtime),
+            None => "end-of-life".into(),
+        };
+        println!(
+            "    {} {} {} {}",
+            ui::sym("•").dimmed(),
+            f.installation.tag(&f.name).bold(),
+            what.warning(),
+            format!("({})", f.reason).dimmed()
+        );
+        println!("      {} {}", ui::sym("→").dimmed(), f.suggestion());
+    }
+    println!();
+}
+
+/// Warns about third-party repositories replacing distro packages, which
+/// is worth a look before applying: a COPR shipping its own build of a
+/// core package takes it over for good.
+fn print_repo_takeovers(takeovers: &[updater::RepoTakeover]) {
+    if takeovers.is_empty() {
+        return;
+    }
+    if ui::accessible() {
+        for t in takeovers {
+            ui::announce(&format!(
+                "Warning: {} would be replaced from third-party repository {}; installed from {}.",
+                t.package, t.repo, t.installed_from
+            ));
+        }
+        return;
+    }
+
+    print!("{}", repo_takeovers_text(takeovers));
+}
+
+fn repo_takeovers_text(takeovers: &[updater::RepoTakeover]) -> String {
+    if takeovers.is_empty() {
+        return String::new();
+    }
+    let mut out = format!(
+        "  {} {} distro package(s) would be replaced by third-party repositories:\n\n",
+        ui::sym("⚠").warning().bold(),
+        takeovers.len().to_string().warning().bold()
+    );
+    for t in takeovers {
+        out.push_str(&format!(
+            "    {} {} from {} {}\n",
+            ui::sym("•").dimmed(),
+            t.package.bold(),
+            t.repo.warning(),
+            format!("(installed from {})", t.installed_from).dimmed()
+        ));
+    }
+    out.push('\n');
+    out
+}
+
+fn print_security_report(advisories: &[security::Advisory], min: Option<security::Severity>) {
+    let scope = match min {
+        Some(min) => format!("{} or higher", min.label()),
+        None => "all severities".to_string(),
+    };
+
+    if ui::accessible() {
+        ui::announce(&format!(
+            "{} pending security advisory(s), {}.",
+            advisories.len(),
+            scope
+        ));
+        for a in advisories {
+            let severity = a.severity.map_or("Unrated", |s| s.label());
+            let cves = if a.cves.is_empty() {
+                "no CVE".to_string()
+            } else {
+                a.cves.join(", ")
+            };
+            ui::announce(&format!(
+                "{}: {}, {}, packages {}.",
+                a.id,
+                severity,
+                cves,
+                a.packages.join(", ")
+            ));
+        }
+        return;
+    }
+
+    println!("\n{}", ui::sym("═").repeat(50).accent());
+    println!("{}", "       Pending Security Advisories".accent().bold());
+    println!("{}\n", ui::sym("═").repeat(50).accent());
+
+    if advisories.is_empty() {
+        println!(
+            "  {} No pending security advisories ({})\n",
+            ui::sym("✓").success().bold(),
+            scope
+        );
+        return;
+    }
+
+    for a in advisories {
+        let severity = match a.severity {
+            Some(security::Severity::Critical) => "Critical".failure().bold(),
+            Some(security::Severity::Important) => "Important".failure(),
+            Some(security::Severity::Moderate) => "Moderate".warning(),
+            Some(security::Severity::Low) => "Low".normal(),
+            None => "Unrated".dimmed(),
+        };
+        println!("  {} [{}]", a.id.white().bold(), severity);
+        if !a.cves.is_empty() {
+            println!("    CVEs:     {}", a.cves.join(", "));
+        }
+        for pkg in &a.packages {
+            println!("    {} {}", ui::sym("•").dimmed(), pkg);
+        }
+        println!();
+    }
+
+    println!("{}", ui::sym("═").repeat(50).accent());
+    println!(
+        "  Total: {} advisory(s), {}",
+        advisories.len().to_string().success().bold(),
+        scope
+    );
+    let level = min
+        .unwrap_or(security::Severity::Low)
+        .label()
+        .to_lowercase();
+    println!(
+        "  Run {} to install only these\n",
+        format!("sudo fup --update-system --min-severity {}", level).accent()
+    );
+}
+
+/// Pipes long output through `$PAGER` (default `less -R`) when stdout is a
+/// terminal; prints it directly otherwise, in accessible mode, or if the pager
+/// can't be started.
+async fn show_in_pager(text: &str) {
+    use tokio::io::AsyncWriteExt;
+
+    if !std::io::stdout().is_terminal() || ui::accessible() {
+        println!("{}", text);
+        return;
+    }
+
+    let pager = std::env::var("PAGER").unwrap_or_else(|_| "less -R".into());
+    let mut parts = pager.split_whitespace();
+    let Some(program) = parts.next() else {
+        println!("{}", text);
+        return;
+    };
+
+    let child = Command::new(program)
+        .args(parts)
+        .stdin(std::process::Stdio::piped())
+        .spawn();
+    let Ok(mut child) = child else {
+        debug!("Cannot start pager {:?}; using the built-in one", program);
+        builtin_pager(text);
+        return;
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // The user quitting the pager early closes the pipe; that's fine
+        let _ = stdin.write_all(text.as_bytes()).await;
+    }
+    let _ = child.wait().await;
+}
+
+/// A screen at a time for systems without less: Enter shows the next one,
+/// `/text` skips to the next line containing text, q quits.
+fn builtin_pager(text: &str) {
+    use std::io::{self, Write};
+
+    let rows = console::Term::stdout().size().0.max(3) as usize - 1;
+    let lines: Vec<&str> = text.lines().collect();
+    let mut top = 0;
+    let mut shown = 0;
+    while top < lines.len() {
+        if shown < top + rows {
+            for line in lines.iter().take(top + rows).skip(shown) {
+                println!("{}", line);
+            }
+            shown = (top + rows).min(lines.len());
+            if shown == lines.len() {
+                return;
+            }
+        }
+        print!("{}", ":".dimmed());
+        let _ = io::stdout().flush();
+        let mut input = String::new();
+        if io::stdin().read_line(&mut input).unwrap_or(0) == 0 {
+            return;
+        }
+        match input.trim() {
+            "q" | "Q" => return,
+            search if search.starts_with('/') => {
+                let needle = search[1..].to_lowercase();
+                let found = lines.iter().enumerate().skip(shown).find(|(_, line)| {
+                    console::strip_ansi_codes(line)
+                        .to_lowercase()
+                        .contains(&needle)
+                });
+                match found {
+                    Some((i, _)) => {
+                        top = i;
+                        shown = i;
+                    }
+                    None => println!("{}", "Pattern not found".warning()),
+                }
+            }
+            _ => top = shown,
+        }
+    }
+}
+
+/// Package changes listed in the summary; reports and history keep them all.
+const SUMMARY_CHANGES: usize = 20;
+
+fn print_summary(summary: &updater::UpdateSummary) {
+    if ui::accessible() {
+        ui::announce(&t!("summary-announce-title"));
+        let results = [
+            ("System", summary.system_updated),
+            ("Flatpak", summary.flatpak_updated),
+            ("Firmware", summary.firmware_updated),
+        ];
+        for (label, updated) in results {
+            if updated {
+                ui::announce(&t!("summary-announce-updated", stage = label));
+            } else {
+                ui::announce(&t!("summary-announce-not-updated", stage = label));
+            }
+        }
+        if let Some(by) = summary.halted_by {
+            let labels: Vec<&str> = summary.halted.iter().map(|s| s.label()).collect();
+            ui::announce(&format!(
+                "Skipped because {} failed: {}.",
+                by.label(),
+                labels.join(", ")
+            ));
+        }
+        if let Some(backup) = &summary.backup {
+            ui::announce(&format!("/etc was backed up to {}.", backup));
+        }
+        ui::announce(&format!("Run {}.", artifacts::run_id()));
+        if let Some(artifacts) = &summary.artifacts {
+            ui::announce(&format!("Run artifacts were saved to {}.", artifacts));
+        }
+        if let Some(limit) = &summary.download_limit {
+            ui::announce(&format!("Downloads were limited to {}/s.", limit));
+        }
+        for (stage, stats) in &summary.stages {
+            let mut sentence = format!(
+                "{} took {}, {} package(s)",
+                stage.label(),
+                history::format_duration(stats.duration_secs),
+                stats.packages
+            );
+            if stats.downloaded_bytes > 0 {
+                sentence.push_str(&format!(
+                    ", {} downloaded",
+                    size::format(stats.downloaded_bytes)
+                ));
+            }
+            ui::announce(&format!("{}.", sentence));
+            for phase in &stats.phases {
+                ui::announce(&format!(
+                    "{}: {}.",
+                    phase.name,
+                    history::format_duration(phase.duration_secs)
+                ));
+            }
+        }
+        for device in &summary.firmware_devices {
+            let versions = match (&device.from, &device.to) {
+                (Some(from), Some(to)) => format!(" from {} to {}", from, to),
+                (None, Some(to)) => format!(" to {}", to),
+                _ => String::new(),
+            };
+            ui::announce(&format!(
+                "Firmware for {}{}: {}.",
+                device.name,
+                versions,
+                device.result.describe()
+            ));
+        }
+        for user in &summary.flatpak_users {
+            ui::announce(&format!("Flatpaks for {}: {}.", user.user, user.describe()));
+        }
+        print_flatpak_eol(&summary.flatpak_eol);
+        if !summary.verification.is_empty() {
+            ui::announce(&format!(
+                "Verification found {} problem(s):",
+                summary.verification.len()
+            ));
+            for problem in &summary.verification {
+                ui::announce(&format!("Problem: {}", problem));
+            }
+        }
+        if !summary.removed.is_empty() {
+            ui::announce(&format!(
+                "{}: {}.",
+                t!("summary-removed"),
+                summary.removed.len()
+            ));
+            for item in summary.removed.iter().take(SUMMARY_CHANGES) {
+                ui::announce(&format!(
+                    "{} {}, {}.",
+                    item.name,
+                    item.version,
+                    item.removal_reason()
+                ));
+            }
+        }
+        if !summary.changes.is_empty() {
+            ui::announce(&format!(
+                "Package changes: {}.",
+                pkgdiff::counts(&summary.changes)
+            ));
+            for change in summary.changes.iter().take(SUMMARY_CHANGES) {
+                ui::announce(&format!(
+                    "{} {}: {}.",
+                    change.name,
+                    change.kind(),
+                    change.transition()
+                ));
+            }
+        }
+        if summary.cancelled {
+            ui::announce(&t!("summary-announce-cancelled"));
+        }
+        if !summary.errors.is_empty() {
+            ui::announce(&t!("summary-announce-errors", count = summary.errors.len()));
+            for err in &summary.errors {
+                ui::announce(&t!("summary-announce-error", error = err));
+            }
+            for hint in summary.hints() {
+                ui::announce(&format!("{} {}", t!("hint-prefix"), hint));
+            }
+        }
+        return;
+    }
+
+    println!("\n{}", ui::sym("═").repeat(45).accent());
+    println!(
+        "{}",
+        format!("           {}", t!("summary-title"))
+            .accent()
+            .bold()
+    );
+    println!("{}", ui::sym("═").repeat(45).accent());
+
+    let check = ui::sym("✓").success();
+    let skip = ui::sym("○").warning();
+    let halted = |stage: updater::Stage| match summary.halted_by {
+        Some(by) if summary.halted.contains(&stage) => {
+            format!(" {}", t!("summary-halted", stage = by.label()).warning())
+        }
+        _ => String::new(),
+    };
+
+    let results = [
+        (
+            t!("summary-system"),
+            summary.system_updated,
+            updater::Stage::System,
+        ),
+        (
+            t!("summary-flatpak"),
+            summary.flatpak_updated,
+            updater::Stage::Flatpak,
+        ),
+        (
+            t!("summary-firmware"),
+            summary.firmware_updated,
+            updater::Stage::Firmware,
+        ),
+    ];
+    for (label, updated, stage) in results {
+        println!(
+            "  {:<15} {}{}",
+            label,
+            if updated { &check } else { &skip },
+            halted(stage)
+        );
+    }
+
+    println!(
+        "  {:<15} {}",
+        t!("summary-run"),
+        artifacts::run_id().dimmed()
+    );
+    if let Some(backup) = &summary.backup {
+        println!("  {:<15} {}", t!("summary-etc-backup"), backup.dimmed());
+    }
+    if let Some(artifacts) = &summary.artifacts {
+        println!("  {:<15} {}", t!("summary-artifacts"), artifacts.dimmed());
+    }
+    if let Some(limit) = &summary.download_limit {
+        println!(
+            "  {:<15} {}/s",
+            t!("summary-download-limit"),
+            limit.dimmed()
+        );
+    }
+
+    if !summary.stages.is_empty() {
+        println!("\n  {}", t!("summary-timing").bold());
+    }
+    for (stage, stats) in &summary.stages {
+        let mut details = t!("summary-packages", count = stats.packages);
+        if stats.downloaded_bytes > 0 {
+            details.push_str(&format!(", {}", size::format(stats.downloaded_bytes)));
+        }
+        if stats.retries > 0 {
+            let outcome = if stats.failed {
+                t!("summary-retried-failed", count = stats.retries)
+            } else {
+                t!("summary-retried-ok", count = stats.retries)
+            };
+            details.push_str(&format!(", {}", outcome));
+        }
+        println!(
+            "  {:<19} {:>8}  {}",
+            stage.label(),
+            history::format_duration(stats.duration_secs),
+            details.dimmed()
+        );
+        for phase in &stats.phases {
+            println!(
+                "    {:<17} {:>8}",
+                phase.name.dimmed(),
+                history::format_duration(phase.duration_secs).dimmed()
+            );
+        }
+    }
+
+    if !summary.firmware_devices.is_empty() {
+        println!("\n  {}", t!("summary-firmware-devices").bold());
+        for device in &summary.firmware_devices {
+            let (mark, note) = match &device.result {
+                updater::FirmwareResult::Updated => (ui::sym("✓").success(), String::new()),
+                updater::FirmwareResult::Failed(_) => (
+                    ui::sym("✗").failure(),
+                    device.result.describe().failure().to_string(),
+                ),
+                other => (
+                    ui::sym("○").warning(),
+                    other.describe().dimmed().to_string(),
+                ),
+            };
+            let versions = match (&device.from, &device.to) {
+                (Some(from), Some(to)) => format!("{} {} {}", from, ui::sym("→"), to),
+                (None, Some(v)) | (Some(v), None) => v.clone(),
+                (None, None) => String::new(),
+            };
+            println!(
+                "    {} {} {} {}",
+                mark,
+                device.name,
+                versions.dimmed(),
+                note
+            );
+        }
+    }
+
+    if !summary.flatpak_users.is_empty() {
+        println!("\n  {}", t!("summary-flatpak-users").bold());
+        for user in &summary.flatpak_users {
+            let (mark, note) = match user.error {
+                Some(_) => (ui::sym("✗").failure(), user.describe().failure()),
+                None => (ui::sym("✓").success(), user.describe().dimmed()),
+            };
+            println!("    {} {} {}", mark, user.user, note);
+        }
+    }
+
+    if !summary.flatpak_eol.is_empty() {
+        println!();
+        print_flatpak_eol(&summary.flatpak_eol);
+    }
+
+    if !summary.removed.is_empty() {
+        println!(
+            "\n  {} ({})",
+            t!("summary-removed").bold(),
+            summary.removed.len()
+        );
+        for item in summary.removed.iter().take(SUMMARY_CHANGES) {
+            let reason = format!("({})", item.removal_reason());
+            println!(
+                "    {} {} {} {}",
+                "-".failure(),
+                item.name,
+                item.version.dimmed(),
+                reason.dimmed()
+            );
+        }
+        if summary.removed.len() > SUMMARY_CHANGES {
+            let more = summary.removed.len() - SUMMARY_CHANGES;
+            println!(
+                "    {} {}",
+                ui::sym("•").dimmed(),
+                t!("summary-more", count = more)
+            );
+        }
+    }
+
+    if !summary.changes.is_empty() {
+        println!(
+            "\n  {} {}",
+            t!("summary-package-changes").bold(),
+            pkgdiff::counts(&summary.changes).dimmed()
+        );
+        for change in summary.changes.iter().take(SUMMARY_CHANGES) {
+            let mark = match change.kind() {
+                "added" => "+".success(),
+                "removed" => "-".failure(),
+                _ => ui::sym("↑").accent(),
+            };
+            let transition = match (&change.from, &change.to) {
+                (Some(from), Some(to)) => format!("{} {} {}", from, ui::sym("→"), to),
+                (None, Some(v)) | (Some(v), None) => v.clone(),
+                (None, None) => String::new(),
+            };
+            println!("    {} {} {}", mark, change.name, transition.dimmed());
+        }
+        if summary.changes.len() > SUMMARY_CHANGES {
+            let more = summary.changes.len() - SUMMARY_CHANGES;
+            println!(
+                "    {} {}",
+                ui::sym("•").dimmed(),
+                t!("summary-more", count = more)
+            );
+        }
+    }
+
+    if summary.cancelled {
+        println!("\n  {} {}", ui::sym("⚠").warning(), t!("summary-cancelled"));
+    }
+
+    if !summary.errors.is_empty() {
+        println!("\n  {} {}", ui::sym("✗").failure(), t!("summary-errors"));
+        for err in &summary.errors {
+            println!("    {} {}", ui::sym("•"), err.failure());
+        }
+        for hint in summary.hints() {
+            println!("    {} {}", t!("hint-prefix").accent().bold(), hint);
+        }
+    }
+
+    if !summary.verification.is_empty() {
+        println!(
+            "\n  {} {}",
+            ui::sym("✗").failure(),
+            t!("summary-verification")
+        );
+        for problem in &summary.verification {
+            println!("    {} {}", ui::sym("•"), problem.failure());
+        }
+    }
+
+    println!("{}", ui::sym("═").repeat(45).accent());
+}
+
+fn print_comparison(current: &history::RunRecord, comparison: &history::Comparison) {
+    let Some(previous) = &comparison.previous else {
+        return;
+    };
+
+    if ui::accessible() {
+        ui::announce(&format!(
+            "Compared to the previous run on {}:",
+            previous.timestamp.format("%Y-%m-%d at %H:%M")
+        ));
+        for (stage, stats) in &current.stages {
+            let mut sentence = format!(
+                "{} took {}, {} package(s)",
+                stage.label(),
+                history::format_duration(stats.duration_secs),
+                stats.packages
+            );
+            if let Some(was) = previous.stages.get(stage) {
+                sentence.push_str(&format!(
+                    "; previously {}, {} package(s)",
+                    history::format_duration(was.duration_secs),
+                    was.packages
+                ));
+            }
+            ui::announce(&format!("{}.", sentence));
+        }
+        for anomaly in &comparison.anomalies {
+            warn!("{}", anomaly);
+            ui::announce(&format!("Warning: {}.", anomaly));
+        }
+        return;
+    }
+
+    println!(
+        "\n  {} {}",
+        "Compared to previous run".bold(),
+        previous
+            .timestamp
+            .format("(%Y-%m-%d %H:%M)")
+            .to_string()
+            .dimmed()
+    );
+
+    for (stage, stats) in &current.stages {
+        let was = previous.stages.get(stage);
+        println!(
+            "    {:<9} {:>8} {}   {:>4} pkg(s) {}",
+            stage.label(),
+            history::format_duration(stats.duration_secs),
+            was.map(|w| format!("(was {})", history::format_duration(w.duration_secs)))
+                .unwrap_or_default()
+                .dimmed(),
+            stats.packages,
+            was.map(|w| format!("(was {})", w.packages))
+                .unwrap_or_default()
+                .dimmed()
+        );
+    }
+
+    if previous.errors.is_empty() != current.errors.is_empty() {
+        let note = if current.errors.is_empty() {
+            "Previous run had errors; this run completed cleanly".success()
+        } else {
+            "Previous run completed cleanly; this run had errors".failure()
+        };
+        println!("    {}", note);
+    }
+
+    for anomaly in &comparison.anomalies {
+        warn!("{}", anomaly);
+        println!("  {} {}", ui::sym("⚠").warning().bold(), anomaly.warning());
+    }
+}
+
+fn print_module_problems(problems: &[String]) {
+    if ui::accessible() {
+        ui::announce(
+            "Warning: kernel modules are missing for the new kernel. Rebooting now may leave you without graphics or other drivers.",
+        );
+        for problem in problems {
+            ui::announce(&format!("{}.", problem));
+        }
+        ui::announce("Run akmods --force or dkms autoinstall, then check again before rebooting.");
+        return;
+    }
+
+    println!("\n{}", ui::sym("═").repeat(45).failure());
+    println!(
+        "{}",
+        format!(
+            "  {} KERNEL MODULES MISSING FOR THE NEW KERNEL",
+            ui::sym("⚠")
+        )
+        .failure()
+        .bold()
+    );
+    println!("{}", ui::sym("═").repeat(45).failure());
+    for problem in problems {
+        println!("  {} {}", ui::sym("✗").failure(), problem);
+    }
+    println!(
+        "\n  Rebooting now may leave you without graphics or other drivers.\n  Run {} or {} and check again before rebooting.",
+        "akmods --force".accent(),
+        "dkms autoinstall".accent()
+    );
+}
+
+/// Lists services still running old code and, when asked to, restarts the
+/// ones not in `[services] exclude` with `systemctl try-restart`.
+async fn handle_outdated_services(
+    services: &[String],
+    restart: bool,
+    config: &config::ServicesConfig,
+) {
+    let (excluded, restartable): (Vec<&String>, Vec<&String>) =
+        services.iter().partition(|s| config.exclude.contains(s));
+
+    if ui::accessible() {
+        ui::announce(&format!(
+            "No reboot required, but {} service(s) use outdated libraries.",
+            services.len()
+        ));
+    } else {
+        println!(
+            "\n{} No reboot required, but {} service(s) use outdated libraries:",
+            ui::sym("⚠").warning(),
+            services.len().to_string().warning().bold()
+        );
+    }
+
+    if !restart {
+        for service in services {
+            if ui::accessible() {
+                ui::announce(&format!("{}.", service));
+            } else {
+                println!("    {} {}", ui::sym("•").dimmed(), service);
+            }
+        }
+        let hint = "Run with --restart-services to restart them instead of rebooting.";
+        if ui::accessible() {
+            ui::announce(hint);
+        } else {
+            println!("  {}", hint.dimmed());
+        }
+        return;
+    }
+
+    for service in restartable {
+        info!("Restarting {}", service);
+        let status = Command::new("systemctl")
+            .args(["try-restart", service])
+            .status()
+            .await;
+        audit::command(
+            "systemctl",
+            &["try-restart", service],
+            status.as_ref().ok().and_then(|s| s.code()),
+        );
+        let ok = status.is_ok_and(|s| s.success());
+        if !ok {
+            warn!("Failed to restart {}", service);
+        }
+        if ui::accessible() {
+            let state = if ok { "restarted" } else { "failed to restart" };
+            ui::announce(&format!("{}: {}.", service, state));
+        } else {
+            let mark = if ok {
+                ui::sym("✓").success()
+            } else {
+                ui::sym("✗").failure()
+            };
+            println!("    {} {}", mark, service);
+        }
+    }
+    for service in excluded {
+        if ui::accessible() {
+            ui::announce(&format!(
+                "{}: not restarted, it is excluded. Log out or reboot to update it.",
+                service
+            ));
+        } else {
+            println!(
+                "    {} {} {}",
+                ui::sym("○").warning(),
+                service,
+                "(excluded - log out or reboot)".dimmed()
+            );
+        }
+    }
+}
+
+/// Prints a fatal error, followed by what to do about it when that's known.
+fn print_error(e: &error::UpdateError) {
+    eprintln!("{} {}", t!("error-prefix").failure().bold(), e);
+    if let Some(hint) = e.hint() {
+        eprintln!("{} {}", t!("hint-prefix").accent().bold(), hint);
+    }
+}
+
+/// Lists the backends about to run with their pending update counts and the
+/// total download.
+fn print_plan(
+    plan: &[(bool, updater::Stage, usize)],
+    items: &[String],
+    download: u64,
+    takeovers: &[updater::RepoTakeover],
+) {
+    if ui::accessible() {
+        for (_, stage, count) in plan.iter().filter(|(enabled, ..)| *enabled) {
+            ui::announce(&t!(
+                "plan-announce-pending",
+                stage = stage.label(),
+                count = count
+            ));
+        }
+        if !items.is_empty() {
+            ui::announce(&t!("plan-announce-only", items = items.join(", ")));
+        }
+        if download > 0 {
+            ui::announce(&t!("plan-announce-download", size = size::format(download)));
+        }
+        print_repo_takeovers(takeovers);
+        return;
+    }
+
+    println!("\n{}", t!("plan-title").bold());
+    for (_, stage, count) in plan.iter().filter(|(enabled, ..)| *enabled) {
+        let pending = t!("plan-pending", count = count.to_string().white().bold());
+        println!(
+            "  {} {:<10} {}",
+            ui::sym("•").accent(),
+            stage.label(),
+            pending
+        );
+    }
+    if !items.is_empty() {
+        println!(
+            "  {} {}",
+            ui::sym("•").accent(),
+            t!("plan-only", items = items.join(", "))
+        );
+    }
+    if download > 0 {
+        println!(
+            "  {} {:<10} {}",
+            ui::sym("•").accent(),
+            t!("plan-download"),
+            size::format(download).white().bold()
+        );
+    }
+    if !takeovers.is_empty() {
+        println!();
+        print_repo_takeovers(takeovers);
+    }
+}
+
+/// The transactions a dry run resolved, per backend, as dnf5 and flatpak
+/// would carry them out.
+fn print_preview(preview: &updater::Preview) {
+    if preview.is_empty() {
+        if ui::accessible() {
+            ui::announce("Dry run: nothing would change.");
+        } else {
+            println!("\n{}", "Dry run: nothing would change.".success());
+        }
+        return;
+    }
+
+    let transaction: Vec<String> = preview
+        .transaction
+        .iter()
+        .map(|item| {
+            let version = match &item.from {
+                Some(from) => format!("{} {} {}", from, ui::sym("→"), item.version),
+                None => item.version.clone(),
+            };
+            format!(
+                "{:<24} {:<40} {} {}",
+                item.action,
+                format!("{} {}", item.name, version),
+                item.repo,
+                size::format(item.size)
+            )
+        })
+        .collect();
+    let flatpak: Vec<String> = preview
+        .flatpak
+        .iter()
+        .map(|(row, bytes)| {
+            if *bytes > 0 {
+                format!("{} ({})", row, size::format(*bytes))
+            } else {
+                row.clone()
+            }
+        })
+        .collect();
+    let sections = [
+        ("System packages", &transaction),
+        ("Removed by autoremove", &preview.autoremove),
+        ("Flatpak updates", &flatpak),
+        ("Unused Flatpak runtimes removed", &preview.flatpak_unused),
+        ("Firmware", &preview.firmware),
+    ];
+
+    if ui::accessible() {
+        for (title, lines) in sections.iter().filter(|(_, lines)| !lines.is_empty()) {
+            ui::announce(&format!(
+                "Dry run, {}: {}.",
+                title.to_lowercase(),
+                lines.len()
+            ));
+            for line in lines.iter() {
+                ui::announce(&line.split_whitespace().collect::<Vec<_>>().join(" "));
+            }
+        }
+        if preview.download_bytes > 0 {
+            ui::announce(&format!(
+                "Total download: {}.",
+                size::format(preview.download_bytes)
+            ));
+        }
+        if !preview.protected.is_empty() {
+            ui::announce(&format!(
+                "Warning: protected packages would be removed: {}. The update stops or asks first, per [system] protected_removal.",
+                preview.protected.join(", ")
+            ));
+        }
+        return;
+    }
+
+    println!(
+        "\n{}",
+        "Dry run: the update would make these changes".bold()
+    );
+    for (title, lines) in sections.iter().filter(|(_, lines)| !lines.is_empty()) {
+        println!("\n  {} ({})", title.bold(), lines.len());
+        for line in lines.iter() {
+            println!("    {}", line);
+        }
+    }
+    if preview.download_bytes > 0 {
+        println!(
+            "\n  {} {}",
+            "Download:".bold(),
+            size::format(preview.download_bytes).white().bold()
+        );
+    }
+    if !preview.protected.is_empty() {
+        println!(
+            "\n  {} Protected packages would be removed: {} (the update stops or asks first, per [system] protected_removal)",
+            ui::sym("⚠").warning(),
+            preview.protected.join(", ").warning()
+        );
+    }
+    println!();
+}
+
+/// Asks whether to complete or roll back an interrupted transaction.
+/// Unattended runs stop, since either choice changes the system.
+fn ask_recovery(
+    found: &updater::InterruptedTransaction,
+    assume_yes: bool,
+) -> error::Result<updater::Recovery> {
+    use std::io::{self, Write};
+
+    let problem = format!(
+        "an interrupted dnf transaction needs attention: {}",
+        found.describe()
+    );
+    // Nobody to ask; failing here would stop every timer run until someone looks
+    if assume_yes || !io::stdin().is_terminal() {
+        warn!(
+            "{}; leaving it alone. Run fup interactively or set [system] interrupted_transaction to \"complete\" or \"rollback\"",
+            problem
+        );
+        return Ok(updater::Recovery::Ask);
+    }
+
+    if ui::accessible() {
+        ui::announce(&format!("Warning: {}.", problem));
+    } else {
+        println!("\n{} {}", ui::sym("⚠").warning().bold(), problem.warning());
+        for package in &found.duplicates {
+            println!("  {} {}", "-".failure(), package);
+        }
+    }
+    let rollback = if found.id.is_some() {
+        ", [r]oll it back"
+    } else {
+        ""
+    };
+    print!("\n[c]omplete it{}, [i]gnore it or [A]bort? ", rollback);
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    match input.trim().to_lowercase().as_str() {
+        "c" | "complete" => Ok(updater::Recovery::Complete),
+        "r" | "rollback" if found.id.is_some() => Ok(updater::Recovery::Rollback),
+        "i" | "ignore" => Ok(updater::Recovery::Ignore),
+        _ => Err(error::UpdateError::Cancelled),
+    }
+}
+
+/// Asks whether to go ahead with the plan. Without a terminal to ask on,
+/// --assume-yes is required so unattended runs don't hang or guess.
+fn confirm_plan(assume_no: bool) -> error::Result<bool> {
+    use std::io::{self, Write};
+
+    if assume_no {
+        return Ok(false);
+    }
+    if !io::stdin().is_terminal() {
+        return Err(error::UpdateError::Preflight(t!("confirm-no-terminal")));
+    }
+
+    print!("\n{} ", t!("confirm-proceed"));
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    let answer = input.trim().to_lowercase();
+    Ok(!answer.is_empty() && t!("confirm-yes").split(',').any(|yes| yes.trim() == answer))
+}
+
+/// `--foo`/`--no-foo` pair: None leaves the config setting in charge.
+fn flag_override(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// How firmware gets the go-ahead: --firmware-force (and dry runs, which
+/// flash nothing) approve it, a terminal asks, anything else leaves it be.
+fn firmware_approval(args: &cli::Args) -> updater::FirmwareApproval {
+    if args.firmware_force || args.dry_run {
+        updater::FirmwareApproval::Forced
+    } else if std::io::stdin().is_terminal() && !args.porcelain {
+        updater::FirmwareApproval::Ask
+    } else {
+        updater::FirmwareApproval::Unattended
+    }
+}
+
+/// Writes the --report file, if one was asked for.
+fn save_report(
+    args: &cli::Args,
+    started: chrono::DateTime<chrono::Local>,
+    summary: &updater::UpdateSummary,
+    restart: Option<&updater::RestartCheck>,
+    module_problems: &[String],
+) {
+    let Some(path) = &args.report else {
+        return;
+    };
+    let report = report::Report {
+        started,
+        finished: chrono::Local::now(),
+        summary,
+        restart,
+        module_problems,
+        dry_run: args.dry_run,
+    };
+    match report::write(path, &report) {
+        Ok(()) if args.quiet => {}
+        Ok(()) if ui::accessible() => {
+            ui::announce(&format!("Report written to {}.", path.display()))
+        }
+        Ok(()) => println!(
+            "{} Report written to {}",
+            ui::sym("✓").success(),
+            path.display()
+        ),
+        Err(e) => warn!("Failed to write report {}: {}", path.display(), e),
+    }
+}
+
+async fn prompt_reboot(preferred: reboot::RebootMethod) -> error::Result<()> {
+    use std::io::{self, Write};
+
+    // Offer the configured method first when it applies
+    let mut methods = reboot::available_methods().await;
+    if let Some(pos) = methods.iter().position(|m| *m == preferred) {
+        let method = methods.remove(pos);
+        methods.insert(0, method);
+    }
+    let exit = methods.len() + 1;
+
+    println!("\n{}", t!("reboot-recommended").warning().bold());
+    for (i, method) in methods.iter().enumerate() {
+        println!("  {}. {}", i + 1, method.label());
+    }
+    println!("  {}. {}", exit, t!("reboot-exit"));
+    print!("\n{} ", t!("reboot-choice", max = exit));
+    io::stdout().flush()?;
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+
+    match input.trim().parse::<usize>() {
+        Ok(n) if (1..exit).contains(&n) => {
+            info!("User requested reboot");
+            reboot::reboot_now(methods[n - 1]).await?;
+        }
+        _ => {
+            println!("{}", t!("reboot-skipped").success());
+        }
+    }
+
+    Ok(())
+}
+
+/// `fup export-bundle` and `fup import-bundle`: moving updates to a host
+/// without internet access on removable media.
+async fn bundle_command(args: &cli::Args, config: &config::Config) -> error::Result<()> {
+    let shutdown = setup_signal_handler().await;
+    let updater = updater::Updater::new(config, args.dry_run, args.quiet, shutdown)
+        .with_excludes(&args.exclude)
+        .with_firmware_devices(&args.firmware_device)
+        .with_firmware_approval(firmware_approval(args));
+    let (system, flatpak, firmware) = (
+        config.system.enabled,
+        config.flatpak.enabled,
+        config.firmware.enabled,
+    );
+
+    match &args.command {
+        Some(cli::Commands::ExportBundle { dir, sign_key }) => {
+            // The checksums cover the whole directory, strays included
+            if std::fs::read_dir(dir).is_ok_and(|mut entries| entries.next().is_some()) {
+                return Err(error::UpdateError::Bundle(format!(
+                    "{} is not empty",
+                    dir.display()
+                )));
+            }
+            std::fs::create_dir_all(dir)?;
+            let manifest = updater
+                .export_bundle(dir, system, flatpak, firmware)
+                .await?;
+            let signing_key = sign_key.clone().or_else(|| {
+                (!config.bundle.signing_key.is_empty())
+                    .then(|| config.bundle.signing_key.clone().into())
+            });
+            bundle::seal(dir, &manifest, signing_key.as_deref())?;
+            if manifest.is_empty() {
+                println!(
+                    "{} Nothing is pending; the bundle at {} is empty",
+                    ui::sym("✓").success(),
+                    dir.display()
+                );
+            } else {
+                println!(
+                    "{} Bundle written to {}: {} package(s), {} Flatpak(s), {} firmware file(s){}",
+                    ui::sym("✓").success(),
+                    dir.display(),
+                    manifest.packages.len(),
+                    manifest.flatpaks.len(),
+                    manifest.firmware.len(),
+                    if signing_key.is_some() {
+                        ", signed"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            Ok(())
+        }
+        Some(cli::Commands::ImportBundle { dir }) => {
+            let manifest = bundle::open(dir, &config.bundle.public_key)?;
+            println!(
+                "Bundle from {} ({}): {} package(s), {} Flatpak(s), {} firmware file(s)",
+                manifest.host,
+                manifest.created,
+                manifest.packages.len(),
+                manifest.flatpaks.len(),
+                manifest.firmware.len()
+            );
+            let installed = updater.import_bundle(dir, &manifest).await?;
+            if !args.dry_run {
+                println!(
+                    "{} Installed {} update(s) from the bundle",
+                    ui::sym("✓").success(),
+                    installed
+                );
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// What a run leaves behind for the `--quiet` status line.
+#[derive(Default)]
+struct RunOutcome {
+    /// Set once update transactions started; listing and check commands,
+    /// and runs stopped by a pre-flight check, never get that far.
+    attempted: bool,
+    summary: Option<updater::UpdateSummary>,
+    reboot: bool,
+    module_problems: usize,
+}
+
+/// The one line `--quiet` prints, as `key=value` fields; `None` when the
+/// run went through cleanly, so cron has nothing to mail.
+fn status_line(result: &error::Result<()>, outcome: &RunOutcome) -> Option<String> {
+    let summary = outcome.summary.as_ref();
+    let verification = summary.map_or(0, |s| s.verification.len());
+    let result_name = result_name(result, outcome);
+    if result_name == "ok" && !outcome.reboot && outcome.module_problems == 0 {
+        return None;
+    }
+
+    let mut fields = vec![
+        format!("result={}", result_name),
+        format!("run={}", artifacts::run_id()),
+    ];
+    if let Some(summary) = summary {
+        for (stage, state) in stage_states(summary) {
+            fields.push(format!("{}={}", stage, state));
+        }
+        fields.push(format!("changes={}", summary.changes.len()));
+    }
+    if verification > 0 {
+        fields.push(format!("verification={}", verification));
+    }
+    if outcome.module_problems > 0 {
+        fields.push(format!("missing_modules={}", outcome.module_problems));
+    }
+    if outcome.reboot {
+        fields.push("reboot=required".to_string());
+    }
+    if let Err(e) = result {
+        fields.push(format!("error={:?}", e.english()));
+    }
+    Some(format!("fup {}", fields.join(" ")))
+}
+
+/// ok, partial (a backend failed), failed, cancelled or outside-window.
+fn result_name(result: &error::Result<()>, outcome: &RunOutcome) -> &'static str {
+    let failed_stage = outcome
+        .summary
+        .as_ref()
+        .is_some_and(|s| s.stages.values().any(|stats| stats.failed) || !s.halted.is_empty());
+    match result {
+        Err(error::UpdateError::Cancelled) => "cancelled",
+        Err(error::UpdateError::OutsideWindow(_)) => "outside-window",
+        Err(_) => "failed",
+        Ok(()) if failed_stage => "partial",
+        Ok(()) => "ok",
+    }
+}
+
+/// Each backend as "system" with ok, failed, cancelled or halted.
+fn stage_states(summary: &updater::UpdateSummary) -> Vec<(String, &'static str)> {
+    let mut states: Vec<(String, &'static str)> = summary
+        .stages
+        .iter()
+        .map(|(stage, stats)| {
+            let state = if stats.cancelled {
+                "cancelled"
+            } else if stats.failed {
+                "failed"
+            } else {
+                "ok"
+            };
+            (stage.label().to_lowercase(), state)
+        })
+        .collect();
+    states.extend(
+        summary
+            .halted
+            .iter()
+            .map(|stage| (stage.label().to_lowercase(), "halted")),
+    );
+    states
+}
+
+/// The retained `check` message: what the last check found pending.
+fn check_message(updates: &updater::AvailableUpdates) -> serde_json::Value {
+    let size = updates.total_size(updates.all_rows());
+    serde_json::json!({
+        "checked": chrono::Local::now().to_rfc3339(),
+        "total": updates.total_count(),
+        "system": updates.system.len(),
+        "flatpak": updates.flatpak.len(),
+        "flatpak_runtimes": updates.flatpak_runtimes.len(),
+        "firmware": updates.firmware.len(),
+        "download_bytes": size.download,
+        "installed_bytes": size.installed,
+    })
+}
+
+/// The retained `run` message: how the last run went, for dashboards.
+fn run_message(result: &error::Result<()>, outcome: &RunOutcome) -> serde_json::Value {
+    let summary = outcome.summary.as_ref();
+    serde_json::json!({
+        "run": artifacts::run_id(),
+        "finished": chrono::Local::now().to_rfc3339(),
+        "result": result_name(result, outcome),
+        "stages": summary.map(|s| stage_states(s).into_iter().collect::<std::collections::BTreeMap<_, _>>()),
+        "changes": summary.map_or(0, |s| s.changes.len()),
+        "reboot_required": outcome.reboot,
+        "error": result.as_ref().err().map(error::UpdateError::english),
+    })
+}
+
+/// Title, body and urgency of the desktop notification after an unattended
+/// run; `None` when it never started updating, or changed nothing and nothing
+/// failed.
+fn desktop_notice(
+    result: &error::Result<()>,
+    outcome: &RunOutcome,
+) -> Option<(String, String, bool)> {
+    if !outcome.attempted {
+        return None;
+    }
+    let summary = outcome.summary.as_ref();
+    let failed: Vec<&str> = summary.map_or(Vec::new(), |s| {
+        s.stages
+            .iter()
+            .filter(|(_, stats)| stats.failed)
+            .map(|(stage, _)| stage.label())
+            .collect()
+    });
+    let changes = summary.map_or(0, |s| s.changes.len());
+    let title = match result {
+        Err(error::UpdateError::Cancelled | error::UpdateError::OutsideWindow(_)) => return None,
+        Err(_) => "Updates failed",
+        Ok(()) if !failed.is_empty() => "Some updates failed",
+        Ok(())
+            if changes > 0 || summary.is_some_and(|s| s.flatpak_updated || s.firmware_updated) =>
+        {
+            "Updates installed"
+        }
+        Ok(()) => return None,
+    };
+
+    let mut body = Vec::new();
+    if changes > 0 {
+        body.push(format!("{} package change(s)", changes));
+    }
+    if let Some(summary) = summary {
+        if summary.flatpak_updated {
+            body.push("Flatpak apps updated".to_string());
+        }
+        if summary.firmware_updated {
+            body.push("Firmware updated".to_string());
+        }
+    }
+    for stage in &failed {
+        body.push(format!("{} update failed", stage));
+    }
+    if let Err(e) = result {
+        body.push(e.to_string().lines().next().unwrap_or_default().to_string());
+    }
+    if outcome.reboot {
+        body.push("Restart to finish installing the updates".to_string());
+    }
+    body.push(format!(
+        "Run {}; details with fup status",
+        artifacts::run_id()
+    ));
+    Some((
+        title.to_string(),
+        body.join("\n"),
+        result.is_err() || !failed.is_empty(),
+    ))
+}
+
+async fn run(
+    args: cli::Args,
+    config: config::Config,
+    outcome: &mut RunOutcome,
+) -> error::Result<()> {
+    let started = chrono::Local::now();
+    info!("Run {}", artifacts::run_id());
+    let shutdown = setup_signal_handler().await;
+    let ostree = match &args.target {
+        Some(target) => system::check_target(target)?,
+        None => false,
+    };
+    system::detect_capabilities();
+
+    // Scopes need systemd as init; in containers commands run directly
+    let mut config = config;
+    if config.scope.enabled && !Path::new("/run/systemd/system").is_dir() {
+        warn!("[scope] enabled but systemd isn't running; running backend commands without scopes");
+        config.scope.enabled = false;
+    }
+
+    #[cfg(not(feature = "dnf5daemon"))]
+    if config.system.backend == updater::DnfBackend::Daemon {
+        warn!(
+            "[system] backend = \"daemon\" needs fup built with the dnf5daemon feature; using the dnf5 CLI"
+        );
+    }
+
+    if args.background || config.system.background {
+        match system::lower_priority(
+            config.system.background_nice,
+            config.system.background_io_class,
+        ) {
+            Ok(()) => info!(
+                "Background mode: backend commands run at nice {} with {} I/O priority",
+                config.system.background_nice.clamp(0, 19),
+                config.system.background_io_class.label()
+            ),
+            Err(e) => warn!("Could not lower priority for background mode: {}", e),
+        }
+    }
+
+    // A fresh cached check answers --refresh --cached without the network
+    let cached = if args.refresh && args.cached {
+        cache::load().filter(|check| check.is_fresh(args.max_age))
+    } else {
+        None
+    };
+
+    // Splay: a fleet started by the same timer shouldn't hit the mirrors at once
+    if let Some(splay) = args
+        .splay
+        .and_then(|s| s.to_std().ok())
+        .filter(|s| !s.is_zero())
+    {
+        let delay = splay.mul_f64(fastrand::f64());
+        info!(
+            "Splay: waiting {} (up to {}) before starting",
+            history::format_duration(delay.as_secs_f64()),
+            history::format_duration(splay.as_secs_f64())
+        );
+        let mut shutdown = shutdown.clone();
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            Ok(_) = shutdown.wait_for(Option::is_some) => return Err(error::UpdateError::Cancelled),
+        }
+    }
+
+    // Network check
+    if !args.no_network_check && (cached.is_none() || args.changelog) {
+        info!("Checking network connectivity...");
+        retry::with_backoff(
+            &config.retry,
+            &shutdown,
+            "Network check",
+            retry::is_transient,
+            || system::check_network(&config.network),
+        )
+        .await?;
+        debug!("Network check passed");
+    }
+
+    let attended =
+        !args.assume_yes && !args.dry_run && !args.quiet && std::io::stdin().is_terminal();
+    let mut updater = updater::Updater::new(&config, args.dry_run, args.quiet, shutdown.clone())
+        .with_excludes(&args.exclude)
+        .with_only(&args.items)
+        .with_flatpak_remotes(&args.flatpak_remote)
+        .with_firmware_devices(&args.firmware_device)
+        .with_firmware_approval(firmware_approval(&args))
+        .with_removals(
+            flag_override(args.autoremove, args.no_autoremove),
+            flag_override(args.remove_unused, args.no_remove_unused),
+            attended,
+        )
+        .with_failure_prompts(attended)
+        .with_prompts(std::io::stdin().is_terminal() && !args.porcelain);
+    if args.kernel_only {
+        updater = updater.with_kernel_only();
+    }
+    if args.background {
+        updater = updater.with_background();
+    }
+
+    // Alternate root: only dnf5 can be pointed at it
+    if let Some(target) = &args.target {
+        info!("Using target root {}", target.display());
+        updater = updater.with_target(target);
+    }
+
+    // Handle --refresh: show available updates
+    if args.refresh {
+        let from_cache = cached.is_some();
+        let (mut updates, new) = match cached {
+            Some(check) => {
+                println!(
+                    "{}",
+                    format!(
+                        "Checked {} ago; use --max-age to re-check sooner",
+                        history::format_duration(check.age().num_seconds() as f64)
+                    )
+                    .dimmed()
+                );
+                (check.updates, check.new)
+            }
+            None => {
+                let updates = updater.check_available_updates().await?;
+                let mut new = None;
+                if args.target.is_none() {
+                    new = cache::store(&updates).new;
+                    status::write_file(&config.notify.status_file, None);
+                    notify::publish(&config.notify, &[("check", check_message(&updates))]).await;
+                }
+                (updates, new)
+            }
+        };
+        if args.only_new {
+            match &new {
+                Some(new) => updates = new.updates.clone(),
+                None => println!(
+                    "{}",
+                    "No earlier check to compare with; showing everything".warning()
+                ),
+            }
+        }
+        updates.retain_flatpak_remotes(&args.flatpak_remote);
+        let pending = updates.total_count();
+        if !args.filter.is_empty() || args.sort.is_some() || args.limit.is_some() {
+            let severities = if args.sort == Some(updater::SortKey::Severity) {
+                match updater.security_advisories().await {
+                    Ok(advisories) => security::package_severities(&advisories),
+                    Err(e) => {
+                        warn!(
+                            "Cannot sort by severity without the security advisories: {}",
+                            e
+                        );
+                        Default::default()
+                    }
+                }
+            } else {
+                Default::default()
+            };
+            updates.slice(&args.filter, args.sort, args.limit, &severities);
+        }
+        let takeovers = updater.repo_takeovers(&updates).await;
+        if updates.is_empty() && pending > 0 {
+            println!(
+                "{}",
+                format!("None of the {} pending update(s) match --filter", pending).warning()
+            );
+        } else {
+            print_available_updates(&updates, new.as_ref(), &takeovers, config.ui.pager).await;
+            if updates.total_count() < pending {
+                println!(
+                    "  {}\n",
+                    format!(
+                        "Listed {} of {} pending update(s)",
+                        updates.total_count(),
+                        pending
+                    )
+                    .dimmed()
+                );
+            }
+        }
+        // Needs the remotes, so not when answering from the cache
+        if !from_cache {
+            print_flatpak_eol(&updater.flatpak_eol().await);
+            if let Some(notice) = self_update::notice(&config.self_update).await {
+                println!("\n{}", notice.warning());
+            }
+        }
+        if args.changelog && !updates.is_empty() {
+            let text = updater.fetch_changelogs(&updates).await;
+            if text.is_empty() {
+                println!("{}", "No changelog information available.".warning());
+            } else {
+                show_in_pager(&text).await;
+            }
+        }
+        return Ok(());
+    }
+
+    if args.security_report {
+        let mut advisories = updater.security_advisories().await?;
+        if let Some(min) = args.min_severity {
+            advisories.retain(|a| a.severity.is_some_and(|s| s >= min));
+        }
+        print_security_report(&advisories, args.min_severity);
+        return Ok(());
+    }
+
+    if let Some(min) = args.min_severity {
+        info!(
+            "Limiting system updates to {} and higher security fixes",
+            min.label()
+        );
+        updater = updater.with_min_severity(min);
+    }
+
+    if ostree {
+        return Err(error::UpdateError::Preflight(
+            "ostree deployments are updated with rpm-ostree; use --refresh to only check them"
+                .into(),
+        ));
+    }
+
+    // Determine what to update
+    let do_system = args.update_all || args.update_system || args.kernel_only;
+    let mut do_flatpak = args.update_all || args.update_flatpak;
+    let mut do_firmware = args.update_firmware || (args.update_all && args.firmware);
+
+    if args.target.is_some() && (do_flatpak || do_firmware) {
+        warn!(
+            "Flatpak and firmware updates only apply to the running system; skipping them for --target"
+        );
+        do_flatpak = false;
+        do_firmware = false;
+    }
+
+    // Maintenance window: timers catching up at boot mustn't update mid-workday
+    if (do_system || do_flatpak || do_firmware) && !args.dry_run && !args.ignore_window {
+        schedule::enforce(&config.schedule, &shutdown).await?;
+    }
+
+    if !args.quiet && !ui::accessible() && config.ui.banner {
+        print_banner();
+    }
+
+    if !args.quiet
+        && let Some(notice) = news::upgrade_notice()
+    {
+        println!("{}\n", notice.dimmed());
+    }
+
+    // Power pre-flight: never flash firmware or start a long update on a dying battery
+    let power = preflight::power_status();
+    preflight::check_battery(&config.power, power, args.force)?;
+    if do_firmware
+        && let Some(problem) = preflight::firmware_power_problem(&config.power, power, args.force)
+    {
+        updater.skip_stage(updater::Stage::Firmware, &problem).await;
+        do_firmware = false;
+    }
+
+    // Signature policy: automation must not quietly install unsigned packages
+    if do_system && !args.dry_run {
+        let root = args.target.as_deref().unwrap_or(Path::new("/"));
+        preflight::check_signatures(root, args.allow_unsigned)?;
+    }
+
+    // Updating on a damaged rpmdb compounds the damage
+    if do_system && config.health.check && !args.dry_run {
+        let root = args.target.as_deref().unwrap_or(Path::new("/"));
+        preflight::check_health(&config.health, root, args.force).await?;
+    }
+
+    // A transaction a crash cut short makes the next one fail in confusing ways
+    if do_system
+        && !args.dry_run
+        && let Some(found) = updater.interrupted_transaction().await
+    {
+        let recovery = match config.system.interrupted_transaction {
+            updater::Recovery::Ask => ask_recovery(&found, args.assume_yes || args.porcelain)?,
+            recovery => {
+                warn!("Interrupted transaction: {}", found.describe());
+                recovery
+            }
+        };
+        updater.recover_transaction(&found, recovery).await?;
+    }
+
+    // The download size feeds the disk space check, the confirmation and
+    // the unattended size limit
+    let confirm = !args.dry_run && !args.assume_yes && (do_system || do_flatpak || do_firmware);
+    let limit = config.run.max_download_bytes().filter(|_| !attended);
+    let download = if (do_system || do_flatpak)
+        && !args.dry_run
+        && (config.disk.check || confirm || limit.is_some())
+    {
+        let bytes = updater.pending_download_bytes(do_system, do_flatpak).await;
+        debug!("Pending download: {} bytes", bytes);
+        bytes
+    } else {
+        0
+    };
+
+    if let Some(limit) = limit
+        && !args.dry_run
+    {
+        preflight::check_download_size(limit, download, args.force)?;
+    }
+
+    // Disk space pre-flight, accounting for what the transactions will download
+    if (do_system || do_flatpak) && config.disk.check && !args.dry_run {
+        let root = args.target.as_deref().unwrap_or(Path::new("/"));
+        preflight::check_disk_space(&config.disk, root, download, args.force)?;
+    }
+
+    // A dry run resolves the real transactions instead of only echoing commands
+    if args.dry_run && !args.porcelain && (do_system || do_flatpak || do_firmware) {
+        print_preview(&updater.preview(do_system, do_flatpak, do_firmware).await);
+    }
+
+    // Last look before anything changes
+    let mut checked = None;
+    if confirm {
+        let mut updates = updater.check_available_updates().await?;
+        updates.retain_flatpak_remotes(&args.flatpak_remote);
+        let plan = [
+            (do_system, updater::Stage::System, updates.system.len()),
+            (do_flatpak, updater::Stage::Flatpak, updates.flatpak_count()),
+            (
+                do_firmware,
+                updater::Stage::Firmware,
+                updates.firmware.len(),
+            ),
+        ];
+        let takeovers = if do_system {
+            updater.repo_takeovers(&updates).await
+        } else {
+            Vec::new()
+        };
+        print_plan(&plan, &args.items, download, &takeovers);
+        if !confirm_plan(args.assume_no)? {
+            println!("{}", "No changes made.".warning());
+            return Ok(());
+        }
+        checked = Some(updates);
+    }
+
+    // GNOME Software's packagekitd otherwise competes for the lock and metadata
+    let packagekit_hold = if do_system && !args.dry_run && args.target.is_none() {
+        packagekit::prepare(&config.packagekit).await
+    } else {
+        None
+    };
+
+    // Per-run directory for the plan, raw command output, device reports and summary
+    let store = if config.artifacts.enabled && !args.dry_run {
+        match artifacts::ArtifactStore::create(&config.artifacts) {
+            Ok(store) => {
+                updater = updater.with_artifacts(store.clone());
+                Some(store)
+            }
+            Err(e) => {
+                warn!("Failed to create run artifacts directory: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(store) = &store {
+        let stages = [
+            (do_system, updater::Stage::System),
+            (do_flatpak, updater::Stage::Flatpak),
+            (do_firmware, updater::Stage::Firmware),
+        ];
+        store.write_json(
+            "plan.json",
+            &artifacts::Plan {
+                stages: stages
+                    .into_iter()
+                    .filter_map(|(enabled, stage)| enabled.then_some(stage))
+                    .collect(),
+                only: &args.items,
+                download_bytes: download,
+                updates: checked.as_ref(),
+            },
+        );
+    }
+
+    // Snapshot /etc before touching system packages
+    let backup = if do_system && config.backup.etc && !args.dry_run && args.target.is_none() {
+        let reference = backup::backup_etc(&config.backup).await?;
+        if ui::accessible() && !args.quiet {
+            ui::announce(&format!("/etc backed up to {}.", reference));
+        } else if !args.quiet {
+            println!("{} /etc backed up ({})", ui::sym("✓").success(), reference);
+        }
+        Some(reference)
+    } else {
+        None
+    };
+
+    // Keep the machine from sleeping or shutting down mid-transaction
+    let inhibitor = if config.power.inhibit && !args.dry_run {
+        system::InhibitorLock::acquire("Installing system updates")
+    } else {
+        None
+    };
+
+    // What is installed now, to show what the run changed
+    let before = if (do_system || do_flatpak) && !args.dry_run {
+        Some(pkgdiff::snapshot(args.target.as_deref()).await)
+    } else {
+        None
+    };
+    let verify = config.verify.enabled && !args.dry_run && (do_system || do_flatpak || do_firmware);
+    let verify_baseline = if verify {
+        verify::baseline(&config.verify, args.target.as_deref()).await
+    } else {
+        verify::Baseline::default()
+    };
+
+    // Run updates
+    let fail_fast = args.fail_fast || (config.run.fail_fast && !args.continue_on_error);
+    let stages: Vec<updater::Stage> = [
+        (do_system, updater::Stage::System),
+        (do_flatpak, updater::Stage::Flatpak),
+        (do_firmware, updater::Stage::Firmware),
+    ]
+    .into_iter()
+    .filter_map(|(enabled, stage)| enabled.then_some(stage))
+    .collect();
+    outcome.attempted = !stages.is_empty() && !args.dry_run;
+    let mut aborted = Ok(());
+    if args.parallel && !stages.is_empty() {
+        info!("Running updates in parallel");
+        // Events already name their backend
+        if !args.porcelain {
+            updater = updater.with_parallel_output();
+        }
+        let waves = updater::parallel_waves(&stages, &config.run.overlap);
+        updater.run_parallel(&waves, fail_fast).await;
+        updater.flush_output();
+    } else {
+        // Sequential execution (default)
+        aborted = updater.run_stages(&stages, fail_fast).await;
+    }
+
+    let retries = args.retry_failed.unwrap_or(config.run.retry_failed);
+    if retries > 0 && aborted.is_ok() {
+        aborted = updater
+            .retry_failed(
+                retries,
+                std::time::Duration::from_secs(config.run.retry_delay_secs),
+                fail_fast,
+            )
+            .await;
+        updater.flush_output();
+    }
+
+    drop(inhibitor);
+    if let Some(hold) = packagekit_hold {
+        hold.release().await;
+    }
+
+    // The cached check no longer describes this system
+    if !args.dry_run && args.target.is_none() {
+        cache::mark_outdated();
+    }
+    // Otherwise GNOME Software keeps offering the updates just applied
+    if do_system && !args.dry_run && args.target.is_none() && shutdown.borrow().is_none() {
+        packagekit::refresh(&config.packagekit).await;
+    }
+
+    // Print summary
+    let mut final_summary = updater.summary().await;
+    final_summary.backup = backup;
+    final_summary.artifacts = store
+        .as_ref()
+        .map(|store| store.path().display().to_string());
+    if let Some(before) = &before {
+        let after = pkgdiff::snapshot(args.target.as_deref()).await;
+        final_summary.changes = pkgdiff::diff(before, &after);
+        audit::changes(&final_summary.changes);
+    }
+    if verify && shutdown.borrow().is_none() {
+        final_summary.verification =
+            verify::run(&config.verify, &verify_baseline, args.target.as_deref()).await;
+    }
+    if args.porcelain {
+        events::emit(events::Event::Summary {
+            system_updated: final_summary.system_updated,
+            flatpak_updated: final_summary.flatpak_updated,
+            firmware_updated: final_summary.firmware_updated,
+            cancelled: final_summary.cancelled,
+            halted: &final_summary.halted,
+            run: history::RunRecord::from_summary(started, &final_summary),
+        });
+    } else if !args.quiet {
+        print_summary(&final_summary);
+    }
+
+    if let Some(store) = &store {
+        store.write_json(
+            "summary.json",
+            &history::RunRecord::from_summary(started, &final_summary),
+        );
+    }
+    if !config.run.summary_file.is_empty() {
+        let record = history::RunRecord::from_summary(started, &final_summary);
+        let written = serde_json::to_string_pretty(&record)
+            .map_err(std::io::Error::other)
+            .and_then(|json| std::fs::write(&config.run.summary_file, json + "\n"));
+        if let Err(e) = written {
+            warn!("Failed to write {}: {}", config.run.summary_file, e);
+        }
+    }
+    outcome.summary = Some(final_summary.clone());
+
+    // Compare with previous runs and record this one
+    if config.history.enabled && !args.dry_run {
+        let record = history::RunRecord::from_summary(started, &final_summary);
+        let previous = history::load(&config.history.file);
+        if !args.porcelain && !args.quiet {
+            print_comparison(&record, &history::compare(&record, &previous));
+        }
+
+        if let Err(e) = history::append(&config.history.file, &record, config.history.max_entries) {
+            warn!("Failed to write run history: {}", e);
+        }
+    }
+
+    if shutdown.borrow().is_some() || final_summary.cancelled {
+        save_report(&args, started, &final_summary, None, &[]);
+        return Err(error::UpdateError::Cancelled);
+    }
+    // Stopped at a failure prompt: the failure is the run's result
+    if let Err(e) = aborted {
+        save_report(&args, started, &final_summary, None, &[]);
+        return Err(e);
+    }
+
+    // Out-of-tree modules must exist for the new kernel before booting it
+    let module_problems =
+        if do_system && config.kernel.verify_modules && !args.dry_run && args.target.is_none() {
+            kmods::verify(&config.kernel).await
+        } else {
+            Vec::new()
+        };
+    outcome.module_problems = module_problems.len();
+    if !module_problems.is_empty() && !args.quiet {
+        print_module_problems(&module_problems);
+    }
+
+    if args.dry_run || args.target.is_some() {
+        save_report(&args, started, &final_summary, None, &module_problems);
+    }
+
+    // Check if reboot needed (a target takes effect when it is next booted)
+    let restart_services = args.restart_services || config.services.restart;
+    let auto_reboot = args.auto_reboot || args.reboot_at.is_some() || config.reboot.auto;
+    if !args.dry_run && args.target.is_none() {
+        let check = updater::check_reboot_required().await.unwrap_or_default();
+        let marker = pending::record(&check);
+        save_report(
+            &args,
+            started,
+            &final_summary,
+            Some(&check),
+            &module_problems,
+        );
+        outcome.reboot = check.reboot.is_some();
+        // Nobody saw this run, so tell whoever is logged in or logs in next
+        if !attended && let Some(marker) = &marker {
+            let rebooting = auto_reboot && module_problems.is_empty();
+            pending::announce(marker, config.reboot.wall && !rebooting, config.reboot.motd).await;
+        }
+
+        match &check.reboot {
+            _ if args.no_reboot_prompt && !restart_services && !auto_reboot => {}
+            Some(_) if args.no_reboot_prompt && !auto_reboot => {}
+            Some(reason) if module_problems.is_empty() && auto_reboot => {
+                info!("Reboot required: {}", reason);
+                let at = args
+                    .reboot_at
+                    .or_else(|| reboot::auto_reboot_time(&config.reboot));
+                let method = reboot::resolve(config.reboot.method).await;
+                // shutdown -r can only schedule a full reboot; the other
+                // methods happen right away and there is nothing to cancel
+                if at.is_none() && method != reboot::RebootMethod::Full {
+                    if args.quiet {
+                        info!("Rebooting now");
+                    } else if ui::accessible() {
+                        ui::announce("Rebooting now.");
+                    } else {
+                        println!("\n{} Rebooting now", ui::sym("⟳").warning().bold());
+                    }
+                    let _ = Command::new("wall")
+                        .arg(&config.reboot.message)
+                        .status()
+                        .await;
+                    reboot::reboot_now(method).await?;
+                } else {
+                    let when = reboot::schedule(at, &config.reboot.message).await?;
+                    if args.quiet {
+                        info!("Reboot scheduled {}", when);
+                    } else if ui::accessible() {
+                        ui::announce(&format!(
+                            "Reboot scheduled {}. Cancel it with shutdown -c.",
+                            when
+                        ));
+                    } else {
+                        println!(
+                            "\n{} Reboot scheduled {} (cancel with {})",
+                            ui::sym("⟳").warning().bold(),
+                            when,
+                            "shutdown -c".accent()
+                        );
+                    }
+                }
+            }
+            Some(reason) if module_problems.is_empty() => {
+                info!("Reboot required: {}", reason);
+                prompt_reboot(config.reboot.method).await?;
+            }
+            Some(_) if args.quiet => {}
+            Some(_) => println!(
+                "\n{}",
+                "A reboot is needed, but don't reboot until the modules above are built."
+                    .failure()
+                    .bold()
+            ),
+            None if !check.services.is_empty() => {
+                handle_outdated_services(&check.services, restart_services, &config.services).await
+            }
+            None if !args.no_reboot_prompt && !args.quiet => {
+                println!("\n{}", "No reboot required.".success())
+            }
+            None => {}
+        }
+    }
+
+    // An update that broke something must not look like a success
+    if !final_summary.verification.is_empty() {
+        return Err(error::UpdateError::Verification(
+            final_summary.verification.len(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let mut args = cli::Args::parse();
+    args.validate();
+    if args.porcelain {
+        args.quiet = true;
+        args.no_reboot_prompt = true;
+        events::set_enabled(true);
+    }
+    ui::set_color(args.color);
+    ui::set_plain(args.plain);
+    ui::set_accessible(args.accessible);
+
+    // If no action specified, show usage
+    if args.is_default() {
+        print_usage();
+        return ExitCode::SUCCESS;
+    }
+
+    // Subcommands that don't need root or configuration
+    match &args.command {
+        Some(cli::Commands::Examples { topic }) => {
+            help::print_examples(topic.as_deref());
+            return ExitCode::SUCCESS;
+        }
+        Some(cli::Commands::News { all }) => {
+            news::print_news(*all);
+            return ExitCode::SUCCESS;
+        }
+        Some(cli::Commands::Config { action }) => {
+            let ok = match action {
+                cli::ConfigAction::Init { force } => {
+                    match config_cmd::init(args.config.as_ref(), *force) {
+                        Ok(_) => true,
+                        Err(e) => {
+                            eprintln!("{} {}", t!("error-prefix").failure().bold(), e);
+                            false
+                        }
+                    }
+                }
+                cli::ConfigAction::Validate => config_cmd::validate(args.config.as_ref()),
+                cli::ConfigAction::Show => {
+                    match config_cmd::show(args.config.as_ref(), args.profile.as_deref()) {
+                        Ok(()) => true,
+                        Err(e) => {
+                            print_error(&e);
+                            false
+                        }
+                    }
+                }
+            };
+            return if ok {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            };
+        }
+        Some(cli::Commands::Completions { shell }) => {
+            cli::print_completions(*shell);
+            return ExitCode::SUCCESS;
+        }
+        // Reports a broken config rather than refusing to start over it
+        Some(cli::Commands::Doctor) => {
+            return if doctor::run(args.config.as_ref(), args.profile.as_deref()).await {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            };
+        }
+        Some(cli::Commands::Man) => {
+            return match cli::print_man_page() {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(e) => {
+                    eprintln!("{} {}", t!("error-prefix").failure().bold(), e);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        // Needs the configuration
+        Some(
+            cli::Commands::Status { .. }
+            | cli::Commands::Clean { .. }
+            | cli::Commands::RepoCheck
+            | cli::Commands::SelfUpdate { .. }
+            | cli::Commands::ExportBundle { .. }
+            | cli::Commands::ImportBundle { .. }
+            | cli::Commands::Mirror { .. },
+        )
+        | None => {}
+    }
+
+    let config = match config::Config::load(args.config.as_ref(), args.profile.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            print_error(&e);
+            return ExitCode::FAILURE;
+        }
+    };
+    ui::set_theme(&config.ui);
+    i18n::set_language(&config.ui.language);
+    if let Some(cli::Commands::Status { json }) = &args.command {
+        status::print(&config, *json).await;
+        return ExitCode::SUCCESS;
+    }
+
+    let _log_guards = setup_logging(args.verbose, args.quiet, &config.logging, &config.telemetry);
+    audit::start(&config.audit);
+
+    if let Some(cli::Commands::Clean { apply }) = &args.command {
+        if *apply && let Err(e) = system::check_root() {
+            print_error(&e);
+            return ExitCode::from(1);
+        }
+        return match clean::run(&config.clean, *apply).await {
+            Ok(()) => {
+                audit::finish("success");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                audit::finish(&format!("failed: {}", e.english()));
+                print_error(&e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if let Some(cli::Commands::SelfUpdate { check }) = &args.command {
+        if !*check && let Err(e) = system::check_root() {
+            print_error(&e);
+            return ExitCode::from(1);
+        }
+        return match self_update::run(&config.self_update, *check).await {
+            Ok(()) => {
+                audit::finish("success");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                audit::finish(&format!("failed: {}", e.english()));
+                print_error(&e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if let Some(cli::Commands::RepoCheck) = &args.command {
+        return match repos::check(&config.network).await {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                print_error(&e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if let Some(cli::Commands::Mirror { action }) = &args.command {
+        let result = match action {
+            cli::MirrorAction::Status => mirror::status(&config.mirror),
+            cli::MirrorAction::Sync => match system::check_root() {
+                Ok(()) => mirror::sync(&config.mirror).await,
+                Err(e) => Err(e),
+            },
+        };
+        return match result {
+            Ok(()) => {
+                audit::finish("success");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                audit::finish(&format!("failed: {}", e.english()));
+                print_error(&e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    if matches!(
+        args.command,
+        Some(cli::Commands::ExportBundle { .. } | cli::Commands::ImportBundle { .. })
+    ) {
+        let result = match system::check_root() {
+            Ok(()) => bundle_command(&args, &config).await,
+            Err(e) => Err(e),
+        };
+        return match result {
+            Ok(()) => {
+                audit::finish("success");
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                audit::finish(&format!("failed: {}", e.english()));
+                print_error(&e);
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    // Root check (not needed for just showing help)
+    if let Err(e) = system::check_root() {
+        print_error(&e);
+        return ExitCode::from(1);
+    }
+
+    // The root of the exported trace; backends and commands are its children
+    let span = tracing::info_span!(
+        target: telemetry::TARGET,
+        "run",
+        otel.name = "fup run",
+        run.id = artifacts::run_id(),
+        outcome = tracing::field::Empty,
+        otel.status_code = tracing::field::Empty,
+        otel.status_message = tracing::field::Empty,
+    );
+    let quiet = args.quiet && !args.porcelain;
+    // A timer run has no terminal, and nobody sees its output
+    let notify = config.notify.desktop
+        && !args.dry_run
+        && !args.porcelain
+        && !std::io::stdin().is_terminal();
+    let notify_config = config.notify.clone();
+    let publish = !args.dry_run && args.target.is_none();
+    let mut outcome = RunOutcome::default();
+    let result = run(args, config, &mut outcome)
+        .instrument(span.clone())
+        .await;
+    telemetry::record_outcome(&span, &result);
+    drop(span);
+
+    if quiet && let Some(line) = status_line(&result, &outcome) {
+        println!("{}", line);
+    }
+    if notify && let Some((title, body, urgent)) = desktop_notice(&result, &outcome) {
+        notify::send(&title, &body, urgent).await;
+    }
+    // Only runs that started updating count as the last run; checks just
+    // refresh the pending counts
+    if publish {
+        status::write_file(
+            &notify_config.status_file,
+            outcome.attempted.then(|| result_name(&result, &outcome)),
+        );
+    }
+    if publish && outcome.attempted {
+        notify::publish(&notify_config, &[("run", run_message(&result, &outcome))]).await;
+    }
+    match result {
+        Ok(()) => {
+            info!("Operation completed successfully");
+            audit::finish("success");
+            ExitCode::SUCCESS
+        }
+        Err(error::UpdateError::Cancelled) => {
+            audit::finish("cancelled");
+            if !quiet {
+                eprintln!("\n{}", "Operation cancelled.".warning());
+            }
+            ExitCode::from(130)
+        }
+        Err(e @ error::UpdateError::OutsideWindow(_)) => {
+            info!("{}", e);
+            audit::finish("outside maintenance window");
+            if !quiet {
+                eprintln!("{}", e.to_string().warning());
+            }
+            ExitCode::from(schedule::EXIT_CODE)
+        }
+        Err(e) => {
+            error!("Operation failed: {}", e);
+            audit::finish(&format!("failed: {}", e.english()));
+            if !quiet {
+                print_error(&e);
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::{Config, ScheduleConfig},
+        error::UpdateError,
+        schedule, self_update,
+        updater::{
+            BoxFuture, CommandRunner, FirmwareApproval, FirmwareResult, Output, Recovery,
+            RunningCommand, Stage, Updater,
+        },
+    };
+    use nix::sys::signal::Signal;
+    use std::{
+        collections::VecDeque,
+        io,
+        os::unix::process::ExitStatusExt,
+        process::ExitStatus,
+        sync::{Arc, Mutex},
+    };
+    use tokio::sync::watch;
+
+    /// What one invocation of a scripted command prints and exits with.
+    #[derive(Clone)]
+    struct Reply {
+        stdout: String,
+        stderr: String,
+        code: i32,
+    }
+
+    impl Reply {
+        fn ok(stdout: &str) -> Self {
+            Self {
+                stdout: stdout.into(),
+                stderr: String::new(),
+                code: 0,
+            }
+        }
+
+        fn fail(code: i32, stderr: &str) -> Self {
+            Self {
+                stdout: String::new(),
+                stderr: stderr.into(),
+                code,
+            }
+        }
+    }
+
+    /// Answers commands from canned output keyed by command-line prefix.
+    /// Each call takes the next reply for its prefix and the last one
+    /// repeats; unscripted commands succeed silently.
+    #[derive(Default)]
+    struct ScriptedRunner {
+        missing: Vec<&'static str>,
+        script: Mutex<Vec<(String, VecDeque<Reply>)>>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl ScriptedRunner {
+        fn on(self, prefix: &str, replies: impl IntoIterator<Item = Reply>) -> Self {
+            self.script
+                .lock()
+                .unwrap()
+                .push((prefix.into(), replies.into_iter().collect()));
+            self
+        }
+
+        fn without(mut self, cmd: &'static str) -> Self {
+            self.missing.push(cmd);
+            self
+        }
+
+        fn calls(&self) -> Vec<String> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        fn called(&self, prefix: &str) -> usize {
+            self.calls()
+                .iter()
+                .filter(|c| c.starts_with(prefix))
+                .count()
+        }
+    }
+
+    impl CommandRunner for ScriptedRunner {
+        fn exists(&self, cmd: &str) -> bool {
+            !self.missing.contains(&cmd)
+        }
+
+        fn spawn(&self, cmd: &str, args: &[&str]) -> io::Result<Box<dyn RunningCommand>> {
+            if !self.exists(cmd) {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            let line = std::iter::once(cmd)
+                .chain(args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            self.calls.lock().unwrap().push(line.clone());
+
+            let mut script = self.script.lock().unwrap();
+            let reply = script
+                .iter_mut()
+                .find(|(prefix, _)| line.starts_with(prefix.as_str()))
+                .and_then(|(_, replies)| {
+                    if replies.len() > 1 {
+                        replies.pop_front()
+                    } else {
+                        replies.front().cloned()
+                    }
+                })
+                .unwrap_or_else(|| Reply::ok(""));
+            Ok(Box::new(Scripted(reply)))
+        }
+    }
+
+    struct Scripted(Reply);
+
+    impl RunningCommand for Scripted {
+        fn stdout(&mut self) -> Output {
+            Box::new(io::Cursor::new(
+                std::mem::take(&mut self.0.stdout).into_bytes(),
+            ))
+        }
+
+        fn stderr(&mut self) -> Output {
+            Box::new(io::Cursor::new(
+                std::mem::take(&mut self.0.stderr).into_bytes(),
+            ))
+        }
+
+        fn wait(&mut self) -> BoxFuture<'_, io::Result<ExitStatus>> {
+            let code = self.0.code;
+            Box::pin(async move { Ok(ExitStatus::from_raw(code << 8)) })
+        }
+
+        fn terminate(&mut self, _signal: Signal) -> BoxFuture<'_, ()> {
+            Box::pin(async {})
+        }
+    }
+
+    fn test_config() -> Config {
+        let mut config = Config::default();
+        config.retry.initial_delay_secs = 0.0;
+        config.retry.max_delay_secs = 0.0;
+        config.retry.jitter = 0.0;
+        config.kernel.keep = 0;
+        config.system.protected = Vec::new();
+        config
+    }
+
+    /// Builds a quiet updater over `runner`. The sender is returned so the
+    /// shutdown channel stays open for the duration of the test.
+    fn updater(
+        config: &Config,
+        runner: &Arc<ScriptedRunner>,
+    ) -> (Updater, watch::Sender<Option<Signal>>) {
+        let (tx, rx) = watch::channel(None);
+        let updater = Updater::new(config, false, true, rx).with_runner(runner.clone());
+        (updater, tx)
+    }
+
+    const DNF_TRANSACTION: &str = "\
+Updating and loading repositories:
+Repositories loaded.
+Package                 Arch    Version         Repository      Size
+Upgrading:
+ bash                   x86_64  5.2.32-1.fc41   updates      8.1 MiB
+ curl                   x86_64  8.9.1-2.fc41    updates    793.3 KiB
+
+Transaction Summary:
+ Upgrading:          2 packages
+ Replacing:          2 packages
+";
+
+    #[tokio::test]
+    async fn check_parses_backend_output() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on(
+                    "dnf5 check-upgrade",
+                    [Reply::ok(
+                        "Last metadata expiration check: 0:12:03 ago.\n\
+                         bash.x86_64  5.2.32-1.fc41  updates\n\
+                         \n\
+                         curl.x86_64  8.9.1-2.fc41  updates\n",
+                    )],
+                )
+                .on(
+                    "flatpak remote-ls --system",
+                    [Reply::ok(
+                        "org.mozilla.firefox/x86_64/stable\tflathub\n\
+                         com.valvesoftware.Steam/x86_64/stable\tflathub\n",
+                    )],
+                )
+                .on(
+                    "flatpak remote-ls --user",
+                    [Reply::ok("org.gnome.Boxes/x86_64/stable\tfedora\n")],
+                )
+                .on(
+                    "fwupdmgr get-updates",
+                    [Reply::ok(
+                        "Devices with no available firmware updates:\n\
+                         \u{2022} UEFI dbx\n\
+                         System Firmware: 1.18.0 \u{2192} 1.19.1\n",
+                    )],
+                ),
+        );
+        let mut config = test_config();
+        config.flatpak.skip = vec!["com.valvesoftware.Steam".into()];
+        let (updater, _tx) = updater(&config, &runner);
+
+        let updates = updater.check_available_updates().await.unwrap();
+
+        assert_eq!(updates.system.len(), 2);
+        assert!(updates.system[0].starts_with("bash"));
+        assert_eq!(
+            updates.flatpak,
+            [
+                "org.mozilla.firefox stable flathub system",
+                "org.gnome.Boxes stable fedora user"
+            ]
+        );
+        assert_eq!(
+            updates.flatpak_by_remote(),
+            [("fedora (user)".to_string(), 1), ("flathub".to_string(), 1)]
+        );
+        assert_eq!(
+            updates.firmware,
+            ["System Firmware: 1.18.0 \u{2192} 1.19.1"]
+        );
+        assert_eq!(runner.called("fwupdmgr refresh --force"), 1);
+    }
+
+    #[tokio::test]
+    async fn unreadable_advisory_json_fails_the_security_report() {
+        let truncated = r#"[{"advisory_name":"FEDORA-2024-1a2b3c","advisory_severity":"Imp"#;
+        let runner = Arc::new(ScriptedRunner::default().on(
+            "dnf5 advisory list --security --with-cve --json",
+            [Reply::ok(truncated)],
+        ));
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        let error = updater.security_advisories().await.unwrap_err();
+        assert!(
+            matches!(error, UpdateError::UnexpectedOutput { .. }),
+            "{:?}",
+            error
+        );
+        assert!(crate::security::parse(r#"{"error":"no repositories"}"#).is_err());
+        assert!(crate::security::parse("[]").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dnf5_json_is_preferred_over_text() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .without("flatpak")
+                .without("fwupdmgr")
+                .on(
+                    "dnf5 check-upgrade --refresh -q --json",
+                    [Reply::ok(
+                        r#"[{"name":"kernel","arch":"x86_64","epoch":"0","version":"6.11.3","release":"300.fc41","repo":"updates"},
+                           {"name":"mesa-dri-drivers","arch":"x86_64","epoch":1,"version":"24.2.4","release":"1.fc41","repo":"copr:mesa-git"}]"#,
+                    )],
+                )
+                .on(
+                    "dnf5 repoquery --installed --json",
+                    [Reply::ok(r#"[{"name":"mesa-dri-drivers","arch":"x86_64","from_repo":"updates"}]"#)],
+                )
+                .on("dnf5 advisory list --security --with-cve --json", [Reply::fail(2, "Unknown argument \"--json\"")])
+                .on(
+                    "dnf5 advisory list --security",
+                    [Reply::ok(
+                        "Name               Type     Severity  Package                          Issued\n\
+                         FEDORA-2024-1a2b3c security Important openssl-1:3.2.1-2.fc41.x86_64     2024-03-01 00:00:00\n",
+                    )],
+                ),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        let updates = updater.check_available_updates().await.unwrap();
+        assert_eq!(
+            updates.system,
+            [
+                "kernel.x86_64  6.11.3-300.fc41  updates",
+                "mesa-dri-drivers.x86_64  1:24.2.4-1.fc41  copr:mesa-git"
+            ]
+        );
+        assert_eq!(runner.called("dnf5 check-upgrade"), 1);
+
+        let takeovers = updater.repo_takeovers(&updates).await;
+        assert_eq!(takeovers.len(), 1);
+        assert_eq!(takeovers[0].installed_from, "updates");
+        assert_eq!(runner.called("dnf5 repoquery --installed --queryformat"), 0);
+
+        // An older dnf5 without --json still gets its advisories listed
+        let advisories = updater.security_advisories().await.unwrap();
+        assert_eq!(advisories.len(), 1);
+        assert_eq!(advisories[0].id, "FEDORA-2024-1a2b3c");
+        assert_eq!(advisories[0].packages, ["openssl-1:3.2.1-2.fc41.x86_64"]);
+
+        // Text rows without the metadata notice, for dnf5 without check-upgrade --json
+        let text: Vec<String> = [
+            "Last metadata expiration check: 0:12:03 ago.",
+            "bash.x86_64  5.2.32-1.fc41  updates",
+        ]
+        .map(String::from)
+        .to_vec();
+        assert_eq!(
+            crate::updater::parse_check_upgrade(&text),
+            ["bash.x86_64  5.2.32-1.fc41  updates"]
+        );
+    }
+
+    #[tokio::test]
+    async fn check_skips_missing_backends() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .without("flatpak")
+                .without("fwupdmgr")
+                .on(
+                    "dnf5 check-upgrade --refresh -q --json",
+                    [Reply::fail(2, "Unknown argument \"--json\"")],
+                )
+                .on(
+                    "dnf5 check-upgrade",
+                    [
+                        Reply::fail(1, "Curl error (6): Couldn't resolve host name"),
+                        Reply::ok("bash.x86_64  5.2.32-1.fc41  updates\n"),
+                    ],
+                ),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        let updates = updater.check_available_updates().await.unwrap();
+
+        assert_eq!(updates.total_count(), 1);
+        assert!(runner.calls().iter().all(|c| c.starts_with("dnf5")));
+        // The unknown --json is not retried; the failed refresh is
+        assert_eq!(runner.called("dnf5 check-upgrade --refresh -q --json"), 1);
+        assert_eq!(runner.called("dnf5 check-upgrade"), 3);
+    }
+
+    #[tokio::test]
+    async fn system_update_runs_dnf_and_autoremove() {
+        let runner =
+            Arc::new(ScriptedRunner::default().on("dnf5 update", [Reply::ok(DNF_TRANSACTION)]));
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        let packages = updater.update_system().await.unwrap();
+
+        assert_eq!(packages, 2);
+        assert_eq!(
+            runner.calls(),
+            ["dnf5 update --refresh -y", "dnf5 autoremove -y"]
+        );
+        assert!(updater.summary().await.system_updated);
+    }
+
+    #[test]
+    fn old_kernels_keep_the_running_one_besides_the_newest() {
+        let installed: Vec<String> = [
+            "1700000000 6.10.5-200.fc40.x86_64",
+            "1730000000 6.11.4-301.fc41.x86_64",
+            "1720000000 6.11.3-300.fc41.x86_64",
+            "1710000000 6.10.9-200.fc40.x86_64",
+            "garbage",
+        ]
+        .map(String::from)
+        .into();
+        let old = |running, keep| crate::updater::old_kernels(&installed, running, keep);
+
+        assert_eq!(
+            old("6.11.4-301.fc41.x86_64", 1),
+            ["6.10.9-200.fc40.x86_64", "6.10.5-200.fc40.x86_64"]
+        );
+        // The running kernel never takes one of the `keep` places
+        assert_eq!(
+            old("6.10.5-200.fc40.x86_64", 1),
+            ["6.11.3-300.fc41.x86_64", "6.10.9-200.fc40.x86_64"]
+        );
+        assert_eq!(old("6.10.5-200.fc40.x86_64", 3), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn kernel_cleanup_names_only_the_kernels() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on("dnf5 update", [Reply::ok(DNF_TRANSACTION)])
+                .on(
+                    "rpm -q kernel-core",
+                    [Reply::ok(
+                        "1730000000 6.11.4-301.fc41.x86_64\n1720000000 6.11.3-300.fc41.x86_64\n",
+                    )],
+                ),
+        );
+        let mut config = test_config();
+        config.kernel.keep = 1;
+        config.system.exclude = vec!["kernel*".into()];
+        let (updater, _tx) = updater(&config, &runner);
+
+        updater.update_system().await.unwrap();
+
+        assert_eq!(runner.called("dnf5 --exclude=kernel* update"), 1);
+        assert_eq!(
+            runner.called("dnf5 remove -y kernel-core-6.11.3-300.fc41.x86_64"),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn protected_removals_abort_the_transaction() {
+        const OBSOLETING: &str = "\
+Upgrading:
+ bash                   x86_64  5.2.32-1.fc41   updates      8.1 MiB
+   replacing bash       x86_64  5.2.26-1.fc40   @System      8.0 MiB
+Installing:
+ kernel-core            x86_64  6.11.3-300.fc41 updates     20.0 MiB
+ pipewire-pulseaudio    x86_64  1.2.0-1.fc41    updates    100.0 KiB
+   replacing pulseaudio x86_64  17.0-1.fc40     @System      1.0 MiB
+Removing:
+ kernel-core            x86_64  6.9.5-200.fc40  @System     60.0 MiB
+";
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on(
+                    "dnf5 update --refresh --assumeno",
+                    [Reply {
+                        code: 1,
+                        ..Reply::ok(OBSOLETING)
+                    }],
+                )
+                .on("dnf5 update --refresh -y", [Reply::ok(OBSOLETING)])
+                .on(
+                    "dnf5 autoremove --assumeno",
+                    [Reply::ok(
+                        "Removing:\n sudo  x86_64  1.9.15-1.fc41  @System  4 MiB\n",
+                    )],
+                ),
+        );
+        let mut config = test_config();
+        config.system.protected = vec!["pulse*".into()];
+        let (guarded, _tx) = updater(&config, &runner);
+
+        let Err(UpdateError::Preflight(reason)) = guarded.update_system().await else {
+            panic!("the update went ahead");
+        };
+        assert!(reason.contains("pulseaudio 17.0-1.fc40 (obsoleted)"));
+        assert_eq!(runner.called("dnf5 update --refresh -y"), 0);
+
+        // An old kernel making way for a new one is no loss; removals are
+        // still reported, and autoremove keeps its hands off sudo
+        config.system.protected = vec!["kernel*".into(), "sudo".into()];
+        let (updater, _tx) = updater(&config, &runner);
+        updater.update_system().await.unwrap();
+        let removed: Vec<(String, String)> = updater
+            .summary()
+            .await
+            .removed
+            .iter()
+            .map(|item| (item.name.clone(), item.removal_reason()))
+            .collect();
+        assert_eq!(
+            removed,
+            [
+                ("pulseaudio".into(), "obsoleted".into()),
+                ("kernel-core".into(), "removed".into())
+            ]
+        );
+        assert_eq!(runner.called("dnf5 autoremove -y"), 0);
+    }
+
+    // With the feature this would reach a real dnf5daemon
+    #[cfg(not(feature = "dnf5daemon"))]
+    #[tokio::test]
+    async fn daemon_backend_falls_back_to_the_cli() {
+        let runner =
+            Arc::new(ScriptedRunner::default().on("dnf5 update", [Reply::ok(DNF_TRANSACTION)]));
+        let mut config = test_config();
+        config.system.backend = crate::updater::DnfBackend::Daemon;
+        let (updater, _tx) = updater(&config, &runner);
+
+        assert_eq!(updater.update_system().await.unwrap(), 2);
+        assert_eq!(runner.called("dnf5 update --refresh -y"), 1);
+    }
+
+    #[tokio::test]
+    async fn scopes_wrap_backend_commands() {
+        let runner =
+            Arc::new(ScriptedRunner::default().on("systemd-run", [Reply::ok(DNF_TRANSACTION)]));
+        let mut config = test_config();
+        config.scope.enabled = true;
+        config.scope.memory_max = "4G".into();
+        let (updater, _tx) = updater(&config, &runner);
+
+        updater.update_system().await.unwrap();
+
+        let calls = runner.calls();
+        let pid = std::process::id();
+        assert_eq!(
+            calls[0],
+            format!(
+                "systemd-run --scope --quiet --collect --unit=fup-system-{}-1 --description=fup: dnf5 update \
+                 --slice=fup.slice --property=MemoryMax=4G -- dnf5 update --refresh -y",
+                pid
+            )
+        );
+        assert!(calls[1].ends_with("-- dnf5 autoremove -y"));
+    }
+
+    #[tokio::test]
+    async fn auto_remove_disabled_skips_autoremove() {
+        let runner = Arc::new(ScriptedRunner::default());
+        let (updater, _tx) = updater(&test_config(), &runner);
+        let updater = updater.with_removals(Some(false), None, false);
+
+        updater.update_system().await.unwrap();
+
+        assert_eq!(runner.called("dnf5 autoremove"), 0);
+    }
+
+    #[tokio::test]
+    async fn excludes_are_passed_to_dnf() {
+        let runner = Arc::new(ScriptedRunner::default());
+        let (updater, _tx) = updater(&test_config(), &runner);
+        let updater = updater.with_excludes(&["kernel*".into(), "mesa-*".into()]);
+
+        updater.update_system().await.unwrap();
+
+        assert!(runner.calls()[0].contains("--exclude=kernel*,mesa-*"));
+    }
+
+    #[test]
+    fn failed_commands_are_classified_by_their_output() {
+        let classify =
+            |output: &str| UpdateError::from_output("dnf5 update".into(), 1, output.into());
+
+        assert!(matches!(
+            classify("Curl error (6): Couldn't resolve host name\nCannot download repomd.xml: all mirrors were tried"),
+            UpdateError::MetadataFetchFailed { reason, .. } if reason.starts_with("Cannot download repomd.xml")
+        ));
+        assert!(matches!(
+            classify(
+                "Transaction failed: Rpm transaction failed.\n  - installing package foo needs 25MB more space on the / filesystem"
+            ),
+            UpdateError::DiskFull { .. }
+        ));
+        assert!(matches!(
+            classify(
+                "OpenPGP check for package \"foo-1.0-1.x86_64\" has failed: the signature is not valid"
+            ),
+            UpdateError::GpgFailure { .. }
+        ));
+        assert!(matches!(
+            classify(
+                "file /usr/bin/foo from install of foo-2.0 conflicts with file from package bar-1.0"
+            ),
+            UpdateError::TransactionTestFailed { .. }
+        ));
+        assert!(matches!(
+            classify("Failed to obtain rpm transaction lock"),
+            UpdateError::LockHeld(_)
+        ));
+        let unknown = classify("scriptlet failed, exit status 1");
+        assert!(unknown.hint().is_none());
+        assert!(matches!(
+            unknown,
+            UpdateError::CommandFailed { code: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn run_ids_are_ulids() {
+        use crate::artifacts::{run_id, ulid};
+
+        assert_eq!(ulid(1469918176385, 0), "01ARYZ6S410000000000000000");
+        assert_eq!(ulid((1 << 48) - 1, u128::MAX), "7ZZZZZZZZZZZZZZZZZZZZZZZZZ");
+        assert!(ulid(1469918176385, u128::MAX) < ulid(1469918176386, 0));
+        assert_eq!(run_id().len(), 26);
+        assert_eq!(run_id(), run_id());
+    }
+
+    #[tokio::test]
+    async fn pending_relabel_stops_system_updates_unless_told_to_warn() {
+        use crate::preflight::{OnProblem, health_problems, health_verdict};
+
+        let root = std::env::temp_dir().join(format!("fup-health-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join(".autorelabel"), "").unwrap();
+        let problems = health_problems(&root).await;
+        std::fs::remove_dir_all(&root).unwrap();
+        assert!(
+            problems
+                .iter()
+                .any(|p| p.contains("SELinux relabel is pending")),
+            "{:?}",
+            problems
+        );
+
+        let mut config = crate::config::HealthConfig::default();
+        assert!(matches!(
+            health_verdict(&config, &problems, false),
+            Err(UpdateError::Preflight(_))
+        ));
+        assert!(health_verdict(&config, &problems, true).is_ok());
+        config.on_problem = OnProblem::Warn;
+        assert!(health_verdict(&config, &problems, false).is_ok());
+        assert!(health_verdict(&crate::config::HealthConfig::default(), &[], false).is_ok());
+    }
+
+    #[test]
+    fn pending_reboot_notice_names_reason_and_packages() {
+        use chrono::TimeZone;
+
+        let mut marker = crate::pending::RebootMarker {
+            reboot_required: true,
+            detected: chrono::Local
+                .with_ymd_and_hms(2026, 3, 1, 4, 30, 0)
+                .unwrap(),
+            reason: "Core libraries or services have been updated".into(),
+            packages: vec!["kernel-core".into(), "glibc".into()],
+        };
+        assert_eq!(
+            crate::pending::notice(&marker),
+            "fup: a reboot is pending to finish installing updates since 2026-03-01 04:30: \
+             Core libraries or services have been updated\nUpdated: kernel-core, glibc"
+        );
+        marker.packages.clear();
+        assert!(!crate::pending::notice(&marker).contains("Updated:"));
+    }
+
+    #[test]
+    fn unattended_runs_notify_graphical_users_of_changes_and_failures() {
+        use crate::notify::{graphical_user, notify_args};
+        use crate::{RunOutcome, desktop_notice, updater::StageStats};
+
+        assert_eq!(
+            graphical_user("Name=alice\nType=wayland\nClass=user\n").as_deref(),
+            Some("alice")
+        );
+        assert_eq!(graphical_user("Name=bob\nType=tty\nClass=user\n"), None);
+        assert_eq!(
+            graphical_user("Name=gdm\nType=wayland\nClass=greeter\n"),
+            None
+        );
+        let args = notify_args("alice", "Updates failed", "...", true);
+        assert_eq!(args[..2], ["--user", "--machine=alice@.host"]);
+        assert_eq!(args[args.len() - 2], "2");
+
+        let mut outcome = RunOutcome {
+            attempted: true,
+            ..Default::default()
+        };
+        let mut summary = crate::updater::UpdateSummary::default();
+        summary.stages.insert(Stage::System, StageStats::default());
+        outcome.summary = Some(summary.clone());
+        // Nothing to update is nothing to say
+        assert_eq!(desktop_notice(&Ok(()), &outcome), None);
+
+        summary.flatpak_updated = true;
+        summary.stages.insert(
+            Stage::Firmware,
+            StageStats {
+                failed: true,
+                ..Default::default()
+            },
+        );
+        outcome.summary = Some(summary);
+        outcome.reboot = true;
+        let (title, body, urgent) = desktop_notice(&Ok(()), &outcome).unwrap();
+        assert_eq!(title, "Some updates failed");
+        assert!(
+            body.starts_with("Flatpak apps updated\nFirmware update failed\nRestart"),
+            "{}",
+            body
+        );
+        assert!(urgent);
+
+        let started = RunOutcome {
+            attempted: true,
+            ..Default::default()
+        };
+        assert_eq!(desktop_notice(&Err(UpdateError::Cancelled), &started), None);
+        let (title, _, _) = desktop_notice(&Err(UpdateError::Verification(2)), &started).unwrap();
+        assert_eq!(title, "Updates failed");
+        // A failed --refresh from a timer installed nothing
+        assert_eq!(
+            desktop_notice(&Err(UpdateError::NoNetwork), &RunOutcome::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn mqtt_messages_describe_the_last_run_for_dashboards() {
+        use crate::config::NotifyConfig;
+        use crate::{RunOutcome, run_message, updater::StageStats};
+
+        let broker = |url: &str| {
+            NotifyConfig {
+                mqtt_broker: url.into(),
+                ..Default::default()
+            }
+            .mqtt_address()
+        };
+        assert_eq!(broker("").unwrap(), None);
+        assert_eq!(
+            broker("mqtt://ha.lan").unwrap(),
+            Some(("ha.lan".into(), 1883))
+        );
+        assert_eq!(
+            broker("tcp://10.0.0.2:1884/").unwrap(),
+            Some(("10.0.0.2".into(), 1884))
+        );
+        assert!(
+            broker("mqtts://ha.lan:8883")
+                .unwrap_err()
+                .to_string()
+                .contains("TLS")
+        );
+        assert!(broker("ha.lan").is_err());
+        assert!(broker("mqtt://ha.lan:port").is_err());
+        assert_eq!(
+            broker("mqtt://[::1]:1884").unwrap(),
+            Some(("[::1]".into(), 1884))
+        );
+        assert_eq!(
+            broker("mqtt://[fd00::2]").unwrap(),
+            Some(("[fd00::2]".into(), 1883))
+        );
+        assert!(
+            broker("mqtt://::1")
+                .unwrap_err()
+                .to_string()
+                .contains("brackets")
+        );
+        assert!(broker("mqtt://[::1").is_err());
+
+        use crate::config_cmd::redacted;
+        let password = toml::Value::String("hunter2".into());
+        assert_eq!(
+            redacted("notify", "mqtt_password", &password).as_str(),
+            Some("<set>")
+        );
+        assert_eq!(
+            redacted("notify", "mqtt_username", &password).as_str(),
+            Some("hunter2")
+        );
+        assert_eq!(
+            redacted(
+                "notify",
+                "mqtt_password",
+                &toml::Value::String(String::new())
+            )
+            .as_str(),
+            Some("")
+        );
+
+        let mut outcome = RunOutcome::default();
+        let mut summary = crate::updater::UpdateSummary::default();
+        summary.stages.insert(Stage::System, StageStats::default());
+        summary.stages.insert(
+            Stage::Firmware,
+            StageStats {
+                failed: true,
+                ..Default::default()
+            },
+        );
+        outcome.summary = Some(summary);
+        outcome.reboot = true;
+        let message = run_message(&Ok(()), &outcome);
+        assert_eq!(message["result"], "partial");
+        assert_eq!(message["stages"]["system"], "ok");
+        assert_eq!(message["stages"]["firmware"], "failed");
+        assert_eq!(message["reboot_required"], true);
+        assert!(message["error"].is_null());
+
+        let message = run_message(
+            &Err(UpdateError::Preflight("no space".into())),
+            &RunOutcome::default(),
+        );
+        assert_eq!(message["result"], "failed");
+        assert!(message["stages"].is_null());
+        assert!(message["error"].as_str().unwrap().contains("no space"));
+    }
+
+    #[test]
+    fn status_file_keeps_the_last_success_across_failed_runs() {
+        use crate::cache::CachedCheck;
+        use crate::status::StatusFile;
+        use chrono::{Local, TimeDelta};
+
+        let monday = Local::now() - TimeDelta::days(2);
+        let tuesday = monday + TimeDelta::days(1);
+        let mut check = CachedCheck {
+            checked: monday,
+            updates: crate::updater::AvailableUpdates {
+                system: vec!["bash".into(), "glibc".into()],
+                firmware: vec!["UEFI".into()],
+                ..Default::default()
+            },
+            outdated: false,
+            new: None,
+        };
+
+        let status = StatusFile::update(None, Some(&check), false, None, monday);
+        let pending = status.pending.clone().unwrap();
+        assert_eq!(
+            (
+                status.version,
+                pending.total,
+                pending.system,
+                pending.firmware
+            ),
+            (1, 3, 2, 1)
+        );
+        assert_eq!(status.last_run, None);
+
+        check.outdated = true;
+        let status = StatusFile::update(Some(status), Some(&check), true, Some("ok"), monday);
+        assert!(status.outdated && status.reboot_required);
+        assert_eq!(status.last_success, Some(monday));
+
+        let status = StatusFile::update(Some(status), Some(&check), true, Some("failed"), tuesday);
+        assert_eq!(status.last_run, Some(tuesday));
+        assert_eq!(status.last_result.as_deref(), Some("failed"));
+        assert_eq!(status.last_success, Some(monday));
+        // What widgets parse, round-tripped through the file
+        let json = serde_json::to_string(&status).unwrap();
+        assert_eq!(serde_json::from_str::<StatusFile>(&json).unwrap(), status);
+    }
+
+    #[test]
+    fn audit_appends_chain_to_the_last_line_without_reading_the_whole_trail() {
+        use crate::audit::last_line;
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("fup-audit-{}.jsonl", std::process::id()));
+        std::fs::write(&path, "").unwrap();
+        // Opened like the trail: appends go to the end wherever reading left off
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+        assert_eq!(last_line(&mut file).unwrap(), None);
+
+        // Longer than one read from the end
+        let long = format!(
+            "{{\"event\":\"changes\",\"changes\":\"{}\"}}",
+            "x".repeat(10_000)
+        );
+        write!(file, "{{\"event\":\"start\"}}\n{}\n\n", long).unwrap();
+        assert_eq!(
+            last_line(&mut file).unwrap().as_deref(),
+            Some(long.as_str())
+        );
+        writeln!(file, "{{\"event\":\"finish\"}}").unwrap();
+        assert_eq!(
+            last_line(&mut file).unwrap().as_deref(),
+            Some("{\"event\":\"finish\"}")
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn quiet_runs_print_a_status_line_only_when_something_needs_attention() {
+        use crate::{RunOutcome, status_line, updater::StageStats};
+
+        let mut outcome = RunOutcome::default();
+        let mut summary = crate::updater::UpdateSummary::default();
+        summary.stages.insert(Stage::System, StageStats::default());
+        outcome.summary = Some(summary.clone());
+        assert_eq!(status_line(&Ok(()), &outcome), None);
+
+        outcome.reboot = true;
+        let line = status_line(&Ok(()), &outcome).unwrap();
+        assert!(line.starts_with("fup result=ok run="), "{}", line);
+        assert!(
+            line.ends_with("system=ok changes=0 reboot=required"),
+            "{}",
+            line
+        );
+
+        summary.stages.insert(
+            Stage::Flatpak,
+            StageStats {
+                failed: true,
+                ..Default::default()
+            },
+        );
+        summary.halted.push(Stage::Firmware);
+        outcome.summary = Some(summary);
+        outcome.reboot = false;
+        let line = status_line(&Ok(()), &outcome).unwrap();
+        assert!(line.contains("result=partial"), "{}", line);
+        assert!(line.contains("flatpak=failed firmware=halted"), "{}", line);
+
+        let line = status_line(
+            &Err(UpdateError::Config("bad \"value\"".into())),
+            &RunOutcome::default(),
+        )
+        .unwrap();
+        assert!(line.starts_with("fup result=failed"), "{}", line);
+        assert!(
+            line.ends_with(r#"error="Configuration error: bad \"value\"""#),
+            "{}",
+            line
+        );
+    }
+
+    #[test]
+    fn parallel_runs_only_allowed_overlaps_together() {
+        use crate::updater::{Stage::*, parallel_waves};
+
+        let all = [System, Flatpak, Firmware];
+        let defaults = crate::config::RunConfig::default();
+        assert_eq!(
+            parallel_waves(&all, &defaults.overlap),
+            vec![vec![System, Flatpak], vec![Firmware]]
+        );
+        assert_eq!(
+            parallel_waves(&all, &[[Firmware, Flatpak]]),
+            vec![vec![System], vec![Flatpak, Firmware]]
+        );
+        assert_eq!(
+            parallel_waves(&[System, Firmware], &[]),
+            vec![vec![System], vec![Firmware]]
+        );
+
+        let unsafe_overlap = crate::config::RunConfig {
+            overlap: vec![[Firmware, System]],
+            ..Default::default()
+        };
+        assert!(matches!(
+            unsafe_overlap.check(),
+            Err(UpdateError::Config(_))
+        ));
+        assert!(defaults.check().is_ok());
+    }
+
+    #[tokio::test]
+    async fn mirror_replaces_repositories_and_prunes_old_versions() {
+        let mut config = test_config();
+        config.mirror.url = "http://mirror.lan/fedora/".into();
+        config.mirror.repos = vec!["fedora".into(), "updates".into()];
+        let runner = Arc::new(ScriptedRunner::default());
+        let (updater, _tx) = updater(&config, &runner);
+
+        updater.update_system().await.unwrap();
+
+        assert!(runner.calls()[0].contains(
+            "--repofrompath=fup-mirror-fedora,http://mirror.lan/fedora/fedora --setopt=fup-mirror-fedora.gpgcheck=1 \
+             --repofrompath=fup-mirror-updates,http://mirror.lan/fedora/updates --setopt=fup-mirror-updates.gpgcheck=1 \
+             --repo=fup-mirror-fedora,fup-mirror-updates update"
+        ));
+
+        let day =
+            |n: u64| std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(n * 86400);
+        let synced = [
+            ("Packages/b/bash-5.2.32-1.fc41.x86_64.rpm", day(3)),
+            ("Packages/b/bash-5.2.26-3.fc41.x86_64.rpm", day(1)),
+            ("Packages/b/bash-5.2.26-1.fc41.x86_64.rpm", day(0)),
+            ("Packages/b/bash-5.2.26-1.fc41.i686.rpm", day(0)),
+            (
+                "Packages/g/glibc-all-langpacks-2.40-9.fc41.x86_64.rpm",
+                day(2),
+            ),
+        ]
+        .map(|(path, time)| (std::path::PathBuf::from(path), time));
+        assert_eq!(
+            crate::mirror::old_packages(&synced, 2),
+            [std::path::PathBuf::from(
+                "Packages/b/bash-5.2.26-1.fc41.x86_64.rpm"
+            )]
+        );
+        assert_eq!(crate::mirror::old_packages(&synced, 1).len(), 2);
+        assert!(crate::mirror::old_packages(&synced, 0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_update_reports_cause_and_output() {
+        let runner = Arc::new(ScriptedRunner::default().on(
+            "dnf5 update",
+            [Reply::fail(1, "Updating and loading repositories:\nProblem: package foo-2.0 requires libbar.so.3, but none of the providers can be installed\n")],
+        ));
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        let err = updater.update_system().await.unwrap_err();
+
+        assert!(err.hint().is_some());
+        match err {
+            UpdateError::DependencyProblem {
+                reason, details, ..
+            } => {
+                assert_eq!(
+                    reason,
+                    "Problem: package foo-2.0 requires libbar.so.3, but none of the providers can be installed"
+                );
+                assert!(details.starts_with("Updating and loading repositories:"));
+            }
+            other => panic!("unexpected error: {other:?}"),
+        }
+        // A dependency problem is not worth retrying
+        assert_eq!(runner.called("dnf5 update"), 1);
+        assert_eq!(runner.called("dnf5 autoremove"), 0);
+    }
+
+    #[tokio::test]
+    async fn fail_fast_skips_backends_after_a_failure() {
+        let stages = [Stage::System, Stage::Flatpak, Stage::Firmware];
+        let runner = Arc::new(ScriptedRunner::default().on(
+            "dnf5 update",
+            [Reply::fail(1, "Problem: conflicting requests\n")],
+        ));
+        let (halting, _tx) = updater(&test_config(), &runner);
+
+        halting.run_stages(&stages, true).await.unwrap();
+
+        let summary = halting.summary().await;
+        assert_eq!(summary.halted, [Stage::Flatpak, Stage::Firmware]);
+        assert_eq!(summary.halted_by, Some(Stage::System));
+        assert!(runner.calls().iter().all(|c| c.starts_with("dnf5")));
+
+        let runner = Arc::new(ScriptedRunner::default().on(
+            "dnf5 update",
+            [Reply::fail(1, "Problem: conflicting requests\n")],
+        ));
+        let (continuing, _tx) = updater(&test_config(), &runner);
+
+        continuing.run_stages(&stages[..2], false).await.unwrap();
+
+        assert!(continuing.summary().await.halted.is_empty());
+        assert!(runner.called("flatpak update") > 0);
+    }
+
+    #[tokio::test]
+    async fn failed_backends_are_retried_at_the_end() {
+        let runner = Arc::new(ScriptedRunner::default().on(
+            "dnf5 update",
+            [
+                Reply::fail(1, "Problem: conflicting requests\n"),
+                Reply::fail(1, "Problem: conflicting requests\n"),
+                Reply::ok(DNF_TRANSACTION),
+            ],
+        ));
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        updater
+            .run_stages(&[Stage::System, Stage::Flatpak], true)
+            .await
+            .unwrap();
+        updater
+            .retry_failed(3, std::time::Duration::ZERO, true)
+            .await
+            .unwrap();
+
+        let summary = updater.summary().await;
+        assert!(summary.errors.is_empty());
+        assert!(summary.halted.is_empty());
+        assert_eq!(summary.stages[&Stage::System].retries, 2);
+        assert!(!summary.stages[&Stage::System].failed);
+        assert_eq!(runner.called("dnf5 update"), 3);
+        assert!(runner.called("flatpak update") > 0);
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let runner = Arc::new(ScriptedRunner::default().on(
+            "dnf5 update",
+            [
+                Reply::fail(
+                    1,
+                    "Curl error (28): Timeout was reached for https://mirror.example/repomd.xml\n",
+                ),
+                Reply::ok(DNF_TRANSACTION),
+            ],
+        ));
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        assert_eq!(updater.update_system().await.unwrap(), 2);
+        assert_eq!(runner.called("dnf5 update"), 2);
+    }
+
+    #[tokio::test]
+    async fn missing_dnf5_is_an_error() {
+        let runner = Arc::new(ScriptedRunner::default().without("dnf5"));
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        let err = updater.update_system().await.unwrap_err();
+
+        assert!(matches!(err, UpdateError::CommandNotFound(cmd) if cmd == "dnf5"));
+        assert!(runner.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn firmware_exit_code_2_means_nothing_to_do() {
+        let runner = Arc::new(ScriptedRunner::default().on(
+            "fwupdmgr update",
+            [Reply::fail(2, "No updatable devices\n")],
+        ));
+        let (updater, _tx) = updater(&test_config(), &runner);
+        let updater = updater.with_firmware_approval(FirmwareApproval::Forced);
+
+        assert_eq!(updater.update_firmware().await.unwrap(), 0);
+        assert!(!updater.summary().await.firmware_updated);
+    }
+
+    const FWUPD_UPDATES: &str = r#"{
+  "Devices" : [
+    {
+      "Name" : "System Firmware",
+      "DeviceId" : "a45df35ac0e948ee180fe216a5f703f32dda163f",
+      "Guid" : ["230c8b18-8d9b-53ec-838b-6cfc0383493a"],
+      "Version" : "1.18.0",
+      "Releases" : [{ "Version" : "1.19.1" }]
+    },
+    {
+      "Name" : "Samsung SSD 980 PRO 1TB",
+      "DeviceId" : "71b677ca0f1bc2c5b804fa1d59e52064ce589293",
+      "Guid" : ["6e1b2d1b-9e2f-5ec5-8f3c-4a59b7bd8b41"],
+      "Version" : "5B2QGXA7",
+      "Releases" : [{ "Version" : "5B2QGXA8" }]
+    },
+    {
+      "Name" : "Thunderbolt Dock",
+      "DeviceId" : "e2f0bbe4b2a1c06cd6f1e2b7bd3e3e8a96f0b2a1",
+      "Guid" : ["0c0d2b7e-3f0a-5b5c-9a6e-1d9f2b4c5e6f"],
+      "Version" : "20.00",
+      "Releases" : [{ "Version" : "21.00" }]
+    }
+  ]
+}"#;
+
+    #[tokio::test]
+    async fn firmware_devices_are_skipped_and_selected() {
+        let runner = Arc::new(
+            ScriptedRunner::default().on("fwupdmgr get-updates --json", [Reply::ok(FWUPD_UPDATES)]),
+        );
+        let mut config = test_config();
+        config.firmware.skip_devices = vec!["samsung ssd*".into()];
+        let (updater, _tx) = updater(&config, &runner);
+        let updater = updater
+            .with_firmware_devices(&[
+                "230C8B18-8D9B-53EC-838B-6CFC0383493A".into(),
+                "*SSD*".into(),
+            ])
+            .with_firmware_approval(FirmwareApproval::Forced);
+
+        assert_eq!(updater.update_firmware().await.unwrap(), 1);
+
+        assert_eq!(runner.called("fwupdmgr update"), 1);
+        assert_eq!(
+            runner.called("fwupdmgr update a45df35ac0e948ee180fe216a5f703f32dda163f -y"),
+            1
+        );
+        let results: Vec<_> = updater
+            .summary()
+            .await
+            .firmware_devices
+            .into_iter()
+            .map(|d| d.result)
+            .collect();
+        assert_eq!(
+            results,
+            [
+                FirmwareResult::Updated,
+                FirmwareResult::Skipped,
+                FirmwareResult::NotSelected
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn one_failing_device_does_not_stop_the_others() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on("fwupdmgr get-updates --json", [Reply::ok(FWUPD_UPDATES)])
+                .on(
+                    "fwupdmgr update 71b6",
+                    [Reply::fail(1, "failed to write-firmware: device busy\n")],
+                ),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+        let updater = updater.with_firmware_approval(FirmwareApproval::Forced);
+
+        let err = updater.update_firmware().await.unwrap_err();
+
+        assert!(err.to_string().contains("Samsung SSD 980 PRO 1TB"));
+        assert_eq!(runner.called("fwupdmgr update"), 3);
+        let summary = updater.summary().await;
+        assert!(summary.firmware_updated);
+        assert!(
+            matches!(&summary.firmware_devices[1].result, FirmwareResult::Failed(reason) if reason.contains("device busy"))
+        );
+    }
+
+    #[tokio::test]
+    async fn fwupd_exit_code_two_means_nothing_to_update() {
+        let runner = Arc::new(ScriptedRunner::default().on(
+            "fwupdmgr get-updates --json",
+            [Reply::fail(2, "No updatable devices")],
+        ));
+        let (nothing, _tx) = updater(&test_config(), &runner);
+        let nothing = nothing.with_firmware_approval(FirmwareApproval::Forced);
+
+        assert_eq!(nothing.update_firmware().await.unwrap(), 0);
+        assert_eq!(runner.called("fwupdmgr update"), 0);
+
+        // Any other failure leaves only the update of everything
+        let runner = Arc::new(
+            ScriptedRunner::default().on("fwupdmgr get-updates --json", [Reply::fail(1, "")]),
+        );
+        let (unlisted, _tx) = updater(&test_config(), &runner);
+        let unlisted = unlisted.with_firmware_approval(FirmwareApproval::Forced);
+
+        unlisted.update_firmware().await.unwrap();
+        assert_eq!(runner.called("fwupdmgr update"), 1);
+    }
+
+    #[tokio::test]
+    async fn unattended_runs_leave_firmware_alone() {
+        let runner = Arc::new(
+            ScriptedRunner::default().on("fwupdmgr get-updates --json", [Reply::ok(FWUPD_UPDATES)]),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        assert_eq!(updater.update_firmware().await.unwrap(), 0);
+
+        assert_eq!(runner.called("fwupdmgr update"), 0);
+        let summary = updater.summary().await;
+        assert!(
+            summary
+                .firmware_devices
+                .iter()
+                .all(|d| d.result == FirmwareResult::NotConfirmed)
+        );
+        assert!(
+            summary
+                .errors
+                .iter()
+                .any(|e| e.contains("--firmware-force"))
+        );
+    }
+
+    #[tokio::test]
+    async fn fwupd_problems_block_a_device() {
+        let json = FWUPD_UPDATES.replacen(
+            r#""Version" : "1.18.0","#,
+            r#""Version" : "1.18.0", "Problems" : ["require-ac-power"],"#,
+            1,
+        );
+        let runner = Arc::new(
+            ScriptedRunner::default().on("fwupdmgr get-updates --json", [Reply::ok(&json)]),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+        let updater = updater
+            .with_firmware_approval(FirmwareApproval::Ask)
+            .with_firmware_devices(&["System*".into()]);
+
+        // Nothing is left to ask about once the only selected device is blocked
+        assert!(updater.update_firmware().await.is_err());
+
+        assert_eq!(runner.called("fwupdmgr update"), 0);
+        assert!(matches!(
+            &updater.summary().await.firmware_devices[0].result,
+            FirmwareResult::Blocked(reason) if reason.contains("require-ac-power")
+        ));
+    }
+
+    #[tokio::test]
+    async fn lvfs_testing_and_branches_are_applied() {
+        let remotes = r#"{ "Remotes" : [ { "Id" : "lvfs", "Enabled" : true }, { "Id" : "lvfs-testing", "Enabled" : false } ] }"#;
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on("fwupdmgr get-remotes --json", [Reply::ok(remotes)])
+                .on("fwupdmgr get-updates --json", [Reply::ok(FWUPD_UPDATES)])
+                .on("fwupdmgr get-devices --json", [Reply::ok(FWUPD_UPDATES)]),
+        );
+        let mut config = test_config();
+        config.firmware.lvfs_testing = Some(true);
+        config
+            .firmware
+            .branches
+            .insert("system*".into(), "vendor-beta".into());
+        let (updater, _tx) = updater(&config, &runner);
+        let updater = updater.with_firmware_approval(FirmwareApproval::Forced);
+
+        updater.update_firmware().await.unwrap();
+
+        assert_eq!(runner.called("fwupdmgr enable-remote lvfs-testing -y"), 1);
+        assert_eq!(
+            runner.called(
+                "fwupdmgr switch-branch a45df35ac0e948ee180fe216a5f703f32dda163f vendor-beta"
+            ),
+            1
+        );
+        // The branch switch replaces the device's regular update
+        assert_eq!(
+            runner.called("fwupdmgr update a45df35ac0e948ee180fe216a5f703f32dda163f"),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_sizes_each_update_and_limits_unattended_downloads() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on(
+                    "dnf5 check-upgrade",
+                    [Reply::ok("bash.x86_64  5.2.32-1.fc41  updates\n")],
+                )
+                .on(
+                    "dnf5 repoquery --upgrades",
+                    [Reply::ok("bash.x86_64 1900000 8300000\n")],
+                )
+                .on(
+                    "flatpak remote-ls --system --updates --app",
+                    [Reply::ok(
+                        "org.mozilla.firefox/x86_64/stable\tflathub\t95.2 MB\t262.1 MB\n",
+                    )],
+                )
+                .on("flatpak remote-ls", [Reply::ok("")])
+                .on(
+                    "fwupdmgr get-updates --json",
+                    [Reply::ok(
+                        r#"{ "Devices" : [{ "DeviceId" : "a45d", "Name" : "System Firmware",
+                             "Releases" : [{ "Version" : "1.19.1", "Size" : 12000000 }] }] }"#,
+                    )],
+                )
+                .on(
+                    "fwupdmgr get-updates",
+                    [Reply::ok("System Firmware: 1.18.0 \u{2192} 1.19.1\n")],
+                ),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        let updates = updater.check_available_updates().await.unwrap();
+
+        let size = |row: &str| updates.sizes.get(row).copied().unwrap_or_default();
+        assert_eq!(
+            size("bash.x86_64  5.2.32-1.fc41  updates"),
+            crate::updater::ItemSize {
+                download: 1_900_000,
+                installed: 8_300_000
+            }
+        );
+        assert_eq!(
+            size("org.mozilla.firefox stable flathub system").installed,
+            262_100_000
+        );
+        assert_eq!(
+            size("System Firmware: 1.18.0 \u{2192} 1.19.1").download,
+            12_000_000
+        );
+        assert_eq!(updates.total_size(updates.all_rows()).download, 109_100_000);
+
+        let mut config = test_config();
+        config.run.max_download = "100M".into();
+        assert_eq!(config.run.max_download_bytes(), Some(100_000_000));
+        assert!(crate::preflight::check_download_size(100_000_000, 109_100_000, false).is_err());
+        assert!(crate::preflight::check_download_size(100_000_000, 109_100_000, true).is_ok());
+        config.run.max_download = "lots".into();
+        assert!(config.run.check().is_err());
+    }
+
+    #[test]
+    fn paged_update_lists_are_never_cut_short() {
+        use crate::{available_updates_text, updater::AvailableUpdates};
+
+        let updates = AvailableUpdates {
+            system: (1..=40)
+                .map(|i| format!("pkg{}.x86_64  1.{}-1.fc41  updates", i, i))
+                .collect(),
+            ..Default::default()
+        };
+
+        let cut = available_updates_text(&updates, None, &[], true);
+        let whole = available_updates_text(&updates, None, &[], false);
+
+        assert!(!cut.contains("pkg33.x86_64"));
+        assert!(cut.contains("...and"));
+        assert!(whole.contains("pkg33.x86_64") && whole.contains("pkg40.x86_64"));
+        assert!(!whole.contains("...and"));
+    }
+
+    #[test]
+    fn refresh_lists_can_be_filtered_sorted_and_limited() {
+        use crate::security::{Advisory, Severity, package_severities};
+        use crate::updater::{AvailableUpdates, ItemSize, SortKey};
+
+        let rows = [
+            "kernel.x86_64  6.11.4-301.fc41  updates",
+            "bash.x86_64  5.2.32-1.fc41  updates",
+            "openssl-libs.x86_64  1:3.2.2-9.fc41  updates",
+        ];
+        let mut updates = AvailableUpdates {
+            system: rows.iter().map(|r| r.to_string()).collect(),
+            firmware: vec!["System Firmware: 1.18.0 \u{2192} 1.19.1".into()],
+            ..Default::default()
+        };
+        updates.sizes.insert(
+            rows[0].into(),
+            ItemSize {
+                download: 90_000_000,
+                installed: 0,
+            },
+        );
+        updates.sizes.insert(
+            rows[2].into(),
+            ItemSize {
+                download: 2_000_000,
+                installed: 0,
+            },
+        );
+        let advisories = [Advisory {
+            id: "FEDORA-2024-1a2b3c".into(),
+            severity: Some(Severity::Important),
+            packages: vec!["openssl-libs-1:3.2.2-9.fc41.x86_64".into()],
+            cves: Vec::new(),
+        }];
+        let severities = package_severities(&advisories);
+
+        let mut by_severity = updates.clone();
+        by_severity.slice(&[], Some(SortKey::Severity), Some(1), &severities);
+        assert_eq!(by_severity.system, [rows[2]]);
+        let mut by_size = updates.clone();
+        by_size.slice(&[], Some(SortKey::Size), None, &severities);
+        assert_eq!(by_size.system, [rows[0], rows[2], rows[1]]);
+        updates.slice(
+            &["KERNEL*".into(), "system*".into()],
+            Some(SortKey::Name),
+            None,
+            &severities,
+        );
+        assert_eq!(updates.system, [rows[0]]);
+        assert_eq!(updates.firmware.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn flatpak_remote_selection_names_refs() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on(
+                    "flatpak remote-ls --system",
+                    [Reply::ok(
+                        "org.mozilla.firefox/x86_64/stable\tflathub\n\
+                         org.fedoraproject.Platform/x86_64/f41\tfedora\n",
+                    )],
+                )
+                .on(
+                    "flatpak remote-ls --user",
+                    [Reply::ok("org.gnome.Boxes/x86_64/stable\tfedora\n")],
+                ),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+        let updater = updater.with_flatpak_remotes(&["flathub".into()]);
+
+        updater.update_flatpak().await.unwrap();
+
+        assert_eq!(
+            runner.called("flatpak update --system -y org.mozilla.firefox"),
+            1
+        );
+        // Nothing from flathub is pending in the user installation
+        assert_eq!(runner.called("flatpak update --user"), 0);
+        assert_eq!(runner.called("flatpak uninstall"), 0);
+    }
+
+    #[tokio::test]
+    async fn flatpak_updates_count_apps_once_and_runtimes_apart() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on(
+                    "flatpak remote-ls --system --updates --app",
+                    [Reply::ok(
+                        "org.mozilla.firefox/x86_64/stable\tflathub\t95.2 MB\n",
+                    )],
+                )
+                .on(
+                    "flatpak remote-ls --system --updates --runtime",
+                    [Reply::ok(
+                        "org.freedesktop.Platform.GL.default/x86_64/24.08\tflathub\t150 MB\n\
+                         org.freedesktop.Platform.GL.default/i386/24.08\tflathub\t140 MB\n\
+                         org.mozilla.firefox.Locale/x86_64/stable\tflathub\t2 MB\n",
+                    )],
+                )
+                .on("flatpak remote-ls --user", [Reply::ok("")]),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        let updates = updater.check_available_updates().await.unwrap();
+        let preview = updater.preview(false, true, false).await;
+
+        assert_eq!(
+            updates.flatpak,
+            ["org.mozilla.firefox stable flathub system"]
+        );
+        assert_eq!(
+            updates.flatpak_runtimes,
+            [
+                "org.freedesktop.Platform.GL.default 24.08 flathub system",
+                "org.mozilla.firefox.Locale stable flathub system"
+            ]
+        );
+        assert_eq!(updates.flatpak_count(), 3);
+        // Both GL arches download, but they're one update
+        assert_eq!(
+            preview.flatpak[1],
+            (
+                "org.freedesktop.Platform.GL.default 24.08 flathub system".to_string(),
+                290_000_000
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn flatpak_apps_update_side_by_side_after_runtimes() {
+        let mut config = test_config();
+        config.flatpak.jobs = 2;
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on(
+                    "flatpak remote-ls --system --updates --app",
+                    [Reply::ok("org.mozilla.firefox/x86_64/stable\tflathub\norg.gnome.Boxes/x86_64/stable\tflathub\n")],
+                )
+                .on("flatpak remote-ls --system --updates --runtime", [Reply::ok("org.gnome.Platform/x86_64/47\tflathub\n")])
+                .on("flatpak update --system -y org.mozilla.firefox", [Reply::fail(1, "error: firefox failed")])
+                .on("flatpak remote-ls --user", [Reply::ok("")]),
+        );
+        let (updater, _tx) = updater(&config, &runner);
+
+        assert!(updater.update_flatpak().await.is_err());
+
+        let calls = runner.calls();
+        let runtime = calls
+            .iter()
+            .position(|c| c == "flatpak update --system -y org.gnome.Platform")
+            .unwrap();
+        let boxes = calls
+            .iter()
+            .position(|c| c == "flatpak update --system -y org.gnome.Boxes")
+            .unwrap();
+        assert!(runtime < boxes);
+        // The failed app doesn't hold up the other one
+        assert_eq!(
+            runner.called("flatpak update --system -y org.mozilla.firefox"),
+            1
+        );
+        assert_eq!(runner.called("flatpak update --user"), 0);
+    }
+
+    #[tokio::test]
+    async fn unlistable_user_flatpaks_leave_the_system_update_alone() {
+        let mut config = test_config();
+        config.flatpak.skip = vec!["org.gnome.Boxes".into()];
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on(
+                    "flatpak remote-ls --system --updates --app",
+                    [Reply::ok("org.mozilla.firefox/x86_64/stable\tflathub\norg.gnome.Boxes/x86_64/stable\tflathub\n")],
+                )
+                .on("flatpak remote-ls --user", [Reply::fail(1, "error: Unable to load user installation")]),
+        );
+        let (unlisted, _tx) = updater(&config, &runner);
+
+        unlisted.update_flatpak().await.unwrap();
+
+        assert_eq!(
+            runner.called("flatpak update --system -y org.mozilla.firefox"),
+            1
+        );
+        assert_eq!(runner.called("flatpak update --user"), 0);
+
+        // The system installation has to be listed to pick its refs
+        let runner = Arc::new(ScriptedRunner::default().on(
+            "flatpak remote-ls --system",
+            [Reply::fail(1, "error: No remote")],
+        ));
+        let (broken, _tx) = updater(&config, &runner);
+        assert!(broken.update_flatpak().await.is_err());
+        assert_eq!(runner.called("flatpak update"), 0);
+    }
+
+    #[tokio::test]
+    async fn eol_flatpaks_and_their_apps_are_flagged() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on(
+                    "flatpak remote-ls --system --columns=ref,options",
+                    [Reply::ok(
+                        "org.mozilla.firefox/x86_64/stable\t\n\
+                         org.gnome.Platform/x86_64/43\teol=The GNOME 43 runtime is no longer supported,eol-rebase=org.gnome.Platform/x86_64/47\n\
+                         com.example.Old/x86_64/stable\teol-rebase=com.example.New\n",
+                    )],
+                )
+                .on(
+                    "flatpak list --system",
+                    [Reply::ok(
+                        "org.mozilla.firefox/x86_64/stable\torg.freedesktop.Platform/x86_64/24.08\n\
+                         org.gnome.Boxes/x86_64/stable\torg.gnome.Platform/x86_64/43\n\
+                         com.example.Old/x86_64/stable\torg.freedesktop.Platform/x86_64/24.08\n",
+                    )],
+                ),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        let eol = updater.flatpak_eol().await;
+
+        assert_eq!(eol.len(), 2);
+        assert_eq!(eol[0].name, "org.gnome.Boxes/x86_64/stable");
+        assert_eq!(
+            eol[0].runtime.as_deref(),
+            Some("org.gnome.Platform/x86_64/43")
+        );
+        assert_eq!(eol[0].reason, "The GNOME 43 runtime is no longer supported");
+        assert_eq!(eol[1].reason, "renamed to com.example.New");
+        assert_eq!(eol[1].suggestion(), "replaced by com.example.New");
+    }
+
+    #[tokio::test]
+    async fn run_stage_records_failure() {
+        let runner = Arc::new(
+            ScriptedRunner::default().on("flatpak update", [Reply::fail(1, "error: oops\n")]),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        assert!(updater.run_stage(Stage::Flatpak).await.is_err());
+        assert!(!updater.summary().await.errors.is_empty());
+    }
+
+    #[tokio::test]
+    async fn dry_run_preview_resolves_transactions() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .without("fwupdmgr")
+                .on(
+                    "dnf5 update --refresh --assumeno",
+                    [Reply {
+                        code: 1,
+                        stderr: String::new(),
+                        stdout: "Package   Arch   Version   Repository   Size\n\
+                         Upgrading:\n \
+                         bash   x86_64   5.2.32-1.fc41   updates   8.1 MiB\n   \
+                         replacing bash   x86_64   5.2.26-1.fc40   updates   8.0 MiB\n\
+                         Installing dependencies:\n \
+                         libnew   x86_64   1.0-1.fc41   updates   120.0 KiB\n\
+                         \n\
+                         Transaction Summary:\n \
+                         Upgrading:  1 package\n\
+                         Need to download 3 MiB.\n\
+                         Operation aborted.\n"
+                            .into(),
+                    }],
+                )
+                .on(
+                    "dnf5 autoremove --assumeno",
+                    [Reply::ok("Removing unused dependencies:\n libfoo   x86_64   1.0-1.fc40   updates   1.2 MiB\n")],
+                )
+                .on("flatpak remote-ls --system", [Reply::ok("org.mozilla.firefox/x86_64/stable\tflathub\t95.2 MB\n")]),
+        );
+        let mut config = test_config();
+        config.flatpak.remove_unused = false;
+        let (updater, _tx) = updater(&config, &runner);
+
+        let preview = updater.preview(true, true, true).await;
+
+        assert_eq!(preview.transaction.len(), 2);
+        assert_eq!(preview.transaction[0].action, "Upgrading");
+        assert_eq!(
+            preview.transaction[0].from.as_deref(),
+            Some("5.2.26-1.fc40")
+        );
+        assert_eq!(preview.transaction[1].action, "Installing dependencies");
+        assert_eq!(preview.autoremove, ["libfoo"]);
+        assert_eq!(
+            preview.flatpak,
+            [(
+                "org.mozilla.firefox stable flathub system".to_string(),
+                95_200_000
+            )]
+        );
+        assert_eq!(preview.download_bytes, 3 * 1024 * 1024 + 95_200_000);
+        assert!(runner.calls().iter().all(|c| !c.contains("-y")));
+    }
+
+    #[tokio::test]
+    async fn interrupted_transactions_are_found_and_recovered() {
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .on(
+                    "dnf5 history info last --json",
+                    [Reply::ok(r#"[{"id":42,"status":"Started"}]"#)],
+                )
+                .on(
+                    "dnf5 check --duplicates",
+                    [Reply::ok(
+                        "bash-5.2.26-1.fc40.x86_64\n duplicate with bash-5.2.32-1.fc41.x86_64\n",
+                    )],
+                ),
+        );
+        let (recovering, _tx) = updater(&test_config(), &runner);
+
+        let found = recovering.interrupted_transaction().await.unwrap();
+        assert_eq!(found.id.as_deref(), Some("42"));
+        assert_eq!(found.duplicates, ["bash-5.2.26-1.fc40.x86_64"]);
+
+        recovering
+            .recover_transaction(&found, Recovery::Complete)
+            .await
+            .unwrap();
+        recovering
+            .recover_transaction(&found, Recovery::Rollback)
+            .await
+            .unwrap();
+        assert_eq!(runner.called("dnf5 remove --duplicates -y"), 1);
+        assert_eq!(runner.called("dnf5 history undo 42 -y"), 1);
+
+        let clean = Arc::new(ScriptedRunner::default().on(
+            "dnf5 history info last --json",
+            [Reply::ok(r#"[{"id":43,"status":"Ok"}]"#)],
+        ));
+        let (after_success, _tx) = updater(&test_config(), &clean);
+        assert!(after_success.interrupted_transaction().await.is_none());
+
+        // A transaction that failed outright left nothing to recover
+        let failed = Arc::new(ScriptedRunner::default().on(
+            "dnf5 history info last --json",
+            [Reply::ok(r#"[{"id":44,"status":"Error"}]"#)],
+        ));
+        let (after_failure, _tx) = updater(&test_config(), &failed);
+        assert!(after_failure.interrupted_transaction().await.is_none());
+
+        // Unattended runs under "ask" go on without touching it
+        assert!(matches!(
+            crate::ask_recovery(&found, true),
+            Ok(Recovery::Ask)
+        ));
+    }
+
+    #[tokio::test]
+    async fn dry_run_changes_nothing() {
+        let runner = Arc::new(ScriptedRunner::default());
+        let (tx, rx) = watch::channel(None);
+        let updater = Updater::new(&test_config(), true, true, rx).with_runner(runner.clone());
+
+        updater.update_system().await.unwrap();
+        updater.update_flatpak().await.unwrap();
+        updater.update_firmware().await.unwrap();
+
+        // Read-only queries may still run
+        assert!(
+            runner
+                .calls()
+                .iter()
+                .all(|c| !c.split_whitespace().any(|w| w == "update"))
+        );
+        drop(tx);
+    }
+
+    #[test]
+    fn self_update_verifies_checksum_and_signature() {
+        use base64::Engine;
+        use ring::signature::KeyPair;
+        use sha2::{Digest, Sha256};
+
+        assert!(self_update::is_newer("2.10.0", "2.9.1"));
+        assert!(!self_update::is_newer("2.1.2", "2.1.2"));
+
+        let binary = b"\x7fELF new fup";
+        let digest: String = Sha256::digest(binary)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        let sums = format!("{}  fup\n", digest);
+        let key = ring::signature::Ed25519KeyPair::from_seed_unchecked(&[7; 32]).unwrap();
+        let engine = base64::engine::general_purpose::STANDARD;
+        let public_key = engine.encode(key.public_key().as_ref());
+        let signature = engine.encode(key.sign(sums.as_bytes()).as_ref());
+
+        assert!(self_update::verify(binary, "fup", &sums, None, "", false).is_err());
+        assert!(self_update::verify(binary, "fup", &sums, None, "", true).is_ok());
+        assert!(self_update::verify(b"tampered", "fup", &sums, None, "", true).is_err());
+        assert!(
+            self_update::verify(binary, "fup", &sums, Some(&signature), &public_key, false).is_ok()
+        );
+        assert!(
+            self_update::verify(
+                b"tampered",
+                "fup",
+                &sums,
+                Some(&signature),
+                &public_key,
+                false
+            )
+            .is_err()
+        );
+        assert!(self_update::verify(binary, "fup", &sums, None, &public_key, true).is_err());
+        let forged = sums.replace(&digest, &"0".repeat(64));
+        assert!(
+            self_update::verify(binary, "fup", &forged, Some(&signature), &public_key, true)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn bundles_leave_out_skipped_firmware() {
+        // Only the skipped device has a download; fetching it would fail
+        let json = FWUPD_UPDATES.replacen(
+            r#"[{ "Version" : "5B2QGXA8" }]"#,
+            r#"[{ "Version" : "5B2QGXA8", "Locations" : ["http://127.0.0.1:9/ssd.cab"] }]"#,
+            1,
+        );
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .without("dnf5")
+                .without("flatpak")
+                .on("fwupdmgr get-updates --json", [Reply::ok(&json)]),
+        );
+        let mut config = test_config();
+        config.firmware.skip_devices = vec!["samsung ssd*".into()];
+        let (updater, _tx) = updater(&config, &runner);
+        let dir = std::env::temp_dir().join(format!("fup-bundle-skip-{}", std::process::id()));
+
+        let manifest = updater
+            .export_bundle(&dir, false, false, true)
+            .await
+            .unwrap();
+
+        assert!(manifest.firmware.is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn bundled_firmware_needs_approval_and_honours_skip_devices() {
+        let manifest = crate::bundle::Manifest {
+            firmware: vec!["fw.cab".into()],
+            ..Default::default()
+        };
+        let dir = std::env::temp_dir().join("fup-bundle-firmware");
+        let cab = dir.join("firmware/fw.cab").display().to_string();
+        let mut config = test_config();
+        config.firmware.skip_devices = vec!["samsung ssd*".into()];
+        let script = || {
+            Arc::new(ScriptedRunner::default().on(
+                &format!("fwupdmgr get-details {} --json", cab),
+                [Reply::ok(FWUPD_UPDATES)],
+            ))
+        };
+
+        let runner = script();
+        let (unattended, _tx) = updater(&config, &runner);
+        assert_eq!(unattended.import_bundle(&dir, &manifest).await.unwrap(), 0);
+        assert_eq!(runner.called("fwupdmgr local-install"), 0);
+
+        let runner = script();
+        let (forced, _tx) = updater(&config, &runner);
+        let forced = forced.with_firmware_approval(FirmwareApproval::Forced);
+        assert_eq!(forced.import_bundle(&dir, &manifest).await.unwrap(), 2);
+        assert_eq!(runner.called("fwupdmgr local-install"), 2);
+        assert_eq!(
+            runner.called(&format!(
+                "fwupdmgr local-install {} a45df35ac0e948ee180fe216a5f703f32dda163f -y",
+                cab
+            )),
+            1
+        );
+        assert_eq!(
+            runner.called(&format!("fwupdmgr local-install {} 71b677ca", cab)),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn bundles_are_sealed_checked_and_installed() {
+        use base64::Engine;
+        use ring::signature::{Ed25519KeyPair, KeyPair};
+
+        let dir = std::env::temp_dir().join(format!("fup-bundle-{}", std::process::id()));
+        let key_file = dir.with_extension("key");
+        let rpm = dir.join("rpms/bash-5.2.32-1.fc41.x86_64.rpm");
+        // Stands in for what dnf5 --downloadonly leaves behind
+        std::fs::create_dir_all(rpm.parent().unwrap()).unwrap();
+        std::fs::write(&rpm, b"rpm").unwrap();
+        let runner = Arc::new(
+            ScriptedRunner::default()
+                .without("flatpak")
+                .without("fwupdmgr"),
+        );
+        let (updater, _tx) = updater(&test_config(), &runner);
+
+        let manifest = updater.export_bundle(&dir, true, true, true).await.unwrap();
+        assert_eq!(manifest.packages, ["bash-5.2.32-1.fc41.x86_64.rpm"]);
+        assert_eq!(
+            runner.called("dnf5 update --refresh -y --downloadonly --destdir="),
+            1
+        );
 
-            Ok(updates)
-        }
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new()).unwrap();
+        let engine = base64::engine::general_purpose::STANDARD;
+        std::fs::write(&key_file, engine.encode(pkcs8.as_ref())).unwrap();
+        let public_key = engine.encode(
+            Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+                .unwrap()
+                .public_key(),
+        );
+        crate::bundle::seal(&dir, &manifest, Some(&key_file)).unwrap();
+        assert_eq!(crate::bundle::open(&dir, &public_key).unwrap(), manifest);
 
-        pub async fn update_system(&self) -> Result<()> {
-            if !crate::system::command_exists("dnf5") {
-                return Err(UpdateError::CommandNotFound("dnf5".into()));
-            }
+        updater.import_bundle(&dir, &manifest).await.unwrap();
+        let install = format!(
+            "dnf5 install -y --disablerepo=* --setopt=localpkg_gpgcheck=1 {}",
+            rpm.display()
+        );
+        assert_eq!(runner.called(&install), 1);
 
-            let spinner = self.create_spinner("Updating system packages...");
+        std::fs::write(&rpm, b"tampered").unwrap();
+        assert!(matches!(
+            crate::bundle::open(&dir, &public_key),
+            Err(UpdateError::Bundle(_))
+        ));
+        std::fs::write(&rpm, b"rpm").unwrap();
+        std::fs::write(dir.join("rpms/smuggled.rpm"), b"rpm").unwrap();
+        assert!(matches!(
+            crate::bundle::open(&dir, &public_key),
+            Err(UpdateError::Bundle(_))
+        ));
+        std::fs::remove_file(dir.join("rpms/smuggled.rpm")).unwrap();
+        assert!(crate::bundle::open(&dir, &engine.encode([7; 32])).is_err());
 
-            self.run_command("dnf5", &["update", "--refresh", "-y"], "[DNF5]")
-                .await?;
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_file(&key_file).unwrap();
+    }
 
-            spinner.set_message("Removing unused packages...");
-            self.run_command("dnf5", &["autoremove", "-y"], "[DNF5]")
-                .await?;
+    #[test]
+    fn plain_output_and_color_flags() {
+        use clap::Parser;
 
-            spinner.finish_with_message("System update complete ✓".green().to_string());
-            self.summary.lock().await.system_updated = true;
-            Ok(())
-        }
+        let args =
+            crate::cli::Args::try_parse_from(["fup", "status", "--plain", "--color", "never"])
+                .unwrap();
+        assert!(args.plain);
+        assert_eq!(args.color, crate::ui::ColorChoice::Never);
+        assert!(!crate::ui::ColorChoice::Never.enabled());
+        assert!(crate::ui::ColorChoice::Always.enabled());
 
-        pub async fn update_flatpak(&self) -> Result<()> {
-            if !crate::system::command_exists("flatpak") {
-                info!("Flatpak not installed, skipping");
-                return Ok(());
-            }
+        crate::ui::set_plain(true);
+        assert_eq!(crate::ui::sym("✓"), "[ok]");
+        assert_eq!(crate::ui::sym("✗"), "[FAIL]");
+        assert_eq!(crate::ui::sym("═").repeat(3), "===");
+        assert!(crate::ui::ticks().is_ascii());
+        crate::ui::set_plain(false);
+        assert_eq!(crate::ui::sym("✓"), "✓");
+    }
 
-            let spinner = self.create_spinner("Updating Flatpak applications...");
+    #[test]
+    fn profiles_override_only_the_keys_they_list() {
+        let mut table: toml::Table = toml::from_str(
+            "[logging]\nlevel = \"info\"\nfile = \"/var/log/fup.log\"\n[flatpak]\nenabled = true\n",
+        )
+        .unwrap();
+        let profile: toml::Table =
+            toml::from_str("[logging]\nlevel = \"debug\"\n[firmware]\nenabled = false\n").unwrap();
 
-            self.run_command("flatpak", &["update", "-y"], "[Flatpak]")
-                .await?;
+        crate::config::apply_profile(&mut table, &profile);
 
-            spinner.set_message("Removing unused Flatpak runtimes...");
-            self.run_command("flatpak", &["uninstall", "--unused", "-y"], "[Flatpak]")
-                .await?;
+        let config: Config = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(config.logging.level, "debug");
+        assert_eq!(
+            config.logging.file,
+            std::path::Path::new("/var/log/fup.log")
+        );
+        assert!(config.flatpak.enabled);
+        assert!(!config.firmware.enabled);
+    }
 
-            spinner.finish_with_message("Flatpak update complete ✓".green().to_string());
-            self.summary.lock().await.flatpak_updated = true;
-            Ok(())
-        }
+    #[test]
+    fn env_overrides_follow_the_setting_type() {
+        let vars = [
+            ("SYSUPDATER_NOTIFY_MQTT_PASSWORD", "1234"),
+            ("SYSUPDATER_FLATPAK_ENABLED", "false"),
+            ("SYSUPDATER_RETRY_MAX_ATTEMPTS", "5"),
+            (
+                "SYSUPDATER_NETWORK_CHECK_URLS",
+                r#"["https://example.com"]"#,
+            ),
+            ("FUP_LOGGING_LEVEL", "debug"),
+        ]
+        .map(|(k, v)| (k.to_string(), v.to_string()));
+        let mut table = toml::Table::new();
 
-        pub async fn update_firmware(&self) -> Result<()> {
-            if !crate::system::command_exists("fwupdmgr") {
-                info!("fwupdmgr not installed, skipping firmware updates");
-                return Ok(());
-            }
+        let applied = crate::config::apply_env_overrides(&mut table, vars.into_iter());
 
-            let spinner = self.create_spinner("Checking for firmware updates...");
+        assert_eq!(applied.len(), 4);
+        let config: Config = toml::Value::Table(table).try_into().unwrap();
+        assert_eq!(config.notify.mqtt_password, "1234");
+        assert!(!config.flatpak.enabled);
+        assert_eq!(config.retry.max_attempts, 5);
+        assert_eq!(config.network.check_urls, ["https://example.com"]);
+        assert_eq!(config.logging.level, Config::default().logging.level);
+    }
 
-            let _ = self
-                .run_command("fwupdmgr", &["refresh", "--force"], "[Firmware]")
-                .await;
+    #[test]
+    fn verification_ignores_problems_from_before_the_update() {
+        let before = [
+            "dnf5 check: foo-1.0 has missing requires of libbar".to_string(),
+            "old.service".to_string(),
+        ];
+        let after = vec![
+            "dnf5 check: foo-1.0 has missing requires of libbar".to_string(),
+            "dnf5 check: baz-2.0 has missing requires of libqux".to_string(),
+            "old.service".to_string(),
+        ];
 
-            spinner.set_message("Applying firmware updates...");
-            match self
-                .run_command("fwupdmgr", &["update", "-y"], "[Firmware]")
-                .await
-            {
-                Ok(_) => {
-                    spinner.finish_with_message("Firmware update complete ✓".green().to_string());
-                    self.summary.lock().await.firmware_updated = true;
-                }
-                Err(UpdateError::CommandFailed { code: 2, .. }) => {
-                    spinner.finish_with_message("No firmware updates available".yellow().to_string());
-                }
-                Err(e) => return Err(e),
-            }
+        let new: Vec<String> = crate::verify::new_problems(after, &before).collect();
 
-            Ok(())
-        }
+        assert_eq!(new, ["dnf5 check: baz-2.0 has missing requires of libqux"]);
     }
 
-    pub async fn check_reboot_required() -> Result<Option<String>> {
-        if !crate::system::command_exists("dnf5") {
-            return Ok(None);
+    #[test]
+    fn reboot_windows_parse_and_may_wrap_past_midnight() {
+        use chrono::NaiveTime;
+        let at = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+
+        assert_eq!(crate::reboot::parse_time(" 03:30 "), Ok(at(3, 30)));
+        for bad in ["3", "25:00", "03:61", "soon", ""] {
+            assert!(crate::reboot::parse_time(bad).is_err(), "{:?}", bad);
         }
 
-        let output = Command::new("dnf5")
-            .args(["needs-restarting", "-r"])
-            .output()
-            .await?;
+        let night = crate::reboot::parse_window("02:00-05:00").unwrap();
+        assert_eq!(night, (at(2, 0), at(5, 0)));
+        assert!(crate::reboot::in_window(night, at(2, 0)));
+        assert!(!crate::reboot::in_window(night, at(5, 0)));
+        assert!(!crate::reboot::in_window(night, at(23, 0)));
 
-        match output.status.code() {
-            Some(0) => Ok(None),
-            Some(1) => {
-                let details = String::from_utf8_lossy(&output.stdout).to_string();
-                Ok(Some(details))
-            }
-            _ => Ok(None),
+        let wrapping = crate::reboot::parse_window("22:00-01:30").unwrap();
+        assert!(crate::reboot::in_window(wrapping, at(23, 15)));
+        assert!(crate::reboot::in_window(wrapping, at(0, 45)));
+        assert!(!crate::reboot::in_window(wrapping, at(1, 30)));
+        assert!(!crate::reboot::in_window(wrapping, at(12, 0)));
+
+        for bad in ["02:00", "02:00-", "2am-5am", "02:00-05:00-06:00"] {
+            assert!(crate::reboot::parse_window(bad).is_err(), "{:?}", bad);
         }
     }
-}
 
-async fn setup_signal_handler() -> tokio::sync::watch::Receiver<bool> {
-    let (tx, rx) = tokio::sync::watch::channel(false);
+    #[test]
+    fn malformed_reboot_window_fails_config_load() {
+        let path = std::env::temp_dir().join(format!("fup-window-{}.toml", std::process::id()));
+        std::fs::write(&path, "[reboot]\nwindow = \"02:00-5\"\n").unwrap();
 
-    tokio::spawn(async move {
-        let mut sigint =
-            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
-                .expect("Failed to setup SIGINT handler");
+        let result = Config::load(Some(&path), None);
+        std::fs::remove_file(&path).unwrap();
 
-        let mut sigterm =
-            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
-                .expect("Failed to setup SIGTERM handler");
+        let Err(UpdateError::Config(message)) = result else {
+            panic!("expected a config error, got {:?}", result.map(|_| ()));
+        };
+        assert!(message.starts_with("[reboot] window:"), "{}", message);
+        assert!(Config::default().reboot.check().is_ok());
+    }
 
-        tokio::select! {
-            _ = sigint.recv() => {
-                warn!("Received SIGINT, shutting down...");
-            }
-            _ = sigterm.recv() => {
-                warn!("Received SIGTERM, shutting down...");
-            }
+    #[test]
+    fn download_rates_are_checked_at_config_load() {
+        for good in ["2M", "500k", "1.5G", "100000", " 2M "] {
+            assert!(crate::updater::is_valid_rate(good.trim()), "{:?}", good);
+        }
+        for bad in ["", "M", "0", "-1M", "2MB", "50%", "inf", "fast"] {
+            assert!(!crate::updater::is_valid_rate(bad), "{:?}", bad);
         }
 
-        let _ = tx.send(true);
-    });
+        let mut network = Config::default().network;
+        assert!(network.check().is_ok());
+        network.max_download_rate = Some("2MB".into());
+        assert!(matches!(network.check(), Err(UpdateError::Config(_))));
+    }
 
-    rx
-}
+    #[test]
+    fn repo_cache_dirs_metadata_expiry_and_url_variables() {
+        use crate::repos::{expand, is_cache_dir, parse_expire};
+        use std::time::Duration;
 
-fn setup_logging(verbose: u8, quiet: bool, log_file: &PathBuf) {
-    use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+        assert!(is_cache_dir("fedora-3b0ee7e6a0bd7f3c", "fedora"));
+        assert!(!is_cache_dir(
+            "fedora-cisco-openh264-1f2a3b4c5d6e7f80",
+            "fedora"
+        ));
+        assert!(!is_cache_dir("fedora-updates-9c2f", "fedora"));
+        assert!(!is_cache_dir("fedora-", "fedora"));
+        assert!(!is_cache_dir("fedora", "fedora"));
+        assert!(is_cache_dir(
+            "fedora-cisco-openh264-1f2a3b4c5d6e7f80",
+            "fedora-cisco-openh264"
+        ));
 
-    let level = if quiet {
-        Level::ERROR
-    } else {
-        match verbose {
-            0 => Level::INFO,
-            1 => Level::DEBUG,
-            _ => Level::TRACE,
+        let hours = |h: u64| Some(Duration::from_secs(h * 3600));
+        for (value, expected) in [
+            ("3600", hours(1)),
+            ("90m", Some(Duration::from_secs(90 * 60))),
+            ("6h", hours(6)),
+            ("2d", hours(48)),
+            ("-1", None),
+            ("never", None),
+            ("Never", None),
+            // Unparseable values get dnf's default of 48 hours
+            ("soon", hours(48)),
+            ("h", hours(48)),
+        ] {
+            assert_eq!(parse_expire(value), expected, "{:?}", value);
         }
-    };
 
-    let file_appender = tracing_appender::rolling::daily(
-        log_file.parent().unwrap_or(&PathBuf::from("/var/log")),
-        log_file.file_name().unwrap_or_default(),
-    );
+        let vars: std::collections::HashMap<String, String> =
+            [("releasever", "41"), ("basearch", "x86_64")]
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .into();
+        for (url, expected) in [
+            (
+                "https://example.com/$releasever/$basearch/",
+                "https://example.com/41/x86_64/",
+            ),
+            (
+                "https://example.com/${releasever}-${basearch}",
+                "https://example.com/41-x86_64",
+            ),
+            (
+                "https://example.com/$unknown/${nope}/",
+                "https://example.com/$unknown/${nope}/",
+            ),
+            (
+                "https://example.com/${unclosed",
+                "https://example.com/${unclosed",
+            ),
+            ("https://example.com/$", "https://example.com/$"),
+            ("no variables", "no variables"),
+        ] {
+            assert_eq!(expand(url, &vars), expected, "{:?}", url);
+        }
+    }
 
-    let (file_writer, _guard) = tracing_appender::non_blocking(file_appender);
+    #[test]
+    fn etckeeper_failures_other_than_a_clean_tree_are_errors() {
+        assert!(crate::backup::nothing_to_commit(
+            "On branch master\nnothing to commit, working tree clean\n"
+        ));
+        assert!(!crate::backup::nothing_to_commit(""));
+        assert!(!crate::backup::nothing_to_commit(
+            "fatal: Unable to create '/etc/.git/index.lock': File exists."
+        ));
+        assert!(!crate::backup::nothing_to_commit(
+            "etckeeper: pre-commit hook failed; aborting commit"
+        ));
+    }
 
-    tracing_subscriber::registry()
-        .with(
-            fmt::layer()
-                .with_target(false)
-                .with_ansi(true)
-                .with_filter(EnvFilter::from_default_env().add_directive(level.into())),
-        )
-        .with(
-            fmt::layer()
-                .with_target(true)
-                .with_ansi(false)
-                .with_writer(file_writer)
-                .with_filter(EnvFilter::new("info")),
-        )
-        .init();
-}
+    #[test]
+    fn unattended_examples_pass_assume_yes() {
+        // Without a terminal to confirm on, an update run fails unless told -y
+        for example in crate::help::EXAMPLES
+            .iter()
+            .filter(|e| matches!(e.topic, "scripting" | "timers" | "fleet"))
+        {
+            for line in example
+                .commands
+                .iter()
+                .filter(|l| l.contains("fup --update"))
+            {
+                assert!(
+                    line.contains("--assume-yes") || line.contains(" -y"),
+                    "{}: {}",
+                    example.title,
+                    line
+                );
+            }
+        }
+    }
 
-fn print_banner() {
-    println!(
-        "{}",
-        r#"
-╔═══════════════════════════════════════════╗
-║               FUP v2.1.2                  ║
-║     Fedora Utility Patchworker            ║
-╚═══════════════════════════════════════════╝"#
-            .cyan()
-    );
-}
+    #[test]
+    fn unknown_config_keys_suggest_the_closest_one() {
+        use crate::config::{edit_distance, suggest_field};
 
-fn print_usage() {
-    println!(
-        "{}",
-        r#"
-╔═══════════════════════════════════════════╗
-║               FUP v2.1.2                  ║
-║     Fedora Utility Patchworker            ║
-╚═══════════════════════════════════════════╝"#
-            .cyan()
-    );
+        for (a, b, distance) in [
+            ("", "", 0),
+            ("keep", "keep", 0),
+            ("", "keep", 4),
+            ("kep", "keep", 1),
+            ("kepe", "keep", 2),
+            ("level", "lvl", 2),
+            ("größe", "grosse", 3),
+        ] {
+            assert_eq!(edit_distance(a, b), distance, "{:?} {:?}", a, b);
+            assert_eq!(edit_distance(b, a), distance, "{:?} {:?}", b, a);
+        }
 
-    println!("\n{}\n", "USAGE".yellow().bold());
-    println!("    {} [OPTIONS]\n", "sudo fup".green());
+        let expected = "expected one of `enabled`, `timeout_mins`, `skip`, `all_users`";
+        for (unknown, hint) in [
+            ("enabeld", Some("enabled")),
+            ("timeout_min", Some("timeout_mins")),
+            ("skips", Some("skip")),
+            ("allusers", Some("all_users")),
+            ("colour", None),
+        ] {
+            let message = format!("unknown field `{}`, {}", unknown, expected);
+            assert_eq!(suggest_field(&message), hint, "{}", unknown);
+        }
+        assert_eq!(suggest_field("missing field `level`"), None);
 
-    println!("{}\n", "COMMANDS".yellow().bold());
+        let Err(UpdateError::Config(message)) = Config::parse(
+            std::path::Path::new("fup.toml"),
+            "[logging]\nlevle = \"debug\"\n",
+        ) else {
+            panic!("an unknown key was accepted");
+        };
+        assert!(
+            message.contains("hint: did you mean `level`?"),
+            "{}",
+            message
+        );
+    }
 
-    let commands = [
-        ("-r, --refresh", "Check and display available updates"),
-        ("-u, --update-all", "Update everything (system + flatpak)"),
-        ("    --update-system", "Update only system packages (dnf5)"),
-        ("    --update-flatpak", "Update only Flatpak applications"),
-        ("    --update-firmware", "Update only firmware"),
-    ];
+    #[test]
+    fn progress_is_parsed_from_dnf_and_flatpak_output() {
+        use crate::progress::{Progress, parse};
+        let at = |phase, current, total, name: &str| {
+            Some(Progress {
+                phase,
+                current,
+                total,
+                name: name.to_string(),
+            })
+        };
 
-    for (cmd, desc) in commands {
-        println!("    {}  {}", cmd.green(), desc);
+        for (stage, line, expected) in [
+            (
+                Stage::System,
+                "[ 3/41] firefox-130.0-1.fc40.x86_64  100% |   9.1 MiB/s |  68.2 MiB |  00m07s",
+                at("Downloading", 3, 41, "firefox-130.0-1.fc40.x86_64"),
+            ),
+            (
+                Stage::System,
+                "[12/84] Upgrading firefox-0:130.0-1.fc40.x86_64  100% |  66.0 MiB/s | 251.3 MiB |  00m04s",
+                at("Installing", 12, 84, "firefox-0:130.0-1.fc40.x86_64"),
+            ),
+            (
+                Stage::System,
+                "[80/84] Cleaning up firefox-0:129.0-1.fc40.x86_64  100% |",
+                at("Installing", 80, 84, "firefox-0:129.0-1.fc40.x86_64"),
+            ),
+            (
+                Stage::System,
+                "[ 1/84] Verify package files  100% |",
+                at("Preparing", 1, 84, "Verify"),
+            ),
+            // Only the last redraw of a line counts
+            (
+                Stage::System,
+                "[1/2] bash-5.2-1.fc41.x86_64  10%\r[2/2] glibc-2.40-1.fc41.x86_64  40%",
+                at("Downloading", 2, 2, "glibc-2.40-1.fc41.x86_64"),
+            ),
+            (Stage::System, "Transaction Summary:", None),
+            (Stage::System, "[a/b] nonsense", None),
+            (Stage::Flatpak, "Updating 2/5…", at("Updating", 2, 5, "")),
+            (
+                Stage::Flatpak,
+                "Installing app/org.mozilla.firefox/x86_64/stable",
+                at("Installing", 0, 0, "org.mozilla.firefox"),
+            ),
+            (
+                Stage::Flatpak,
+                "Uninstalling org.gnome.Platform",
+                at("Removing", 0, 0, "org.gnome.Platform"),
+            ),
+            (Stage::Flatpak, "Looking for updates…", None),
+            (Stage::Firmware, "[ 1/2] Downloading…", None),
+        ] {
+            assert_eq!(parse(stage, line), expected, "{:?}", line);
+        }
     }
 
-    println!("\n{}\n", "OPTIONS".yellow().bold());
+    #[test]
+    fn package_diffs_list_upgrades_additions_and_removals() {
+        use crate::pkgdiff::{Snapshot, counts, diff};
+        let snapshot = |packages: &[(Stage, &str, &str)]| Snapshot {
+            packages: packages
+                .iter()
+                .map(|(stage, name, v)| ((*stage, name.to_string()), v.to_string()))
+                .collect(),
+        };
+        let before = snapshot(&[
+            (Stage::System, "bash.x86_64", "5.2.26-1.fc40"),
+            (Stage::System, "glibc.x86_64", "2.39-1.fc40"),
+            (Stage::System, "pulseaudio.x86_64", "17.0-1.fc40"),
+            (
+                Stage::System,
+                "kernel-core.x86_64",
+                "6.10.5-200.fc40, 6.10.9-200.fc40",
+            ),
+            (Stage::Flatpak, "org.mozilla.firefox", "130.0 (abc123)"),
+        ]);
+        let after = snapshot(&[
+            (Stage::System, "bash.x86_64", "5.2.32-1.fc41"),
+            (Stage::System, "glibc.x86_64", "2.39-1.fc40"),
+            (Stage::System, "pipewire-pulseaudio.x86_64", "1.2.0-1.fc41"),
+            (
+                Stage::System,
+                "kernel-core.x86_64",
+                "6.10.9-200.fc40, 6.11.4-301.fc41",
+            ),
+            (Stage::Flatpak, "org.mozilla.firefox", "130.0 (def456)"),
+        ]);
 
-    let options = [
-        ("-f, --firmware", "Include firmware in --update-all"),
-        ("-n, --dry-run", "Preview actions without executing"),
-        ("    --no-reboot-prompt", "Skip reboot prompt after updates"),
-        ("    --no-network-check", "Skip connectivity verification"),
-        ("    --parallel", "Run updates concurrently"),
-        ("-c, --config <FILE>", "Use custom config file"),
-        ("-v, --verbose", "Increase verbosity (-v, -vv, -vvv)"),
-        ("-q, --quiet", "Minimal output"),
-    ];
+        let changes = diff(&before, &after);
 
-    for (opt, desc) in options {
-        println!("    {}  {}", opt.cyan(), desc);
+        let rows: Vec<(Stage, &str, &str)> = changes
+            .iter()
+            .map(|c| (c.backend, c.name.as_str(), c.kind()))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (Stage::System, "bash.x86_64", "upgraded"),
+                (Stage::System, "kernel-core.x86_64", "upgraded"),
+                (Stage::System, "pipewire-pulseaudio.x86_64", "added"),
+                (Stage::System, "pulseaudio.x86_64", "removed"),
+                (Stage::Flatpak, "org.mozilla.firefox", "upgraded"),
+            ]
+        );
+        assert_eq!(counts(&changes), "3 upgraded, 1 added, 1 removed");
+        assert_eq!(changes[2].transition(), "+ 1.2.0-1.fc41");
+        assert_eq!(changes[3].transition(), "- 17.0-1.fc40");
+        assert!(diff(&after, &after).is_empty());
     }
 
-    println!("\n{}\n", "EXAMPLES".yellow().bold());
-
-    let examples = [
-        ("fup --refresh", "Show what updates are available"),
-        ("fup --update-all", "Update system and flatpak"),
-        ("fup --update-all -f", "Update everything including firmware"),
-        ("fup --update-system", "Update only dnf5 packages"),
-        ("fup --dry-run -u", "Preview full update"),
-    ];
+    #[test]
+    fn log_files_rotate_by_day_and_size_and_keep_only_the_newest() {
+        use crate::logfile::{RotatingFile, Rotation};
+        use std::io::Write;
+        use std::time::{Duration, SystemTime};
 
-    for (cmd, desc) in examples {
-        println!("    {}  {}", format!("sudo {}", cmd).green(), format!("# {}", desc).dimmed());
-    }
+        let dir = std::env::temp_dir().join(format!("fup-logrotate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let rotated = |dir: &std::path::Path| -> Vec<String> {
+            let mut names: Vec<String> = std::fs::read_dir(dir)
+                .unwrap()
+                .flatten()
+                .filter_map(|e| e.file_name().into_string().ok())
+                .filter(|n| n != "fup.log")
+                .collect();
+            names.sort();
+            names
+        };
+        // Rotated files are named after the log's last write, to the second
+        let last_written = |path: &std::path::Path, ago: u64| {
+            let file = std::fs::File::options().append(true).open(path).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(ago))
+                .unwrap();
+        };
 
-    println!(
-        "\n{}\n    /etc/fup.toml\n    ~/.config/fup/config.toml\n",
-        "CONFIG FILES".yellow().bold()
-    );
-}
+        let mut config = test_config().logging;
+        config.file = dir.join("fup.log");
+        config.compress = false;
+        config.keep = 2;
 
-fn print_available_updates(updates: &updater::AvailableUpdates) {
-    println!("\n{}", "═".repeat(50).cyan());
-    println!("{}", "         Available Updates".cyan().bold());
-    println!("{}\n", "═".repeat(50).cyan());
+        for (rotation, case) in [(Rotation::Never, "never"), (Rotation::Daily, "daily")] {
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(&config.file, "yesterday\n").unwrap();
+            last_written(&config.file, 2 * 86400);
+            config.rotation = rotation;
 
-    if updates.is_empty() {
-        println!("  {} Your system is up to date!\n", "✓".green().bold());
-        return;
-    }
+            let mut log = RotatingFile::open(&config).unwrap();
+            log.write_all(b"today\n").unwrap();
+            log.flush().unwrap();
 
-    // System packages
-    if !updates.system.is_empty() {
-        println!(
-            "  {} {} package(s)\n",
-            "System".yellow().bold(),
-            updates.system.len().to_string().white().bold()
-        );
-        for pkg in updates.system.iter().take(15) {
-            let parts: Vec<&str> = pkg.split_whitespace().collect();
-            if let Some(name) = parts.first() {
-                let version = parts.get(1).unwrap_or(&"");
-                println!("    {} {}", "•".dimmed(), format!("{} {}", name, version.dimmed()));
+            let names = rotated(&dir);
+            if rotation == Rotation::Never {
+                assert!(names.is_empty(), "{}: {:?}", case, names);
+                assert_eq!(
+                    std::fs::read_to_string(&config.file).unwrap(),
+                    "yesterday\ntoday\n"
+                );
+            } else {
+                assert_eq!(names.len(), 1, "{}: {:?}", case, names);
+                assert_eq!(
+                    std::fs::read_to_string(dir.join(&names[0])).unwrap(),
+                    "yesterday\n"
+                );
+                assert_eq!(std::fs::read_to_string(&config.file).unwrap(), "today\n");
             }
+            std::fs::remove_dir_all(&dir).unwrap();
         }
-        if updates.system.len() > 15 {
-            println!(
-                "    {} ...and {} more",
-                "•".dimmed(),
-                (updates.system.len() - 15).to_string().yellow()
-            );
+
+        // A write that would take the file past max_size_mb starts a new one
+        std::fs::create_dir_all(&dir).unwrap();
+        config.rotation = Rotation::Size;
+        config.max_size_mb = 1;
+        let chunk = vec![b'x'; 600 * 1024];
+        let mut log = RotatingFile::open(&config).unwrap();
+        for ago in [40, 30, 20, 10] {
+            log.write_all(&chunk).unwrap();
+            last_written(&config.file, ago);
         }
-        println!();
-    }
+        log.write_all(&chunk).unwrap();
+        log.flush().unwrap();
 
-    // Flatpak
-    if !updates.flatpak.is_empty() {
-        println!(
-            "  {} {} app(s)\n",
-            "Flatpak".yellow().bold(),
-            updates.flatpak.len().to_string().white().bold()
+        assert_eq!(rotated(&dir).len(), 2, "{:?}", rotated(&dir));
+        assert_eq!(
+            std::fs::metadata(&config.file).unwrap().len(),
+            chunk.len() as u64
         );
-        for app in updates.flatpak.iter().take(10) {
-            let name = app.split_whitespace().next().unwrap_or(app.as_str());
-            println!("    {} {}", "•".dimmed(), name);
-        }
-        if updates.flatpak.len() > 10 {
-            println!(
-                "    {} ...and {} more",
-                "•".dimmed(),
-                (updates.flatpak.len() - 10).to_string().yellow()
+        for name in rotated(&dir) {
+            assert_eq!(
+                std::fs::metadata(dir.join(name)).unwrap().len(),
+                chunk.len() as u64
             );
         }
-        println!();
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    // Firmware
-    if !updates.firmware.is_empty() {
-        println!(
-            "  {} {} device(s)\n",
-            "Firmware".yellow().bold(),
-            updates.firmware.len().to_string().white().bold()
-        );
-        for fw in &updates.firmware {
-            println!("    {} {}", "•".dimmed(), fw);
+    #[test]
+    fn cache_ages_take_a_unit() {
+        use chrono::TimeDelta;
+
+        for (text, expected) in [
+            ("90", TimeDelta::seconds(90)),
+            ("90s", TimeDelta::seconds(90)),
+            ("30m", TimeDelta::minutes(30)),
+            (" 6h ", TimeDelta::hours(6)),
+            ("2d", TimeDelta::days(2)),
+            ("0", TimeDelta::zero()),
+        ] {
+            assert_eq!(crate::cache::parse_age(text), Ok(expected), "{:?}", text);
+        }
+        for text in ["", "h", "-1h", "1.5h", "2w", "6 h", "6hours"] {
+            assert!(crate::cache::parse_age(text).is_err(), "{:?}", text);
         }
-        println!();
     }
 
-    println!("{}", "═".repeat(50).cyan());
-    println!(
-        "  Total: {} update(s) available",
-        updates.total_count().to_string().green().bold()
-    );
-    println!(
-        "  Run {} to install\n",
-        "sudo fup --update-all".cyan()
-    );
-}
-
-fn print_summary(summary: &updater::UpdateSummary) {
-    println!("\n{}", "═".repeat(45).cyan());
-    println!("{}", "           Update Summary".cyan().bold());
-    println!("{}", "═".repeat(45).cyan());
+    #[test]
+    fn new_updates_are_told_from_ones_already_pending() {
+        use crate::updater::{AvailableUpdates, ItemSize};
+        let rows = |rows: &[&str]| rows.iter().map(|r| r.to_string()).collect::<Vec<_>>();
 
-    let check = "✓".green();
-    let skip = "○".yellow();
+        let previous = AvailableUpdates {
+            system: rows(&[
+                "bash.x86_64   5.2.32-1.fc41  updates",
+                "glibc.x86_64 2.40-1.fc41 updates",
+            ]),
+            flatpak: rows(&["org.mozilla.firefox stable 130.0 flathub"]),
+            firmware: rows(&["System Firmware: 1.18.0 → 1.19.1"]),
+            ..AvailableUpdates::default()
+        };
+        let now = AvailableUpdates {
+            system: rows(&[
+                // Only the column padding changed
+                "bash.x86_64 5.2.32-1.fc41 updates",
+                "glibc.x86_64 2.40-2.fc41 updates",
+                "curl.x86_64 8.9.1-2.fc41 updates",
+            ]),
+            flatpak: rows(&["org.mozilla.firefox stable 130.0 flathub"]),
+            flatpak_runtimes: rows(&["org.gnome.Platform 47 47.1 flathub"]),
+            firmware: rows(&["System Firmware: 1.18.0 → 1.19.1"]),
+            sizes: [
+                ("bash.x86_64 5.2.32-1.fc41 updates", 100),
+                ("curl.x86_64 8.9.1-2.fc41 updates", 200),
+            ]
+            .map(|(row, download)| {
+                (
+                    row.to_string(),
+                    ItemSize {
+                        download,
+                        installed: 0,
+                    },
+                )
+            })
+            .into(),
+        };
 
-    println!(
-        "  System (dnf5):  {}",
-        if summary.system_updated { &check } else { &skip }
-    );
-    println!(
-        "  Flatpak:        {}",
-        if summary.flatpak_updated { &check } else { &skip }
-    );
-    println!(
-        "  Firmware:       {}",
-        if summary.firmware_updated { &check } else { &skip }
-    );
+        let new = now.new_since(&previous);
 
-    if !summary.errors.is_empty() {
-        println!("\n  {} Errors:", "✗".red());
-        for err in &summary.errors {
-            println!("    • {}", err.red());
-        }
+        assert_eq!(
+            new.system,
+            [
+                "glibc.x86_64 2.40-2.fc41 updates",
+                "curl.x86_64 8.9.1-2.fc41 updates"
+            ]
+        );
+        assert!(new.flatpak.is_empty());
+        assert_eq!(new.flatpak_runtimes, ["org.gnome.Platform 47 47.1 flathub"]);
+        assert!(new.firmware.is_empty());
+        assert_eq!(
+            new.sizes.keys().collect::<Vec<_>>(),
+            ["curl.x86_64 8.9.1-2.fc41 updates"]
+        );
+        assert_eq!(
+            now.new_since(&AvailableUpdates::default()).total_count(),
+            now.total_count()
+        );
     }
 
-    println!("{}", "═".repeat(45).cyan());
-}
-
-async fn prompt_reboot() -> error::Result<()> {
-    use std::io::{self, Write};
+    #[test]
+    fn commands_are_found_like_a_shell_would() {
+        use std::ffi::OsStr;
+        use std::os::unix::fs::PermissionsExt;
+        use std::path::{Path, PathBuf};
 
-    println!("\n{}", "A system reboot is recommended.".yellow().bold());
-    println!("  1. Reboot now");
-    println!("  2. Exit without rebooting");
-    print!("\nChoice [1/2]: ");
-    io::stdout().flush()?;
+        let dir = std::env::temp_dir().join(format!("fup-path-{}", std::process::id()));
+        let (first, second) = (dir.join("first"), dir.join("second"));
+        for (path, mode) in [
+            (first.join("tool"), 0o644),
+            (second.join("tool"), 0o755),
+            (second.join("dir-tool"), 0o755),
+        ] {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+        }
+        std::fs::create_dir_all(first.join("dir-tool")).unwrap();
+        let path = std::env::join_paths([Path::new(""), &first, &second]).unwrap();
+        let search = |cmd: &str| crate::system::search_path(cmd, Some(&path));
 
-    let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
+        // Skips the non-executable file and the directory of the same name
+        assert_eq!(search("tool"), Some(second.join("tool")));
+        assert_eq!(search("dir-tool"), Some(second.join("dir-tool")));
+        assert_eq!(search("missing"), None);
+        // Paths are taken as they are, and an empty PATH entry doesn't mean the current directory
+        let tool = second.join("tool").display().to_string();
+        assert_eq!(search(&tool), Some(PathBuf::from(&tool)));
+        assert_eq!(search(&first.join("tool").display().to_string()), None);
+        assert_eq!(crate::system::search_path("sh", Some(OsStr::new(""))), None);
+        assert!(crate::system::search_path("sh", None).is_some());
 
-    match input.trim() {
-        "1" => {
-            info!("User requested reboot");
-            Command::new("systemctl").args(["reboot"]).status().await?;
-        }
-        _ => {
-            println!("{}", "Exiting without reboot.".green());
-        }
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 
-    Ok(())
-}
+    #[test]
+    fn unsigned_repos_are_found_across_repo_dirs() {
+        let sections = crate::repos::ini_sections(
+            "stray = ignored\n# comment\n; comment\n\n[ main ]\n gpgcheck = 1 \nempty=\n[other]\nbaseurl=a=b\n",
+        );
+        let names: Vec<&str> = sections.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["main", "other"]);
+        assert_eq!(sections[0].1.get("gpgcheck").map(String::as_str), Some("1"));
+        assert_eq!(sections[0].1.get("empty").map(String::as_str), Some(""));
+        assert_eq!(
+            sections[1].1.get("baseurl").map(String::as_str),
+            Some("a=b")
+        );
+        assert_eq!(sections[1].1.len(), 1);
 
-async fn run(args: cli::Args) -> error::Result<()> {
-    let config = config::Config::load(args.config.as_ref());
-    let shutdown = setup_signal_handler().await;
+        let root = std::env::temp_dir().join(format!("fup-repos-{}", std::process::id()));
+        for (file, content) in [
+            ("etc/dnf/dnf.conf", "[main]\ngpgcheck=True\n"),
+            (
+                "etc/yum.repos.d/local.repo",
+                "[signed]\nbaseurl=https://example.com\n\
+                 [unsigned]\ngpgcheck=0\n\
+                 [disabled]\nenabled=0\ngpgcheck=0\n\
+                 [packages-unchecked]\ngpgcheck=1\npkg_gpgcheck=no\n",
+            ),
+            // Overridden by the file of the same name in /etc
+            (
+                "usr/share/dnf5/repos.d/local.repo",
+                "[shadowed]\ngpgcheck=0\n",
+            ),
+            (
+                "usr/share/dnf5/repos.d/vendor.repo",
+                "[signed]\ngpgcheck=0\n[vendor]\ngpgcheck=off\n",
+            ),
+            ("etc/yum.repos.d/notes.txt", "[text]\ngpgcheck=0\n"),
+        ] {
+            let path = root.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
 
-    // Network check
-    if !args.no_network_check {
-        info!("Checking network connectivity...");
-        let timeout = Duration::from_secs(config.network.timeout_secs);
-        system::check_network(&config.network.check_url, timeout).await?;
-        debug!("Network check passed");
+        assert_eq!(
+            crate::preflight::unsigned_repos(&root),
+            ["unsigned", "packages-unchecked", "vendor"]
+        );
+
+        // Without gpgcheck in [main] repos default to unchecked, as in libdnf5
+        std::fs::write(root.join("etc/dnf/dnf.conf"), "[main]\n").unwrap();
+        assert_eq!(
+            crate::preflight::unsigned_repos(&root),
+            ["signed", "unsigned", "packages-unchecked", "vendor"]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
     }
 
-    let updater = updater::Updater::new(args.dry_run, args.quiet);
+    #[test]
+    fn background_runs_lower_cpu_and_io_priority() {
+        use crate::system::{IoClass, lower_priority};
+        use nix::libc;
 
-    // Handle --refresh: show available updates
-    if args.refresh {
-        let updates = updater.check_available_updates().await?;
-        print_available_updates(&updates);
-        return Ok(());
+        // Both priorities are per thread on Linux, so the test runner's own stay as they are
+        std::thread::spawn(|| {
+            // SAFETY: plain syscalls on the calling thread.
+            let current = || unsafe {
+                (
+                    libc::getpriority(libc::PRIO_PROCESS, 0),
+                    libc::syscall(libc::SYS_ioprio_get, 1, 0) as i32,
+                )
+            };
+            // Only raising the niceness is allowed without privileges
+            let nicer = (current().0 + 1).min(19);
+            for (nice, class, expected) in [
+                (nicer, IoClass::BestEffort, (nicer, (2 << 13) | 7)),
+                (25, IoClass::Idle, (19, 3 << 13)),
+            ] {
+                lower_priority(nice, class).unwrap();
+                assert_eq!(current(), expected, "{} {}", nice, class.label());
+            }
+        })
+        .join()
+        .unwrap();
     }
 
-    let summary = Arc::new(Mutex::new(updater::UpdateSummary::default()));
-
-    // Determine what to update
-    let do_system = args.update_all || args.update_system;
-    let do_flatpak = args.update_all || args.update_flatpak;
-    let do_firmware = args.update_firmware || (args.update_all && args.firmware);
+    #[test]
+    fn packagekit_is_only_told_about_changes_unless_asked_to_refresh() {
+        let mut config = Config::default().packagekit;
+        let commands = |config: &crate::config::PackageKitConfig| -> Vec<String> {
+            crate::packagekit::refresh_commands(config)
+                .into_iter()
+                .map(|(cmd, args)| format!("{} {}", cmd, args.join(" ")))
+                .collect()
+        };
+        let state_changed = "busctl call org.freedesktop.PackageKit /org/freedesktop/PackageKit \
+                             org.freedesktop.PackageKit StateHasChanged s posttrans";
 
-    if !args.quiet {
-        print_banner();
+        assert_eq!(commands(&config), [state_changed]);
+        config.refresh_cache = true;
+        assert_eq!(
+            commands(&config),
+            [state_changed, "pkcon refresh force --noninteractive"]
+        );
+        config.refresh_after = false;
+        config.refresh_cache = false;
+        assert!(commands(&config).is_empty());
     }
 
-    // Run updates
-    if args.parallel && (do_system || do_flatpak || do_firmware) {
-        info!("Running updates in parallel");
-        let (sys_res, flat_res, fw_res) = tokio::join!(
-            async {
-                if do_system { updater.update_system().await } else { Ok(()) }
-            },
-            async {
-                if do_flatpak { updater.update_flatpak().await } else { Ok(()) }
-            },
-            async {
-                if do_firmware { updater.update_firmware().await } else { Ok(()) }
-            },
-        );
+    #[test]
+    fn ui_theme_from_config() {
+        let config: Config = toml::from_str(
+            "[ui]\naccent = \"bright blue\"\npalette = \"colorblind\"\nsuccess_glyph = \"OK\"\n",
+        )
+        .unwrap();
+        let theme = crate::ui::Theme::new(&config.ui);
+        assert_eq!(theme.accent.dotted(), "blue.bright");
+        assert_eq!(theme.success, colored::Color::BrightBlue);
+        assert_ne!(theme.failure, colored::Color::Red);
+        assert_eq!(theme.glyph("✓"), "OK");
+        assert_eq!(theme.glyph("✗"), "✗");
 
-        for res in [sys_res, flat_res, fw_res] {
-            if let Err(e) = res {
-                summary.lock().await.errors.push(e.to_string());
-            }
-        }
-    } else {
-        // Sequential execution (default)
-        if do_system {
-            if let Err(e) = updater.update_system().await {
-                error!("System update failed: {}", e);
-                summary.lock().await.errors.push(e.to_string());
-            }
-        }
+        assert!(toml::from_str::<Config>("[ui]\naccent = \"teal\"\n").is_err());
+    }
 
-        if *shutdown.borrow() {
-            return Err(error::UpdateError::Cancelled);
-        }
+    #[test]
+    fn translations_match_the_english_catalog() {
+        use crate::i18n;
 
-        if do_flatpak {
-            if let Err(e) = updater.update_flatpak().await {
-                error!("Flatpak update failed: {}", e);
-                summary.lock().await.errors.push(e.to_string());
+        let placeholders = |text: &str| -> Vec<String> {
+            text.split("{ $")
+                .skip(1)
+                .filter_map(|rest| rest.split_once(" }"))
+                .map(|(name, _)| name.into())
+                .collect()
+        };
+        let english = i18n::parse(i18n::LOCALES[0].1);
+        for (name, source) in &i18n::LOCALES[1..] {
+            for (key, text) in i18n::parse(source) {
+                let original = english
+                    .get(key)
+                    .unwrap_or_else(|| panic!("{}: {} is not in en.ftl", name, key));
+                assert_eq!(
+                    placeholders(text),
+                    placeholders(original),
+                    "{}: {}",
+                    name,
+                    key
+                );
             }
         }
 
-        if *shutdown.borrow() {
-            return Err(error::UpdateError::Cancelled);
-        }
-
-        if do_firmware {
-            if let Err(e) = updater.update_firmware().await {
-                error!("Firmware update failed: {}", e);
-                summary.lock().await.errors.push(e.to_string());
+        let env = |vars: &'static [(&str, &str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
             }
-        }
-    }
+        };
+        let de = 1;
+        assert_eq!(i18n::resolve("auto", env(&[("LANG", "de_DE.UTF-8")])), de);
+        assert_eq!(
+            i18n::resolve("auto", env(&[("LANG", "de_DE.UTF-8"), ("LC_ALL", "C")])),
+            0
+        );
+        assert_eq!(
+            i18n::resolve(
+                "auto",
+                env(&[("LANG", "fr_FR.UTF-8"), ("LANGUAGE", "fr:de")])
+            ),
+            de
+        );
+        assert_eq!(i18n::resolve("auto", env(&[])), 0);
+        assert_eq!(i18n::resolve("de", env(&[("LANG", "en_US.UTF-8")])), de);
 
-    // Print summary
-    let final_summary = updater.summary().await;
-    print_summary(&final_summary);
+        let args = [("mins", "30".to_string()), ("cmd", "dnf5".to_string())];
+        assert_eq!(
+            i18n::translate(0, "error-timeout", &args),
+            "Command timed out after 30 min without output: dnf5"
+        );
+        assert!(
+            i18n::translate(de, "error-timeout", &args)
+                .starts_with("Zeitüberschreitung nach 30 Min.")
+        );
+        assert_eq!(
+            i18n::translate(de, "no-such-message", &[]),
+            "no-such-message"
+        );
 
-    // Check if reboot needed
-    if !args.no_reboot_prompt && !args.dry_run {
-        if let Ok(Some(reason)) = updater::check_reboot_required().await {
-            info!("Reboot required: {}", reason);
-            prompt_reboot().await?;
-        } else {
-            println!("\n{}", "No reboot required.".green());
-        }
+        // Whatever LANG says, stored and parsed text stays English
+        assert_eq!(
+            UpdateError::Config("bad".into()).english(),
+            "Configuration error: bad"
+        );
     }
 
-    Ok(())
-}
+    #[test]
+    fn porcelain_events_are_tagged_json() {
+        use crate::events::{Event, Stream};
 
-#[tokio::main]
-async fn main() -> ExitCode {
-    let args = cli::Args::parse();
+        let start = serde_json::to_value(Event::PhaseStart {
+            stage: Stage::Flatpak,
+        })
+        .unwrap();
+        assert_eq!(
+            start,
+            serde_json::json!({ "event": "phase-start", "stage": "flatpak" })
+        );
 
-    // If no action specified, show usage
-    if args.is_default() {
-        print_usage();
-        return ExitCode::SUCCESS;
+        let output = serde_json::to_value(Event::CommandOutput {
+            stage: Stage::System,
+            command: "dnf5 update --refresh -y",
+            stream: Stream::Stderr,
+            line: "Error: mirror timeout",
+        })
+        .unwrap();
+        assert_eq!(output["event"], "command-output");
+        assert_eq!(output["stream"], "stderr");
+        assert_eq!(output["line"], "Error: mirror timeout");
+
+        let stats = crate::updater::StageStats {
+            packages: 3,
+            failed: true,
+            ..Default::default()
+        };
+        let end = serde_json::to_value(Event::PhaseEnd {
+            stage: Stage::System,
+            stats: &stats,
+            error: Some("boom"),
+        })
+        .unwrap();
+        assert_eq!(end["event"], "phase-end");
+        assert_eq!(end["packages"], 3);
+        assert_eq!(end["failed"], true);
+        assert_eq!(end["error"], "boom");
     }
 
-    let config = config::Config::load(args.config.as_ref());
-    setup_logging(args.verbose, args.quiet, &config.logging.file);
+    #[test]
+    fn maintenance_windows() {
+        let at =
+            |text: &str| chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap();
+        let config = ScheduleConfig {
+            allowed_windows: vec!["Sat 02:00-06:00".into(), "Mon-Fri 23:00-01:00".into()],
+            ..Default::default()
+        };
 
-    // Root check (not needed for just showing help)
-    if let Err(e) = system::check_root() {
-        eprintln!("{} {}", "Error:".red().bold(), e);
-        return ExitCode::from(1);
-    }
+        // 2026-10-17 is a Saturday
+        assert_eq!(
+            schedule::next_opening(&config, at("2026-10-17 03:00")).unwrap(),
+            None
+        );
+        // Friday's window runs past midnight into Saturday
+        assert_eq!(
+            schedule::next_opening(&config, at("2026-10-17 00:30")).unwrap(),
+            None
+        );
+        assert_eq!(
+            schedule::next_opening(&config, at("2026-10-17 07:00")).unwrap(),
+            Some(at("2026-10-19 23:00"))
+        );
+        assert_eq!(
+            schedule::next_opening(&config, at("2026-10-16 12:00")).unwrap(),
+            Some(at("2026-10-16 23:00"))
+        );
 
-    match run(args).await {
-        Ok(()) => {
-            info!("Operation completed successfully");
-            ExitCode::SUCCESS
-        }
-        Err(error::UpdateError::Cancelled) => {
-            eprintln!("\n{}", "Operation cancelled.".yellow());
-            ExitCode::from(130)
-        }
-        Err(e) => {
-            error!("Operation failed: {}", e);
-            eprintln!("{} {}", "Error:".red().bold(), e);
-            ExitCode::FAILURE
-        }
+        let invalid = ScheduleConfig {
+            allowed_windows: vec!["Caturday 02:00-06:00".into()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            schedule::next_opening(&invalid, at("2026-10-17 03:00")),
+            Err(UpdateError::Config(_))
+        ));
     }
-}
\ No newline at end of file
+}